@@ -1,120 +1,480 @@
-use crate::resp::{RespError, RespValue};
+use crate::commands::{self, COMMANDS, CommandSpec};
+use crate::config::{self, RuntimeConfig};
+use crate::network::AsyncStream;
+use crate::replication::PeerStatus;
+use crate::resp::{Protocol, RespError, RespValue};
 use crate::server::CommandResult;
 
 use crate::types::VersionVector;
 use crate::wrapper::ServerWrapper;
-use bytes::{Buf, Bytes, BytesMut};
-use std::io::Cursor;
-use std::sync::Arc;
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, error, info};
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, debug, error, info, info_span, warn};
+
+/// How many entries SLOWLOG GET returns when not given a count
+const SLOWLOG_GET_DEFAULT: usize = 10;
+
+/// Queued responses are written out once they reach this, even part way through a batch
+const RESPONSE_WRITE_AT: usize = 64 * 1024;
+
+/// The most capacity a connection's response buffer keeps between batches
+const RESPONSE_BUF_RETAIN: usize = 256 * 1024;
+
+/// The reply to a connection over `server.max_connections`, before it is closed
+const MAX_CLIENTS_REACHED: &str = "ERR max number of clients reached";
+
+/// The client connections an API server has open, at most `max` of them
+#[derive(Debug)]
+pub struct Clients {
+    permits: Arc<Semaphore>,
+    max: usize,
+}
+
+impl Clients {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            max,
+        }
+    }
+
+    /// Room for one more connection, held until it closes, or None at the limit
+    fn admit(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.permits).try_acquire_owned().ok()
+    }
+
+    /// How many connections are open
+    pub fn open(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+
+/// A client connection's socket, and the responses queued for its next write
+///
+/// The response buffer is kept from one batch of commands to the next rather than
+/// allocated per response.
+struct Connection<S> {
+    socket: S,
+    out: BytesMut,
+    /// Every connection the server has open, for INFO
+    clients: Arc<Clients>,
+}
+
+impl<S: AsyncStream> Connection<S> {
+    fn new(socket: S, clients: Arc<Clients>) -> Self {
+        Self {
+            socket,
+            out: BytesMut::new(),
+            clients,
+        }
+    }
+
+    /// Queue a response, never failing, so command handlers can return this directly
+    fn respond(&mut self, protocol: Protocol, response: RespValue) -> std::io::Result<()> {
+        response.serialize(protocol, &mut self.out);
+        Ok(())
+    }
+
+    /// Write out what is queued once it reaches `RESPONSE_WRITE_AT`, without flushing
+    async fn write_if_full(&mut self) -> std::io::Result<()> {
+        if self.out.len() >= RESPONSE_WRITE_AT {
+            self.socket.write_all(&self.out).await?;
+            self.out.clear();
+        }
+        Ok(())
+    }
+
+    /// Write out and flush what is queued, ready for the next batch
+    ///
+    /// A buffer that grew past `RESPONSE_BUF_RETAIN` is let go of rather than kept for
+    /// the life of the connection.
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if !self.out.is_empty() {
+            self.socket.write_all(&self.out).await?;
+            self.socket.flush().await?;
+        }
+        if self.out.capacity() > RESPONSE_BUF_RETAIN {
+            self.out = BytesMut::new();
+        } else {
+            self.out.clear();
+        }
+        Ok(())
+    }
+}
 
 /// API server handling RESP protocol over TCP
 ///
 /// Receives Redis-protocol commands, calls ServerWrapper methods,
-/// and returns RESP-formatted responses.
+/// and returns RESP-formatted responses. Plaintext unless given a TLS acceptor.
 pub struct ApiServer {
     wrapper: Arc<ServerWrapper>,
     addr: String,
+    slowlog: Arc<SlowLog>,
+    tls: Option<TlsAcceptor>,
+    shutdown: CancellationToken,
+    read_buffer_size: usize,
+    max_request_size: usize,
+    clients: Arc<Clients>,
 }
 
 impl ApiServer {
     pub fn new(wrapper: Arc<ServerWrapper>, addr: String) -> Self {
-        Self { wrapper, addr }
+        Self {
+            slowlog: Arc::new(SlowLog::new(Arc::clone(wrapper.runtime_config()))),
+            wrapper,
+            addr,
+            tls: None,
+            shutdown: CancellationToken::new(),
+            read_buffer_size: config::default_read_buffer_size(),
+            max_request_size: config::default_max_request_size(),
+            clients: Arc::new(Clients::new(config::default_max_connections())),
+        }
+    }
+
+    /// Serve TLS only, every connection handshaking with `acceptor` first
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Log commands that take at least `threshold`, keeping the latest `max_len` for SLOWLOG
+    ///
+    /// These are the node's runtime settings, shared with CONFIG SET (which can change them
+    /// again), so they otherwise come from the config the wrapper's node was built with.
+    pub fn with_slowlog(self, threshold: Duration, max_len: usize) -> Self {
+        self.wrapper
+            .runtime_config()
+            .set_slowlog(threshold, max_len);
+        self
+    }
+
+    /// Read into at least `read_buffer_size` bytes of buffer, and close connections that
+    /// send a request over `max_request_size` bytes with a protocol error
+    pub fn with_request_limits(mut self, read_buffer_size: usize, max_request_size: usize) -> Self {
+        self.read_buffer_size = read_buffer_size;
+        self.max_request_size = max_request_size;
+        self
+    }
+
+    /// Serve at most `max` connections at once, refusing any more with an error
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.clients = Arc::new(Clients::new(max));
+        self
+    }
+
+    /// The client connections open, for metrics
+    pub fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+
+    /// Stop serving once `shutdown` is cancelled, see `run`
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 
+    /// Accept connections until an error occurs or the shutdown token is cancelled
+    ///
+    /// A connection over `max_connections` is sent an error and closed. On shutdown it
+    /// stops accepting, lets each connection finish the command it is running, closes
+    /// them, and returns once they have all closed.
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(&self.addr).await?;
         info!("API server listening on {}", self.addr);
 
+        let connections = TaskTracker::new();
         loop {
-            let (socket, addr) = listener.accept().await?;
+            let (socket, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.cancelled() => break,
+            };
             debug!("New connection from {}", addr);
 
             let wrapper = Arc::clone(&self.wrapper);
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(socket, wrapper).await {
+            let slowlog = Arc::clone(&self.slowlog);
+            let tls = self.tls.clone();
+            let shutdown = self.shutdown.clone();
+            let limits = (self.read_buffer_size, self.max_request_size);
+            let clients = Arc::clone(&self.clients);
+            let permit = clients.admit();
+            connections.spawn(async move {
+                let result = match tls {
+                    Some(tls) => match tls.accept(socket).await {
+                        Ok(stream) => {
+                            Self::handle_connection(
+                                stream, wrapper, slowlog, clients, permit, shutdown, limits,
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            debug!("TLS handshake with {} failed: {}", addr, e);
+                            return;
+                        }
+                    },
+                    None => {
+                        Self::handle_connection(
+                            socket, wrapper, slowlog, clients, permit, shutdown, limits,
+                        )
+                        .await
+                    }
+                };
+                if let Err(e) = result {
                     error!("Connection error: {}", e);
                 }
             });
         }
+
+        info!(
+            "API server draining {} connections for shutdown",
+            connections.len()
+        );
+        connections.close();
+        connections.wait().await;
+        Ok(())
     }
 
     async fn handle_connection(
-        mut socket: TcpStream,
+        socket: impl AsyncStream,
         wrapper: Arc<ServerWrapper>,
+        slowlog: Arc<SlowLog>,
+        clients: Arc<Clients>,
+        permit: Option<OwnedSemaphorePermit>,
+        shutdown: CancellationToken,
+        (read_buffer_size, max_request_size): (usize, usize),
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut buffer = BytesMut::with_capacity(4096);
+        let mut conn = Connection::new(socket, clients);
+        // Held until the connection closes
+        let Some(_permit) = permit else {
+            warn!("Refusing a connection, {} are open", conn.clients.max());
+            let response = RespValue::Error(MAX_CLIENTS_REACHED.to_string());
+            conn.respond(Protocol::default(), response)?;
+            conn.flush().await?;
+            return Ok(());
+        };
+        let mut buffer = BytesMut::with_capacity(read_buffer_size);
+        let mut protocol = Protocol::default();
 
         loop {
-            let n = socket.read_buf(&mut buffer).await?;
+            // Parsing hands the buffer's bytes to the commands, leaving it to grow from nothing
+            buffer.reserve(read_buffer_size);
+            // Shutdown only closes a connection between commands, never part way through one
+            let n = tokio::select! {
+                n = conn.socket.read_buf(&mut buffer) => n?,
+                _ = shutdown.cancelled() => {
+                    debug!("Closing connection for shutdown");
+                    return Ok(());
+                }
+            };
             if n == 0 {
                 debug!("Connection closed");
                 return Ok(());
             }
 
-            let mut cursor = Cursor::new(&buffer[..]);
-            match RespValue::parse(&mut cursor) {
-                Ok(value) => {
-                    let pos = cursor.position() as usize;
-                    buffer.advance(pos);
-
-                    let response = Self::process_command(&wrapper, value).await;
-
-                    let mut response_buf = BytesMut::new();
-                    response.serialize(&mut response_buf);
-                    socket.write_all(&response_buf).await?;
-                }
-                Err(RespError::Incomplete) => {
-                    continue;
-                }
-                Err(e) => {
-                    error!("Protocol error: {}", e);
-                    let response = RespValue::Error(format!("ERR {}", e));
-                    let mut response_buf = BytesMut::new();
-                    response.serialize(&mut response_buf);
-                    socket.write_all(&response_buf).await?;
-                    return Ok(());
+            // Answer every command the read completed, in one write unless they add up
+            let mut closing = false;
+            loop {
+                let parsed = match RespValue::parse_buffer(&mut buffer) {
+                    // Only ever a single request left, the rest having been parsed
+                    Err(RespError::Incomplete) if buffer.len() > max_request_size => {
+                        Err(RespError::TooLarge(max_request_size))
+                    }
+                    parsed => parsed,
+                };
+                match parsed {
+                    Ok(value) => {
+                        Self::process_command(&wrapper, &slowlog, value, &mut protocol, &mut conn)
+                            .await?;
+                        conn.write_if_full().await?;
+                    }
+                    Err(RespError::Incomplete) => break,
+                    Err(e) => {
+                        error!("Protocol error: {}", e);
+                        let response = RespValue::Error(format!("ERR {}", e));
+                        conn.respond(protocol, response)?;
+                        closing = true;
+                        break;
+                    }
                 }
             }
+            conn.flush().await?;
+            if closing {
+                return Ok(());
+            }
         }
     }
 
-    async fn process_command(wrapper: &Arc<ServerWrapper>, value: RespValue) -> RespValue {
+    /// Run a command and queue its response, part written already for a large SMEMBERS
+    ///
+    /// It runs in a `command` span, and the time taken, for SLOWLOG, includes serializing
+    /// the response and any of it written on the way, which for SMEMBERS is most of it.
+    async fn process_command(
+        wrapper: &Arc<ServerWrapper>,
+        slowlog: &SlowLog,
+        value: RespValue,
+        protocol: &mut Protocol,
+        conn: &mut Connection<impl AsyncStream>,
+    ) -> std::io::Result<()> {
         let parts = match value.as_bulk_string_array() {
             Some(parts) if !parts.is_empty() => parts,
-            _ => return RespValue::Error("ERR invalid command format".to_string()),
+            _ => {
+                let response = RespValue::Error("ERR invalid command format".to_string());
+                return conn.respond(*protocol, response);
+            }
         };
 
         let cmd = String::from_utf8_lossy(&parts[0]).to_uppercase();
+        let spec = commands::lookup(&cmd);
+        let key = spec
+            .and_then(|spec| spec.key(&parts))
+            .map(|k| String::from_utf8_lossy(k).to_string())
+            .unwrap_or_default();
+        let span = info_span!("command", command = %cmd, %key);
+        let started = Instant::now();
+        let written = Self::run_command(wrapper, slowlog, spec, &cmd, &parts, protocol, conn)
+            .instrument(span)
+            .await;
+        slowlog.record(&cmd, &parts[1..], started.elapsed());
+        written
+    }
+
+    /// Dispatch a command, once `spec`, its entry in the command table, accepts its arity
+    async fn run_command(
+        wrapper: &Arc<ServerWrapper>,
+        slowlog: &SlowLog,
+        spec: Option<&CommandSpec>,
+        cmd: &str,
+        parts: &[Bytes],
+        protocol: &mut Protocol,
+        conn: &mut Connection<impl AsyncStream>,
+    ) -> std::io::Result<()> {
+        let Some(spec) = spec else {
+            let response = RespValue::Error(format!("ERR unknown command '{}'", cmd));
+            return conn.respond(*protocol, response);
+        };
+        if !spec.accepts(parts.len()) {
+            let response = RespValue::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                spec.name
+            ));
+            return conn.respond(*protocol, response);
+        }
 
-        match cmd.as_str() {
-            "SADD" => Self::cmd_sadd(wrapper, &parts).await,
-            "SREM" => Self::cmd_srem(wrapper, &parts).await,
-            "SCARD" => Self::cmd_scard(wrapper, &parts).await,
-            "SISMEMBER" => Self::cmd_sismember(wrapper, &parts).await,
-            "SMISMEMBER" => Self::cmd_smismember(wrapper, &parts).await,
-            "SMEMBERS" => Self::cmd_smembers(wrapper, &parts).await,
-            "PING" => RespValue::SimpleString("PONG".to_string()),
+        let response = match cmd {
+            "SADD" => Self::cmd_sadd(wrapper, parts).await,
+            "SADDEX" => Self::cmd_saddex(wrapper, parts).await,
+            "SADDNX" => Self::cmd_saddnx(wrapper, parts).await,
+            "SREM" => Self::cmd_srem(wrapper, parts).await,
+            "SCARD" => Self::cmd_scard(wrapper, parts).await,
+            "SISMEMBER" => Self::cmd_sismember(wrapper, parts).await,
+            "SRANDMEMBER" => Self::cmd_srandmember(wrapper, parts).await,
+            "SMISMEMBER" => Self::cmd_smismember(wrapper, parts).await,
+            "SMEMBERS" => {
+                return Self::cmd_smembers(wrapper, parts, *protocol, conn).await;
+            }
+            "SINTERCARD" => Self::cmd_sintercard(wrapper, parts).await,
+            "SSYNC" => Self::cmd_ssync(wrapper, parts).await,
+            "VV" => RespValue::BulkString(Bytes::from(wrapper.vv().await.to_string())),
+            "SVV" => Self::cmd_svv(wrapper, parts).await,
+            "SDOTS" => Self::cmd_sdots(wrapper, parts).await,
+            "SACTORS" => Self::cmd_sactors(wrapper, parts).await,
+            "INFO" => Self::cmd_info(wrapper, parts, &conn.clients).await,
+            "CLUSTER" => Self::cmd_cluster(wrapper, parts, *protocol).await,
+            "REPLICATION" => Self::cmd_replication(wrapper, *protocol).await,
+            "SUBSCRIBE" => Self::cmd_subscribe(wrapper, parts).await,
+            "MAINTENANCE" => Self::cmd_maintenance(wrapper, parts).await,
+            "CHECKPOINT" => Self::cmd_checkpoint(wrapper).await,
+            "SAVE" => Self::cmd_save(wrapper, parts).await,
+            "LOAD" => Self::cmd_load(wrapper, parts).await,
+            "PING" => match parts.get(1) {
+                Some(message) => RespValue::BulkString(message.clone()),
+                None => RespValue::SimpleString("PONG".to_string()),
+            },
+            "HEALTH" => Self::cmd_health(wrapper).await,
+            "SLOWLOG" => Self::cmd_slowlog(slowlog, parts),
+            "CONFIG" => Self::cmd_config(wrapper.runtime_config(), parts),
+            "HELLO" => Self::cmd_hello(parts, protocol),
+            "COMMAND" => Self::cmd_command(parts),
             _ => RespValue::Error(format!("ERR unknown command '{}'", cmd)),
+        };
+
+        conn.respond(*protocol, response)
+    }
+
+    /// A command's set name, refused unless it is UTF-8
+    ///
+    /// Set names are stored as text, so one that isn't UTF-8 would otherwise be stored
+    /// mangled, and could collide with another name.
+    fn set_name(arg: &Bytes) -> Result<String, RespValue> {
+        std::str::from_utf8(arg)
+            .map(str::to_string)
+            .map_err(|_| RespValue::Error("ERR set names must be UTF-8".to_string()))
+    }
+
+    /// A write's members, and the causal context after a trailing `CONTEXT vv:...`
+    ///
+    /// CONTEXT is only taken as the option when it comes after at least one member, so
+    /// `SADD key CONTEXT vv:1` adds two members.
+    fn write_context(args: &[Bytes]) -> Result<(&[Bytes], Option<VersionVector>), RespValue> {
+        match args {
+            [members @ .., option, context]
+                if !members.is_empty() && option.eq_ignore_ascii_case(b"CONTEXT") =>
+            {
+                std::str::from_utf8(context)
+                    .ok()
+                    .and_then(|context| context.strip_prefix("vv:"))
+                    .and_then(VersionVector::from_str)
+                    .map(|vv| (members, Some(vv)))
+                    .ok_or_else(|| {
+                        RespValue::Error("ERR invalid CONTEXT version vector".to_string())
+                    })
+            }
+            _ => Ok((args, None)),
         }
     }
 
-    async fn cmd_sadd(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
-        if parts.len() < 3 {
-            return RespValue::Error(
-                "ERR wrong number of arguments for 'sadd' command".to_string(),
-            );
+    /// `DEGRADED acked:1/2 vv:...`, the reply to a write committed here but acked by fewer
+    /// peers than synchronous replication needs in time
+    fn degraded(vv: Option<&VersionVector>, acked: usize, needed: usize) -> RespValue {
+        let mut reply = format!("DEGRADED acked:{}/{}", acked, needed);
+        if let Some(vv) = vv {
+            reply.push_str(&format!(" vv:{}", vv.to_string()));
         }
+        RespValue::SimpleString(reply)
+    }
 
-        let key_name = String::from_utf8_lossy(&parts[1]).to_string();
-        let members = &parts[2..];
-        match wrapper.sadd(&key_name, members).await {
+    async fn cmd_sadd(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+        let (members, client_vv) = match Self::write_context(&parts[2..]) {
+            Ok(split) => split,
+            Err(e) => return e,
+        };
+        match wrapper.sadd(&key_name, members, client_vv.as_ref()).await {
             Ok(CommandResult::Ok { vv: Some(vv) }) => {
                 RespValue::SimpleString(format!("OK vv:{}", vv.to_string()))
             }
             Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Ok(CommandResult::Degraded { vv, acked, needed }) => {
+                Self::degraded(vv.as_deref(), acked, needed)
+            }
+            Ok(CommandResult::NotReady(vv)) => {
+                RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
+            }
             Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
             Err(e) => {
                 error!("{}", e);
@@ -124,21 +484,85 @@ impl ApiServer {
         }
     }
 
-    async fn cmd_srem(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
-        if parts.len() < 3 {
-            return RespValue::Error(
-                "ERR wrong number of arguments for 'srem' command".to_string(),
-            );
+    /// `SADDEX key seconds member [member ...] [CONTEXT vv:...]`
+    async fn cmd_saddex(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+        let ttl = match String::from_utf8_lossy(&parts[2]).parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+            _ => {
+                return RespValue::Error("ERR invalid expire time in 'saddex' command".to_string());
+            }
+        };
+        let (members, client_vv) = match Self::write_context(&parts[3..]) {
+            Ok(split) => split,
+            Err(e) => return e,
+        };
+
+        match wrapper
+            .saddex(&key_name, members, ttl, client_vv.as_ref())
+            .await
+        {
+            Ok(CommandResult::Ok { vv: Some(vv) }) => {
+                RespValue::SimpleString(format!("OK vv:{}", vv.to_string()))
+            }
+            Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Ok(CommandResult::Degraded { vv, acked, needed }) => {
+                Self::degraded(vv.as_deref(), acked, needed)
+            }
+            Ok(CommandResult::NotReady(vv)) => {
+                RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
+            }
+            Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
         }
+    }
 
-        let key_name = String::from_utf8_lossy(&parts[1]).to_string();
-        let members = &parts[2..];
+    /// `SADDNX key member [member ...] [CONTEXT vv:...]`, replying with how many were added
+    async fn cmd_saddnx(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+        let (members, client_vv) = match Self::write_context(&parts[2..]) {
+            Ok(split) => split,
+            Err(e) => return e,
+        };
+        match wrapper.saddnx(&key_name, members, client_vv.as_ref()).await {
+            Ok(CommandResult::Integer(added)) => RespValue::Integer(added),
+            Ok(CommandResult::NotReady(vv)) => {
+                RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
+            }
+            Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    async fn cmd_srem(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+        let (members, client_vv) = match Self::write_context(&parts[2..]) {
+            Ok(split) => split,
+            Err(e) => return e,
+        };
 
-        match wrapper.srem(&key_name, members).await {
+        match wrapper.srem(&key_name, members, client_vv.as_ref()).await {
             Ok(CommandResult::Ok { vv: Some(vv) }) => {
                 RespValue::SimpleString(format!("OK vv:{}", vv.to_string()))
             }
             Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Ok(CommandResult::Degraded { vv, acked, needed }) => {
+                Self::degraded(vv.as_deref(), acked, needed)
+            }
+            Ok(CommandResult::NotReady(vv)) => {
+                RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
+            }
             Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
             Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
             _ => RespValue::Error("ERR unexpected result".to_string()),
@@ -146,13 +570,10 @@ impl ApiServer {
     }
 
     async fn cmd_scard(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
-        if parts.len() < 2 {
-            return RespValue::Error(
-                "ERR wrong number of arguments for 'scard' command".to_string(),
-            );
-        }
-
-        let key_name = String::from_utf8_lossy(&parts[1]).to_string();
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
 
         let client_vv = if parts.len() > 2 {
             let vv_str = String::from_utf8_lossy(&parts[2]);
@@ -176,17 +597,80 @@ impl ApiServer {
         }
     }
 
-    async fn cmd_smembers(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
-        if parts.len() < 2 {
-            return RespValue::Error(
-                "ERR wrong number of arguments for 'smembers' command".to_string(),
-            );
+    /// Queues the array header, then each batch of members as storage yields it, writing
+    /// them out as they pile up, so a huge set is never held in memory. A storage error part way through can only be
+    /// reported by dropping the connection.
+    async fn cmd_smembers(
+        wrapper: &Arc<ServerWrapper>,
+        parts: &[Bytes],
+        protocol: Protocol,
+        conn: &mut Connection<impl AsyncStream>,
+    ) -> std::io::Result<()> {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return conn.respond(protocol, e),
+        };
+
+        // `[MATCH pattern] [vv:...]`
+        let mut options = &parts[2..];
+        let mut client_vv = None;
+        if let Some((last, rest)) = options.split_last()
+            && let Some(vv_str) = String::from_utf8_lossy(last).strip_prefix("vv:")
+        {
+            client_vv = VersionVector::from_str(vv_str);
+            options = rest;
         }
+        let pattern = match options {
+            [] => None,
+            [option, pattern] if option.eq_ignore_ascii_case(b"MATCH") => Some(pattern.as_ref()),
+            _ => {
+                let response = RespValue::Error("ERR syntax error".to_string());
+                return conn.respond(protocol, response);
+            }
+        };
 
-        let key_name = String::from_utf8_lossy(&parts[1]).to_string();
+        let mut members = match wrapper
+            .smembers_stream(&key_name, pattern, client_vv.as_ref())
+            .await
+        {
+            Ok(Ok(members)) => members,
+            Ok(Err(CommandResult::NotReady(vv))) => {
+                let response = RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()));
+                return conn.respond(protocol, response);
+            }
+            Ok(Err(CommandResult::Error(msg))) => {
+                return conn.respond(protocol, RespValue::Error(msg));
+            }
+            Err(e) => {
+                let response = RespValue::Error(format!("ERR database error: {}", e));
+                return conn.respond(protocol, response);
+            }
+            _ => {
+                let response = RespValue::Error("ERR unexpected result".to_string());
+                return conn.respond(protocol, response);
+            }
+        };
 
-        let client_vv = if parts.len() > 2 {
-            let vv_str = String::from_utf8_lossy(&parts[2]);
+        RespValue::serialize_array_header(members.len as usize, &mut conn.out);
+        while let Some(batch) = members.next_batch().await {
+            let batch = batch.map_err(std::io::Error::other)?;
+            for member in batch {
+                RespValue::BulkString(member).serialize(protocol, &mut conn.out);
+            }
+            conn.write_if_full().await?;
+        }
+        Ok(())
+    }
+
+    async fn cmd_sismember(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+        let member = &parts[2];
+
+        let client_vv = if parts.len() > 3 {
+            let vv_str = String::from_utf8_lossy(&parts[3]);
             if let Some(vv_str) = vv_str.strip_prefix("vv:") {
                 VersionVector::from_str(vv_str)
             } else {
@@ -196,14 +680,11 @@ impl ApiServer {
             None
         };
 
-        match wrapper.smembers(&key_name, client_vv.as_ref()).await {
-            Ok(CommandResult::BytesArray(members)) => {
-                let results: Vec<RespValue> = members
-                    .iter()
-                    .map(|bytes| RespValue::BulkString(bytes.clone()))
-                    .collect();
-                RespValue::Array(results)
-            }
+        match wrapper
+            .sismember(&key_name, member, client_vv.as_ref())
+            .await
+        {
+            Ok(CommandResult::Integer(val)) => RespValue::Integer(val),
             Ok(CommandResult::NotReady(vv)) => {
                 RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
             }
@@ -213,32 +694,54 @@ impl ApiServer {
         }
     }
 
-    async fn cmd_sismember(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
-        if parts.len() < 3 {
+    /// `SINTERCARD numkeys key [key ...] [LIMIT limit] [vv:...]`
+    async fn cmd_sintercard(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let numkeys = match String::from_utf8_lossy(&parts[1]).parse::<usize>() {
+            Ok(numkeys) if numkeys > 0 => numkeys,
+            _ => return RespValue::Error("ERR numkeys should be greater than 0".to_string()),
+        };
+        let Some((keys, mut options)) = parts[2..].split_at_checked(numkeys) else {
             return RespValue::Error(
-                "ERR wrong number of arguments for 'sismember' command".to_string(),
+                "ERR Number of keys can't be greater than number of args".to_string(),
             );
+        };
+        let mut set_names = Vec::with_capacity(keys.len());
+        for key in keys {
+            match Self::set_name(key) {
+                Ok(set_name) => set_names.push(set_name),
+                Err(e) => return e,
+            }
         }
 
-        let key_name = String::from_utf8_lossy(&parts[1]).to_string();
-        let member = &parts[2];
-
-        let client_vv = if parts.len() > 3 {
-            let vv_str = String::from_utf8_lossy(&parts[3]);
-            if let Some(vv_str) = vv_str.strip_prefix("vv:") {
-                VersionVector::from_str(vv_str)
-            } else {
-                None
+        let mut client_vv = None;
+        if let Some((last, rest)) = options.split_last()
+            && let Some(vv_str) = String::from_utf8_lossy(last).strip_prefix("vv:")
+        {
+            client_vv = VersionVector::from_str(vv_str);
+            options = rest;
+        }
+        let limit = match options {
+            [] => None,
+            [option, limit] if option.eq_ignore_ascii_case(b"LIMIT") => {
+                match String::from_utf8_lossy(limit).parse::<i64>() {
+                    // LIMIT 0 counts everything, as in Redis
+                    Ok(limit) if limit >= 0 => (limit > 0).then_some(limit as u64),
+                    Ok(_) => return RespValue::Error("ERR LIMIT can't be negative".to_string()),
+                    Err(_) => {
+                        return RespValue::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        );
+                    }
+                }
             }
-        } else {
-            None
+            _ => return RespValue::Error("ERR syntax error".to_string()),
         };
 
         match wrapper
-            .sismember(&key_name, member, client_vv.as_ref())
+            .sintercard(&set_names, limit, client_vv.as_ref())
             .await
         {
-            Ok(CommandResult::Integer(val)) => RespValue::Integer(val),
+            Ok(CommandResult::Integer(count)) => RespValue::Integer(count),
             Ok(CommandResult::NotReady(vv)) => {
                 RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
             }
@@ -248,14 +751,61 @@ impl ApiServer {
         }
     }
 
-    async fn cmd_smismember(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
-        if parts.len() < 3 {
-            return RespValue::Error(
-                "ERR wrong number of arguments for 'smismember' command".to_string(),
-            );
+    /// `SRANDMEMBER key [count] [vv:...]`: one member or nil without a count, else an
+    /// array, of distinct members for a positive count and possibly repeated ones for a
+    /// negative count
+    async fn cmd_srandmember(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+
+        let mut options = &parts[2..];
+        let mut client_vv = None;
+        if let Some((last, rest)) = options.split_last()
+            && let Some(vv_str) = String::from_utf8_lossy(last).strip_prefix("vv:")
+        {
+            client_vv = VersionVector::from_str(vv_str);
+            options = rest;
         }
+        let count = match options {
+            [] => None,
+            [count] => match String::from_utf8_lossy(count).parse::<i64>() {
+                Ok(count) => Some(count),
+                Err(_) => {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                }
+            },
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        };
 
-        let key_name = String::from_utf8_lossy(&parts[1]).to_string();
+        match wrapper
+            .srandmember(&key_name, count.unwrap_or(1), client_vv.as_ref())
+            .await
+        {
+            Ok(CommandResult::BytesArray(members)) if count.is_none() => members
+                .into_iter()
+                .next()
+                .map_or(RespValue::Null, RespValue::BulkString),
+            Ok(CommandResult::BytesArray(members)) => {
+                RespValue::Array(members.into_iter().map(RespValue::BulkString).collect())
+            }
+            Ok(CommandResult::NotReady(vv)) => {
+                RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
+            }
+            Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    async fn cmd_smismember(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
 
         let (members, client_vv) = {
             let mut member_end = parts.len();
@@ -291,4 +841,699 @@ impl ApiServer {
             _ => RespValue::Error("ERR unexpected result".to_string()),
         }
     }
+
+    async fn cmd_ssync(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+
+        match wrapper.ssync(&key_name).await {
+            Ok(CommandResult::Integer(changed)) => RespValue::Integer(changed),
+            Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    /// `SACTORS key [vv:...]`: the actors with dots in the set
+    async fn cmd_sactors(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+
+        let client_vv = parts.get(2).and_then(|vv| {
+            String::from_utf8_lossy(vv)
+                .strip_prefix("vv:")
+                .and_then(VersionVector::from_str)
+        });
+
+        match wrapper.set_actors(&key_name, client_vv.as_ref()).await {
+            Ok(Ok(actors)) => RespValue::Array(
+                actors
+                    .into_iter()
+                    .map(|actor_id| RespValue::BulkString(Bytes::from(actor_id.to_string())))
+                    .collect(),
+            ),
+            Ok(Err(CommandResult::NotReady(vv))) => {
+                RespValue::Error(format!("NOTREADY vv:{}", vv.to_string()))
+            }
+            Ok(Err(CommandResult::Error(msg))) => RespValue::Error(msg),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    /// `SDOTS key member`: the element's dots, as `actor:counter` strings
+    async fn cmd_sdots(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+
+        match wrapper.element_dots(&key_name, &parts[2]).await {
+            Ok(dots) => RespValue::Array(
+                dots.into_iter()
+                    .map(|dot| {
+                        RespValue::BulkString(Bytes::from(format!(
+                            "{}:{}",
+                            dot.actor_id, dot.counter
+                        )))
+                    })
+                    .collect(),
+            ),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+        }
+    }
+
+    async fn cmd_svv(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let key_name = match Self::set_name(&parts[1]) {
+            Ok(key_name) => key_name,
+            Err(e) => return e,
+        };
+
+        match wrapper.set_vv(&key_name).await {
+            Ok(CommandResult::Ok { vv: Some(vv) }) => {
+                RespValue::BulkString(Bytes::from(vv.to_string()))
+            }
+            Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    async fn cmd_cluster(
+        wrapper: &Arc<ServerWrapper>,
+        parts: &[Bytes],
+        protocol: Protocol,
+    ) -> RespValue {
+        let subcommand = parts
+            .get(1)
+            .map(|s| String::from_utf8_lossy(s).to_uppercase())
+            .unwrap_or_default();
+        let node_id = |arg: &Bytes| String::from_utf8_lossy(arg).parse::<u16>().ok();
+
+        let result = match (subcommand.as_str(), parts.len()) {
+            ("MEET", 4 | 5) => {
+                let addr = String::from_utf8_lossy(&parts[2]).to_string();
+                let transfer = match parts.get(4) {
+                    None => false,
+                    Some(flag) if flag.eq_ignore_ascii_case(b"TRANSFER") => true,
+                    Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+                };
+                let Some(node_id) = node_id(&parts[3]) else {
+                    return RespValue::Error("ERR invalid node id".to_string());
+                };
+                wrapper.cluster_meet(&addr, node_id, transfer).await
+            }
+            ("FORGET", 3) => {
+                let Some(node_id) = node_id(&parts[2]) else {
+                    return RespValue::Error("ERR invalid node id".to_string());
+                };
+                wrapper.cluster_forget(node_id).await
+            }
+            ("INFO", 2) => return Self::cmd_replication(wrapper, protocol).await,
+            ("MEET" | "FORGET" | "INFO", _) => {
+                return RespValue::Error(format!(
+                    "ERR wrong number of arguments for 'cluster|{}' command",
+                    subcommand.to_lowercase()
+                ));
+            }
+            _ => {
+                return RespValue::Error(format!(
+                    "ERR unknown subcommand '{}' for 'cluster' command",
+                    subcommand
+                ));
+            }
+        };
+
+        match result {
+            Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    /// SUBSCRIBE SET pattern [pattern ...] replicates only the sets matching a pattern,
+    /// SUBSCRIBE LIST the patterns (none for every set)
+    async fn cmd_subscribe(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let subcommand = String::from_utf8_lossy(&parts[1]).to_uppercase();
+        let result = match (subcommand.as_str(), parts.len()) {
+            ("SET", 3..) => {
+                let patterns = parts[2..]
+                    .iter()
+                    .map(|pattern| String::from_utf8_lossy(pattern).to_string())
+                    .collect();
+                wrapper.subscribe(patterns).await
+            }
+            ("LIST", 2) => wrapper.subscriptions().await,
+            ("SET" | "LIST", _) => {
+                return RespValue::Error(format!(
+                    "ERR wrong number of arguments for 'subscribe|{}' command",
+                    subcommand.to_lowercase()
+                ));
+            }
+            _ => {
+                return RespValue::Error(format!(
+                    "ERR unknown subcommand '{}' for 'subscribe' command",
+                    subcommand
+                ));
+            }
+        };
+
+        match result {
+            Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Ok(CommandResult::BytesArray(patterns)) => {
+                RespValue::Array(patterns.into_iter().map(RespValue::BulkString).collect())
+            }
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    async fn cmd_maintenance(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let result = match parts.get(1) {
+            None => wrapper.maintenance(false).await,
+            Some(flag) if parts.len() == 2 && flag.eq_ignore_ascii_case(b"VACUUM") => {
+                wrapper.maintenance(true).await
+            }
+            Some(flag) if parts.len() <= 3 && flag.eq_ignore_ascii_case(b"COMPACT") => {
+                let set_name = match parts.get(2).map(Self::set_name).transpose() {
+                    Ok(set_name) => set_name,
+                    Err(e) => return e,
+                };
+                wrapper.compact_dots(set_name.as_deref()).await
+            }
+            Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+        };
+
+        match result {
+            Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Ok(CommandResult::Integer(deleted)) => RespValue::Integer(deleted),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    /// The frames written back, and 1 if the WAL was truncated (0 if readers held it)
+    async fn cmd_checkpoint(wrapper: &Arc<ServerWrapper>) -> RespValue {
+        match wrapper.checkpoint().await {
+            Ok(checkpoint) => RespValue::Array(vec![
+                RespValue::Integer(checkpoint.frames as i64),
+                RespValue::Integer(checkpoint.truncated as i64),
+            ]),
+            Err(e) => RespValue::Error(format!("ERR database error: {}", e)),
+        }
+    }
+
+    async fn cmd_save(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let path = String::from_utf8_lossy(&parts[1]).to_string();
+        match wrapper.save(&path).await {
+            Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Err(e) => {
+                error!("{}", e);
+                RespValue::Error(format!("ERR save failed: {}", e))
+            }
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    async fn cmd_load(wrapper: &Arc<ServerWrapper>, parts: &[Bytes]) -> RespValue {
+        let path = String::from_utf8_lossy(&parts[1]).to_string();
+        match wrapper.load(&path).await {
+            Ok(CommandResult::Ok { vv: None }) => RespValue::SimpleString("OK".to_string()),
+            Ok(CommandResult::Error(msg)) => RespValue::Error(msg),
+            Err(e) => {
+                error!("{}", e);
+                RespValue::Error(format!("ERR load failed: {}", e))
+            }
+            _ => RespValue::Error("ERR unexpected result".to_string()),
+        }
+    }
+
+    /// The API server's own `clients` section, then the node's from the wrapper
+    async fn cmd_info(
+        wrapper: &Arc<ServerWrapper>,
+        parts: &[Bytes],
+        clients: &Clients,
+    ) -> RespValue {
+        let section = parts.get(1).map(|s| String::from_utf8_lossy(s).to_string());
+        let mut info = String::new();
+        if section
+            .as_deref()
+            .is_none_or(|s| s.eq_ignore_ascii_case("clients"))
+        {
+            info.push_str("# Clients\r\n");
+            info.push_str(&format!("connected_clients:{}\r\n", clients.open()));
+            info.push_str(&format!("maxclients:{}\r\n", clients.max()));
+        }
+        info.push_str(&wrapper.info(section.as_deref()).await);
+        RespValue::BulkString(Bytes::from(info))
+    }
+
+    /// `REPLICATION` / `CLUSTER INFO`: each peer's address, liveness, unacked operations,
+    /// last completed send and reported version vector
+    ///
+    /// A RESP3 map keyed by peer actor id, or on RESP2 one `actor:field=value,...` line per
+    /// peer, as INFO does, with the VV last since it is comma separated itself. Times are
+    /// milliseconds ago, and -1 (or the VV empty) when there is nothing to report yet.
+    async fn cmd_replication(wrapper: &Arc<ServerWrapper>, protocol: Protocol) -> RespValue {
+        let statuses = wrapper.replication_status().await;
+        let millis = |ago: Option<Duration>| ago.map_or(-1, |ago| ago.as_millis() as i64);
+        let fields = |status: &PeerStatus, lag: Option<u64>| {
+            vec![
+                ("node_id", status.peer.node_id.to_string()),
+                ("epoch", status.peer.epoch.to_string()),
+                ("addr", status.peer.addr.clone()),
+                ("status", if status.up { "up" } else { "down" }.to_string()),
+                ("last_seen_ms", millis(status.last_seen).to_string()),
+                ("last_sent_ms", millis(status.last_sent).to_string()),
+                ("unacked", status.unacked.to_string()),
+                ("lag", lag.map_or(-1, |lag| lag as i64).to_string()),
+                (
+                    "vv",
+                    status
+                        .vv
+                        .as_ref()
+                        .map(|vv| vv.to_string())
+                        .unwrap_or_default(),
+                ),
+            ]
+        };
+
+        match protocol {
+            Protocol::Resp3 => {
+                let bulk = |s: String| RespValue::BulkString(Bytes::from(s));
+                RespValue::Map(
+                    statuses
+                        .iter()
+                        .map(|(status, lag)| {
+                            let peer = fields(status, *lag)
+                                .into_iter()
+                                .map(|(name, value)| (bulk(name.to_string()), bulk(value)))
+                                .collect();
+                            (
+                                bulk(status.peer.actor_id().to_string()),
+                                RespValue::Map(peer),
+                            )
+                        })
+                        .collect(),
+                )
+            }
+            Protocol::Resp2 => {
+                let mut out = String::new();
+                for (status, lag) in &statuses {
+                    let peer: Vec<String> = fields(status, *lag)
+                        .into_iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect();
+                    out.push_str(&format!(
+                        "{}:{}\r\n",
+                        status.peer.actor_id(),
+                        peer.join(",")
+                    ));
+                }
+                RespValue::BulkString(Bytes::from(out))
+            }
+        }
+    }
+
+    async fn cmd_health(wrapper: &Arc<ServerWrapper>) -> RespValue {
+        match wrapper.health().await {
+            CommandResult::Error(msg) => RespValue::Error(msg),
+            _ => RespValue::SimpleString("OK".to_string()),
+        }
+    }
+
+    fn cmd_slowlog(slowlog: &SlowLog, parts: &[Bytes]) -> RespValue {
+        let subcommand = parts
+            .get(1)
+            .map(|s| String::from_utf8_lossy(s).to_uppercase())
+            .unwrap_or_default();
+
+        match (subcommand.as_str(), parts.len()) {
+            ("GET", 2 | 3) => {
+                let count = match parts.get(2) {
+                    None => SLOWLOG_GET_DEFAULT,
+                    Some(arg) => match String::from_utf8_lossy(arg).parse::<usize>() {
+                        Ok(count) => count,
+                        Err(_) => {
+                            return RespValue::Error(
+                                "ERR value is not an integer or out of range".to_string(),
+                            );
+                        }
+                    },
+                };
+                let entries = slowlog
+                    .get(count)
+                    .into_iter()
+                    .map(|entry| {
+                        RespValue::Array(vec![
+                            RespValue::Integer(entry.id as i64),
+                            RespValue::Integer(entry.timestamp as i64),
+                            RespValue::Integer(entry.duration.as_micros() as i64),
+                            RespValue::BulkString(Bytes::from(entry.command)),
+                            entry.key.map_or(RespValue::Null, RespValue::BulkString),
+                            RespValue::Integer(entry.args as i64),
+                        ])
+                    })
+                    .collect();
+                RespValue::Array(entries)
+            }
+            ("LEN", 2) => RespValue::Integer(slowlog.len() as i64),
+            ("RESET", 2) => {
+                slowlog.reset();
+                RespValue::SimpleString("OK".to_string())
+            }
+            ("GET" | "LEN" | "RESET", _) => RespValue::Error(format!(
+                "ERR wrong number of arguments for 'slowlog|{}' command",
+                subcommand.to_lowercase()
+            )),
+            _ => RespValue::Error(format!(
+                "ERR unknown subcommand '{}' for 'slowlog' command",
+                subcommand
+            )),
+        }
+    }
+
+    /// `CONFIG GET pattern [pattern ...]` / `CONFIG SET name value`, on the settings in
+    /// `RuntimeConfig`, named by their path in config.toml
+    fn cmd_config(runtime: &RuntimeConfig, parts: &[Bytes]) -> RespValue {
+        let subcommand = parts
+            .get(1)
+            .map(|s| String::from_utf8_lossy(s).to_uppercase())
+            .unwrap_or_default();
+        let arg = |i: usize| String::from_utf8_lossy(&parts[i]).to_string();
+
+        match (subcommand.as_str(), parts.len()) {
+            ("GET", 3..) => {
+                let mut settings: Vec<(String, String)> = Vec::new();
+                for pattern in (2..parts.len()).map(arg) {
+                    for setting in runtime.get(&pattern) {
+                        if !settings.contains(&setting) {
+                            settings.push(setting);
+                        }
+                    }
+                }
+                let bulk = |s: String| RespValue::BulkString(Bytes::from(s));
+                RespValue::Map(
+                    settings
+                        .into_iter()
+                        .map(|(name, value)| (bulk(name), bulk(value)))
+                        .collect(),
+                )
+            }
+            ("SET", 4) => match runtime.set(&arg(2), &arg(3)) {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => RespValue::Error(format!("ERR CONFIG SET failed: {}", e)),
+            },
+            ("GET" | "SET", _) => RespValue::Error(format!(
+                "ERR wrong number of arguments for 'config|{}' command",
+                subcommand.to_lowercase()
+            )),
+            _ => RespValue::Error(format!(
+                "ERR unknown subcommand '{}' for 'config' command",
+                subcommand
+            )),
+        }
+    }
+
+    /// `COMMAND [COUNT | INFO [name ...]]`: the command table, each command described as
+    /// Redis does, `[name, arity, flags, first key, last key, key step]`
+    fn cmd_command(parts: &[Bytes]) -> RespValue {
+        let describe = |spec: &CommandSpec| {
+            let key = spec.first_key as i64;
+            let flags = spec
+                .flags
+                .iter()
+                .map(|flag| RespValue::SimpleString(flag.name().to_string()))
+                .collect();
+            RespValue::Array(vec![
+                RespValue::BulkString(Bytes::from_static(spec.name.as_bytes())),
+                RespValue::Integer(spec.arity()),
+                RespValue::Array(flags),
+                RespValue::Integer(key),
+                RespValue::Integer(key),
+                RespValue::Integer(key.min(1)),
+            ])
+        };
+        let subcommand = parts
+            .get(1)
+            .map(|s| String::from_utf8_lossy(s).to_uppercase())
+            .unwrap_or_default();
+
+        match (subcommand.as_str(), parts.len()) {
+            ("", 1) | ("INFO", 2) => RespValue::Array(COMMANDS.iter().map(describe).collect()),
+            ("COUNT", 2) => RespValue::Integer(COMMANDS.len() as i64),
+            ("INFO", _) => RespValue::Array(
+                parts[2..]
+                    .iter()
+                    .map(|name| {
+                        commands::lookup(&String::from_utf8_lossy(name))
+                            .map_or(RespValue::Null, describe)
+                    })
+                    .collect(),
+            ),
+            ("COUNT", _) => RespValue::Error(
+                "ERR wrong number of arguments for 'command|count' command".to_string(),
+            ),
+            _ => RespValue::Error(format!(
+                "ERR unknown subcommand '{}' for 'command' command",
+                subcommand
+            )),
+        }
+    }
+
+    /// `HELLO [protover]`: switch the connection to RESP2 or RESP3, replying, in the new
+    /// protocol, with a map describing the server
+    fn cmd_hello(parts: &[Bytes], protocol: &mut Protocol) -> RespValue {
+        if let Some(option) = parts.get(2) {
+            return RespValue::Error(format!(
+                "ERR Syntax error in HELLO option '{}'",
+                String::from_utf8_lossy(option)
+            ));
+        }
+        if let Some(version) = parts.get(1) {
+            *protocol = match String::from_utf8_lossy(version).parse::<i64>() {
+                Ok(2) => Protocol::Resp2,
+                Ok(3) => Protocol::Resp3,
+                Ok(_) => {
+                    return RespValue::Error("NOPROTO unsupported protocol version".to_string());
+                }
+                Err(_) => {
+                    return RespValue::Error(
+                        "ERR Protocol version is not an integer or out of range".to_string(),
+                    );
+                }
+            };
+        }
+
+        let field = |name: &str| RespValue::BulkString(Bytes::from(name.to_string()));
+        let proto = match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        RespValue::Map(vec![
+            (field("server"), field("bigsets")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), RespValue::Integer(proto)),
+        ])
+    }
+}
+
+/// Commands that took at least a threshold to run, for SLOWLOG
+///
+/// Each is logged at warn as it finishes, and the latest `max_len` are kept, newest first.
+/// Only the command, its first argument and how many arguments there were are kept: the
+/// rest may be thousands of members. A zero threshold records nothing. The threshold and
+/// `max_len` are the `Tunables` of the moment, so CONFIG SET changes them.
+#[derive(Debug)]
+pub struct SlowLog {
+    runtime: Arc<RuntimeConfig>,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+}
+
+/// One command kept by the SlowLog
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowLogEntry {
+    /// Increases by one for each slow command, including those no longer kept
+    pub id: u64,
+    /// When the command finished, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub command: String,
+    /// The first argument, the set's name for set commands
+    pub key: Option<Bytes>,
+    /// How many arguments followed the command
+    pub args: usize,
+}
+
+impl SlowLog {
+    pub fn new(runtime: Arc<RuntimeConfig>) -> Self {
+        Self {
+            runtime,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Log and keep `command` if it took at least the threshold, returning whether it did
+    pub fn record(&self, command: &str, args: &[Bytes], duration: Duration) -> bool {
+        let tunables = self.runtime.tunables();
+        let threshold = tunables.slowlog_threshold;
+        if threshold.is_zero() || duration < threshold {
+            return false;
+        }
+
+        let key = args.first().cloned();
+        warn!(
+            "Slow command {} key={} args={} took {:?}",
+            command,
+            key.as_ref()
+                .map(|k| String::from_utf8_lossy(k).to_string())
+                .unwrap_or_default(),
+            args.len(),
+            duration
+        );
+
+        let max_len = tunables.slowlog_max_len;
+        if max_len == 0 {
+            self.reset();
+            return true;
+        }
+        let entry = SlowLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            duration,
+            command: command.to_string(),
+            key,
+            args: args.len(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        // Dropping the oldest, more than one if max_len was lowered
+        entries.truncate(max_len - 1);
+        entries.push_front(entry);
+        true
+    }
+
+    /// The latest `count` slow commands, newest first
+    pub fn get(&self, count: usize) -> Vec<SlowLogEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().take(count).cloned().collect()
+    }
+
+    /// How many slow commands are kept
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget every kept slow command
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Tunables;
+    use tracing::level_filters::LevelFilter;
+
+    fn slowlog(threshold: Duration, max_len: usize) -> SlowLog {
+        SlowLog::new(Arc::new(RuntimeConfig::from(Tunables {
+            log_level: LevelFilter::INFO,
+            slowlog_threshold: threshold,
+            slowlog_max_len: max_len,
+            ack_timeout_ms: 500,
+            retry_backoff_ms: 100,
+            max_retries: 5,
+        })))
+    }
+
+    #[test]
+    fn test_slowlog_keeps_the_latest_slow_commands() {
+        let slowlog = slowlog(Duration::from_millis(10), 2);
+        let args = |args: &[&'static str]| args.iter().map(|a| Bytes::from(*a)).collect::<Vec<_>>();
+
+        assert!(!slowlog.record("SCARD", &args(&["fast"]), Duration::from_millis(9)));
+        assert!(slowlog.record("SMEMBERS", &args(&["big"]), Duration::from_millis(10)));
+        assert!(slowlog.record("SADD", &args(&["s", "a", "b"]), Duration::from_millis(20)));
+        assert!(slowlog.record("PING", &[], Duration::from_millis(30)));
+
+        // The oldest was dropped to make room, ids carry on from it
+        let entries = slowlog.get(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, entries[0].command.as_str()), (2, "PING"));
+        assert_eq!(entries[0].key, None);
+        assert_eq!((entries[1].id, entries[1].command.as_str()), (1, "SADD"));
+        assert_eq!(entries[1].key, Some(Bytes::from("s")));
+        assert_eq!(entries[1].args, 3);
+        assert_eq!(entries[1].duration, Duration::from_millis(20));
+        assert_eq!(slowlog.get(1), entries[..1]);
+
+        slowlog.reset();
+        assert!(slowlog.is_empty());
+    }
+
+    #[test]
+    fn test_slowlog_with_no_threshold_records_nothing() {
+        let slowlog = slowlog(Duration::ZERO, 128);
+        assert!(!slowlog.record("SMEMBERS", &[], Duration::from_secs(60)));
+        assert_eq!(slowlog.len(), 0);
+    }
+
+    #[test]
+    fn test_slowlog_follows_config_set() {
+        let slowlog = slowlog(Duration::from_millis(10), 3);
+        for command in ["SADD", "SREM", "SCARD"] {
+            slowlog.record(command, &[], Duration::from_millis(10));
+        }
+
+        slowlog.runtime.set("server.slowlog_max_len", "2").unwrap();
+        slowlog
+            .runtime
+            .set("server.slowlog_threshold_ms", "100")
+            .unwrap();
+        assert!(!slowlog.record("PING", &[], Duration::from_millis(50)));
+        assert!(slowlog.record("SMEMBERS", &[], Duration::from_millis(100)));
+        let commands: Vec<String> = slowlog.get(10).into_iter().map(|e| e.command).collect();
+        assert_eq!(commands, ["SMEMBERS", "SCARD"]);
+    }
+
+    #[tokio::test]
+    async fn test_connection_lets_go_of_a_grown_response_buffer() {
+        let (socket, mut client) = tokio::io::duplex(4 * RESPONSE_BUF_RETAIN);
+        let mut conn = Connection::new(socket, Arc::new(Clients::new(1)));
+
+        conn.respond(Protocol::Resp2, RespValue::SimpleString("OK".to_string()))
+            .unwrap();
+        conn.flush().await.unwrap();
+        let kept = conn.out.capacity();
+        assert!(kept > 0);
+
+        // One huge response is written, and then its buffer isn't kept
+        let big = Bytes::from(vec![b'x'; 2 * RESPONSE_BUF_RETAIN]);
+        conn.respond(Protocol::Resp2, RespValue::BulkString(big))
+            .unwrap();
+        conn.flush().await.unwrap();
+        assert_eq!(conn.out.capacity(), 0);
+
+        drop(conn);
+        let mut written = Vec::new();
+        client.read_to_end(&mut written).await.unwrap();
+        assert!(written.starts_with(b"+OK\r\n$524288\r\nxxx"));
+        assert_eq!(
+            written.len(),
+            "+OK\r\n$524288\r\n".len() + 2 * RESPONSE_BUF_RETAIN + 2
+        );
+    }
 }
@@ -1,10 +1,12 @@
 use bigsets::{
-    ApiServer, ReplicationListener, ReplicationManager, Server, ServerWrapper, SqliteStorage,
-    config::{ClusterConfig, Config, ReplicaInfo, ReplicationConfig, ServerConfig, StorageConfig},
+    NodeBuilder,
+    config::{
+        ClusterConfig, Compression, Config, ReplicaInfo, ReplicationConfig, ReplicationMode,
+        ServerConfig, StorageConfig,
+    },
 };
 use clap::Parser;
-use std::sync::Arc;
-use std::{collections::BTreeSet, path::PathBuf};
+use std::path::PathBuf;
 use tempfile::TempDir;
 use tracing::{error, info};
 
@@ -42,11 +44,42 @@ fn generate_node_configs(num_nodes: u16, data_dir: Option<PathBuf>) -> Vec<NodeS
         buffer_size: 1000,
         ack_timeout_ms: 500,
         rbilt_startup_delay_ms: 1000,
+        max_batch: 1,
+        flush_interval_ms: 10,
+        digest_buckets: 16,
+        heartbeat_interval_ms: 1000,
+        heartbeat_miss_limit: 3,
+        sent_log_size: 10_000,
+        gc_interval_ms: 60_000,
+        snapshot_lag_threshold: 10_000,
+        mode: ReplicationMode::Op,
+        delta_interval_ms: 100,
+        sync: false,
+        write_quorum: 2,
+        fanout: 0,
+        sync_timeout_ms: 1000,
+        subscriptions: Vec::new(),
+        compression: Compression::None,
+        packed_vv: false,
+        tls: None,
     };
 
     let storage_config = StorageConfig {
         sqlite_cache_size: 10000,
         sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
     };
 
     for node_id in 1..=num_nodes {
@@ -66,6 +99,14 @@ fn generate_node_configs(num_nodes: u16, data_dir: Option<PathBuf>) -> Vec<NodeS
             api_addr: format!("127.0.0.1:{}", 6379 + node_id - 1),
             replication_addr: format!("127.0.0.1:{}", 7379 + node_id - 1),
             db_path,
+            slowlog_threshold_ms: 0,
+            slowlog_max_len: 128,
+            log_level: "info".to_string(),
+            tls: None,
+            http_addr: None,
+            read_buffer_size: 4096,
+            max_request_size: 64 * 1024 * 1024,
+            max_connections: 10_000,
         };
 
         let config = Config {
@@ -88,7 +129,7 @@ fn generate_node_configs(num_nodes: u16, data_dir: Option<PathBuf>) -> Vec<NodeS
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    let _telemetry = bigsets::telemetry::init();
 
     let args = Args::parse();
 
@@ -109,6 +150,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let node_setups = generate_node_configs(args.nodes, args.data_dir);
 
     for setup in &node_setups {
+        setup.config.validate()?;
         info!(
             "Node {}: API={}, Replication={}, DB={:?}",
             setup.config.server.node_id,
@@ -119,90 +161,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let mut tasks = Vec::new();
+    let mut shutdowns = Vec::new();
 
     for setup in &node_setups {
-        let config = setup.config.clone();
-        let node_id = config.server.node_id;
-
-        let task = tokio::spawn(async move {
-            // this looks like main.rs, maybe it should be a mod
-            let storage = match SqliteStorage::open(&config.server.db_path, &config.storage) {
-                Ok(s) => Arc::new(s),
-                Err(e) => {
-                    error!("Failed to create storage for node {}: {}", node_id, e);
-                    return;
-                }
-            };
-
-            let server = match Server::new(config.server.actor_id(), Arc::clone(&storage)).await {
-                Ok(s) => Arc::new(s),
-                Err(e) => {
-                    error!("Failed to create server for node {}: {}", node_id, e);
-                    return;
-                }
-            };
-
-            let peers: BTreeSet<_> = config
-                .cluster
-                .replicas
-                .iter()
-                .filter(|r| r.actor_id() != config.server.actor_id())
-                .cloned()
-                .collect();
-            tracing::info!("Node {} configured with {} peers", node_id, peers.len());
-            let replication = Arc::new(ReplicationManager::new(
-                peers,
-                config.replication.buffer_size,
-            ));
-
-            let wrapper = Arc::new(ServerWrapper::new(
-                Arc::clone(&server),
-                Arc::clone(&replication),
-            ));
-
-            let api_server = ApiServer::new(Arc::clone(&wrapper), config.server.api_addr.clone());
-            let api_handle = tokio::spawn(async move {
-                if let Err(e) = api_server.run().await {
-                    error!("API server error: {}", e);
-                }
-            });
-
-            let replication_server = ReplicationListener::new(
-                Arc::clone(&server),
-                Arc::clone(&replication),
-                config.server.replication_addr.clone(),
-            );
-            let repl_handle = tokio::spawn(async move {
-                if let Err(e) = replication_server.run().await {
-                    error!("Replication server error: {}", e);
-                }
-            });
-
-            if let Err(e) = tokio::try_join!(api_handle, repl_handle) {
-                error!("Node {} error: {}", node_id, e);
+        let node_id = setup.config.server.node_id;
+        let node = match NodeBuilder::new(setup.config.clone()).build().await {
+            Ok(node) => node,
+            Err(e) => {
+                error!("Failed to start node {}: {}", node_id, e);
+                continue;
             }
-        });
+        };
+        info!(
+            "Node {} configured with {} peers",
+            node_id,
+            node.replication().peers().await.len()
+        );
+        shutdowns.push(node.shutdown_token());
 
-        tasks.push(task);
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = node.run().await {
+                error!("Node {} error: {}", node_id, e);
+            }
+        }));
     }
 
     let _keep_alive = node_setups;
 
     info!("All nodes started. Press Ctrl+C to stop.");
 
+    let wait_for_nodes = async {
+        for task in tasks {
+            if let Err(e) = task.await {
+                error!("Task error: {}", e);
+            }
+        }
+    };
+    tokio::pin!(wait_for_nodes);
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             info!("Received Ctrl+C, shutting down...");
-        }
-        result = async {
-            for task in tasks {
-                if let Err(e) = task.await {
-                    error!("Task error: {}", e);
-                }
+            for shutdown in shutdowns {
+                shutdown.cancel();
             }
-        } => {
+            wait_for_nodes.await;
+        }
+        _ = &mut wait_for_nodes => {
             info!("All tasks completed");
-            result
         }
     }
 
@@ -1,7 +1,8 @@
 use crate::ActorId;
-use crate::types::Operation;
-use std::collections::HashMap;
-use std::time::Instant;
+use crate::types::{Dot, OpType, Operation, VersionVector};
+use bytes::Bytes;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
 /// Sender-side unacked buffer for retry logic
 ///
@@ -23,21 +24,70 @@ impl UnackedBuffer {
     pub fn add(&mut self, peer_id: ActorId, op: Operation) {
         self.ops
             .entry(peer_id)
-            .or_insert_with(Vec::new)
+            .or_default()
             .push((op, Instant::now(), 0));
     }
 
     /// Remove a specific operation from the buffer after acknowledgment
     pub fn remove(&mut self, peer_id: &ActorId, op_index: usize) -> bool {
-        if let Some(ops) = self.ops.get_mut(peer_id) {
-            if op_index < ops.len() {
-                ops.remove(op_index);
-                return true;
-            }
+        if let Some(ops) = self.ops.get_mut(peer_id)
+            && op_index < ops.len()
+        {
+            ops.remove(op_index);
+            return true;
         }
         false
     }
 
+    /// Remove the operation identified by `dot` once the peer has acknowledged it
+    pub fn ack(&mut self, peer_id: &ActorId, dot: Dot) -> bool {
+        if let Some(ops) = self.ops.get_mut(peer_id)
+            && let Some(index) = ops.iter().position(|(op, _, _)| op.dot() == dot)
+        {
+            ops.remove(index);
+            return true;
+        }
+        false
+    }
+
+    /// Collect the operations for a peer whose ack is overdue
+    ///
+    /// An operation is overdue once `timeout(retry_count)` has passed since it was last sent.
+    /// Overdue operations are returned in send order, stamped as resent now with their retry
+    /// count incremented. Those that already used up `max_retries` are removed from the
+    /// buffer and returned separately as given up.
+    pub fn take_overdue<F>(
+        &mut self,
+        peer_id: &ActorId,
+        max_retries: u32,
+        timeout: F,
+    ) -> (Vec<Operation>, Vec<Operation>)
+    where
+        F: Fn(u32) -> Duration,
+    {
+        let mut resend = Vec::new();
+        let mut given_up = Vec::new();
+
+        if let Some(ops) = self.ops.get_mut(peer_id) {
+            let now = Instant::now();
+            ops.retain_mut(|(op, sent_at, retries)| {
+                if now.duration_since(*sent_at) < timeout(*retries) {
+                    return true;
+                }
+                if *retries >= max_retries {
+                    given_up.push(op.clone());
+                    return false;
+                }
+                *sent_at = now;
+                *retries += 1;
+                resend.push(op.clone());
+                true
+            });
+        }
+
+        (resend, given_up)
+    }
+
     /// Get all unacked operations for a specific peer
     pub fn get_peer_ops(&self, peer_id: &ActorId) -> Option<&[(Operation, Instant, u32)]> {
         self.ops.get(peer_id).map(|v| v.as_slice())
@@ -66,6 +116,16 @@ impl UnackedBuffer {
         self.ops.keys().collect()
     }
 
+    /// Remove every unacked operation for a peer, returning them in send order
+    pub fn take_peer(&mut self, peer_id: &ActorId) -> Vec<Operation> {
+        self.ops
+            .remove(peer_id)
+            .into_iter()
+            .flatten()
+            .map(|(op, _, _)| op)
+            .collect()
+    }
+
     /// Clear all unacked operations for a specific peer
     pub fn clear_peer(&mut self, peer_id: &ActorId) {
         self.ops.remove(peer_id);
@@ -112,6 +172,24 @@ impl PendingBuffer {
         true
     }
 
+    /// Check if an operation with this dot is already waiting in the buffer
+    pub fn contains_dot(&self, dot: Dot) -> bool {
+        self.ops.iter().any(|op| op.dot() == dot)
+    }
+
+    /// The causal gap: actors we are missing operations from
+    ///
+    /// These are the actors some buffered operation depends on (through its context)
+    /// beyond what `local_vv` has seen.
+    pub fn missing_actors(&self, local_vv: &VersionVector) -> BTreeSet<ActorId> {
+        self.ops
+            .iter()
+            .flat_map(|op| op.context.counters.iter())
+            .filter(|(actor_id, counter)| local_vv.get(**actor_id) < **counter)
+            .map(|(actor_id, _)| *actor_id)
+            .collect()
+    }
+
     /// Check if the buffer is full
     pub fn is_full(&self) -> bool {
         self.ops.len() >= self.max_size
@@ -170,11 +248,172 @@ impl PendingBuffer {
     }
 }
 
+/// Sender-side log of recently sent operations, keyed by dot
+///
+/// Lets a peer that lost operations (e.g. to a full pending buffer) ask for them again.
+/// Bounded: once `max_size` operations are held the oldest is evicted.
+#[derive(Debug, Clone)]
+pub struct SentLog {
+    ops: HashMap<Dot, Operation>,
+    order: VecDeque<Dot>,
+    /// Highest counter evicted per actor, operations up to it can no longer be served
+    evicted: HashMap<ActorId, u64>,
+    max_size: usize,
+}
+
+impl SentLog {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            ops: HashMap::new(),
+            order: VecDeque::new(),
+            evicted: HashMap::new(),
+            max_size,
+        }
+    }
+
+    /// Record a sent operation, evicting the oldest if the log is full
+    pub fn add(&mut self, op: Operation) {
+        if self.max_size == 0 {
+            return;
+        }
+        while self.order.len() >= self.max_size {
+            if let Some(dot) = self.order.pop_front() {
+                self.ops.remove(&dot);
+                let evicted = self.evicted.entry(dot.actor_id).or_default();
+                *evicted = (*evicted).max(dot.counter);
+            }
+        }
+        let dot = op.dot();
+        if self.ops.insert(dot, op).is_none() {
+            self.order.push_back(dot);
+        }
+    }
+
+    /// Every logged operation `vv` has not seen, in causal (per actor counter) order
+    ///
+    /// The flag is false if some operations `vv` has not seen were already evicted,
+    /// so the result is not the whole gap.
+    pub fn since(&self, vv: &VersionVector) -> (Vec<Operation>, bool) {
+        let mut ops: Vec<Operation> = self
+            .ops
+            .values()
+            .filter(|op| !vv.contains_dot(op.dot()))
+            .cloned()
+            .collect();
+        ops.sort_by_key(Operation::dot);
+
+        let complete = self
+            .evicted
+            .iter()
+            .all(|(actor_id, counter)| vv.get(*actor_id) >= *counter);
+
+        (ops, complete)
+    }
+
+    /// Number of operations in the log
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Check if the log is empty
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Sender-side record of what each peer is owed a delta for (delta-state replication)
+///
+/// Operations are not sent in delta mode. Each one marks the sets it touched as changed
+/// for every peer, along with the members it removed, since a removed member is absent
+/// from the state and its (now empty) dots must be sent explicitly. A peer's changes are
+/// only dropped once it acks a delta covering them, so a lost delta is covered by the next.
+#[derive(Debug, Clone, Default)]
+pub struct DeltaBuffer {
+    peers: HashMap<ActorId, PeerDelta>,
+}
+
+/// The changes one peer has not acked yet
+#[derive(Debug, Clone, Default)]
+pub struct PeerDelta {
+    /// Each changed set, with the counter of the latest operation on it
+    pub sets: HashMap<String, u64>,
+    /// Removed members per set, with the counter of the latest operation removing each
+    pub removed: HashMap<String, HashMap<Bytes, u64>>,
+    /// What the recorded operations' contexts had seen
+    pub context: VersionVector,
+    /// The counter of the latest operation recorded
+    pub through: u64,
+    /// The counter of the latest operation the peer has acked a delta for
+    pub acked: u64,
+}
+
+impl PeerDelta {
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+}
+
+impl DeltaBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a local operation as owed to `peer_id`
+    pub fn record(&mut self, peer_id: ActorId, op: &Operation) {
+        let peer = self.peers.entry(peer_id).or_default();
+        let counter = op.dot().counter;
+        for set_name in op.set_names() {
+            peer.sets.insert(set_name.to_string(), counter);
+        }
+        if let OpType::Remove { elements, .. } = &op.op_type {
+            let removed = peer.removed.entry(op.set_name.clone()).or_default();
+            for element in elements {
+                removed.insert(element.clone(), counter);
+            }
+        }
+        peer.context.merge(&op.context);
+        peer.through = peer.through.max(counter);
+    }
+
+    /// A copy of what `peer_id` is owed, None if nothing
+    pub fn pending(&self, peer_id: &ActorId) -> Option<PeerDelta> {
+        self.peers
+            .get(peer_id)
+            .filter(|peer| !peer.is_empty())
+            .cloned()
+    }
+
+    /// The peer acked a delta covering our operations up to `through`
+    ///
+    /// Changes recorded since the delta was computed are kept for the next one.
+    pub fn ack(&mut self, peer_id: &ActorId, through: u64) {
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return;
+        };
+        peer.sets.retain(|_, counter| *counter > through);
+        peer.removed.retain(|set_name, removed| {
+            removed.retain(|_, counter| *counter > through);
+            !removed.is_empty() && peer.sets.contains_key(set_name)
+        });
+        peer.acked = peer.acked.max(through);
+    }
+
+    /// Number of sets with changes `peer_id` has not acked
+    pub fn peer_count(&self, peer_id: &ActorId) -> usize {
+        self.peers.get(peer_id).map_or(0, |peer| peer.sets.len())
+    }
+
+    pub fn clear_peer(&mut self, peer_id: &ActorId) {
+        self.peers.remove(peer_id);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::types::{ActorId, Dot, OpType, VersionVector};
     use bytes::Bytes;
+    use std::sync::Arc;
 
     fn create_test_op(set_name: &str, counter: u64) -> Operation {
         Operation {
@@ -186,8 +425,10 @@ mod tests {
                     counter,
                 },
                 removed_dots: vec![],
+                expires_at: None,
             },
-            context: VersionVector::new(),
+            context: Arc::new(VersionVector::new()),
+            trace_context: Default::default(),
         }
     }
 
@@ -244,6 +485,61 @@ mod tests {
         assert!(!buffer.remove(&peer_1, 0)); // Nothing left to remove
     }
 
+    #[test]
+    fn test_unacked_buffer_ack() {
+        let mut buffer = UnackedBuffer::new();
+        let peer_1 = ActorId::from_node_id(1);
+        let peer_2 = ActorId::from_node_id(2);
+        let op1 = create_test_op("set1", 1);
+        let op2 = create_test_op("set1", 2);
+
+        buffer.add(peer_1, op1.clone());
+        buffer.add(peer_1, op2.clone());
+        buffer.add(peer_2, op2.clone());
+
+        assert!(buffer.ack(&peer_1, op2.dot()));
+        assert_eq!(buffer.peer_count(&peer_1), 1);
+        assert_eq!(buffer.get_peer_ops(&peer_1).unwrap()[0].0, op1);
+        assert_eq!(buffer.peer_count(&peer_2), 1); // Other peers still waiting
+
+        assert!(!buffer.ack(&peer_1, op2.dot())); // Already acked
+        assert!(buffer.ack(&peer_1, op1.dot()));
+        assert_eq!(buffer.peer_count(&peer_1), 0);
+    }
+
+    #[test]
+    fn test_unacked_buffer_take_overdue() {
+        let mut buffer = UnackedBuffer::new();
+        let peer_1 = ActorId::from_node_id(1);
+        buffer.add(peer_1, create_test_op("set1", 1));
+        buffer.add(peer_1, create_test_op("set1", 2));
+
+        // Nothing is overdue yet
+        let (resend, given_up) = buffer.take_overdue(&peer_1, 1, |_| Duration::from_secs(60));
+        assert!(resend.is_empty());
+        assert!(given_up.is_empty());
+
+        // Everything is overdue: resent in order, retry count bumped
+        let (resend, given_up) = buffer.take_overdue(&peer_1, 1, |_| Duration::ZERO);
+        assert_eq!(resend.len(), 2);
+        assert_eq!(resend[0].dot().counter, 1);
+        assert_eq!(resend[1].dot().counter, 2);
+        assert!(given_up.is_empty());
+        assert!(
+            buffer
+                .get_peer_ops(&peer_1)
+                .unwrap()
+                .iter()
+                .all(|(_, _, r)| *r == 1)
+        );
+
+        // Retries exhausted: dropped from the buffer
+        let (resend, given_up) = buffer.take_overdue(&peer_1, 1, |_| Duration::ZERO);
+        assert!(resend.is_empty());
+        assert_eq!(given_up.len(), 2);
+        assert_eq!(buffer.peer_count(&peer_1), 0);
+    }
+
     #[test]
     fn test_unacked_buffer_get_peer_ops() {
         let mut buffer = UnackedBuffer::new();
@@ -330,6 +626,16 @@ mod tests {
         assert!(buffer.is_full());
     }
 
+    #[test]
+    fn test_pending_buffer_contains_dot() {
+        let mut buffer = PendingBuffer::new(10);
+        let op = create_test_op("set1", 1);
+        buffer.add(op.clone());
+
+        assert!(buffer.contains_dot(op.dot()));
+        assert!(!buffer.contains_dot(create_test_op("set1", 2).dot()));
+    }
+
     #[test]
     fn test_pending_buffer_remove() {
         let mut buffer = PendingBuffer::new(10);
@@ -422,4 +728,95 @@ mod tests {
         assert!(ops[0].1 >= before);
         assert!(ops[0].1 <= after);
     }
+
+    #[test]
+    fn test_pending_buffer_missing_actors() {
+        let mut buffer = PendingBuffer::new(10);
+        let actor_1 = ActorId::from_node_id(1);
+        let actor_2 = ActorId::from_node_id(2);
+
+        // An op from actor 1 that depends on actor 2's third op
+        let mut op = create_test_op("set1", 5);
+        Arc::make_mut(&mut op.context).update(actor_1, 4);
+        Arc::make_mut(&mut op.context).update(actor_2, 3);
+        buffer.add(op);
+
+        let mut local_vv = VersionVector::new();
+        local_vv.update(actor_1, 4);
+        local_vv.update(actor_2, 1);
+        assert_eq!(buffer.missing_actors(&local_vv), BTreeSet::from([actor_2]));
+
+        local_vv.update(actor_2, 3);
+        assert!(buffer.missing_actors(&local_vv).is_empty());
+    }
+
+    #[test]
+    fn test_sent_log_since() {
+        let mut log = SentLog::new(10);
+        for counter in [3, 1, 2] {
+            log.add(create_test_op("set1", counter));
+        }
+
+        let mut vv = VersionVector::new();
+        vv.update(ActorId::from_node_id(1), 1);
+
+        let (ops, complete) = log.since(&vv);
+        assert!(complete);
+        let counters: Vec<u64> = ops.iter().map(|op| op.dot().counter).collect();
+        assert_eq!(counters, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_sent_log_eviction() {
+        let mut log = SentLog::new(2);
+        for counter in 1..=3 {
+            log.add(create_test_op("set1", counter));
+        }
+        assert_eq!(log.len(), 2);
+
+        // Op 1 was evicted, so a peer that has not seen it cannot be fully served
+        let (ops, complete) = log.since(&VersionVector::new());
+        assert_eq!(ops.len(), 2);
+        assert!(!complete);
+
+        let mut vv = VersionVector::new();
+        vv.update(ActorId::from_node_id(1), 1);
+        let (ops, complete) = log.since(&vv);
+        assert_eq!(ops.len(), 2);
+        assert!(complete);
+    }
+
+    #[test]
+    fn test_delta_buffer_keeps_changes_made_after_the_acked_delta() {
+        let mut buffer = DeltaBuffer::new();
+        let peer_1 = ActorId::from_node_id(2);
+        buffer.record(peer_1, &create_test_op("set1", 1));
+        let remove = Operation {
+            set_name: "set2".to_owned(),
+            op_type: OpType::Remove {
+                elements: vec![Bytes::from("gone")],
+                dot: Dot::new(ActorId::from_node_id(1), 2),
+                removed_dots: vec![],
+            },
+            context: Arc::new(VersionVector::new()),
+            trace_context: Default::default(),
+        };
+        buffer.record(peer_1, &remove);
+
+        let pending = buffer.pending(&peer_1).unwrap();
+        assert_eq!(pending.through, 2);
+        assert_eq!(pending.sets.len(), 2);
+        assert_eq!(pending.removed["set2"].len(), 1);
+
+        // A delta computed through op 1 was acked while op 2 was being recorded
+        buffer.ack(&peer_1, 1);
+        let pending = buffer.pending(&peer_1).unwrap();
+        assert_eq!(pending.acked, 1);
+        assert_eq!(pending.sets.keys().collect::<Vec<_>>(), vec!["set2"]);
+        assert!(pending.removed.contains_key("set2"));
+
+        buffer.ack(&peer_1, 2);
+        assert!(buffer.pending(&peer_1).is_none());
+        assert_eq!(buffer.peer_count(&peer_1), 0);
+    }
 }
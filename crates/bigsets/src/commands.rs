@@ -0,0 +1,161 @@
+use Flag::{Admin, Fast, ReadOnly, Write};
+
+/// A RESP command's arity, flags and key position, checked before it is dispatched
+///
+/// Arities count every part of the command, its name included, as Redis does: SADD's
+/// minimum of 3 is `SADD key member`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandSpec {
+    /// Lower case, as COMMAND reports it and errors name it
+    pub name: &'static str,
+    pub min_args: usize,
+    /// None when any number of arguments past `min_args` is accepted
+    pub max_args: Option<usize>,
+    pub flags: &'static [Flag],
+    /// Where the command's key is, 0 for commands without one. Only SINTERCARD has more
+    /// than one, and this is the first of them
+    pub first_key: usize,
+}
+
+/// What a command does, as reported by COMMAND
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    /// Changes a set
+    Write,
+    /// Reads a set
+    ReadOnly,
+    /// Administers the node rather than touching a set
+    Admin,
+    /// Constant time, or nearly
+    Fast,
+}
+
+impl Flag {
+    /// The name Redis gives the flag
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::ReadOnly => "readonly",
+            Flag::Admin => "admin",
+            Flag::Fast => "fast",
+        }
+    }
+}
+
+impl CommandSpec {
+    const fn new(
+        name: &'static str,
+        min_args: usize,
+        max_args: Option<usize>,
+        flags: &'static [Flag],
+        first_key: usize,
+    ) -> Self {
+        Self {
+            name,
+            min_args,
+            max_args,
+            flags,
+            first_key,
+        }
+    }
+
+    /// Whether a command of `args` parts, its name included, has an acceptable arity
+    pub fn accepts(&self, args: usize) -> bool {
+        args >= self.min_args && self.max_args.is_none_or(|max| args <= max)
+    }
+
+    /// Redis's single number for the arity: exact when positive, a minimum when negative
+    pub fn arity(&self) -> i64 {
+        match self.max_args {
+            Some(max) if max == self.min_args => max as i64,
+            _ => -(self.min_args as i64),
+        }
+    }
+
+    /// The command's key among its `parts`, if it has one
+    pub fn key<'a, T>(&self, parts: &'a [T]) -> Option<&'a T> {
+        (self.first_key > 0)
+            .then(|| parts.get(self.first_key))
+            .flatten()
+    }
+}
+
+/// Every command the RESP API serves
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec::new("sadd", 3, None, &[Write, Fast], 1),
+    CommandSpec::new("saddex", 4, None, &[Write, Fast], 1),
+    CommandSpec::new("saddnx", 3, None, &[Write, Fast], 1),
+    CommandSpec::new("srem", 3, None, &[Write, Fast], 1),
+    CommandSpec::new("scard", 2, Some(3), &[ReadOnly, Fast], 1),
+    CommandSpec::new("smembers", 2, Some(5), &[ReadOnly], 1),
+    CommandSpec::new("sismember", 3, Some(4), &[ReadOnly, Fast], 1),
+    CommandSpec::new("srandmember", 2, Some(4), &[ReadOnly], 1),
+    CommandSpec::new("smismember", 3, None, &[ReadOnly, Fast], 1),
+    CommandSpec::new("sintercard", 3, None, &[ReadOnly], 2),
+    CommandSpec::new("ssync", 2, Some(2), &[Write], 1),
+    CommandSpec::new("vv", 1, Some(1), &[ReadOnly, Fast], 0),
+    CommandSpec::new("svv", 2, Some(2), &[ReadOnly], 1),
+    CommandSpec::new("sdots", 3, Some(3), &[ReadOnly, Fast], 1),
+    CommandSpec::new("sactors", 2, Some(3), &[ReadOnly], 1),
+    CommandSpec::new("info", 1, Some(2), &[], 0),
+    CommandSpec::new("cluster", 2, None, &[Admin], 0),
+    CommandSpec::new("replication", 1, Some(1), &[Admin], 0),
+    CommandSpec::new("subscribe", 2, None, &[Admin], 0),
+    CommandSpec::new("maintenance", 1, Some(3), &[Admin], 0),
+    CommandSpec::new("checkpoint", 1, Some(1), &[Admin], 0),
+    CommandSpec::new("save", 2, Some(2), &[Admin], 0),
+    CommandSpec::new("load", 2, Some(2), &[Admin, Write], 0),
+    CommandSpec::new("health", 1, Some(1), &[Fast], 0),
+    CommandSpec::new("slowlog", 2, None, &[Admin], 0),
+    CommandSpec::new("config", 2, None, &[Admin], 0),
+    CommandSpec::new("hello", 1, None, &[Fast], 0),
+    CommandSpec::new("ping", 1, Some(2), &[Fast], 0),
+    CommandSpec::new("command", 1, None, &[], 0),
+];
+
+/// The command named `name`, in any case
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arity() {
+        let sadd = lookup("SADD").unwrap();
+        assert!(!sadd.accepts(2));
+        assert!(sadd.accepts(3));
+        assert!(sadd.accepts(1000));
+        assert_eq!(sadd.arity(), -3);
+        assert_eq!(sadd.flags, &[Flag::Write, Flag::Fast]);
+
+        let scard = lookup("scard").unwrap();
+        assert!(scard.accepts(3));
+        assert!(!scard.accepts(4));
+        assert_eq!(scard.arity(), -2);
+        assert_eq!(scard.key(&["SCARD", "s"]), Some(&"s"));
+        assert_eq!(scard.key(&["SCARD"]), None);
+
+        let ssync = lookup("SSync").unwrap();
+        assert_eq!(ssync.arity(), 2);
+        assert_eq!(lookup("ping").unwrap().key(&["PING", "hello"]), None);
+        assert!(lookup("flushall").is_none());
+    }
+
+    #[test]
+    fn test_commands_are_named_once() {
+        for (i, spec) in COMMANDS.iter().enumerate() {
+            assert_eq!(spec.name, spec.name.to_lowercase());
+            assert!(spec.min_args >= 1 && spec.max_args.is_none_or(|max| max >= spec.min_args));
+            assert!(
+                COMMANDS[..i].iter().all(|other| other.name != spec.name),
+                "{} is in the table twice",
+                spec.name
+            );
+        }
+    }
+}
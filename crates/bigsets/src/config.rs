@@ -1,6 +1,11 @@
 use crate::types::ActorId;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::level_filters::LevelFilter;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -13,11 +18,40 @@ pub struct Config {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub node_id: u16,
+    /// The epoch to start at (0 by default); startup moves to a newer one if peers have seen
+    /// writes from it that the database lacks
     #[serde(default)]
     pub epoch: u8,
     pub api_addr: String,
     pub replication_addr: String,
     pub db_path: PathBuf,
+    /// Commands that take at least this long are logged and kept for SLOWLOG
+    /// (0, the default, records none)
+    #[serde(default)]
+    pub slowlog_threshold_ms: u64,
+    /// How many slow commands SLOWLOG keeps, the oldest dropped first
+    #[serde(default = "default_slowlog_max_len")]
+    pub slowlog_max_len: usize,
+    /// Log at this level and above: off, error, warn, info (the default), debug or trace
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    /// Serve the client API over TLS; plaintext when absent
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Also serve the API as JSON over HTTP on this address (needs the `http` feature)
+    #[serde(default)]
+    pub http_addr: Option<String>,
+    /// Bytes of buffer each read from a client has room for, at least (4096 by default)
+    #[serde(default = "default_read_buffer_size")]
+    pub read_buffer_size: usize,
+    /// The largest request a client may send, in bytes; a connection part way through a
+    /// larger one gets a protocol error and is closed (64 MiB by default)
+    #[serde(default = "default_max_request_size")]
+    pub max_request_size: usize,
+    /// The most client connections served at once; any more are sent an error and closed
+    /// (10000 by default)
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
 }
 
 impl ServerConfig {
@@ -27,12 +61,45 @@ impl ServerConfig {
     }
 }
 
+fn default_slowlog_max_len() -> usize {
+    128
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+pub(crate) fn default_read_buffer_size() -> usize {
+    4096
+}
+
+pub(crate) fn default_max_request_size() -> usize {
+    64 * 1024 * 1024
+}
+
+pub(crate) fn default_max_connections() -> usize {
+    10_000
+}
+
+/// The certificate a TLS listener presents, from PEM files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClusterConfig {
     pub replicas: Vec<ReplicaInfo>,
 }
 
+/// A node in the cluster
+///
+/// Written as a table, or as `node_id@addr` / `node_id:epoch@addr`, which is how
+/// replicas are given in an environment variable (comma separated).
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord)]
+#[serde(try_from = "ReplicaEntry")]
 pub struct ReplicaInfo {
     pub node_id: u16,
     #[serde(default)]
@@ -40,6 +107,47 @@ pub struct ReplicaInfo {
     pub addr: String,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReplicaEntry {
+    Table {
+        node_id: u16,
+        #[serde(default)]
+        epoch: u8,
+        addr: String,
+    },
+    Short(String),
+}
+
+impl TryFrom<ReplicaEntry> for ReplicaInfo {
+    type Error = String;
+
+    fn try_from(entry: ReplicaEntry) -> Result<Self, String> {
+        let short = match entry {
+            ReplicaEntry::Table {
+                node_id,
+                epoch,
+                addr,
+            } => {
+                return Ok(ReplicaInfo {
+                    node_id,
+                    epoch,
+                    addr,
+                });
+            }
+            ReplicaEntry::Short(short) => short,
+        };
+        let malformed = || format!("replica {:?} is not node_id[:epoch]@addr", short);
+        let (actor, addr) = short.trim().split_once('@').ok_or_else(malformed)?;
+        let (node_id, epoch) = actor.split_once(':').unwrap_or((actor, "0"));
+        Ok(ReplicaInfo {
+            node_id: node_id.parse().map_err(|_| malformed())?,
+            epoch: epoch.parse().map_err(|_| malformed())?,
+            addr: addr.to_string(),
+        })
+    }
+}
+
 impl ReplicaInfo {
     /// Get the ActorId for this replica
     pub fn actor_id(&self) -> ActorId {
@@ -54,20 +162,1172 @@ pub struct ReplicationConfig {
     pub buffer_size: usize,
     pub ack_timeout_ms: u64,
     pub rbilt_startup_delay_ms: u64,
+    /// Coalesce up to this many operations per peer into one message (1 disables batching)
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+    /// How long a partial batch may wait before it is sent anyway
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    /// Number of digest buckets a set is split into when comparing replicas
+    #[serde(default = "default_digest_buckets")]
+    pub digest_buckets: u32,
+    /// How often a heartbeat is sent to each peer
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u64,
+    /// Consecutive missed heartbeats before a peer is considered down
+    #[serde(default = "default_heartbeat_miss_limit")]
+    pub heartbeat_miss_limit: u32,
+    /// How many sent operations are kept to answer peers' missing operations requests
+    #[serde(default = "default_sent_log_size")]
+    pub sent_log_size: usize,
+    /// How often causally stable dots are garbage collected
+    #[serde(default = "default_gc_interval_ms")]
+    pub gc_interval_ms: u64,
+    /// At startup, take a state transfer from a peer that is this many operations ahead
+    /// (or from any peer if we have nothing at all), rather than replaying operations
+    #[serde(default = "default_snapshot_lag_threshold")]
+    pub snapshot_lag_threshold: u64,
+    /// Whether operations are sent to peers, or periodic deltas of the sets they changed
+    #[serde(default)]
+    pub mode: ReplicationMode,
+    /// How often each peer is sent a delta, in delta mode
+    #[serde(default = "default_delta_interval_ms")]
+    pub delta_interval_ms: u64,
+    /// Writes wait for peers' acks until `write_quorum` replicas have them before replying
+    /// (op mode only)
+    #[serde(default)]
+    pub sync: bool,
+    /// How many replicas, this one included, a synchronous write waits to be on: 2 (the
+    /// default) waits for one peer's ack. Capped by the peers a write is sent to.
+    #[serde(default = "default_write_quorum")]
+    pub write_quorum: usize,
+    /// How many peers each write is pushed to, the first that are up; the rest pull it
+    /// from the writer when its heartbeat shows them behind. 0 (the default) pushes to
+    /// every peer. Op mode only; every node should use the same fanout.
+    #[serde(default)]
+    pub fanout: usize,
+    /// How long a synchronous write waits for acks before replying that it is degraded
+    #[serde(default = "default_sync_timeout_ms")]
+    pub sync_timeout_ms: u64,
+    /// Set-name patterns (Redis glob dialect) this node replicates; peers only push it
+    /// operations on matching sets. Empty (the default) for every set. Op mode only.
+    #[serde(default)]
+    pub subscriptions: Vec<String>,
+    /// How replication frames this node sends are compressed
+    #[serde(default)]
+    pub compression: Compression,
+    /// Send version vectors packed, with delta encoded counters, rather than as a list of
+    /// entries. Turn on once every node reads packed ones: older builds don't.
+    #[serde(default)]
+    pub packed_vv: bool,
+    /// Replicate over TLS; plaintext when absent
+    #[serde(default)]
+    pub tls: Option<ReplicationTlsConfig>,
+}
+
+/// Replication over TLS, with every node holding a certificate signed by the cluster's CA
+///
+/// A node's certificate is both the one its listener presents and the one it connects
+/// to peers with, so it must name the host (address or DNS name) peers reach it by.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationTlsConfig {
+    /// The cluster's CA certificate, in PEM
+    pub ca_cert_path: PathBuf,
+    /// This node's certificate chain, leaf first
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Refuse connections from peers without a certificate signed by the CA, so nothing
+    /// else can push operations (the default)
+    #[serde(default = "default_require_client_cert")]
+    pub require_client_cert: bool,
+}
+
+fn default_require_client_cert() -> bool {
+    true
+}
+
+/// Compression of replication frames
+///
+/// Chosen by the sender per frame (every node decodes every algorithm), so
+/// nodes with different settings interoperate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd at the given level (1-22, 3 is zstd's default)
+    Zstd { level: i32 },
+}
+
+/// How local writes reach peers
+///
+/// Every node in a cluster should use the same mode: a node in op mode neither sends
+/// deltas nor applies its own operations to its peers' state any other way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationMode {
+    /// Each operation is sent to every peer, acked and retransmitted until it is
+    #[default]
+    Op,
+    /// Every `delta_interval_ms` each peer is sent the state of the sets changed since
+    /// the last delta it acked. Slower to arrive, but a lost delta is covered by the next.
+    Delta,
+}
+
+fn default_delta_interval_ms() -> u64 {
+    100
+}
+
+fn default_write_quorum() -> usize {
+    2
+}
+
+fn default_sync_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_max_batch() -> usize {
+    1
+}
+
+fn default_flush_interval_ms() -> u64 {
+    10
+}
+
+fn default_digest_buckets() -> u32 {
+    16
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    1000
+}
+
+fn default_heartbeat_miss_limit() -> u32 {
+    3
+}
+
+fn default_sent_log_size() -> usize {
+    10_000
+}
+
+fn default_gc_interval_ms() -> u64 {
+    60_000
+}
+
+fn default_snapshot_lag_threshold() -> u64 {
+    10_000
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageConfig {
     pub sqlite_cache_size: i32,
     pub sqlite_busy_timeout: i32,
+    /// Most connections the SQLite read pool opens. They are `query_only`, and writes have
+    /// a connection of their own, so reads never wait on a write for a connection.
+    /// Was `pool_max_size`, which configs may still use.
+    #[serde(default = "default_read_pool_size", alias = "pool_max_size")]
+    pub read_pool_size: u32,
+    /// Connections the SQLite read pool keeps open when idle
+    #[serde(default = "default_pool_min_idle")]
+    pub pool_min_idle: u32,
+    /// How often the WAL is checkpointed and truncated (0, the default, leaves it to SQLite)
+    #[serde(default)]
+    pub checkpoint_interval_ms: u64,
+    /// Sets with at least this many elements get an in-memory Bloom filter, so a
+    /// SISMEMBER for an absent element skips the database (0, the default, builds none)
+    #[serde(default)]
+    pub bloom_min_elements: u64,
+    /// The false positive rate the Bloom filters are sized for
+    #[serde(default = "default_bloom_fp_rate")]
+    pub bloom_fp_rate: f64,
+    /// Longest set name a client can write to, in bytes
+    #[serde(default = "default_max_key_length")]
+    pub max_key_length: usize,
+    /// Largest member a client can add, in bytes
+    #[serde(default = "default_max_element_size")]
+    pub max_element_size: usize,
+    /// Most members a client can add or remove in one command
+    #[serde(default = "default_max_members_per_command")]
+    pub max_members_per_command: usize,
+    /// How often elements added with SADDEX are looked for once expired, and removed
+    /// (0 never removes them, though reads still leave them out)
+    #[serde(default = "default_expiry_interval_ms")]
+    pub expiry_interval_ms: u64,
+    /// Keep a log of every operation written or applied, so peers that lost operations can
+    /// be sent them even after a restart (SQLite only; off by default)
+    #[serde(default)]
+    pub op_log: bool,
+    /// Bytes of the database SQLite reads through a memory map rather than read() calls,
+    /// on each connection (0, the default, maps none)
+    #[serde(default)]
+    pub mmap_size: u64,
+    /// SQLite page size in bytes, a power of two from 512 to 65536 (0, the default, leaves
+    /// SQLite's 4096). Only a new database takes it: WAL mode fixes the page size of an
+    /// existing one, even through VACUUM.
+    #[serde(default)]
+    pub page_size: u32,
+    /// Encrypt the database on disk with SQLCipher (needs the `sqlcipher` feature);
+    /// plaintext when absent
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+/// The key a SQLCipher database is encrypted with, given inline or in a file
+///
+/// Set one of the two. A key file keeps the key out of the config and the environment,
+/// and is what should be used: an inline `key` is for trying encryption out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// The passphrase itself
+    #[serde(default)]
+    pub key: Option<String>,
+    /// A file holding the passphrase, a trailing newline ignored
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+}
+
+impl EncryptionConfig {
+    /// The passphrase, read from `key_file` if that's where it is
+    pub fn passphrase(&self) -> std::io::Result<String> {
+        let passphrase = match (&self.key, &self.key_file) {
+            (Some(key), _) => key.clone(),
+            (None, Some(key_file)) => {
+                let contents = std::fs::read_to_string(key_file)?;
+                contents.trim_end_matches(['\r', '\n']).to_string()
+            }
+            (None, None) => String::new(),
+        };
+        if passphrase.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the encryption key is empty",
+            ));
+        }
+        Ok(passphrase)
+    }
+}
+
+impl StorageConfig {
+    /// Check the pool sizes make sense: at least one connection, and no more idle than the maximum.
+    /// And that the Bloom filter false positive rate is a probability, the page size is one
+    /// SQLite supports, the client write limits allow something, and encryption has one
+    /// key and is compiled in.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.read_pool_size < 1 {
+            return Err(format!(
+                "read_pool_size must be at least 1, not {}",
+                self.read_pool_size
+            ));
+        }
+        if self.pool_min_idle < 1 || self.pool_min_idle > self.read_pool_size {
+            return Err(format!(
+                "pool_min_idle must be between 1 and read_pool_size ({}), not {}",
+                self.read_pool_size, self.pool_min_idle
+            ));
+        }
+        if !(self.bloom_fp_rate > 0.0 && self.bloom_fp_rate < 1.0) {
+            return Err(format!(
+                "bloom_fp_rate must be between 0 and 1, not {}",
+                self.bloom_fp_rate
+            ));
+        }
+        if self.page_size != 0
+            && !(self.page_size.is_power_of_two() && (512..=65536).contains(&self.page_size))
+        {
+            return Err(format!(
+                "page_size must be a power of two from 512 to 65536, not {}",
+                self.page_size
+            ));
+        }
+        for (name, limit) in [
+            ("max_key_length", self.max_key_length),
+            ("max_element_size", self.max_element_size),
+            ("max_members_per_command", self.max_members_per_command),
+        ] {
+            if limit < 1 {
+                return Err(format!("{} must be at least 1", name));
+            }
+        }
+        if let Some(encryption) = &self.encryption {
+            if encryption.key.is_some() == encryption.key_file.is_some() {
+                return Err("encryption needs one of key or key_file".to_string());
+            }
+            if !cfg!(feature = "sqlcipher") {
+                return Err("encryption needs bigsets built with the sqlcipher feature".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_read_pool_size() -> u32 {
+    16
+}
+
+fn default_pool_min_idle() -> u32 {
+    1
+}
+
+fn default_bloom_fp_rate() -> f64 {
+    0.01
+}
+
+pub(crate) fn default_max_key_length() -> usize {
+    1024
+}
+
+pub(crate) fn default_max_element_size() -> usize {
+    64 * 1024
+}
+
+pub(crate) fn default_max_members_per_command() -> usize {
+    100_000
+}
+
+fn default_expiry_interval_ms() -> u64 {
+    1000
 }
 
+/// Why a configuration can't be used
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The file couldn't be read, or doesn't deserialize into a Config
+    #[error(transparent)]
+    Load(#[from] config::ConfigError),
+    /// A setting that can't work, with the field it is in
+    #[error("{field}: {message}")]
+    Invalid { field: String, message: String },
+}
+
+fn environment() -> config::Environment {
+    config::Environment::with_prefix(ENV_PREFIX)
+        .separator("__")
+        .try_parsing(true)
+        .list_separator(",")
+        .with_list_parse_key("cluster.replicas")
+}
+
+fn invalid(field: impl Into<String>, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field: field.into(),
+        message: message.into(),
+    }
+}
+
+/// Prefix of the environment variables that override config settings, see `Config::from_env`
+pub const ENV_PREFIX: &str = "BIGSETS";
+
 impl Config {
-    pub fn from_file(path: &str) -> Result<Self, config::ConfigError> {
-        let settings = config::Config::builder()
-            .add_source(config::File::with_name(path))
-            .build()?;
+    /// Load and validate the configuration in a TOML file, overridden by any
+    /// environment variables, see `from_env`
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        Self::load(
+            config::Config::builder().add_source(config::File::with_name(path)),
+            environment(),
+        )
+    }
+
+    /// Load and validate the configuration in environment variables alone, for
+    /// deployments without a config file
+    ///
+    /// A setting's variable is its path in config.toml, upper cased, after `BIGSETS` and
+    /// with `__` between the parts: `BIGSETS__SERVER__NODE_ID` for `server.node_id`,
+    /// `BIGSETS__REPLICATION__TLS__CA_CERT_PATH` for `replication.tls.ca_cert_path`.
+    /// `BIGSETS__CLUSTER__REPLICAS` is a comma separated list of `node_id[:epoch]@addr`.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::load(config::Config::builder(), environment())
+    }
+
+    /// Deserialize `sources` with `env` layered on top, then validate the result
+    fn load(
+        sources: config::ConfigBuilder<config::builder::DefaultState>,
+        env: config::Environment,
+    ) -> Result<Self, ConfigError> {
+        let config: Config = sources.add_source(env).build()?.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the settings make sense together, naming the field of the first that doesn't
+    ///
+    /// Deserializing only checks each setting on its own; these would otherwise fail at
+    /// runtime, or quietly never work (say, two replicas with one node_id).
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let server = &self.server;
+        let mut addrs = vec![
+            ("server.api_addr", &server.api_addr),
+            ("server.replication_addr", &server.replication_addr),
+        ];
+        if let Some(http_addr) = &server.http_addr {
+            addrs.push(("server.http_addr", http_addr));
+        }
+        for (i, (field, addr)) in addrs.iter().enumerate() {
+            if let Some((other, _)) = addrs[..i].iter().find(|(_, a)| a == addr) {
+                return Err(invalid(*field, format!("{} is also {}", addr, other)));
+            }
+        }
+
+        parse_log_level(&server.log_level)
+            .map_err(|message| invalid("server.log_level", message))?;
+        for (field, value) in [
+            ("server.read_buffer_size", server.read_buffer_size),
+            ("server.max_connections", server.max_connections),
+        ] {
+            if value == 0 {
+                return Err(invalid(field, "must be at least 1, not 0"));
+            }
+        }
+        if server.max_request_size < server.read_buffer_size {
+            return Err(invalid(
+                "server.max_request_size",
+                format!(
+                    "must be at least server.read_buffer_size ({}), not {}",
+                    server.read_buffer_size, server.max_request_size
+                ),
+            ));
+        }
+
+        let mut node_ids = HashMap::new();
+        let mut replica_addrs = HashMap::new();
+        for (i, replica) in self.cluster.replicas.iter().enumerate() {
+            let field = format!("cluster.replicas[{}]", i);
+            if let Some(first) = node_ids.insert(replica.node_id, i) {
+                return Err(invalid(
+                    format!("{}.node_id", field),
+                    format!(
+                        "node {} is also cluster.replicas[{}]",
+                        replica.node_id, first
+                    ),
+                ));
+            }
+            if let Some(first) = replica_addrs.insert(&replica.addr, i) {
+                return Err(invalid(
+                    format!("{}.addr", field),
+                    format!("{} is also cluster.replicas[{}]", replica.addr, first),
+                ));
+            }
+            if replica.node_id == server.node_id {
+                if replica.epoch != server.epoch {
+                    return Err(invalid(
+                        format!("{}.epoch", field),
+                        format!(
+                            "this node is at epoch {} (server.epoch), not {}",
+                            server.epoch, replica.epoch
+                        ),
+                    ));
+                }
+            } else if replica.addr == server.replication_addr {
+                return Err(invalid(
+                    format!("{}.addr", field),
+                    format!(
+                        "node {} is at {}, this node's server.replication_addr",
+                        replica.node_id, replica.addr
+                    ),
+                ));
+            }
+        }
+        if !node_ids.contains_key(&server.node_id) {
+            return Err(invalid(
+                "cluster.replicas",
+                format!(
+                    "this node (server.node_id {}) must be one of the replicas",
+                    server.node_id
+                ),
+            ));
+        }
+
+        let replication = &self.replication;
+        for (field, value) in [
+            ("replication.buffer_size", replication.buffer_size),
+            ("replication.max_batch", replication.max_batch),
+            (
+                "replication.digest_buckets",
+                replication.digest_buckets as usize,
+            ),
+            (
+                "replication.heartbeat_miss_limit",
+                replication.heartbeat_miss_limit as usize,
+            ),
+            ("replication.write_quorum", replication.write_quorum),
+        ] {
+            if value == 0 {
+                return Err(invalid(field, "must be at least 1, not 0"));
+            }
+        }
+        if replication.sync && replication.mode == ReplicationMode::Delta {
+            return Err(invalid(
+                "replication.sync",
+                "deltas are not acked per write, so delta mode can't be synchronous",
+            ));
+        }
+        if replication.sync && replication.write_quorum > self.cluster.replicas.len() {
+            return Err(invalid(
+                "replication.write_quorum",
+                format!(
+                    "there are only {} replicas, not {}",
+                    self.cluster.replicas.len(),
+                    replication.write_quorum
+                ),
+            ));
+        }
+        if replication.fanout > 0 {
+            if replication.mode == ReplicationMode::Delta {
+                return Err(invalid(
+                    "replication.fanout",
+                    "deltas go to every peer, so delta mode has no fanout",
+                ));
+            }
+            if replication.sync && replication.fanout + 1 < replication.write_quorum {
+                return Err(invalid(
+                    "replication.fanout",
+                    format!(
+                        "writes pushed to {} peers can't reach a write_quorum of {}",
+                        replication.fanout, replication.write_quorum
+                    ),
+                ));
+            }
+        }
+        if !replication.subscriptions.is_empty() && replication.mode == ReplicationMode::Delta {
+            return Err(invalid(
+                "replication.subscriptions",
+                "deltas go to every peer, so delta mode can't subscribe to a subset of sets",
+            ));
+        }
+        if let Compression::Zstd { level } = replication.compression
+            && !zstd::compression_level_range().contains(&level)
+        {
+            return Err(invalid(
+                "replication.compression.level",
+                format!(
+                    "zstd levels are {:?}, not {}",
+                    zstd::compression_level_range(),
+                    level
+                ),
+            ));
+        }
+
+        self.storage
+            .validate()
+            .map_err(|message| invalid("storage", message))
+    }
+}
+
+fn parse_log_level(level: &str) -> Result<LevelFilter, String> {
+    level.parse().map_err(|_| {
+        format!(
+            "{} is not one of off, error, warn, info, debug or trace",
+            level
+        )
+    })
+}
+
+/// The settings CONFIG SET can change while the server runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tunables {
+    pub log_level: LevelFilter,
+    pub slowlog_threshold: Duration,
+    pub slowlog_max_len: usize,
+    pub ack_timeout_ms: u64,
+    pub retry_backoff_ms: u64,
+    pub max_retries: u32,
+}
+
+/// The settings a node is running with, for CONFIG GET and CONFIG SET
+///
+/// What the tunables tune (the slow log, retransmission, logging) reads them each time it
+/// uses them, so a change applies straight away. It isn't written back to the config file.
+/// The other settings CONFIG GET reports need a restart to change.
+#[derive(Debug)]
+pub struct RuntimeConfig {
+    tunables: RwLock<Tunables>,
+    /// Settings that can't change while running, by their path in config.toml
+    fixed: Vec<(&'static str, String)>,
+}
+
+impl RuntimeConfig {
+    pub fn new(config: &Config) -> Self {
+        let server = &config.server;
+        let runtime = Self::for_replication(&config.replication);
+        {
+            let mut tunables = runtime.tunables.write().unwrap();
+            tunables.log_level = parse_log_level(&server.log_level).unwrap_or(LevelFilter::INFO);
+            tunables.slowlog_threshold = Duration::from_millis(server.slowlog_threshold_ms);
+            tunables.slowlog_max_len = server.slowlog_max_len;
+        }
+        Self {
+            fixed: vec![
+                ("server.node_id", server.node_id.to_string()),
+                ("server.epoch", server.epoch.to_string()),
+                ("server.api_addr", server.api_addr.clone()),
+                ("server.replication_addr", server.replication_addr.clone()),
+                (
+                    "server.http_addr",
+                    server.http_addr.clone().unwrap_or_default(),
+                ),
+                ("server.db_path", server.db_path.display().to_string()),
+                (
+                    "server.read_buffer_size",
+                    server.read_buffer_size.to_string(),
+                ),
+                (
+                    "server.max_request_size",
+                    server.max_request_size.to_string(),
+                ),
+                ("server.max_connections", server.max_connections.to_string()),
+            ],
+            ..runtime
+        }
+    }
+
+    /// Replication's tunables alone, for a ReplicationManager made without a whole Config
+    ///
+    /// The slow log is off, and logging is at info.
+    pub fn for_replication(replication: &ReplicationConfig) -> Self {
+        Self::from(Tunables {
+            log_level: LevelFilter::INFO,
+            slowlog_threshold: Duration::ZERO,
+            slowlog_max_len: default_slowlog_max_len(),
+            ack_timeout_ms: replication.ack_timeout_ms,
+            retry_backoff_ms: replication.retry_backoff_ms,
+            max_retries: replication.max_retries,
+        })
+    }
+
+    /// The current tunables
+    pub fn tunables(&self) -> Tunables {
+        self.tunables.read().unwrap().clone()
+    }
+
+    /// Every setting whose path matches the glob `pattern` (`*` and `?`), with its value
+    pub fn get(&self, pattern: &str) -> Vec<(String, String)> {
+        let tunables = self.tunables();
+        let values = [
+            (
+                "server.log_level",
+                tunables.log_level.to_string().to_lowercase(),
+            ),
+            (
+                "server.slowlog_threshold_ms",
+                tunables.slowlog_threshold.as_millis().to_string(),
+            ),
+            (
+                "server.slowlog_max_len",
+                tunables.slowlog_max_len.to_string(),
+            ),
+            (
+                "replication.ack_timeout_ms",
+                tunables.ack_timeout_ms.to_string(),
+            ),
+            (
+                "replication.retry_backoff_ms",
+                tunables.retry_backoff_ms.to_string(),
+            ),
+            ("replication.max_retries", tunables.max_retries.to_string()),
+        ];
+        values
+            .into_iter()
+            .chain(self.fixed.iter().cloned())
+            .filter(|(name, _)| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    /// Change a tunable, named by its path in config.toml
+    pub fn set(&self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| invalid(name, format!("{} is not a whole number", value)))
+        };
+        let mut tunables = self.tunables.write().unwrap();
+        match name {
+            "server.log_level" => {
+                let level = parse_log_level(value).map_err(|message| invalid(name, message))?;
+                crate::telemetry::set_log_level(level);
+                tunables.log_level = level;
+            }
+            "server.slowlog_threshold_ms" => {
+                tunables.slowlog_threshold = Duration::from_millis(number()?)
+            }
+            "server.slowlog_max_len" => tunables.slowlog_max_len = number()? as usize,
+            "replication.ack_timeout_ms" => tunables.ack_timeout_ms = number()?,
+            "replication.retry_backoff_ms" => tunables.retry_backoff_ms = number()?,
+            "replication.max_retries" => {
+                tunables.max_retries = u32::try_from(number()?)
+                    .map_err(|_| invalid(name, format!("{} is too large", value)))?
+            }
+            _ if self.fixed.iter().any(|(fixed, _)| *fixed == name) => {
+                return Err(invalid(
+                    name,
+                    "can't be changed while running, only in the config file (with a restart)",
+                ));
+            }
+            _ => return Err(invalid(name, "no such setting")),
+        }
+        Ok(())
+    }
+
+    /// Set the slow log's threshold and how many commands it keeps
+    pub fn set_slowlog(&self, threshold: Duration, max_len: usize) {
+        let mut tunables = self.tunables.write().unwrap();
+        tunables.slowlog_threshold = threshold;
+        tunables.slowlog_max_len = max_len;
+    }
+}
+
+impl From<Tunables> for RuntimeConfig {
+    fn from(tunables: Tunables) -> Self {
+        Self {
+            tunables: RwLock::new(tunables),
+            fixed: Vec::new(),
+        }
+    }
+}
+
+/// Whether `text` matches the glob `pattern`, as Redis matches them: `*` is any run of
+/// bytes, `?` any one byte, `[abc]`, `[a-z]` and `[^abc]` one byte in or out of a class,
+/// and `\` makes the next byte literal
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Where to resume after the last `*`: the pattern after it, and the text it has taken
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p + 1, t));
+            p += 1;
+            continue;
+        }
+        match glob_token(&pattern[p..]) {
+            Some((token, len)) if token.matches(text[t]) => {
+                p += len;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more byte
+                Some((after, taken)) => {
+                    star = Some((after, taken + 1));
+                    p = after;
+                    t = taken + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// One part of a glob pattern that matches a single byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GlobToken {
+    /// `?`
+    Any,
+    /// A byte, escaped or not
+    Byte(u8),
+    /// `[...]`: inclusive byte ranges, a lone byte being a range of one
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+impl GlobToken {
+    fn matches(&self, c: u8) -> bool {
+        match self {
+            GlobToken::Any => true,
+            GlobToken::Byte(b) => *b == c,
+            GlobToken::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+        }
+    }
+}
+
+/// The token at the start of `pattern`, which isn't `*`, and how many bytes of the
+/// pattern it takes; None at the end of the pattern. A `[` with no closing `]` is a
+/// literal `[`, and so is a `\` that ends the pattern.
+pub(crate) fn glob_token(pattern: &[u8]) -> Option<(GlobToken, usize)> {
+    match *pattern.first()? {
+        b'?' => Some((GlobToken::Any, 1)),
+        b'\\' if pattern.len() > 1 => Some((GlobToken::Byte(pattern[1]), 2)),
+        b'[' => Some(glob_class(pattern).unwrap_or((GlobToken::Byte(b'['), 1))),
+        c => Some((GlobToken::Byte(c), 1)),
+    }
+}
+
+fn glob_class(pattern: &[u8]) -> Option<(GlobToken, usize)> {
+    let negated = pattern.get(1) == Some(&b'^');
+    let mut i = if negated { 2 } else { 1 };
+    let mut ranges = Vec::new();
+    loop {
+        let lo = match *pattern.get(i)? {
+            b']' => break,
+            b'\\' => {
+                i += 1;
+                *pattern.get(i)?
+            }
+            c => c,
+        };
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&c| c != b']') {
+            let hi = pattern[i + 2];
+            ranges.push((lo.min(hi), lo.max(hi)));
+            i += 3;
+        } else {
+            ranges.push((lo, lo));
+            i += 1;
+        }
+    }
+    Some((GlobToken::Class { negated, ranges }, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml_config(TOML)
+    }
+
+    fn toml_config(toml: &str) -> Config {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    /// Load from `toml`, if any, overridden by `vars` rather than the real environment
+    fn load(toml: Option<&str>, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let mut sources = config::Config::builder();
+        if let Some(toml) = toml {
+            sources = sources.add_source(config::File::from_str(toml, config::FileFormat::Toml));
+        }
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::load(sources, environment().source(Some(vars)))
+    }
+
+    const TOML: &str = r#"
+        [server]
+        node_id = 1
+        api_addr = "127.0.0.1:6379"
+        replication_addr = "127.0.0.1:7379"
+        db_path = "./data/node-1.db"
+
+        [cluster]
+        replicas = [
+            { node_id = 1, addr = "127.0.0.1:7379" },
+            { node_id = 2, addr = "127.0.0.1:7380" },
+        ]
+
+        [replication]
+        max_retries = 5
+        retry_backoff_ms = 100
+        buffer_size = 1000
+        ack_timeout_ms = 500
+        rbilt_startup_delay_ms = 1000
+
+        [storage]
+        sqlite_cache_size = 10000
+        sqlite_busy_timeout = 5000
+    "#;
+
+    fn invalid_field(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected an invalid field, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate_accepts_a_sensible_config() {
+        config().validate().unwrap();
+        Config::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/../../config.toml")).unwrap();
+    }
+
+    #[test]
+    fn test_validate_names_the_offending_field() {
+        let mut c = config();
+        c.cluster.replicas[1].node_id = 1;
+        assert_eq!(invalid_field(&c), "cluster.replicas[1].node_id");
+
+        let mut c = config();
+        c.cluster.replicas[1].addr = "127.0.0.1:7379".to_string();
+        assert_eq!(invalid_field(&c), "cluster.replicas[1].addr");
+
+        // Another node at our address, with our own entry elsewhere
+        let mut c = config();
+        c.cluster.replicas[0].addr = "10.0.0.1:7379".to_string();
+        c.cluster.replicas[1].addr = "127.0.0.1:7379".to_string();
+        assert_eq!(invalid_field(&c), "cluster.replicas[1].addr");
+
+        let mut c = config();
+        c.cluster.replicas.remove(0);
+        assert_eq!(invalid_field(&c), "cluster.replicas");
+
+        let mut c = config();
+        c.cluster.replicas[0].epoch = 1;
+        assert_eq!(invalid_field(&c), "cluster.replicas[0].epoch");
+
+        let mut c = config();
+        c.server.http_addr = Some(c.server.api_addr.clone());
+        assert_eq!(invalid_field(&c), "server.http_addr");
+
+        let mut c = config();
+        c.replication.buffer_size = 0;
+        assert_eq!(invalid_field(&c), "replication.buffer_size");
+
+        let mut c = config();
+        c.replication.compression = Compression::Zstd { level: 99 };
+        assert_eq!(invalid_field(&c), "replication.compression.level");
+
+        let mut c = config();
+        c.replication.sync = true;
+        c.replication.mode = ReplicationMode::Delta;
+        assert_eq!(invalid_field(&c), "replication.sync");
+
+        let mut c = config();
+        c.replication.sync = true;
+        c.replication.write_quorum = c.cluster.replicas.len() + 1;
+        assert_eq!(invalid_field(&c), "replication.write_quorum");
+
+        let mut c = config();
+        c.cluster.replicas.push(ReplicaInfo {
+            node_id: 3,
+            epoch: 0,
+            addr: "127.0.0.1:7381".to_string(),
+        });
+        c.replication.sync = true;
+        c.replication.write_quorum = 3;
+        c.replication.fanout = 1;
+        assert_eq!(invalid_field(&c), "replication.fanout");
+
+        let mut c = config();
+        c.replication.mode = ReplicationMode::Delta;
+        c.replication.subscriptions = vec!["user:*".to_string()];
+        assert_eq!(invalid_field(&c), "replication.subscriptions");
+
+        let mut c = config();
+        c.server.log_level = "loud".to_string();
+        assert_eq!(invalid_field(&c), "server.log_level");
+
+        let mut c = config();
+        c.server.read_buffer_size = 0;
+        assert_eq!(invalid_field(&c), "server.read_buffer_size");
+
+        let mut c = config();
+        c.server.max_connections = 0;
+        assert_eq!(invalid_field(&c), "server.max_connections");
+
+        let mut c = config();
+        c.server.max_request_size = c.server.read_buffer_size - 1;
+        assert_eq!(invalid_field(&c), "server.max_request_size");
+
+        let mut c = config();
+        c.storage.read_pool_size = 0;
+        assert_eq!(invalid_field(&c), "storage");
+    }
+
+    #[test]
+    fn test_invalid_config_message() {
+        let mut c = config();
+        c.replication.buffer_size = 0;
+        assert_eq!(
+            c.validate().unwrap_err().to_string(),
+            "replication.buffer_size: must be at least 1, not 0"
+        );
+    }
+
+    #[test]
+    fn test_encryption_needs_one_key_and_the_feature() {
+        let mut c = config();
+        let key = || EncryptionConfig {
+            key: Some("secret".to_string()),
+            key_file: None,
+        };
+        c.storage.encryption = Some(key());
+        let message = c.storage.validate().map(|_| String::new());
+        if cfg!(feature = "sqlcipher") {
+            assert_eq!(message, Ok(String::new()));
+        } else {
+            assert!(message.unwrap_err().contains("sqlcipher feature"));
+        }
+
+        c.storage.encryption = Some(EncryptionConfig {
+            key_file: Some(PathBuf::from("key")),
+            ..key()
+        });
+        assert!(c.storage.validate().unwrap_err().contains("one of key"));
+        c.storage.encryption = Some(EncryptionConfig {
+            key: None,
+            key_file: None,
+        });
+        assert!(c.storage.validate().unwrap_err().contains("one of key"));
+    }
+
+    #[test]
+    fn test_encryption_key_file() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let key_file = temp.path().join("key");
+        std::fs::write(&key_file, "secret\n").unwrap();
+        let encryption = EncryptionConfig {
+            key: None,
+            key_file: Some(key_file.clone()),
+        };
+        assert_eq!(encryption.passphrase().unwrap(), "secret");
+
+        std::fs::write(&key_file, "\n").unwrap();
+        assert!(encryption.passphrase().is_err());
+        std::fs::remove_file(&key_file).unwrap();
+        assert!(encryption.passphrase().is_err());
+    }
+
+    #[test]
+    fn test_environment_overrides_the_file() {
+        let config = load(
+            Some(TOML),
+            &[
+                ("BIGSETS__SERVER__NODE_ID", "2"),
+                ("BIGSETS__SERVER__REPLICATION_ADDR", "127.0.0.1:7380"),
+                ("BIGSETS__REPLICATION__COMPRESSION__ALGORITHM", "zstd"),
+                ("BIGSETS__REPLICATION__COMPRESSION__LEVEL", "3"),
+                ("BIGSETS__STORAGE__BLOOM_FP_RATE", "0.05"),
+                ("OTHER__SERVER__NODE_ID", "3"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.server.node_id, 2);
+        assert_eq!(config.server.replication_addr, "127.0.0.1:7380");
+        assert_eq!(config.server.api_addr, "127.0.0.1:6379");
+        assert_eq!(
+            config.replication.compression,
+            Compression::Zstd { level: 3 }
+        );
+        assert_eq!(config.storage.bloom_fp_rate, 0.05);
+        assert_eq!(config.cluster.replicas.len(), 2);
+    }
+
+    #[test]
+    fn test_pool_max_size_still_sets_the_read_pool_size() {
+        let config = load(Some(TOML), &[("BIGSETS__STORAGE__POOL_MAX_SIZE", "8")]).unwrap();
+        assert_eq!(config.storage.read_pool_size, 8);
+    }
+
+    #[test]
+    fn test_environment_alone() {
+        let config = load(
+            None,
+            &[
+                ("BIGSETS__SERVER__NODE_ID", "3"),
+                ("BIGSETS__SERVER__EPOCH", "1"),
+                ("BIGSETS__SERVER__API_ADDR", "0.0.0.0:6379"),
+                ("BIGSETS__SERVER__REPLICATION_ADDR", "0.0.0.0:7379"),
+                ("BIGSETS__SERVER__DB_PATH", "/data/node.db"),
+                (
+                    "BIGSETS__CLUSTER__REPLICAS",
+                    "1@node-1:7379,2@node-2:7379,3:1@node-3:7379",
+                ),
+                ("BIGSETS__REPLICATION__MAX_RETRIES", "5"),
+                ("BIGSETS__REPLICATION__RETRY_BACKOFF_MS", "100"),
+                ("BIGSETS__REPLICATION__BUFFER_SIZE", "1000"),
+                ("BIGSETS__REPLICATION__ACK_TIMEOUT_MS", "500"),
+                ("BIGSETS__REPLICATION__RBILT_STARTUP_DELAY_MS", "1000"),
+                ("BIGSETS__STORAGE__SQLITE_CACHE_SIZE", "10000"),
+                ("BIGSETS__STORAGE__SQLITE_BUSY_TIMEOUT", "5000"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.server.actor_id(), ActorId::new(3, 1));
+        assert_eq!(
+            config.cluster.replicas[2],
+            ReplicaInfo {
+                node_id: 3,
+                epoch: 1,
+                addr: "node-3:7379".to_string(),
+            }
+        );
+        assert_eq!(config.replication.max_batch, 1);
+    }
+
+    #[test]
+    fn test_environment_overrides_are_validated() {
+        let e = load(Some(TOML), &[("BIGSETS__REPLICATION__BUFFER_SIZE", "0")]).unwrap_err();
+        assert!(
+            matches!(e, ConfigError::Invalid { field, .. } if field == "replication.buffer_size")
+        );
+
+        let e = load(
+            Some(TOML),
+            &[("BIGSETS__CLUSTER__REPLICAS", "1@a:7379,two@b:7379")],
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("two@b:7379"), "{}", e);
+    }
+
+    #[test]
+    fn test_runtime_config_sets_tunables_only() {
+        let runtime = RuntimeConfig::new(&config());
+        assert_eq!(
+            runtime.get("replication.*_ms"),
+            vec![
+                ("replication.ack_timeout_ms".to_string(), "500".to_string()),
+                (
+                    "replication.retry_backoff_ms".to_string(),
+                    "100".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            runtime.get("server.node_id"),
+            vec![("server.node_id".to_string(), "1".to_string())]
+        );
+
+        runtime.set("replication.ack_timeout_ms", "250").unwrap();
+        runtime.set("server.log_level", "debug").unwrap();
+        assert_eq!(runtime.tunables().ack_timeout_ms, 250);
+        assert_eq!(runtime.tunables().log_level, LevelFilter::DEBUG);
+        assert_eq!(runtime.get("server.log_level")[0].1, "debug".to_string());
+
+        let field = |e: ConfigError| match e {
+            ConfigError::Invalid { field, .. } => field,
+            other => panic!("expected an invalid field, got {:?}", other),
+        };
+        assert_eq!(
+            field(runtime.set("server.node_id", "2").unwrap_err()),
+            "server.node_id"
+        );
+        assert_eq!(
+            field(runtime.set("replication.max_retries", "-1").unwrap_err()),
+            "replication.max_retries"
+        );
+        assert!(runtime.set("no.such_setting", "1").is_err());
+        assert_eq!(runtime.tunables().max_retries, 5);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"server.*", b"server.node_id"));
+        assert!(glob_match(b"*_ms", b"replication.ack_timeout_ms"));
+        assert!(glob_match(b"s?t*", b"set-1"));
+        assert!(glob_match(b"*a*b", b"xaxxab"));
+        assert!(!glob_match(b"*a*b", b"xaxxa"));
+        assert!(!glob_match(b"server", b"server.node_id"));
+        assert!(!glob_match(b"?", b""));
 
-        settings.try_deserialize()
+        assert!(glob_match(b"[abc]x", b"bx"));
+        assert!(!glob_match(b"[abc]x", b"dx"));
+        assert!(glob_match(b"[^abc]x", b"dx"));
+        assert!(glob_match(b"[a-c][z-x]", b"by"));
+        assert!(glob_match(b"[ab", b"[ab"), "an unclosed class is literal");
+        assert!(!glob_match(b"[]]", b"]"), "an empty class matches nothing");
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"[\\]x]", b"]"));
+        assert!(glob_match(b"*\\", b"a\\"));
     }
 }
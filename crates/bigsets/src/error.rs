@@ -0,0 +1,109 @@
+use std::io;
+use thiserror::Error;
+
+/// Errors from the `Server` and `ServerWrapper` API
+///
+/// Storage backends report everything as a `rusqlite::Error`, with other failures boxed
+/// in `ToSqlConversionFailure`; converting one unboxes those into their own variants.
+#[derive(Debug, Error)]
+pub enum BigsetError {
+    /// The storage backend failed, e.g. SQLite reported an error or the disk is full
+    #[error(transparent)]
+    Storage(rusqlite::Error),
+    /// No storage connection came free within the pool's timeout
+    #[error("storage connection pool exhausted: {0}")]
+    PoolExhausted(r2d2::Error),
+    /// Reading or writing a file, such as a backup
+    #[error("I/O error: {0}")]
+    Io(io::Error),
+    /// Data that doesn't decode, such as a corrupt or unsupported backup
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    /// State that isn't causally ahead of ours, so applying it would lose operations
+    #[error("causality error: {0}")]
+    Causality(String),
+    /// A remote operation no replica could have made, refused rather than applied
+    #[error("invalid operation: {0}")]
+    InvalidOperation(String),
+    /// A task the server relies on has stopped
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, BigsetError>;
+
+impl From<rusqlite::Error> for BigsetError {
+    fn from(e: rusqlite::Error) -> Self {
+        let rusqlite::Error::ToSqlConversionFailure(boxed) = e else {
+            return BigsetError::Storage(e);
+        };
+        let boxed = match boxed.downcast::<r2d2::Error>() {
+            Ok(e) => return BigsetError::PoolExhausted(*e),
+            Err(boxed) => boxed,
+        };
+        match boxed.downcast::<io::Error>() {
+            Ok(e) => (*e).into(),
+            Err(boxed) => BigsetError::Storage(rusqlite::Error::ToSqlConversionFailure(boxed)),
+        }
+    }
+}
+
+impl From<io::Error> for BigsetError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData => BigsetError::Protocol(e.to_string()),
+            _ => BigsetError::Io(e),
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for BigsetError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        BigsetError::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_errors_are_unboxed() {
+        let boxed = |e: Box<dyn std::error::Error + Send + Sync>| {
+            BigsetError::from(rusqlite::Error::ToSqlConversionFailure(e))
+        };
+
+        assert!(matches!(
+            BigsetError::from(rusqlite::Error::QueryReturnedNoRows),
+            BigsetError::Storage(rusqlite::Error::QueryReturnedNoRows)
+        ));
+        assert!(matches!(
+            boxed(Box::new(io::Error::from(io::ErrorKind::NotFound))),
+            BigsetError::Io(e) if e.kind() == io::ErrorKind::NotFound
+        ));
+        assert!(matches!(
+            boxed(Box::new(io::Error::new(io::ErrorKind::InvalidData, "bad magic"))),
+            BigsetError::Protocol(msg) if msg == "bad magic"
+        ));
+        assert!(matches!(
+            boxed("the writer thread has stopped".into()),
+            BigsetError::Storage(rusqlite::Error::ToSqlConversionFailure(_))
+        ));
+    }
+
+    #[test]
+    fn test_pool_timeout_is_pool_exhausted() {
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(std::time::Duration::from_millis(10))
+            .build(r2d2_sqlite::SqliteConnectionManager::memory())
+            .unwrap();
+        let _held = pool.get().unwrap();
+        let e = pool.get().unwrap_err();
+
+        assert!(matches!(
+            BigsetError::from(rusqlite::Error::ToSqlConversionFailure(Box::new(e))),
+            BigsetError::PoolExhausted(_)
+        ));
+    }
+}
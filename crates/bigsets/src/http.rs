@@ -0,0 +1,342 @@
+use crate::error::{BigsetError, Result};
+use crate::server::CommandResult;
+use crate::types::VersionVector;
+use crate::wrapper::ServerWrapper;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Json, Path, State};
+use axum::http::StatusCode;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use bytes::Bytes;
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Header carrying a version vector, in the same form as RESP's `vv:` argument
+///
+/// Writes return the version vector they produced in it, a read given one is causal
+/// (like a RESP read with `vv:`), and a read that isn't ready yet returns the server's.
+pub const VV_HEADER: &str = "x-bigsets-vv";
+
+/// A write answered 202 Accepted rather than 204 was acked by fewer peers than synchronous
+/// replication needs; this says how many, as `acked/needed`
+pub const ACKS_HEADER: &str = "x-bigsets-acks";
+
+/// API server handling JSON over HTTP, for clients without a Redis client
+///
+/// Each route calls the same ServerWrapper method as the RESP command it stands for:
+///
+/// - `GET /sets/{name}` - SCARD, `{"cardinality": n}`
+/// - `GET /sets/{name}/members` - SMEMBERS, `{"members": [...]}`, streamed from storage
+/// - `POST /sets/{name}/members` - SADD the `{"members": [...]}` in the body
+/// - `DELETE /sets/{name}/members` - SREM the `{"members": [...]}` in the body
+/// - `GET /sets/{name}/members/{member}` - SISMEMBER, `{"member": bool}`
+/// - `PUT /sets/{name}/members/{member}` - SADD one member
+/// - `DELETE /sets/{name}/members/{member}` - SREM one member
+/// - `POST /sets/{name}/contains` - SMISMEMBER the `{"members": [...]}` in the body,
+///   `{"members": [bool, ...]}`
+///
+/// Members are JSON strings, so members that aren't UTF-8 are returned lossily.
+/// Errors are `{"error": message}`: 400 for a bad request, 503 while loading, when a
+/// causal read isn't ready or when storage has no connection free, 500 for storage errors.
+pub struct HttpServer {
+    wrapper: Arc<ServerWrapper>,
+    addr: String,
+    shutdown: CancellationToken,
+}
+
+impl HttpServer {
+    pub fn new(wrapper: Arc<ServerWrapper>, addr: String) -> Self {
+        Self {
+            wrapper,
+            addr,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Stop serving once `shutdown` is cancelled, see `run`
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Serve until an error occurs or the shutdown token is cancelled
+    ///
+    /// On shutdown it stops accepting, and returns once the requests in flight are answered.
+    pub async fn run(self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        info!("HTTP API listening on {}", self.addr);
+        axum::serve(listener, router(self.wrapper))
+            .with_graceful_shutdown(self.shutdown.cancelled_owned())
+            .await
+    }
+}
+
+fn router(wrapper: Arc<ServerWrapper>) -> Router {
+    Router::new()
+        .route("/sets/{name}", get(scard))
+        .route(
+            "/sets/{name}/members",
+            get(smembers).post(sadd).delete(srem),
+        )
+        .route(
+            "/sets/{name}/members/{member}",
+            get(sismember).put(sadd_one).delete(srem_one),
+        )
+        .route("/sets/{name}/contains", post(smismember))
+        .with_state(wrapper)
+}
+
+#[derive(Debug, Deserialize)]
+struct MembersRequest {
+    members: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Cardinality {
+    cardinality: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct Membership {
+    member: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Memberships {
+    members: Vec<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+type Wrapper = State<Arc<ServerWrapper>>;
+
+async fn sadd(
+    State(wrapper): Wrapper,
+    Path(name): Path<String>,
+    ClientVv(client_vv): ClientVv,
+    request: Json<MembersRequest>,
+) -> Response {
+    let Some(members) = members(request) else {
+        return no_members();
+    };
+    write_response(wrapper.sadd(&name, &members, client_vv.as_ref()).await)
+}
+
+async fn sadd_one(
+    State(wrapper): Wrapper,
+    Path((name, member)): Path<(String, String)>,
+    ClientVv(client_vv): ClientVv,
+) -> Response {
+    write_response(
+        wrapper
+            .sadd(&name, &[Bytes::from(member)], client_vv.as_ref())
+            .await,
+    )
+}
+
+async fn srem(
+    State(wrapper): Wrapper,
+    Path(name): Path<String>,
+    ClientVv(client_vv): ClientVv,
+    request: Json<MembersRequest>,
+) -> Response {
+    let Some(members) = members(request) else {
+        return no_members();
+    };
+    write_response(wrapper.srem(&name, &members, client_vv.as_ref()).await)
+}
+
+async fn srem_one(
+    State(wrapper): Wrapper,
+    Path((name, member)): Path<(String, String)>,
+    ClientVv(client_vv): ClientVv,
+) -> Response {
+    write_response(
+        wrapper
+            .srem(&name, &[Bytes::from(member)], client_vv.as_ref())
+            .await,
+    )
+}
+
+async fn scard(
+    State(wrapper): Wrapper,
+    Path(name): Path<String>,
+    ClientVv(client_vv): ClientVv,
+) -> Response {
+    match wrapper.scard(&name, client_vv.as_ref()).await {
+        Ok(CommandResult::Integer(cardinality)) => {
+            Json(Cardinality { cardinality }).into_response()
+        }
+        other => failure(other),
+    }
+}
+
+async fn sismember(
+    State(wrapper): Wrapper,
+    Path((name, member)): Path<(String, String)>,
+    ClientVv(client_vv): ClientVv,
+) -> Response {
+    match wrapper
+        .sismember(&name, &Bytes::from(member), client_vv.as_ref())
+        .await
+    {
+        Ok(CommandResult::Integer(val)) => Json(Membership { member: val == 1 }).into_response(),
+        other => failure(other),
+    }
+}
+
+async fn smismember(
+    State(wrapper): Wrapper,
+    Path(name): Path<String>,
+    ClientVv(client_vv): ClientVv,
+    request: Json<MembersRequest>,
+) -> Response {
+    let Some(members) = members(request) else {
+        return no_members();
+    };
+    match wrapper
+        .smismember(&name, &members, client_vv.as_ref())
+        .await
+    {
+        Ok(CommandResult::BoolArray(members)) => Json(Memberships { members }).into_response(),
+        other => failure(other),
+    }
+}
+
+/// Writes the body a batch of members at a time as storage yields them, like RESP's
+/// SMEMBERS. A storage error part way through can only be reported by dropping the
+/// connection.
+async fn smembers(
+    State(wrapper): Wrapper,
+    Path(name): Path<String>,
+    ClientVv(client_vv): ClientVv,
+) -> Response {
+    let members = match wrapper
+        .smembers_stream(&name, None, client_vv.as_ref())
+        .await
+    {
+        Ok(Ok(members)) => members,
+        Ok(Err(result)) => return failure(Ok(result)),
+        Err(e) => return failure(Err(e)),
+    };
+
+    // Whether a member has been written yet, so the next needs a comma before it
+    let batches = stream::unfold((members, false), |(mut members, mut written)| async move {
+        let chunk = members.next_batch().await?.map(|batch| {
+            let mut chunk = Vec::new();
+            for member in batch {
+                if written {
+                    chunk.push(b',');
+                }
+                written = true;
+                serde_json::to_writer(&mut chunk, &String::from_utf8_lossy(&member))
+                    .expect("a string serializes");
+            }
+            Bytes::from(chunk)
+        });
+        Some((chunk, (members, written)))
+    });
+    let body = stream::iter([Ok(Bytes::from_static(b"{\"members\":["))])
+        .chain(batches)
+        .chain(stream::iter([Ok(Bytes::from_static(b"]}"))]));
+
+    (
+        [("content-type", "application/json")],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// The members of a request body, None if there are none
+fn members(Json(request): Json<MembersRequest>) -> Option<Vec<Bytes>> {
+    (!request.members.is_empty()).then(|| request.members.into_iter().map(Bytes::from).collect())
+}
+
+/// The version vector a read or write must see, from `VV_HEADER`
+struct ClientVv(Option<VersionVector>);
+
+impl<S: Sync> FromRequestParts<S> for ClientVv {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Response> {
+        let Some(value) = parts.headers.get(VV_HEADER) else {
+            return Ok(ClientVv(None));
+        };
+        value
+            .to_str()
+            .ok()
+            .and_then(VersionVector::from_str)
+            .map(|vv| ClientVv(Some(vv)))
+            .ok_or_else(|| error_response(StatusCode::BAD_REQUEST, "invalid version vector"))
+    }
+}
+
+/// No content, with the version vector the write produced
+fn write_response(result: Result<CommandResult>) -> Response {
+    match result {
+        Ok(CommandResult::Ok { vv: Some(vv) }) => {
+            (StatusCode::NO_CONTENT, [(VV_HEADER, vv.to_string())]).into_response()
+        }
+        Ok(CommandResult::Ok { vv: None }) => StatusCode::NO_CONTENT.into_response(),
+        // Committed here, but acked by too few peers (synchronous replication)
+        Ok(CommandResult::Degraded { vv, acked, needed }) => {
+            let acks = format!("{}/{}", acked, needed);
+            match vv {
+                Some(vv) => (
+                    StatusCode::ACCEPTED,
+                    [(VV_HEADER, vv.to_string()), (ACKS_HEADER, acks)],
+                )
+                    .into_response(),
+                None => (StatusCode::ACCEPTED, [(ACKS_HEADER, acks)]).into_response(),
+            }
+        }
+        other => failure(other),
+    }
+}
+
+fn failure(result: Result<CommandResult>) -> Response {
+    match result {
+        Ok(CommandResult::NotReady(vv)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(VV_HEADER, vv.to_string())],
+            error_response(StatusCode::SERVICE_UNAVAILABLE, "NOTREADY"),
+        )
+            .into_response(),
+        Ok(CommandResult::Error(msg)) if msg.starts_with("LOADING") => {
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &msg)
+        }
+        Ok(CommandResult::Error(msg)) => error_response(StatusCode::BAD_REQUEST, &msg),
+        Ok(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "unexpected result"),
+        Err(e @ BigsetError::PoolExhausted(_)) => {
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &e.to_string())
+        }
+        Err(e) => {
+            error!("{}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("database error: {}", e),
+            )
+        }
+    }
+}
+
+fn no_members() -> Response {
+    error_response(StatusCode::BAD_REQUEST, "no members given")
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response {
+    let error = error.to_string();
+    (status, Json(ErrorBody { error })).into_response()
+}
@@ -0,0 +1,300 @@
+//! A JSON form of `Operation`, for operators dumping the pending buffer or the op log
+//!
+//! It is for reading and diffing, not the wire: replication stays protobuf. Dots are
+//! strings in the version vector's `actor:counter` form, e.g. `"v0:1:0:5"`, and the
+//! context is the version vector's string form. An element is a JSON string when it is
+//! valid UTF-8, otherwise `{"base64": "..."}`.
+//!
+//! ```json
+//! {"set":"s","op":"add","dot":"v0:1:0:5","elements":["a",{"base64":"/w=="}],
+//!  "removed_dots":["v0:2:0:3"],"context":"v0:1:0:4,v0:2:0:3"}
+//! ```
+
+use crate::telemetry::TraceContext;
+use crate::types::{ActorId, Dot, OpType, Operation, SetAdd, VersionVector};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize)]
+struct JsonOperation {
+    set: String,
+    #[serde(flatten)]
+    op: JsonOpType,
+    context: String,
+    #[serde(default, skip_serializing_if = "TraceContext::is_empty")]
+    trace: TraceContext,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JsonOpType {
+    Add {
+        dot: String,
+        elements: Vec<JsonBytes>,
+        removed_dots: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        dot: String,
+        elements: Vec<JsonBytes>,
+        removed_dots: Vec<String>,
+    },
+    AddMulti {
+        dot: String,
+        sets: Vec<JsonSetAdd>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonSetAdd {
+    set: String,
+    elements: Vec<JsonBytes>,
+    removed_dots: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonBytes {
+    Utf8(String),
+    Base64 { base64: String },
+}
+
+impl Operation {
+    /// This operation as a line of JSON, see the module docs for the format
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&JsonOperation::from(self)).expect("operations serialize")
+    }
+
+    /// Parse an operation `to_json` wrote
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let parsed: JsonOperation = serde_json::from_str(json)?;
+        parsed.try_into().map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<&Operation> for JsonOperation {
+    fn from(operation: &Operation) -> Self {
+        let op = match &operation.op_type {
+            OpType::Add {
+                elements,
+                dot,
+                removed_dots,
+                expires_at,
+            } => JsonOpType::Add {
+                dot: dot_to_string(dot),
+                elements: elements.iter().map(bytes_to_json).collect(),
+                removed_dots: removed_dots.iter().map(dot_to_string).collect(),
+                expires_at: *expires_at,
+            },
+            OpType::Remove {
+                elements,
+                dot,
+                removed_dots,
+            } => JsonOpType::Remove {
+                dot: dot_to_string(dot),
+                elements: elements.iter().map(bytes_to_json).collect(),
+                removed_dots: removed_dots.iter().map(dot_to_string).collect(),
+            },
+            OpType::AddMulti { sets, dot } => JsonOpType::AddMulti {
+                dot: dot_to_string(dot),
+                sets: sets
+                    .iter()
+                    .map(|set| JsonSetAdd {
+                        set: set.set_name.clone(),
+                        elements: set.elements.iter().map(bytes_to_json).collect(),
+                        removed_dots: set.removed_dots.iter().map(dot_to_string).collect(),
+                    })
+                    .collect(),
+            },
+        };
+        Self {
+            set: operation.set_name.clone(),
+            op,
+            context: operation.context.to_string(),
+            trace: operation.trace_context.clone(),
+        }
+    }
+}
+
+impl TryFrom<JsonOperation> for Operation {
+    type Error = String;
+
+    fn try_from(json: JsonOperation) -> Result<Self, String> {
+        let op_type = match json.op {
+            JsonOpType::Add {
+                dot,
+                elements,
+                removed_dots,
+                expires_at,
+            } => OpType::Add {
+                elements: json_to_elements(elements)?,
+                dot: parse_dot(&dot)?,
+                removed_dots: parse_dots(&removed_dots)?,
+                expires_at,
+            },
+            JsonOpType::Remove {
+                dot,
+                elements,
+                removed_dots,
+            } => OpType::Remove {
+                elements: json_to_elements(elements)?,
+                dot: parse_dot(&dot)?,
+                removed_dots: parse_dots(&removed_dots)?,
+            },
+            JsonOpType::AddMulti { dot, sets } => OpType::AddMulti {
+                sets: sets
+                    .into_iter()
+                    .map(|set| {
+                        Ok(SetAdd {
+                            set_name: set.set,
+                            elements: json_to_elements(set.elements)?,
+                            removed_dots: parse_dots(&set.removed_dots)?,
+                        })
+                    })
+                    .collect::<Result<_, String>>()?,
+                dot: parse_dot(&dot)?,
+            },
+        };
+        let context = VersionVector::from_str(&json.context)
+            .ok_or_else(|| format!("invalid context {:?}", json.context))?;
+        Ok(Operation {
+            set_name: json.set,
+            op_type,
+            context: Arc::new(context),
+            trace_context: json.trace,
+        })
+    }
+}
+
+/// A dot as one entry of a version vector's string form, `actor:counter`
+fn dot_to_string(dot: &Dot) -> String {
+    format!("{}:{}", dot.actor_id, dot.counter)
+}
+
+fn parse_dot(s: &str) -> Result<Dot, String> {
+    let invalid = || format!("invalid dot {:?}", s);
+    let (actor_id, counter) = s.rsplit_once(':').ok_or_else(invalid)?;
+    let actor_id = ActorId::from_str(actor_id).map_err(|_| invalid())?;
+    let counter = counter.parse().map_err(|_| invalid())?;
+    Ok(Dot::new(actor_id, counter))
+}
+
+fn parse_dots(dots: &[String]) -> Result<Vec<Dot>, String> {
+    dots.iter().map(|dot| parse_dot(dot)).collect()
+}
+
+fn bytes_to_json(bytes: &Bytes) -> JsonBytes {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => JsonBytes::Utf8(s.to_string()),
+        Err(_) => JsonBytes::Base64 {
+            base64: STANDARD.encode(bytes),
+        },
+    }
+}
+
+fn json_to_elements(elements: Vec<JsonBytes>) -> Result<Vec<Bytes>, String> {
+    elements
+        .into_iter()
+        .map(|element| match element {
+            JsonBytes::Utf8(s) => Ok(Bytes::from(s)),
+            JsonBytes::Base64 { base64 } => STANDARD
+                .decode(&base64)
+                .map(Bytes::from)
+                .map_err(|e| format!("invalid base64 element {:?}: {}", base64, e)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dot(node_id: u16, counter: u64) -> Dot {
+        Dot::new(ActorId::from_node_id(node_id), counter)
+    }
+
+    fn context(dots: &[Dot]) -> Arc<VersionVector> {
+        let mut vv = VersionVector::new();
+        for dot in dots {
+            vv.update(dot.actor_id, dot.counter);
+        }
+        Arc::new(vv)
+    }
+
+    #[test]
+    fn test_operations_round_trip_through_json() {
+        let binary = Bytes::from_static(&[0xff, 0x00, 0x80]);
+        let add = Operation {
+            set_name: "s".to_string(),
+            op_type: OpType::Add {
+                elements: vec![Bytes::from("a"), binary.clone()],
+                dot: dot(1, 5),
+                removed_dots: vec![dot(2, 3)],
+                expires_at: Some(1_700_000_000_000),
+            },
+            context: context(&[dot(1, 4), dot(2, 3)]),
+            trace_context: TraceContext::from([("traceparent".to_string(), "00-1".to_string())]),
+        };
+        let remove = Operation {
+            set_name: "s".to_string(),
+            op_type: OpType::Remove {
+                elements: vec![Bytes::from("a")],
+                dot: Dot::new(ActorId::from_u64(1 << 40).unwrap(), 1),
+                removed_dots: vec![dot(1, 5)],
+            },
+            context: context(&[dot(1, 5)]),
+            trace_context: TraceContext::new(),
+        };
+        let add_multi = Operation {
+            set_name: String::new(),
+            op_type: OpType::AddMulti {
+                sets: vec![
+                    SetAdd {
+                        set_name: "x".to_string(),
+                        elements: vec![Bytes::from("1")],
+                        removed_dots: vec![],
+                    },
+                    SetAdd {
+                        set_name: "y".to_string(),
+                        elements: vec![binary],
+                        removed_dots: vec![dot(2, 1)],
+                    },
+                ],
+                dot: dot(3, 1),
+            },
+            context: context(&[]),
+            trace_context: TraceContext::new(),
+        };
+
+        for operation in [add, remove, add_multi] {
+            let json = operation.to_json();
+            assert_eq!(Operation::from_json(&json).unwrap(), operation, "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_json_is_readable() {
+        let operation = Operation {
+            set_name: "s".to_string(),
+            op_type: OpType::Add {
+                elements: vec![Bytes::from("a"), Bytes::from_static(&[0xff])],
+                dot: dot(1, 5),
+                removed_dots: vec![dot(2, 3)],
+                expires_at: None,
+            },
+            context: context(&[dot(1, 4), dot(2, 3)]),
+            trace_context: TraceContext::new(),
+        };
+
+        assert_eq!(
+            operation.to_json(),
+            r#"{"set":"s","op":"add","dot":"v0:1:0:5","elements":["a",{"base64":"/w=="}],"removed_dots":["v0:2:0:3"],"context":"v0:1:0:4,v0:2:0:3"}"#
+        );
+        assert!(Operation::from_json(r#"{"set":"s","op":"remove","dot":"5","elements":[],"removed_dots":[],"context":""}"#).is_err());
+    }
+}
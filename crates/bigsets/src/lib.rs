@@ -1,21 +1,40 @@
 // Architecture modules
 pub mod api;
 pub mod buffers;
+pub mod commands;
 pub mod config;
+pub mod error;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod json;
+pub mod network;
+pub mod node;
 pub mod proto;
 pub mod replication;
 pub mod resp;
 pub mod server;
 pub mod storage;
+pub mod telemetry;
+pub mod tls;
 pub mod types;
 pub mod wrapper;
+mod writer;
 
 // Public exports
 pub use api::ApiServer;
-pub use buffers::{PendingBuffer, UnackedBuffer};
+pub use buffers::{DeltaBuffer, PendingBuffer, SentLog, UnackedBuffer};
 pub use config::Config;
+pub use error::BigsetError;
+#[cfg(feature = "http")]
+pub use http::HttpServer;
+pub use node::{Node, NodeBuilder};
 pub use replication::{ReplicationListener, ReplicationManager};
-pub use server::{CommandResult, Server};
-pub use storage::SqliteStorage;
-pub use types::{ActorId, ActorIdError, Dot, OpType, Operation, VersionVector};
+pub use server::{CommandResult, Limits, MembersStream, Server};
+#[cfg(feature = "rocksdb")]
+pub use storage::RocksdbStorage;
+pub use storage::{MemoryStorage, ShardedStorage, SqliteStorage, Storage};
+pub use types::{
+    ActorId, ActorIdError, Dot, DotCloud, DotRange, OpType, Operation, SetAdd, SetInfo,
+    VersionVector,
+};
 pub use wrapper::ServerWrapper;
@@ -0,0 +1,270 @@
+use crate::config::ReplicationTlsConfig;
+use async_trait::async_trait;
+use rustls_pki_types::ServerName;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::warn;
+
+/// Size of each direction of an in-memory connection's buffer
+const IN_MEMORY_BUFFER: usize = 64 * 1024;
+
+/// How long an accepted TLS connection has to complete its handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshaken TLS connections waiting to be accepted
+const TLS_ACCEPT_QUEUE: usize = 64;
+
+/// A bidirectional byte stream between two replicas
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// One replication connection, whatever the transport underneath
+pub type Connection = Box<dyn AsyncStream>;
+
+/// How replicas reach each other
+///
+/// Replication speaks length-prefixed frames over a connection (see `replication::frame`),
+/// so a transport only has to open and accept connections. `TcpTransport` is used in
+/// production; `InMemoryTransport` lets a whole cluster run in one process without sockets.
+#[async_trait]
+pub trait NetworkTransport: Send + Sync {
+    /// Open a connection to the replica listening on `addr`
+    async fn connect(&self, addr: &str) -> io::Result<Connection>;
+
+    /// Start listening for connections on `addr`
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn TransportListener>>;
+}
+
+/// Accepts connections for a bound address
+#[async_trait]
+pub trait TransportListener: Send {
+    /// Wait for the next connection, returning it and a description of the remote end
+    async fn accept(&mut self) -> io::Result<(Connection, String)>;
+}
+
+/// Plain TCP
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+#[async_trait]
+impl NetworkTransport for TcpTransport {
+    async fn connect(&self, addr: &str) -> io::Result<Connection> {
+        Ok(Box::new(TcpStream::connect(addr).await?))
+    }
+
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn TransportListener>> {
+        Ok(Box::new(TcpListener::bind(addr).await?))
+    }
+}
+
+#[async_trait]
+impl TransportListener for TcpListener {
+    async fn accept(&mut self) -> io::Result<(Connection, String)> {
+        let (stream, peer_addr) = TcpListener::accept(self).await?;
+        Ok((Box::new(stream), peer_addr.to_string()))
+    }
+}
+
+/// TCP with TLS, see `ReplicationTlsConfig`
+///
+/// A peer is verified against the host part of the address it is reached at. Handshakes
+/// of accepted connections run on their own tasks, so one that stalls or fails (a host
+/// without a valid certificate) holds up no other and only gets a warning logged.
+#[derive(Clone)]
+pub struct TlsTransport {
+    connector: TlsConnector,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsTransport {
+    pub fn new(config: &ReplicationTlsConfig) -> io::Result<Self> {
+        let (connector, acceptor) = crate::tls::replication(config)?;
+        Ok(Self {
+            connector,
+            acceptor,
+        })
+    }
+}
+
+#[async_trait]
+impl NetworkTransport for TlsTransport {
+    async fn connect(&self, addr: &str) -> io::Result<Connection> {
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Box::new(self.connector.connect(name, stream).await?))
+    }
+
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn TransportListener>> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, incoming) = mpsc::channel(TLS_ACCEPT_QUEUE);
+        let acceptor = self.acceptor.clone();
+        let accepting = tokio::spawn(async move {
+            loop {
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let handshake =
+                        tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await;
+                    match handshake {
+                        Ok(Ok(stream)) => {
+                            let connection: Connection = Box::new(stream);
+                            let _ = tx.send(Ok((connection, peer_addr.to_string()))).await;
+                        }
+                        Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", peer_addr, e),
+                        Err(_) => warn!("TLS handshake with {} timed out", peer_addr),
+                    }
+                });
+            }
+        });
+        Ok(Box::new(TlsListener {
+            incoming,
+            accepting,
+        }))
+    }
+}
+
+struct TlsListener {
+    incoming: mpsc::Receiver<io::Result<(Connection, String)>>,
+    accepting: JoinHandle<()>,
+}
+
+#[async_trait]
+impl TransportListener for TlsListener {
+    async fn accept(&mut self) -> io::Result<(Connection, String)> {
+        self.incoming
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(io::Error::from(io::ErrorKind::NotConnected)))
+    }
+}
+
+impl Drop for TlsListener {
+    // Stops listening, as dropping a TcpListener would
+    fn drop(&mut self) {
+        self.accepting.abort();
+    }
+}
+
+/// A network of in-process replicas connected by in-memory pipes
+///
+/// Clones share the same network, so give every replica in a test a clone.
+/// Connecting to an address nobody is listening on (or whose listener was
+/// dropped) is refused, just like a replica that is down.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryTransport {
+    listeners: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<DuplexStream>>>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl NetworkTransport for InMemoryTransport {
+    async fn connect(&self, addr: &str) -> io::Result<Connection> {
+        let listeners = self.listeners.lock().unwrap();
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, addr.to_string());
+
+        let incoming = listeners.get(addr).ok_or_else(refused)?;
+        let (local, remote) = tokio::io::duplex(IN_MEMORY_BUFFER);
+        incoming.send(remote).map_err(|_| refused())?;
+        Ok(Box::new(local))
+    }
+
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn TransportListener>> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.get(addr).is_some_and(|l| !l.is_closed()) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, addr.to_string()));
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        listeners.insert(addr.to_string(), tx);
+        Ok(Box::new(InMemoryListener {
+            addr: addr.to_string(),
+            incoming: rx,
+        }))
+    }
+}
+
+struct InMemoryListener {
+    addr: String,
+    incoming: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+#[async_trait]
+impl TransportListener for InMemoryListener {
+    async fn accept(&mut self) -> io::Result<(Connection, String)> {
+        let stream = self
+            .incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, self.addr.clone()))?;
+        Ok((Box::new(stream), format!("in-memory peer of {}", self.addr)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_in_memory_connect_requires_listener() {
+        let transport = InMemoryTransport::new();
+        let err = transport.connect("node-1").await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let listener = transport.bind("node-1").await.unwrap();
+        assert!(transport.connect("node-1").await.is_ok());
+        assert_eq!(
+            transport.bind("node-1").await.err().unwrap().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        // A dropped listener is a replica that went down, and its address can be reused
+        drop(listener);
+        let err = transport.connect("node-1").await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(transport.bind("node-1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_round_trip() {
+        let transport = InMemoryTransport::new();
+        let mut listener = transport.bind("node-1").await.unwrap();
+
+        let mut client = transport.connect("node-1").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+
+        server.write_all(b"pong").await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"pong");
+    }
+}
@@ -0,0 +1,355 @@
+use crate::api::ApiServer;
+use crate::config::{Config, RuntimeConfig};
+#[cfg(feature = "http")]
+use crate::http::HttpServer;
+use crate::network::{NetworkTransport, TcpTransport, TlsTransport};
+use crate::replication::{ReplicationListener, ReplicationManager};
+use crate::server::{Limits, Server};
+use crate::storage::{SqliteStorage, Storage};
+use crate::types::ActorId;
+use crate::wrapper::ServerWrapper;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How long connections get to finish their commands once shutdown starts
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds a `Node` from a `Config`, wiring its components together in the right order
+pub struct NodeBuilder {
+    config: Config,
+    transport: Option<Arc<dyn NetworkTransport>>,
+}
+
+impl NodeBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            transport: None,
+        }
+    }
+
+    /// Reach peers over `transport`, instead of TCP or the TLS the config asks for
+    pub fn with_transport(mut self, transport: Arc<dyn NetworkTransport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Validate the config, open storage (creating its directory), choose the epoch to write
+    /// at (see `choose_epoch`), and create the server, replication manager and wrapper on top
+    pub async fn build(self) -> Result<Node, BoxError> {
+        let mut config = self.config;
+        config.validate()?;
+
+        if let Some(parent) = config.server.db_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        info!("Opening database at: {:?}", config.server.db_path);
+        let storage = Arc::new(SqliteStorage::open(
+            &config.server.db_path,
+            &config.storage,
+        )?);
+
+        // Peers are every other node, whatever epoch the config names it at
+        let peers = config
+            .cluster
+            .replicas
+            .iter()
+            .filter(|r| r.node_id != config.server.node_id)
+            .cloned()
+            .collect();
+        let transport: Arc<dyn NetworkTransport> = match (self.transport, &config.replication.tls) {
+            (Some(transport), _) => transport,
+            (None, Some(tls)) => {
+                info!("Replicating over TLS");
+                Arc::new(TlsTransport::new(tls)?)
+            }
+            (None, None) => Arc::new(TcpTransport),
+        };
+        let replication =
+            ReplicationManager::with_transport(peers, config.replication.clone(), transport);
+
+        config.server.epoch = choose_epoch(&config, storage.as_ref(), &replication).await?;
+        let runtime = Arc::new(RuntimeConfig::new(&config));
+        crate::telemetry::set_log_level(runtime.tunables().log_level);
+        let actor_id = config.server.actor_id();
+        info!("Actor ID: {}", actor_id);
+
+        let server = Arc::new(
+            Server::new(actor_id, storage)
+                .await?
+                .with_limits(Limits::new(&config.storage)),
+        );
+        let replication = Arc::new(replication.with_runtime_config(runtime));
+
+        let wrapper = Arc::new(ServerWrapper::new(
+            Arc::clone(&server),
+            Arc::clone(&replication),
+        ));
+
+        Ok(Node {
+            config,
+            server,
+            replication,
+            wrapper,
+            shutdown: CancellationToken::new(),
+        })
+    }
+}
+
+/// The epoch this node writes at, so it never hands out a dot it handed out before
+///
+/// `(node_id, epoch)` pairs are distinct actors, and an actor's counters must only go up.
+/// The candidate is the newest epoch of this node that the config, the stored epoch, the
+/// local version vector or any reachable peer knows of. If a peer has seen more of the
+/// candidate's events than the local version vector has, the local state was lost or
+/// restored from an older copy, and counting on from it would reuse dots, so the next
+/// epoch is taken instead. The choice is stored, and peers follow it (see
+/// `ReplicationManager::renew_peer`) when they hear from the node.
+///
+/// With no peer reachable only local knowledge is used: a node that lost its database
+/// while the rest of the cluster is down can't tell, and should be given a new epoch in
+/// its config.
+async fn choose_epoch(
+    config: &Config,
+    storage: &SqliteStorage,
+    replication: &ReplicationManager,
+) -> Result<u8, BoxError> {
+    let node_id = config.server.node_id;
+    let local_vv = storage.load_vv()?;
+    let stored = storage.load_epoch()?;
+
+    let timeout = Duration::from_millis(config.replication.ack_timeout_ms);
+    let mut peer_vvs = Vec::new();
+    for peer in &replication.peers().await {
+        match tokio::time::timeout(timeout, replication.request_vv(peer)).await {
+            Ok(Ok(vv)) => peer_vvs.push(vv),
+            Ok(Err(e)) => warn!(
+                "Could not ask peer {} for its version vector: {}",
+                peer.addr, e
+            ),
+            Err(_) => warn!("Peer {} did not send its version vector in time", peer.addr),
+        }
+    }
+    if peer_vvs.is_empty() && !replication.peers().await.is_empty() {
+        warn!(
+            "No peer reachable to check epoch {} against",
+            config.server.epoch
+        );
+    }
+
+    let candidate = std::iter::once(config.server.epoch)
+        .chain(stored)
+        .chain(local_vv.latest_epoch(node_id))
+        .chain(peer_vvs.iter().filter_map(|vv| vv.latest_epoch(node_id)))
+        .max()
+        .unwrap_or_default();
+    let counter = local_vv.get(ActorId::new(node_id, candidate));
+    let seen = peer_vvs
+        .iter()
+        .map(|vv| vv.get(ActorId::new(node_id, candidate)))
+        .max()
+        .unwrap_or(0);
+
+    let epoch = if seen > counter {
+        let next = candidate.checked_add(1).ok_or_else(|| {
+            format!(
+                "node {} has used up its epochs, give it a new node_id",
+                node_id
+            )
+        })?;
+        warn!(
+            "Peers have seen {} events from epoch {} but only {} are stored here, moving to epoch {}",
+            seen, candidate, counter, next
+        );
+        next
+    } else {
+        candidate
+    };
+    if stored != Some(epoch) {
+        storage.save_epoch(epoch)?;
+    }
+    Ok(epoch)
+}
+
+/// One node's components, built by `NodeBuilder`, and how they are run
+///
+/// `run` serves the node. Tests that want only some of it can take the parts they need,
+/// e.g. the wrapper to serve with their own `ApiServer`.
+pub struct Node {
+    config: Config,
+    server: Arc<Server>,
+    replication: Arc<ReplicationManager>,
+    wrapper: Arc<ServerWrapper>,
+    shutdown: CancellationToken,
+}
+
+impl Node {
+    pub fn builder(config: Config) -> NodeBuilder {
+        NodeBuilder::new(config)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    pub fn replication(&self) -> &Arc<ReplicationManager> {
+        &self.replication
+    }
+
+    pub fn wrapper(&self) -> &Arc<ServerWrapper> {
+        &self.wrapper
+    }
+
+    /// Cancelling this shuts the node down, see `run`
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// The RESP API server, with the configured TLS, request and connection limits
+    ///
+    /// Its slow log settings are the node's `RuntimeConfig`, which the config starts off.
+    pub fn api_server(&self) -> std::io::Result<ApiServer> {
+        let server = &self.config.server;
+        let mut api_server = ApiServer::new(Arc::clone(&self.wrapper), server.api_addr.clone())
+            .with_request_limits(server.read_buffer_size, server.max_request_size)
+            .with_max_connections(server.max_connections)
+            .with_shutdown(self.shutdown.clone());
+        if let Some(tls) = &server.tls {
+            api_server = api_server.with_tls(crate::tls::acceptor(&tls.cert_path, &tls.key_path)?);
+            info!("API server using TLS");
+        }
+        Ok(api_server)
+    }
+
+    /// The HTTP/JSON API server, if the config gives it an address
+    #[cfg(feature = "http")]
+    pub fn http_server(&self) -> Option<HttpServer> {
+        let addr = self.config.server.http_addr.clone()?;
+        Some(HttpServer::new(Arc::clone(&self.wrapper), addr).with_shutdown(self.shutdown.clone()))
+    }
+
+    pub fn replication_listener(&self) -> ReplicationListener {
+        ReplicationListener::new(
+            Arc::clone(&self.server),
+            Arc::clone(&self.replication),
+            self.config.server.replication_addr.clone(),
+        )
+        .with_shutdown(self.shutdown.clone())
+    }
+
+    /// Serve the node until the shutdown token is cancelled, or its endpoint servers stop
+    ///
+    /// Starts the endpoint servers and the replication, checkpoint and expiry loops. On shutdown
+    /// the endpoint servers drain (given `DRAIN_TIMEOUT`), peers get a final send of what
+    /// they haven't acked, and the storage is checkpointed.
+    pub async fn run(&self) -> Result<(), BoxError> {
+        // Reads wait for startup learning (RBILT) in the replication listener
+        self.server.set_ready(false);
+
+        let api_server = self.api_server()?;
+        let mut api_handle = tokio::spawn(async move {
+            if let Err(e) = api_server.run().await {
+                error!("API server error: {}", e);
+            }
+        });
+        info!("API server started on {}", self.config.server.api_addr);
+
+        let http_handle = self.spawn_http();
+
+        let replication_listener = self.replication_listener();
+        let mut repl_handle = tokio::spawn(async move {
+            if let Err(e) = replication_listener.run().await {
+                error!("Replication server error: {}", e);
+            }
+        });
+        info!(
+            "Replication server started on {}",
+            self.config.server.replication_addr
+        );
+
+        // Dropped on return, which stops them
+        let mut background = JoinSet::new();
+        background.spawn(Arc::clone(&self.replication).run_retransmit());
+        background.spawn(Arc::clone(&self.replication).run_batch_flush());
+        background.spawn(Arc::clone(&self.replication).run_delta_sync(Arc::clone(&self.server)));
+        background.spawn(Arc::clone(&self.replication).run_heartbeat(Arc::clone(&self.server)));
+        background.spawn(Arc::clone(&self.replication).run_dot_gc(Arc::clone(&self.server)));
+        let checkpoint_interval_ms = self.config.storage.checkpoint_interval_ms;
+        if checkpoint_interval_ms > 0 {
+            background.spawn(Arc::clone(&self.server).run_checkpoints(checkpoint_interval_ms));
+        }
+        let expiry_interval_ms = self.config.storage.expiry_interval_ms;
+        if expiry_interval_ms > 0 {
+            background.spawn(Arc::clone(&self.wrapper).run_expiry(expiry_interval_ms));
+        }
+
+        // Biased, as the endpoint servers stop too once the token is cancelled
+        tokio::select! {
+            biased;
+            _ = self.shutdown.cancelled() => {}
+            result = async { tokio::try_join!(&mut api_handle, &mut repl_handle) } => {
+                result?;
+                return Ok(());
+            }
+        }
+
+        // Stop accepting, and let connections finish the commands they are running
+        info!("Shutting down");
+        let drained = tokio::time::timeout(DRAIN_TIMEOUT, async {
+            let _ = tokio::join!(api_handle, repl_handle);
+            if let Some(http_handle) = http_handle {
+                let _ = http_handle.await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Connections still open after {:?}, shutting down anyway",
+                DRAIN_TIMEOUT
+            );
+        }
+        background.abort_all();
+
+        // Nothing more is written now: send peers what they haven't acked, then checkpoint
+        if tokio::time::timeout(DRAIN_TIMEOUT, self.replication.send_deltas(&self.server))
+            .await
+            .is_err()
+        {
+            warn!("Final deltas to peers timed out");
+        }
+        self.replication.shutdown().await;
+        self.server.shutdown().await?;
+        info!("Node {} stopped", self.server.actor_id());
+        Ok(())
+    }
+
+    #[cfg(feature = "http")]
+    fn spawn_http(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let http_server = self.http_server()?;
+        Some(tokio::spawn(async move {
+            if let Err(e) = http_server.run().await {
+                error!("HTTP API server error: {}", e);
+            }
+        }))
+    }
+
+    #[cfg(not(feature = "http"))]
+    fn spawn_http(&self) -> Option<tokio::task::JoinHandle<()>> {
+        if let Some(http_addr) = &self.config.server.http_addr {
+            warn!(
+                "Not serving HTTP on {}: built without the http feature",
+                http_addr
+            );
+        }
+        None
+    }
+}
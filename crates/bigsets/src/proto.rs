@@ -6,31 +6,68 @@ pub mod replication {
 // Don't glob re-export to avoid naming conflicts with crate::types
 // Users should access protobuf types via proto::replication::*
 
-use crate::types::{Dot, OpType, Operation, VersionVector};
+use crate::storage::Digest;
+use crate::types::{
+    ActorId, Dot, DotCloud, DotRange, ElementDots, OpType, Operation, SetAdd, SetDelta,
+    SetSnapshot, VersionVector,
+};
+use std::sync::Arc;
 
-/// Convert internal Operation to protobuf Operation
-pub fn operation_to_proto(op: &Operation) -> replication::Operation {
-    let context = version_vector_to_proto(&op.context);
+/// The most removed dots one operation may expand to, across all its sets; a run of a
+/// few bytes on the wire could otherwise ask for billions
+const MAX_REMOVED_DOTS: u64 = 1 << 22;
+
+/// Convert internal Operation to protobuf Operation, its context packed if `packed_vv`
+pub fn operation_to_proto(op: &Operation, packed_vv: bool) -> replication::Operation {
+    let context = version_vector_to_proto(&op.context, packed_vv);
 
     let op_type = match &op.op_type {
         OpType::Add {
             elements,
             dot,
             removed_dots,
-        } => Some(replication::operation::OpType::Add(replication::AddOp {
-            elements: elements.clone(),
-            dot: Some(dot_to_proto(dot)),
-            removed_dots: removed_dots.iter().map(dot_to_proto).collect(),
-        })),
+            expires_at,
+        } => {
+            let (removed_dots, removed_ranges) = removed_dots_to_proto(removed_dots);
+            Some(replication::operation::OpType::Add(replication::AddOp {
+                elements: elements.clone(),
+                dot: Some(dot_to_proto(dot)),
+                removed_dots,
+                removed_ranges,
+                expires_at: expires_at.unwrap_or(0),
+            }))
+        }
         OpType::Remove {
             elements,
             dot,
             removed_dots,
-        } => Some(replication::operation::OpType::Remove(
-            replication::RemoveOp {
-                elements: elements.clone(),
+        } => {
+            let (removed_dots, removed_ranges) = removed_dots_to_proto(removed_dots);
+            Some(replication::operation::OpType::Remove(
+                replication::RemoveOp {
+                    elements: elements.clone(),
+                    dot: Some(dot_to_proto(dot)),
+                    removed_dots,
+                    removed_ranges,
+                },
+            ))
+        }
+        OpType::AddMulti { sets, dot } => Some(replication::operation::OpType::MultiAdd(
+            replication::MultiAddOp {
+                sets: sets
+                    .iter()
+                    .map(|set| {
+                        let (removed_dots, removed_ranges) =
+                            removed_dots_to_proto(&set.removed_dots);
+                        replication::SetAdd {
+                            set_name: set.set_name.clone(),
+                            elements: set.elements.clone(),
+                            removed_dots,
+                            removed_ranges,
+                        }
+                    })
+                    .collect(),
                 dot: Some(dot_to_proto(dot)),
-                removed_dots: removed_dots.iter().map(dot_to_proto).collect(),
             },
         )),
     };
@@ -39,41 +76,555 @@ pub fn operation_to_proto(op: &Operation) -> replication::Operation {
         set_name: op.set_name.clone(),
         context: Some(context),
         op_type,
+        trace_context: op
+            .trace_context
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
     }
 }
 
 /// Convert protobuf Operation to internal Operation
 pub fn proto_to_operation(proto: &replication::Operation) -> Option<Operation> {
     let context = proto_to_version_vector(proto.context.as_ref()?)?;
+    let mut budget = MAX_REMOVED_DOTS;
 
     let op_type = match proto.op_type.as_ref()? {
         replication::operation::OpType::Add(add_op) => OpType::Add {
             elements: add_op.elements.clone(),
             dot: proto_to_dot(add_op.dot.as_ref()?)?,
-            removed_dots: add_op
-                .removed_dots
-                .iter()
-                .filter_map(proto_to_dot)
-                .collect(),
+            removed_dots: proto_to_removed_dots(
+                &add_op.removed_dots,
+                &add_op.removed_ranges,
+                &mut budget,
+            )?,
+            expires_at: (add_op.expires_at != 0).then_some(add_op.expires_at),
         },
         replication::operation::OpType::Remove(rem_op) => OpType::Remove {
             elements: rem_op.elements.clone(),
             dot: proto_to_dot(rem_op.dot.as_ref()?)?,
-            removed_dots: rem_op
-                .removed_dots
+            removed_dots: proto_to_removed_dots(
+                &rem_op.removed_dots,
+                &rem_op.removed_ranges,
+                &mut budget,
+            )?,
+        },
+        replication::operation::OpType::MultiAdd(multi_op) => OpType::AddMulti {
+            sets: multi_op
+                .sets
                 .iter()
-                .filter_map(proto_to_dot)
-                .collect(),
+                .map(|set| {
+                    Some(SetAdd {
+                        set_name: set.set_name.clone(),
+                        elements: set.elements.clone(),
+                        removed_dots: proto_to_removed_dots(
+                            &set.removed_dots,
+                            &set.removed_ranges,
+                            &mut budget,
+                        )?,
+                    })
+                })
+                .collect::<Option<_>>()?,
+            dot: proto_to_dot(multi_op.dot.as_ref()?)?,
         },
     };
 
     Some(Operation {
         set_name: proto.set_name.clone(),
         op_type,
-        context,
+        context: Arc::new(context),
+        trace_context: proto
+            .trace_context
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
     })
 }
 
+impl Operation {
+    /// The length of this operation encoded by `operation_to_proto`, worked out from its
+    /// fields without encoding it, for deciding when a batch is big enough to send or
+    /// worth compressing
+    pub fn estimated_wire_size(&self, packed_vv: bool) -> usize {
+        let op_type = match &self.op_type {
+            OpType::Add {
+                elements,
+                dot,
+                removed_dots,
+                expires_at,
+            } => {
+                elements_len(elements)
+                    + message_len(dot_len(dot))
+                    + removed_dots_len(removed_dots)
+                    + uint_len(expires_at.unwrap_or(0))
+            }
+            OpType::Remove {
+                elements,
+                dot,
+                removed_dots,
+            } => {
+                elements_len(elements) + message_len(dot_len(dot)) + removed_dots_len(removed_dots)
+            }
+            OpType::AddMulti { sets, dot } => {
+                let sets: usize = sets
+                    .iter()
+                    .map(|set| {
+                        message_len(
+                            bytes_len(set.set_name.len())
+                                + elements_len(&set.elements)
+                                + removed_dots_len(&set.removed_dots),
+                        )
+                    })
+                    .sum();
+                sets + message_len(dot_len(dot))
+            }
+        };
+
+        let trace_context: usize = self
+            .trace_context
+            .iter()
+            .map(|(k, v)| message_len(bytes_len(k.len()) + bytes_len(v.len())))
+            .sum();
+
+        bytes_len(self.set_name.len())
+            + message_len(version_vector_len(&self.context, packed_vv))
+            + message_len(op_type)
+            + trace_context
+    }
+}
+
+// Encoded field lengths, tag included; every field number is below 16, so tags are one byte
+
+/// A string or bytes field, left out when empty
+fn bytes_len(len: usize) -> usize {
+    if len == 0 { 0 } else { message_len(len) }
+}
+
+/// A message field, or one entry of a repeated field, always written
+fn message_len(len: usize) -> usize {
+    1 + prost::encoding::encoded_len_varint(len as u64) + len
+}
+
+/// A uint64 field, left out when zero
+fn uint_len(value: u64) -> usize {
+    if value == 0 {
+        0
+    } else {
+        1 + prost::encoding::encoded_len_varint(value)
+    }
+}
+
+fn elements_len(elements: &[bytes::Bytes]) -> usize {
+    elements.iter().map(|e| message_len(e.len())).sum()
+}
+
+fn dot_len(dot: &Dot) -> usize {
+    bytes_len(dot.actor_id.bytes().len()) + uint_len(dot.counter)
+}
+
+/// Removed dots as `removed_dots_to_proto` splits them into lone dots and runs
+fn removed_dots_len(dots: &[Dot]) -> usize {
+    DotRange::from_dots(dots)
+        .iter()
+        .map(|range| {
+            let actor_len = bytes_len(range.actor_id.bytes().len());
+            if range.start == range.end {
+                message_len(actor_len + uint_len(range.start))
+            } else {
+                message_len(actor_len + uint_len(range.start) + uint_len(range.end))
+            }
+        })
+        .sum()
+}
+
+/// A version vector as `version_vector_to_proto` encodes it
+fn version_vector_len(vv: &VersionVector, packed: bool) -> usize {
+    if packed {
+        return bytes_len(vv.to_packed_bytes().len());
+    }
+    vv.counters
+        .iter()
+        .map(|(actor_id, &counter)| {
+            message_len(bytes_len(actor_id.bytes().len()) + uint_len(counter))
+        })
+        .sum()
+}
+
+/// Wrap outgoing operations in a message: a lone operation as-is, several as a batch
+pub fn operations_to_message(
+    ops: &[Operation],
+    packed_vv: bool,
+) -> replication::ReplicationMessage {
+    let payload = match ops {
+        [op] => {
+            replication::replication_message::Payload::Operation(operation_to_proto(op, packed_vv))
+        }
+        ops => replication::replication_message::Payload::Batch(replication::OperationBatch {
+            operations: ops
+                .iter()
+                .map(|op| operation_to_proto(op, packed_vv))
+                .collect(),
+        }),
+    };
+
+    replication::ReplicationMessage {
+        payload: Some(payload),
+    }
+}
+
+/// Build an anti-entropy request for the given digest buckets of a set (all of it if `buckets` is empty)
+pub fn sync_request_to_message(
+    set_name: &str,
+    vv: &VersionVector,
+    buckets: &[u32],
+    bucket_count: u32,
+    packed_vv: bool,
+) -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::SyncRequest(
+            replication::SyncRequest {
+                set_name: set_name.to_string(),
+                vv: Some(version_vector_to_proto(vv, packed_vv)),
+                bucket_count,
+                buckets: buckets.to_vec(),
+            },
+        )),
+    }
+}
+
+/// Build a request comparing our bucket digests for a set with a peer's
+pub fn digest_request_to_message(
+    set_name: &str,
+    digests: &[Digest],
+) -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::DigestRequest(
+            replication::DigestRequest {
+                set_name: set_name.to_string(),
+                bucket_digests: digests
+                    .iter()
+                    .map(|d| bytes::Bytes::copy_from_slice(d))
+                    .collect(),
+            },
+        )),
+    }
+}
+
+/// The buckets where the requester's digests differ from `digests`
+///
+/// A digest of the wrong length never matches.
+pub fn differing_buckets(proto: &replication::DigestRequest, digests: &[Digest]) -> Vec<u32> {
+    proto
+        .bucket_digests
+        .iter()
+        .zip(digests)
+        .enumerate()
+        .filter(|(_, (theirs, ours))| theirs.as_ref() != ours.as_slice())
+        .map(|(bucket, _)| bucket as u32)
+        .collect()
+}
+
+/// The set name and version vector of an anti-entropy request
+pub fn proto_to_sync_request(proto: &replication::SyncRequest) -> Option<(String, VersionVector)> {
+    let vv = proto_to_version_vector(proto.vv.as_ref()?)?;
+    Some((proto.set_name.clone(), vv))
+}
+
+/// Build the reply to an anti-entropy request
+pub fn sync_response_to_proto(
+    set_name: &str,
+    vv: &VersionVector,
+    elements: &[ElementDots],
+    packed_vv: bool,
+) -> replication::SyncResponse {
+    replication::SyncResponse {
+        set_name: set_name.to_string(),
+        vv: Some(version_vector_to_proto(vv, packed_vv)),
+        elements: elements.iter().map(element_dots_to_proto).collect(),
+    }
+}
+
+/// The responder's version vector and the elements of an anti-entropy reply
+pub fn proto_to_sync_response(
+    proto: &replication::SyncResponse,
+) -> Option<(VersionVector, Vec<ElementDots>)> {
+    let vv = proto_to_version_vector(proto.vv.as_ref()?)?;
+    let elements = proto
+        .elements
+        .iter()
+        .map(proto_to_element_dots)
+        .collect::<Option<_>>()?;
+    Some((vv, elements))
+}
+
+/// Build a state transfer request
+pub fn snapshot_request_to_message() -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::SnapshotRequest(
+            replication::SnapshotRequest {},
+        )),
+    }
+}
+
+/// Build a request for a peer's version vector
+pub fn vv_request_to_message() -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::VvRequest(
+            replication::VvRequest {},
+        )),
+    }
+}
+
+/// Split a snapshot into chunks of at most `max_elements` elements, the last one carrying `vv`
+pub fn snapshot_to_chunks(
+    vv: &VersionVector,
+    sets: &[SetSnapshot],
+    max_elements: usize,
+    packed_vv: bool,
+) -> Vec<replication::SnapshotChunk> {
+    let mut chunks: Vec<replication::SnapshotChunk> = sets
+        .iter()
+        .flat_map(|(set_name, elements)| {
+            elements
+                .chunks(max_elements.max(1))
+                .map(move |chunk| replication::SnapshotChunk {
+                    set_name: set_name.clone(),
+                    elements: chunk.iter().map(element_dots_to_proto).collect(),
+                    done: false,
+                    vv: None,
+                })
+        })
+        .collect();
+
+    chunks.push(replication::SnapshotChunk {
+        done: true,
+        vv: Some(version_vector_to_proto(vv, packed_vv)),
+        ..Default::default()
+    });
+    chunks
+}
+
+/// Wrap a snapshot chunk for pushing to a peer, rather than replying to its request
+pub fn snapshot_chunk_to_message(
+    chunk: replication::SnapshotChunk,
+) -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::SnapshotChunk(
+            chunk,
+        )),
+    }
+}
+
+/// Add a chunk to the snapshot being received
+///
+/// Returns the snapshot's version vector once the last chunk arrives, or None for a malformed chunk.
+pub fn add_snapshot_chunk(
+    sets: &mut Vec<SetSnapshot>,
+    chunk: &replication::SnapshotChunk,
+) -> Option<Option<VersionVector>> {
+    let elements = chunk
+        .elements
+        .iter()
+        .map(proto_to_element_dots)
+        .collect::<Option<Vec<_>>>()?;
+
+    if !elements.is_empty() {
+        match sets.last_mut() {
+            Some((set_name, existing)) if *set_name == chunk.set_name => existing.extend(elements),
+            _ => sets.push((chunk.set_name.clone(), elements)),
+        }
+    }
+
+    if chunk.done {
+        Some(Some(proto_to_version_vector(chunk.vv.as_ref()?)?))
+    } else {
+        Some(None)
+    }
+}
+
+/// Build a request for the operations a peer sent that `from_vv` has not seen
+pub fn missing_ops_to_message(
+    from_vv: &VersionVector,
+    packed_vv: bool,
+) -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::MissingOps(
+            replication::MissingOps {
+                from_vv: Some(version_vector_to_proto(from_vv, packed_vv)),
+            },
+        )),
+    }
+}
+
+/// The version vector a missing operations request starts from
+pub fn proto_to_missing_ops(proto: &replication::MissingOps) -> Option<VersionVector> {
+    proto_to_version_vector(proto.from_vv.as_ref()?)
+}
+
+/// Build the reply to a missing operations request
+pub fn missing_ops_response_to_proto(
+    ops: &[Operation],
+    complete: bool,
+    packed_vv: bool,
+) -> replication::MissingOpsResponse {
+    replication::MissingOpsResponse {
+        operations: ops
+            .iter()
+            .map(|op| operation_to_proto(op, packed_vv))
+            .collect(),
+        complete,
+    }
+}
+
+/// Build an RBILT startup request for at most `max_ops` of the operations `vv` has not seen
+pub fn rbilt_request_to_message(
+    vv: &VersionVector,
+    max_ops: u32,
+    packed_vv: bool,
+) -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::RbiltRequest(
+            replication::RbiltRequest {
+                vv: Some(version_vector_to_proto(vv, packed_vv)),
+                max_ops,
+            },
+        )),
+    }
+}
+
+/// The version vector and chunk size of an RBILT request
+pub fn proto_to_rbilt_request(proto: &replication::RbiltRequest) -> Option<(VersionVector, u32)> {
+    Some((proto_to_version_vector(proto.vv.as_ref()?)?, proto.max_ops))
+}
+
+/// Build one chunk of the reply to an RBILT request
+pub fn rbilt_response_to_proto(
+    ops: &[Operation],
+    done: bool,
+    complete: bool,
+    packed_vv: bool,
+) -> replication::RbiltResponse {
+    replication::RbiltResponse {
+        operations: ops
+            .iter()
+            .map(|op| operation_to_proto(op, packed_vv))
+            .collect(),
+        done,
+        complete,
+    }
+}
+
+/// Build a heartbeat from this node, carrying its version vector and the set-name
+/// patterns it replicates
+pub fn heartbeat_to_message(
+    actor_id: ActorId,
+    vv: &VersionVector,
+    subscriptions: &[String],
+    packed_vv: bool,
+) -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::Heartbeat(
+            replication::Heartbeat {
+                subscriptions: subscriptions.to_vec(),
+                ..heartbeat_to_proto(actor_id, vv, packed_vv)
+            },
+        )),
+    }
+}
+
+/// A heartbeat on its own, the reply to a `VvRequest`
+pub fn heartbeat_to_proto(
+    actor_id: ActorId,
+    vv: &VersionVector,
+    packed_vv: bool,
+) -> replication::Heartbeat {
+    replication::Heartbeat {
+        actor_id: actor_id.bytes().to_vec().into(),
+        vv: Some(version_vector_to_proto(vv, packed_vv)),
+        subscriptions: Vec::new(),
+    }
+}
+
+/// The sender of a heartbeat and its version vector (empty if it sent none)
+pub fn proto_to_heartbeat(proto: &replication::Heartbeat) -> Option<(ActorId, VersionVector)> {
+    let actor_id = ActorId::from_bytes(&proto.actor_id).ok()?;
+    let vv = match &proto.vv {
+        Some(vv) => proto_to_version_vector(vv)?,
+        None => VersionVector::new(),
+    };
+    Some((actor_id, vv))
+}
+
+/// Build a delta of the sets a sender changed (delta-state replication)
+pub fn delta_to_message(
+    actor_id: ActorId,
+    vv: &VersionVector,
+    sets: &[SetDelta],
+    packed_vv: bool,
+) -> replication::ReplicationMessage {
+    replication::ReplicationMessage {
+        payload: Some(replication::replication_message::Payload::Delta(
+            replication::Delta {
+                actor_id: actor_id.bytes().to_vec().into(),
+                vv: Some(version_vector_to_proto(vv, packed_vv)),
+                sets: sets
+                    .iter()
+                    .map(|(set_name, elements)| replication::SetDelta {
+                        set_name: set_name.clone(),
+                        elements: elements.iter().map(element_dots_to_proto).collect(),
+                    })
+                    .collect(),
+            },
+        )),
+    }
+}
+
+/// The sender of a delta, what it covers, and its sets
+pub fn proto_to_delta(
+    proto: &replication::Delta,
+) -> Option<(ActorId, VersionVector, Vec<SetDelta>)> {
+    let actor_id = ActorId::from_bytes(&proto.actor_id).ok()?;
+    let vv = proto_to_version_vector(proto.vv.as_ref()?)?;
+    let sets = proto
+        .sets
+        .iter()
+        .map(|set| {
+            let elements = set
+                .elements
+                .iter()
+                .map(proto_to_element_dots)
+                .collect::<Option<_>>()?;
+            Some((set.set_name.clone(), elements))
+        })
+        .collect::<Option<_>>()?;
+    Some((actor_id, vv, sets))
+}
+
+/// Build the ack for an applied operation
+pub fn ack_to_proto(op: &Operation) -> replication::Ack {
+    replication::Ack {
+        set_name: op.set_name.clone(),
+        operation_dot: Some(dot_to_proto(&op.dot())),
+    }
+}
+
+/// The dot of the operation a peer acknowledged
+pub fn proto_to_ack(proto: &replication::Ack) -> Option<Dot> {
+    proto_to_dot(proto.operation_dot.as_ref()?)
+}
+
+fn element_dots_to_proto((value, dots): &ElementDots) -> replication::ElementDots {
+    replication::ElementDots {
+        value: value.clone(),
+        dots: dots.iter().map(dot_to_proto).collect(),
+    }
+}
+
+fn proto_to_element_dots(proto: &replication::ElementDots) -> Option<ElementDots> {
+    let dots = proto.dots.iter().map(proto_to_dot).collect::<Option<_>>()?;
+    Some((proto.value.clone(), dots))
+}
+
 fn dot_to_proto(dot: &Dot) -> replication::Dot {
     replication::Dot {
         actor_id: dot.actor_id.bytes().to_vec().into(),
@@ -89,7 +640,62 @@ fn proto_to_dot(proto: &replication::Dot) -> Option<Dot> {
     })
 }
 
-fn version_vector_to_proto(vv: &VersionVector) -> replication::VersionVector {
+/// Removed dots as lone dots plus runs of consecutive counters, which is much smaller
+/// when an element's whole history of adds is removed at once
+fn removed_dots_to_proto(dots: &[Dot]) -> (Vec<replication::Dot>, Vec<replication::DotRange>) {
+    let mut lone = Vec::new();
+    let mut ranges = Vec::new();
+    for range in DotRange::from_dots(dots) {
+        if range.start == range.end {
+            lone.push(dot_to_proto(&Dot::new(range.actor_id, range.start)));
+        } else {
+            ranges.push(replication::DotRange {
+                actor_id: range.actor_id.bytes().to_vec().into(),
+                start: range.start,
+                end: range.end,
+            });
+        }
+    }
+    (lone, ranges)
+}
+
+/// Removed dots back from lone dots and runs, or `None` for a bad actor id, an inverted
+/// run, or more dots than the operation's `budget` has left
+///
+/// The runs are merged as runs, so a peer can't make us walk a huge one dot by dot before
+/// the budget turns it away.
+fn proto_to_removed_dots(
+    dots: &[replication::Dot],
+    ranges: &[replication::DotRange],
+    budget: &mut u64,
+) -> Option<Vec<Dot>> {
+    let mut cloud = DotCloud::new();
+    for dot in dots {
+        cloud.insert(proto_to_dot(dot)?);
+    }
+    for range in ranges {
+        let actor_id = ActorId::from_bytes(&range.actor_id).ok()?;
+        if range.start > range.end {
+            return None;
+        }
+        cloud.insert_range(DotRange::new(actor_id, range.start, range.end));
+    }
+    *budget = budget.checked_sub(cloud.len())?;
+    Some(cloud.to_dots())
+}
+
+/// A version vector packed (`replication.packed_vv`) or as a list of entries
+///
+/// Every build that can send them packed reads both forms, so a cluster turns packing on
+/// once all its nodes run one; an older node would take a packed VV for an empty one.
+fn version_vector_to_proto(vv: &VersionVector, packed: bool) -> replication::VersionVector {
+    if packed {
+        return replication::VersionVector {
+            entries: Vec::new(),
+            packed: vv.to_packed_bytes().into(),
+        };
+    }
+
     let entries = vv
         .counters
         .iter()
@@ -99,10 +705,17 @@ fn version_vector_to_proto(vv: &VersionVector) -> replication::VersionVector {
         })
         .collect();
 
-    replication::VersionVector { entries }
+    replication::VersionVector {
+        entries,
+        packed: Default::default(),
+    }
 }
 
 fn proto_to_version_vector(proto: &replication::VersionVector) -> Option<VersionVector> {
+    if !proto.packed.is_empty() {
+        return VersionVector::from_packed_bytes(&proto.packed);
+    }
+
     let mut counters = std::collections::HashMap::new();
     for entry in &proto.entries {
         let actor_id = crate::types::ActorId::from_bytes(&entry.actor_id).ok()?;
@@ -110,3 +723,249 @@ fn proto_to_version_vector(proto: &replication::VersionVector) -> Option<Version
     }
     Some(VersionVector { counters })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use prost::Message;
+
+    #[test]
+    fn test_removed_dots_travel_as_runs() {
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+        let mut removed_dots: Vec<Dot> = (1..=100).map(|c| Dot::new(actor_a, c)).collect();
+        removed_dots.push(Dot::new(actor_b, 7));
+
+        let op = Operation {
+            set_name: "s".to_string(),
+            op_type: OpType::Remove {
+                elements: vec![Bytes::from("x")],
+                dot: Dot::new(actor_b, 8),
+                removed_dots: removed_dots.clone(),
+            },
+            context: Arc::new(VersionVector::new()),
+            trace_context: Default::default(),
+        };
+
+        let proto = operation_to_proto(&op, false);
+        match &proto.op_type {
+            Some(replication::operation::OpType::Remove(rem_op)) => {
+                assert_eq!(rem_op.removed_dots.len(), 1);
+                assert_eq!(rem_op.removed_ranges.len(), 1);
+            }
+            other => panic!("Expected RemoveOp, got {:?}", other),
+        }
+        assert_eq!(proto_to_operation(&proto).unwrap(), op);
+    }
+
+    /// A remove whose removed dots are only `range`, as a peer might send it
+    fn remove_with_range(range: replication::DotRange) -> replication::Operation {
+        let actor = ActorId::from_node_id(1);
+        let op = Operation {
+            set_name: "s".to_string(),
+            op_type: OpType::Remove {
+                elements: vec![Bytes::from("x")],
+                dot: Dot::new(actor, 8),
+                removed_dots: vec![],
+            },
+            context: Arc::new(VersionVector::new()),
+            trace_context: Default::default(),
+        };
+        let mut proto = operation_to_proto(&op, false);
+        match &mut proto.op_type {
+            Some(replication::operation::OpType::Remove(rem_op)) => {
+                rem_op.removed_ranges.push(range)
+            }
+            other => panic!("Expected RemoveOp, got {:?}", other),
+        }
+        proto
+    }
+
+    #[test]
+    fn test_huge_removed_range_is_rejected_without_expanding() {
+        let actor = ActorId::from_node_id(1);
+        let proto = remove_with_range(replication::DotRange {
+            actor_id: actor.bytes().to_vec().into(),
+            start: 1,
+            end: u64::MAX,
+        });
+        assert_eq!(proto_to_operation(&proto), None);
+    }
+
+    #[test]
+    fn test_removed_dots_cap_covers_the_whole_operation() {
+        let actor = ActorId::from_node_id(1);
+        let half = MAX_REMOVED_DOTS / 2 + 1;
+        let set = |name: &str, start: u64| replication::SetAdd {
+            set_name: name.to_string(),
+            elements: vec![Bytes::from("x")],
+            removed_dots: vec![],
+            removed_ranges: vec![replication::DotRange {
+                actor_id: actor.bytes().to_vec().into(),
+                start,
+                end: start + half - 1,
+            }],
+        };
+        let op = Operation {
+            set_name: String::new(),
+            op_type: OpType::AddMulti {
+                sets: vec![],
+                dot: Dot::new(actor, 1),
+            },
+            context: Arc::new(VersionVector::new()),
+            trace_context: Default::default(),
+        };
+        let mut proto = operation_to_proto(&op, false);
+        match &mut proto.op_type {
+            Some(replication::operation::OpType::MultiAdd(multi_op)) => {
+                multi_op.sets = vec![set("a", 1), set("b", half + 1)];
+            }
+            other => panic!("Expected MultiAddOp, got {:?}", other),
+        }
+        assert_eq!(proto_to_operation(&proto), None);
+    }
+
+    #[test]
+    fn test_inverted_removed_range_is_rejected() {
+        let actor = ActorId::from_node_id(1);
+        let proto = remove_with_range(replication::DotRange {
+            actor_id: actor.bytes().to_vec().into(),
+            start: 10,
+            end: 9,
+        });
+        assert_eq!(proto_to_operation(&proto), None);
+    }
+
+    #[test]
+    fn test_removed_range_with_bad_actor_is_rejected() {
+        let proto = remove_with_range(replication::DotRange {
+            actor_id: vec![0xff; 3].into(),
+            start: 1,
+            end: 2,
+        });
+        assert_eq!(proto_to_operation(&proto), None);
+
+        // And a bad lone dot the same
+        let mut proto = remove_with_range(replication::DotRange {
+            actor_id: ActorId::from_node_id(1).bytes().to_vec().into(),
+            start: 1,
+            end: 2,
+        });
+        if let Some(replication::operation::OpType::Remove(rem_op)) = &mut proto.op_type {
+            rem_op.removed_dots.push(replication::Dot {
+                actor_id: vec![0xff; 3].into(),
+                counter: 4,
+            });
+        }
+        assert_eq!(proto_to_operation(&proto), None);
+    }
+
+    #[test]
+    fn test_estimated_wire_size_matches_encoding() {
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::new(300, 2);
+        let mut context = VersionVector::new();
+        context.update(actor_a, 200);
+        context.update(actor_b, 1);
+        let context = Arc::new(context);
+
+        let big = Bytes::from(vec![b'x'; 300]);
+        let removed_dots: Vec<Dot> = (1..=150)
+            .map(|c| Dot::new(actor_a, c))
+            .chain([Dot::new(actor_a, 180), Dot::new(actor_b, 1)])
+            .collect();
+        let ops = [
+            Operation {
+                set_name: "s".to_string(),
+                op_type: OpType::Add {
+                    elements: vec![Bytes::from("a"), Bytes::new(), big.clone()],
+                    dot: Dot::new(actor_a, 201),
+                    removed_dots: vec![],
+                    expires_at: Some(1_760_000_000_000),
+                },
+                context: context.clone(),
+                trace_context: [(
+                    "traceparent".to_string(),
+                    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+                )]
+                .into(),
+            },
+            Operation {
+                set_name: "a set".to_string(),
+                op_type: OpType::Remove {
+                    elements: vec![big.clone()],
+                    dot: Dot::new(actor_a, 202),
+                    removed_dots: removed_dots.clone(),
+                },
+                context: context.clone(),
+                trace_context: Default::default(),
+            },
+            Operation {
+                set_name: String::new(),
+                op_type: OpType::AddMulti {
+                    sets: vec![
+                        SetAdd {
+                            set_name: "a".to_string(),
+                            elements: vec![big],
+                            removed_dots,
+                        },
+                        SetAdd {
+                            set_name: "b".to_string(),
+                            elements: vec![Bytes::from("y")],
+                            removed_dots: vec![],
+                        },
+                    ],
+                    dot: Dot::new(actor_a, 203),
+                },
+                context: Arc::new(VersionVector::new()),
+                trace_context: Default::default(),
+            },
+        ];
+
+        for op in &ops {
+            for packed_vv in [false, true] {
+                let encoded = operation_to_proto(op, packed_vv).encoded_len();
+                assert_eq!(
+                    op.estimated_wire_size(packed_vv),
+                    encoded,
+                    "{:?}",
+                    op.op_type
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_version_vectors_decode_packed_or_as_entries() {
+        // A dozen nodes that have each written about a million times
+        let mut vv = VersionVector::new();
+        for node_id in 1..=12u16 {
+            vv.update(
+                ActorId::from_node_id(node_id),
+                1_000_000 + u64::from(node_id) * 37,
+            );
+        }
+        vv.update(ActorId::from_u64(1 << 40).unwrap(), 3);
+
+        let entries = version_vector_to_proto(&vv, false);
+        let packed = version_vector_to_proto(&vv, true);
+        assert!(packed.entries.is_empty());
+        assert!(entries.packed.is_empty());
+        assert_eq!(proto_to_version_vector(&entries).unwrap(), vv);
+        assert_eq!(proto_to_version_vector(&packed).unwrap(), vv);
+        assert!(packed.encoded_len() * 2 < entries.encoded_len());
+
+        // Packed wins when a sender sets both, and must parse
+        let both = replication::VersionVector {
+            entries: entries.entries.clone(),
+            packed: packed.packed.clone(),
+        };
+        assert_eq!(proto_to_version_vector(&both).unwrap(), vv);
+        let truncated = replication::VersionVector {
+            entries: Vec::new(),
+            packed: packed.packed.slice(..packed.packed.len() - 1),
+        };
+        assert!(proto_to_version_vector(&truncated).is_none());
+    }
+}
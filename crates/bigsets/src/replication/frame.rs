@@ -0,0 +1,169 @@
+use crate::config::Compression;
+use prost::Message;
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Frame body encodings, the first byte after the length prefix
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+
+/// Bodies shorter than this are always sent raw, compressing them would not pay for itself
+const MIN_COMPRESS_LEN: usize = 512;
+
+/// Refuse to decompress a frame beyond this, rather than let a peer exhaust our memory
+const MAX_DECOMPRESSED_LEN: u64 = 256 * 1024 * 1024;
+
+/// Length-prefixed framing shared by both ends of a replication connection
+///
+/// Each frame is a 4 byte big-endian length, then one byte saying how the body is
+/// encoded (raw or zstd), then the encoded protobuf message. The length covers the
+/// encoding byte and the body.
+pub(crate) async fn write_frame<W, M>(
+    writer: &mut W,
+    msg: &M,
+    compression: Compression,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    M: Message,
+{
+    let buf = msg.encode_to_vec();
+    let (encoding, body) = match compression {
+        Compression::Zstd { level } if buf.len() >= MIN_COMPRESS_LEN => {
+            (ZSTD, zstd::bulk::compress(&buf, level)?)
+        }
+        _ => (RAW, buf),
+    };
+
+    // Write length prefix (4 bytes big-endian)
+    writer.write_u32(body.len() as u32 + 1).await?;
+
+    // Write encoding and message body
+    writer.write_u8(encoding).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await
+}
+
+/// Read the body of the next frame, decompressed
+///
+/// Returns None if the peer closed the connection between frames.
+pub(crate) async fn read_frame<R>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    // Read length prefix (4 bytes big-endian)
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len == 0 {
+        return Err(invalid_data("empty frame"));
+    }
+
+    // Read encoding and message body
+    let encoding = reader.read_u8().await?;
+    let mut buf = vec![0u8; len - 1];
+    reader.read_exact(&mut buf).await?;
+
+    match encoding {
+        RAW => Ok(Some(buf)),
+        ZSTD => {
+            let mut body = Vec::new();
+            zstd::stream::Decoder::new(&buf[..])?
+                .take(MAX_DECOMPRESSED_LEN + 1)
+                .read_to_end(&mut body)?;
+            if body.len() as u64 > MAX_DECOMPRESSED_LEN {
+                return Err(invalid_data("decompressed frame too large"));
+            }
+            Ok(Some(body))
+        }
+        other => Err(invalid_data(&format!("unknown frame encoding {}", other))),
+    }
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ActorId, Dot, OpType, Operation, VersionVector};
+    use bytes::Bytes;
+
+    /// A large add, like the result of a set union, with realistic repetitive members
+    fn large_add() -> crate::proto::replication::ReplicationMessage {
+        let op = Operation {
+            set_name: "users:active".to_string(),
+            op_type: OpType::Add {
+                elements: (0..10_000)
+                    .map(|i| Bytes::from(format!("user:{:08}", i)))
+                    .collect(),
+                dot: Dot::new(ActorId::from_node_id(1), 42),
+                removed_dots: vec![],
+                expires_at: None,
+            },
+            context: std::sync::Arc::new(VersionVector::new()),
+            trace_context: Default::default(),
+        };
+        crate::proto::operations_to_message(&[op], false)
+    }
+
+    async fn round_trip(
+        msg: &crate::proto::replication::ReplicationMessage,
+        compression: Compression,
+    ) -> (usize, Vec<u8>) {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, msg, compression).await.unwrap();
+        let body = read_frame(&mut &wire[..]).await.unwrap().unwrap();
+        (wire.len(), body)
+    }
+
+    #[tokio::test]
+    async fn test_small_frames_are_sent_raw() {
+        let msg = crate::proto::heartbeat_to_message(
+            ActorId::from_node_id(1),
+            &VersionVector::new(),
+            &[],
+            false,
+        );
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &msg, Compression::Zstd { level: 3 })
+            .await
+            .unwrap();
+        assert_eq!(wire[4], RAW);
+        assert_eq!(wire.len(), 5 + msg.encoded_len());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_encoding_is_rejected() {
+        let wire = [0, 0, 0, 2, 9, 0];
+        let err = read_frame(&mut &wire[..]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    /// Level 3, the default, shrinks a 10k element add (a ~150KB frame) to well under a third
+    #[tokio::test]
+    async fn test_zstd_shrinks_a_large_add() {
+        let msg = large_add();
+        let (raw_len, body) = round_trip(&msg, Compression::None).await;
+        assert_eq!(body, msg.encode_to_vec());
+
+        let (zstd_len, body) = round_trip(&msg, Compression::Zstd { level: 3 }).await;
+        assert_eq!(body, msg.encode_to_vec());
+        assert!(zstd_len * 3 < raw_len);
+    }
+
+    /// Level 19 gets the large add smaller still, but is slow enough to leave out of the
+    /// normal run. Levels 1-3 suit replication, where frames are sent as soon as they are written.
+    #[tokio::test]
+    #[ignore]
+    async fn test_higher_levels_compress_further() {
+        let msg = large_add();
+        let (level_3, _) = round_trip(&msg, Compression::Zstd { level: 3 }).await;
+        let (level_19, body) = round_trip(&msg, Compression::Zstd { level: 19 }).await;
+        assert_eq!(body, msg.encode_to_vec());
+        assert!(level_19 <= level_3);
+    }
+}
@@ -1,83 +1,1465 @@
-use crate::buffers::{PendingBuffer, UnackedBuffer};
-use crate::config::ReplicaInfo;
-use crate::types::Operation;
+use crate::buffers::{DeltaBuffer, PendingBuffer, SentLog, UnackedBuffer};
+use crate::config::{
+    ReplicaInfo, ReplicationConfig, ReplicationMode, RuntimeConfig, Tunables, glob_match,
+};
+use crate::network::{NetworkTransport, TcpTransport};
+use crate::replication::frame::{read_frame, write_frame};
+use crate::replication::metrics::ReplicationMetrics;
+use crate::replication::server::SNAPSHOT_CHUNK_ELEMENTS;
+use crate::server::Server;
+use crate::types::{ActorId, Dot, Operation, SetSnapshot, VersionVector};
 use prost::Message;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tracing::{debug, warn};
+use tokio::sync::{RwLock, mpsc, oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, warn};
+
+/// The most unacked operations one flush sends a peer; the rest wait for the next
+/// flush, or are retransmitted once they are overdue
+const MAX_FLUSH_OPERATIONS: usize = 4096;
+
+/// Liveness of a peer, as seen from heartbeats and other messages it sends us
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub peer: ReplicaInfo,
+    pub up: bool,
+    /// How long ago we last heard from the peer, None if never
+    pub last_seen: Option<Duration>,
+    /// Operations waiting for the peer to acknowledge them
+    pub unacked: usize,
+    /// How long ago a send of ours to the peer last completed, None if never
+    pub last_sent: Option<Duration>,
+    /// The version vector the peer last reported in a heartbeat, None if it hasn't
+    pub vv: Option<VersionVector>,
+}
+
+/// What a peer's sender task is asked to do, done in the order asked
+enum Outgoing {
+    /// Buffer an operation for the peer and deliver it
+    Operation {
+        operation: Operation,
+        /// Told once the sender has tried to deliver the operation
+        sent: oneshot::Sender<()>,
+    },
+    /// Send the peer everything it has not acked, whether or not a batch has filled;
+    /// told once it has been tried
+    Flush(oneshot::Sender<()>),
+    /// Resend the peer the operations whose ack is overdue, see `retransmit_overdue`;
+    /// told once it has been tried
+    Retransmit(Tunables, oneshot::Sender<()>),
+}
+
+/// A synchronous write's wait for peers to ack its operation, see `enqueue_synced`
+#[derive(Debug)]
+pub struct AckWait {
+    dot: Dot,
+    /// How many acks the write waits for
+    needed: usize,
+    acks: watch::Receiver<usize>,
+}
+
+/// The acks of an operation a synchronous write is waiting on
+struct AckWatch {
+    /// The peers whose acks count: those sent the operation itself, not elided
+    holders: HashSet<ActorId>,
+    acks: watch::Sender<usize>,
+}
+
+/// A peer's send queue, and the task draining it
+struct PeerSender {
+    queue: mpsc::UnboundedSender<Outgoing>,
+    task: JoinHandle<()>,
+}
 
 pub struct ReplicationManager {
-    peers: BTreeSet<ReplicaInfo>,
+    /// Changed at runtime by CLUSTER MEET and CLUSTER FORGET, so always iterate a copy
+    peers: RwLock<BTreeSet<ReplicaInfo>>,
+    config: ReplicationConfig,
+    /// Where the retry settings are read from, so CONFIG SET can change them
+    runtime: Arc<RuntimeConfig>,
+    /// How peers are reached, and how the listener accepts them
+    transport: Arc<dyn NetworkTransport>,
+    /// Each peer's ordered send queue, feeding its sender task (see `enqueue`)
+    senders: RwLock<HashMap<ActorId, PeerSender>>,
     pending_buffer: Arc<RwLock<PendingBuffer>>,
     unsent_buffer: Arc<RwLock<UnackedBuffer>>,
+    sent_log: RwLock<SentLog>,
+    /// Operations per peer waiting for a batch to fill (only used when batching)
+    queued: RwLock<HashMap<ActorId, usize>>,
+    /// What each peer is owed a delta for (only used in delta mode)
+    deltas: RwLock<DeltaBuffer>,
+    /// (peer, set) pairs with an anti-entropy exchange in flight
+    syncing: RwLock<HashSet<(ActorId, String)>>,
+    /// Peers with a missing operations request in flight
+    recovering: RwLock<HashSet<ActorId>>,
+    /// When each peer last sent us a message
+    last_seen: RwLock<HashMap<ActorId, Instant>>,
+    /// The latest version vector each peer reported in a heartbeat
+    peer_vvs: RwLock<HashMap<ActorId, VersionVector>>,
+    /// When a send of operations to each peer last completed, its acks read
+    last_sent: RwLock<HashMap<ActorId, Instant>>,
+    /// Acks so far for each operation a synchronous write is waiting on, by dot
+    ack_watches: std::sync::Mutex<HashMap<Dot, AckWatch>>,
+    /// Set-name patterns this node replicates, changed by SUBSCRIBE SET; empty for every set
+    subscriptions: RwLock<Vec<String>>,
+    /// The patterns each peer last sent in a heartbeat; every set until it has
+    peer_subscriptions: RwLock<HashMap<ActorId, Vec<String>>>,
+    /// Peers get the benefit of the doubt until they have had time to heartbeat
+    started: Instant,
+    metrics: ReplicationMetrics,
+}
+
+/// Whether a node subscribed to `patterns` replicates any set `operation` touches
+fn subscribed(patterns: &[String], operation: &Operation) -> bool {
+    patterns.is_empty()
+        || operation.set_names().iter().any(|set_name| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(pattern.as_bytes(), set_name.as_bytes()))
+        })
 }
 
 impl ReplicationManager {
-    pub fn new(peers: BTreeSet<ReplicaInfo>, buffer_size: usize) -> Self {
+    pub fn new(peers: BTreeSet<ReplicaInfo>, config: ReplicationConfig) -> Self {
+        Self::with_transport(peers, config, Arc::new(TcpTransport))
+    }
+
+    /// A manager reaching its peers over `transport` instead of TCP
+    pub fn with_transport(
+        peers: BTreeSet<ReplicaInfo>,
+        config: ReplicationConfig,
+        transport: Arc<dyn NetworkTransport>,
+    ) -> Self {
         Self {
-            peers,
-            pending_buffer: Arc::new(RwLock::new(PendingBuffer::new(buffer_size))),
+            peers: RwLock::new(peers),
+            transport,
+            senders: RwLock::new(HashMap::new()),
+            pending_buffer: Arc::new(RwLock::new(PendingBuffer::new(config.buffer_size))),
             unsent_buffer: Arc::new(RwLock::new(UnackedBuffer::new())),
+            sent_log: RwLock::new(SentLog::new(config.sent_log_size)),
+            runtime: Arc::new(RuntimeConfig::for_replication(&config)),
+            subscriptions: RwLock::new(config.subscriptions.clone()),
+            config,
+            queued: RwLock::new(HashMap::new()),
+            deltas: RwLock::new(DeltaBuffer::new()),
+            syncing: RwLock::new(HashSet::new()),
+            recovering: RwLock::new(HashSet::new()),
+            last_seen: RwLock::new(HashMap::new()),
+            peer_vvs: RwLock::new(HashMap::new()),
+            last_sent: RwLock::new(HashMap::new()),
+            ack_watches: std::sync::Mutex::new(HashMap::new()),
+            peer_subscriptions: RwLock::new(HashMap::new()),
+            started: Instant::now(),
+            metrics: ReplicationMetrics::default(),
         }
     }
 
-    /// Send operation to all peers
+    /// Read the retry settings from `runtime`, shared with the rest of the node, rather
+    /// than only from the ReplicationConfig
+    pub fn with_runtime_config(mut self, runtime: Arc<RuntimeConfig>) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Send operation to all peers, and wait until each peer's sender has tried to deliver it
     ///
-    /// Attempts to send to each peer. On failure, buffers in unacked_buffer
-    /// for retry. This is fire-and-forget from the caller's perspective.
+    /// See `enqueue`. The senders deliver concurrently, so this waits as long as the
+    /// slowest peer takes, not all of them in turn. Unacked operations stay buffered for
+    /// retry, so this never fails.
     pub async fn send(
-        &self,
+        self: &Arc<Self>,
         operation: Operation,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        tracing::info!(
-            "ReplicationManager::send called, peers count={}",
-            self.peers.len()
+        for sent in self.enqueue(operation).await {
+            // A sender only goes away when its peer is forgotten
+            let _ = sent.await;
+        }
+        Ok(())
+    }
+
+    /// Queue an operation for every peer, returning once it is queued
+    ///
+    /// Each peer has one sender task fed by a channel, so operations leave for a peer
+    /// in the order they were queued, and never over two connections at once. The
+    /// sender buffers the operation in the unacked_buffer, then sends the peer
+    /// everything it has not yet acknowledged, oldest first. A peer that was
+    /// unreachable for earlier writes catches up on the next one.
+    /// When batching is enabled (`max_batch` > 1) the send is deferred until
+    /// `max_batch` operations are queued for the peer, or `run_batch_flush` fires.
+    /// A peer that is down is not tried; its operations wait in the unacked_buffer
+    /// until it is heard from again (see `mark_seen`).
+    ///
+    /// Returns a receiver per peer that resolves once its sender has tried to deliver
+    /// the operation, for callers that want to wait.
+    ///
+    /// A peer that replicates none of the operation's sets (see `subscribe`) is sent it
+    /// elided, which keeps its version vector contiguous without the effect.
+    ///
+    /// In delta mode the operation is only recorded against each peer, for its next delta
+    /// (see `send_deltas`), and there is nothing to wait for.
+    pub async fn enqueue(self: &Arc<Self>, operation: Operation) -> Vec<oneshot::Receiver<()>> {
+        self.queue(operation, false).await.0
+    }
+
+    /// `enqueue`, and with `synced` a watch on the operation's acks for `enqueue_synced`,
+    /// set up under the peers lock with the sends
+    async fn queue(
+        self: &Arc<Self>,
+        operation: Operation,
+        synced: bool,
+    ) -> (Vec<oneshot::Receiver<()>>, Option<AckWait>) {
+        let peers = self.peers.read().await;
+        if self.config.mode == ReplicationMode::Delta {
+            let mut deltas = self.deltas.write().await;
+            for peer in peers.iter() {
+                deltas.record(peer.actor_id(), &operation);
+            }
+            self.sent_log.write().await.add(operation);
+            return (Vec::new(), None);
+        }
+
+        let targets = self.fanout_peers(&peers).await;
+        let holders: HashSet<ActorId> = {
+            let interests = self.peer_subscriptions.read().await;
+            targets
+                .iter()
+                .map(ReplicaInfo::actor_id)
+                .filter(|actor_id| {
+                    interests
+                        .get(actor_id)
+                        .is_none_or(|patterns| subscribed(patterns, &operation))
+                })
+                .collect()
+        };
+
+        let dot = operation.dot();
+        let needed = self
+            .config
+            .write_quorum
+            .saturating_sub(1)
+            .min(holders.len());
+        let wait = (synced && self.config.sync && needed > 0).then(|| {
+            let (watch, acks) = watch::channel(0);
+            self.ack_watches.lock().unwrap().insert(
+                dot,
+                AckWatch {
+                    holders: holders.clone(),
+                    acks: watch,
+                },
+            );
+            AckWait { dot, needed, acks }
+        });
+
+        let mut senders = self.senders.write().await;
+        self.sent_log.write().await.add(operation.clone());
+        debug!(
+            "Queueing operation {} for {} peers, {} of them elided",
+            dot,
+            targets.len(),
+            targets.len() - holders.len()
         );
-        for peer in &self.peers {
-            tracing::info!("Attempting to send to peer: {}", peer.addr);
-            if let Err(e) = self.send_to_peer(&peer.addr, &operation).await {
-                warn!("Failed to send operation to peer {}: {}", peer.addr, e);
-                // Buffer for retry
-                self.unsent_buffer
-                    .write()
-                    .await
-                    .add(peer.actor_id(), operation.clone());
+
+        let elided = (holders.len() < targets.len()).then(|| operation.elided());
+        let delivered = targets
+            .iter()
+            .map(|peer| {
+                let (sent, delivered) = oneshot::channel();
+                let sender = senders
+                    .entry(peer.actor_id())
+                    .or_insert_with(|| self.spawn_peer_sender(peer.actor_id()));
+                let operation = match &elided {
+                    Some(elided) if !holders.contains(&peer.actor_id()) => elided.clone(),
+                    _ => operation.clone(),
+                };
+                let _ = sender.queue.send(Outgoing::Operation { operation, sent });
+                delivered
+            })
+            .collect();
+        (delivered, wait)
+    }
+
+    /// The peers an operation is pushed to: every peer, or with a `fanout`, that many of
+    /// them, those that are up first
+    ///
+    /// The others learn it from the writer, when its heartbeat shows them behind (see
+    /// `ReplicationListener::spawn_catch_up`).
+    async fn fanout_peers(&self, peers: &BTreeSet<ReplicaInfo>) -> Vec<ReplicaInfo> {
+        let fanout = self.config.fanout;
+        if fanout == 0 || fanout >= peers.len() {
+            return peers.iter().cloned().collect();
+        }
+
+        let (mut up, mut down) = (Vec::new(), Vec::new());
+        for peer in peers {
+            if self.is_up(peer).await {
+                up.push(peer.clone());
             } else {
-                debug!("Sent operation to peer {}", peer.addr);
+                down.push(peer.clone());
             }
         }
-        tracing::info!("ReplicationManager::send finished");
-        Ok(())
+        up.extend(down);
+        up.truncate(fanout);
+        up
+    }
+
+    /// Queue an operation as `enqueue` does, and with `sync` on, watch for peers' acks of it
+    ///
+    /// Returns what to pass `wait_for_acks`, None if the write need not wait: `sync` is off,
+    /// or there are no peers. A write waits for `write_quorum - 1` acks, at most one from
+    /// each peer it is pushed to that replicates its sets. The watch starts before the operation is queued, so no
+    /// ack can be missed.
+    pub async fn enqueue_synced(self: &Arc<Self>, operation: Operation) -> Option<AckWait> {
+        self.queue(operation, true).await.1
+    }
+
+    /// Wait until enough peers ack a synchronous write's operation, or `sync_timeout_ms`
+    /// passes. Returns the acks and how many were needed, which are fewer on timeout.
+    pub async fn wait_for_acks(&self, mut wait: AckWait) -> (usize, usize) {
+        let needed = wait.needed;
+        let _ = tokio::time::timeout(
+            Duration::from_millis(self.config.sync_timeout_ms),
+            wait.acks.wait_for(|acked| *acked >= needed),
+        )
+        .await;
+        self.ack_watches.lock().unwrap().remove(&wait.dot);
+        let acked = *wait.acks.borrow();
+        (acked, needed)
+    }
+
+    /// Drop an operation a peer acked from its unacked operations, counting the ack for a
+    /// synchronous write waiting on it. False if the peer had already acked it.
+    fn record_ack(&self, buffer: &mut UnackedBuffer, peer_id: &ActorId, dot: Dot) -> bool {
+        if !buffer.ack(peer_id, dot) {
+            return false;
+        }
+        if let Some(watch) = self.ack_watches.lock().unwrap().get(&dot)
+            && watch.holders.contains(peer_id)
+        {
+            watch.acks.send_modify(|acked| *acked += 1);
+        }
+        true
+    }
+
+    /// Hand a peer's sender `job`, to do after everything already queued for the peer,
+    /// starting the sender if the peer has none yet
+    async fn ask_sender(self: &Arc<Self>, peer_id: ActorId, job: Outgoing) {
+        let mut senders = self.senders.write().await;
+        let sender = senders
+            .entry(peer_id)
+            .or_insert_with(|| self.spawn_peer_sender(peer_id));
+        let _ = sender.queue.send(job);
+    }
+
+    /// Start the task that sends everything queued for one peer, in order
+    fn spawn_peer_sender(self: &Arc<Self>, peer_id: ActorId) -> PeerSender {
+        let (queue, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(Arc::clone(self).run_peer_sender(peer_id, rx));
+        PeerSender { queue, task }
+    }
+
+    /// Deliver a peer's queued operations, flushes and retransmits until the peer is
+    /// forgotten or its queue closed
+    ///
+    /// Everything queued while a send is in flight is done together next: the operations
+    /// go out in one send, and any number of flushes or retransmits asked for meanwhile
+    /// are done once.
+    async fn run_peer_sender(
+        self: Arc<Self>,
+        peer_id: ActorId,
+        mut queue: mpsc::UnboundedReceiver<Outgoing>,
+    ) {
+        while let Some(first) = queue.recv().await {
+            let mut outgoing = vec![first];
+            while let Ok(next) = queue.try_recv() {
+                outgoing.push(next);
+            }
+
+            let mut operations = Vec::new();
+            let mut done = Vec::with_capacity(outgoing.len());
+            let (mut flush, mut retransmit) = (false, None);
+            for out in outgoing {
+                match out {
+                    Outgoing::Operation { operation, sent } => {
+                        operations.push(operation);
+                        done.push(sent);
+                    }
+                    Outgoing::Flush(flushed) => {
+                        flush = true;
+                        done.push(flushed);
+                    }
+                    Outgoing::Retransmit(tunables, retransmitted) => {
+                        retransmit = Some(tunables);
+                        done.push(retransmitted);
+                    }
+                }
+            }
+
+            // Buffered under the peers lock, so a peer being forgotten cannot be left any
+            let peer = {
+                let peers = self.peers.read().await;
+                let Some(peer) = peers.iter().find(|p| p.actor_id() == peer_id).cloned() else {
+                    return;
+                };
+                let mut buffer = self.unsent_buffer.write().await;
+                for operation in &operations {
+                    buffer.add(peer_id, operation.clone());
+                }
+                peer
+            };
+
+            if flush {
+                match self.flush_peer(&peer).await {
+                    Ok(acked) => debug!("Peer {} acked {} operations", peer.addr, acked),
+                    Err(e) => warn!("Failed to flush to peer {}: {}", peer.addr, e),
+                }
+            } else if !operations.is_empty() {
+                self.deliver(&peer, operations.len()).await;
+            }
+            if let Some(tunables) = retransmit {
+                self.retransmit_to(&peer, &tunables).await;
+            }
+            for told in done {
+                let _ = told.send(());
+            }
+        }
+    }
+
+    /// Send a peer its unacked operations, after `queued` more were buffered for it
+    async fn deliver(&self, peer: &ReplicaInfo, queued: usize) {
+        if !self.is_up(peer).await {
+            debug!("Peer {} is down, buffering operations", peer.addr);
+            return;
+        }
+
+        if self.config.max_batch > 1 {
+            let mut waiting = self.queued.write().await;
+            let count = waiting.entry(peer.actor_id()).or_default();
+            *count += queued;
+            if *count < self.config.max_batch {
+                return;
+            }
+            *count = 0;
+        }
+
+        match self.flush_peer(peer).await {
+            Ok(acked) => debug!("Peer {} acked {} operations", peer.addr, acked),
+            // Unacked operations stay buffered for retry
+            Err(e) => warn!("Failed to send operations to peer {}: {}", peer.addr, e),
+        }
+    }
+
+    /// Send a peer its unacked operations, oldest first and at most
+    /// `MAX_FLUSH_OPERATIONS` of them, and drop the ones it acknowledges
+    ///
+    /// Returns the number of operations acknowledged.
+    async fn flush_peer(
+        &self,
+        peer: &ReplicaInfo,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let peer_id = peer.actor_id();
+        let operations: Vec<Operation> = self
+            .unsent_buffer
+            .read()
+            .await
+            .get_peer_ops(&peer_id)
+            .map(|ops| {
+                ops.iter()
+                    .take(MAX_FLUSH_OPERATIONS)
+                    .map(|(op, _, _)| op.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if operations.is_empty() {
+            return Ok(0);
+        }
+
+        let acked = self.send_to_peer(peer, &operations).await?;
+
+        let mut buffer = self.unsent_buffer.write().await;
+        Ok(acked
+            .into_iter()
+            .filter(|dot| self.record_ack(&mut buffer, &peer_id, *dot))
+            .count())
+    }
+
+    /// Make a last attempt to deliver what peers have not acked, before shutting down
+    ///
+    /// Closes every peer's send queue and waits for its sender to finish with what was
+    /// queued, then sends each peer everything it has not acked: partial batches, and
+    /// peers thought to be down, included. Each step gets `ack_timeout_ms`. Whatever is
+    /// still unacked after this the peers learn later, by anti-entropy or when they
+    /// next start (RBILT), so it is only logged.
+    ///
+    /// Returns the number of operations left unacked, counted once per peer.
+    pub async fn shutdown(self: &Arc<Self>) -> usize {
+        let timeout = Duration::from_millis(self.runtime.tunables().ack_timeout_ms.max(1));
+
+        let senders = std::mem::take(&mut *self.senders.write().await);
+        let deadline = tokio::time::Instant::now() + timeout;
+        for (peer_id, PeerSender { queue, task }) in senders {
+            drop(queue);
+            if tokio::time::timeout_at(deadline, task).await.is_err() {
+                warn!("Sender for peer {} did not finish before shutdown", peer_id);
+            }
+        }
+
+        let mut flushes = JoinSet::new();
+        for peer in self.peers().await {
+            let manager = Arc::clone(self);
+            flushes.spawn(async move {
+                match tokio::time::timeout(timeout, manager.flush_peer(&peer)).await {
+                    Ok(Ok(acked)) => debug!("Peer {} acked {} operations", peer.addr, acked),
+                    Ok(Err(e)) => warn!("Failed final send to peer {}: {}", peer.addr, e),
+                    Err(_) => warn!("Final send to peer {} timed out", peer.addr),
+                }
+            });
+        }
+        flushes.join_all().await;
+
+        let unacked = self.unsent_buffer.read().await.total_count();
+        if unacked > 0 {
+            warn!("Shutting down with {} operations unacked by peers", unacked);
+        }
+        unacked
+    }
+
+    /// Send partially filled batches every `flush_interval_ms`
+    ///
+    /// Bounds how long an operation can sit waiting for a batch to fill.
+    /// Returns straight away when batching is disabled.
+    pub async fn run_batch_flush(self: Arc<Self>) {
+        if self.config.max_batch <= 1 {
+            return;
+        }
+
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.flush_interval_ms.max(1)));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            self.flush_queued().await;
+        }
+    }
+
+    /// Send every peer that has operations waiting for a batch to fill, and wait until
+    /// each has been tried
+    ///
+    /// The flush goes through the peer's sender, after whatever is queued ahead of it, and
+    /// the peers are flushed at once. Sends give up after `ack_timeout_ms`, so a stalled
+    /// peer holds up the others by no more than that.
+    pub async fn flush_queued(self: &Arc<Self>) {
+        let mut flushes = Vec::new();
+        for peer in self.peers().await {
+            let waiting = self
+                .queued
+                .write()
+                .await
+                .insert(peer.actor_id(), 0)
+                .unwrap_or(0);
+            if waiting == 0 || !self.is_up(&peer).await {
+                continue;
+            }
+            let (flushed, flush) = oneshot::channel();
+            self.ask_sender(peer.actor_id(), Outgoing::Flush(flushed))
+                .await;
+            flushes.push(flush);
+        }
+        for flush in flushes {
+            let _ = flush.await;
+        }
+    }
+
+    /// Send each peer a delta every `delta_interval_ms`, until the manager is dropped
+    ///
+    /// Returns straight away in op mode.
+    pub async fn run_delta_sync(self: Arc<Self>, server: Arc<Server>) {
+        if self.config.mode != ReplicationMode::Delta {
+            return;
+        }
+
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.delta_interval_ms.max(1)));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            self.send_deltas(&server).await;
+        }
+    }
+
+    /// Send every peer that is up a delta of the sets changed since the last one it acked
+    ///
+    /// The peers are sent theirs at once, and each send gives up after `ack_timeout_ms`,
+    /// so a stalled peer holds up the others by no more than that.
+    /// Returns the number of peers that applied theirs. A delta that is lost, or refused
+    /// because the peer has not yet seen something it depends on, is covered by the next.
+    pub async fn send_deltas(self: &Arc<Self>, server: &Arc<Server>) -> usize {
+        let timeout = self.send_timeout();
+        let mut sends = JoinSet::new();
+        for peer in self.peers().await {
+            if !self.is_up(&peer).await {
+                continue;
+            }
+            let manager = Arc::clone(self);
+            let server = Arc::clone(server);
+            sends.spawn(async move {
+                match tokio::time::timeout(timeout, manager.send_delta(&server, &peer)).await {
+                    Ok(Ok(applied)) => applied,
+                    Ok(Err(e)) => {
+                        warn!("Failed to send a delta to peer {}: {}", peer.addr, e);
+                        false
+                    }
+                    Err(_) => {
+                        warn!("Sending a delta to peer {} timed out", peer.addr);
+                        false
+                    }
+                }
+            });
+        }
+        sends
+            .join_all()
+            .await
+            .into_iter()
+            .filter(|applied| *applied)
+            .count()
     }
 
-    /// Send a single operation to a peer
+    /// Send one peer a delta of the sets it is owed, returning whether it applied it
     ///
-    /// Opens a new connection, sends the operation, and closes.
+    /// The delta holds each changed set's elements with dots of ours the peer has not
+    /// acked, and the members we removed, all as of our latest recorded operation.
+    async fn send_delta(
+        &self,
+        server: &Server,
+        peer: &ReplicaInfo,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let peer_id = peer.actor_id();
+        let Some(pending) = self.deltas.read().await.pending(&peer_id) else {
+            return Ok(false);
+        };
+
+        // The peer's heartbeat may show it further on with our operations than its acks do
+        let own = server.actor_id();
+        let reported = self
+            .peer_vvs
+            .read()
+            .await
+            .get(&peer_id)
+            .map_or(0, |vv| vv.get(own));
+        let mut from = server.version_vector().read().await.clone();
+        from.counters.insert(own, pending.acked.max(reported));
+        let mut vv = pending.context.clone();
+        vv.counters.insert(own, pending.through);
+
+        let sets = pending
+            .sets
+            .keys()
+            .map(|set_name| {
+                let removed = pending
+                    .removed
+                    .get(set_name)
+                    .map(|removed| removed.keys().cloned().collect())
+                    .unwrap_or_default();
+                (set_name.clone(), removed)
+            })
+            .collect();
+        let sets = server.delta(sets, from, vv.clone()).await?;
+
+        let mut stream = self.transport.connect(&peer.addr).await?;
+        write_frame(
+            &mut stream,
+            &crate::proto::delta_to_message(own, &vv, &sets, self.config.packed_vv),
+            self.config.compression,
+        )
+        .await?;
+        stream.shutdown().await?;
+
+        let buf = read_frame(&mut stream)
+            .await?
+            .ok_or("peer closed the connection without a delta ack")?;
+        let applied = crate::proto::replication::DeltaAck::decode(&buf[..])?.applied;
+        self.last_sent.write().await.insert(peer_id, Instant::now());
+
+        if applied {
+            debug!(
+                "Peer {} applied a delta of {} sets through {}",
+                peer.addr,
+                sets.len(),
+                pending.through
+            );
+            self.deltas.write().await.ack(&peer_id, pending.through);
+        } else {
+            debug!(
+                "Peer {} is not ready for a delta through {}",
+                peer.addr, pending.through
+            );
+        }
+        Ok(applied)
+    }
+
+    /// Retransmit unacknowledged operations until the manager is dropped
+    ///
+    /// Wakes every `retry_backoff_ms` and resends whatever has waited too long
+    /// for an ack (see `retry_timeout`). Meant to be spawned alongside the listener.
+    /// The backoff is read again on each wake, so CONFIG SET changes it straight away.
+    pub async fn run_retransmit(self: Arc<Self>) {
+        loop {
+            let backoff = self.runtime.tunables().retry_backoff_ms.max(1);
+            tokio::time::sleep(Duration::from_millis(backoff)).await;
+            self.retransmit_overdue().await;
+        }
+    }
+
+    /// Resend every operation whose ack is overdue, and give up on those out of retries,
+    /// waiting until each peer has been tried
+    ///
+    /// The resend goes through the peer's sender, after whatever is queued ahead of it, so
+    /// a peer is never sent operations over two connections at once. The peers are
+    /// retransmitted to at once, and sends give up after `ack_timeout_ms`, which counts as
+    /// a failed try: a stalled peer holds up the others by no more than that. Peers that
+    /// are down are skipped, so they do not use up retries while unreachable.
+    pub async fn retransmit_overdue(self: &Arc<Self>) {
+        let tunables = self.runtime.tunables();
+        let mut retransmits = Vec::new();
+        for peer in self.peers().await {
+            if !self.is_up(&peer).await {
+                continue;
+            }
+            let (retransmitted, retransmit) = oneshot::channel();
+            self.ask_sender(
+                peer.actor_id(),
+                Outgoing::Retransmit(tunables.clone(), retransmitted),
+            )
+            .await;
+            retransmits.push(retransmit);
+        }
+        for retransmit in retransmits {
+            let _ = retransmit.await;
+        }
+    }
+
+    /// Resend one peer the operations whose ack is overdue, on its sender, see
+    /// `retransmit_overdue`
+    async fn retransmit_to(&self, peer: &ReplicaInfo, tunables: &Tunables) {
+        let peer_id = peer.actor_id();
+        let (resend, given_up) = self.unsent_buffer.write().await.take_overdue(
+            &peer_id,
+            tunables.max_retries,
+            |retries| Self::retry_timeout(tunables, retries),
+        );
+
+        for op in &given_up {
+            error!(
+                "Giving up on operation {} for set={} to peer {} after {} retries",
+                op.dot(),
+                op.set_name,
+                peer.addr,
+                tunables.max_retries
+            );
+        }
+
+        if resend.is_empty() {
+            return;
+        }
+
+        debug!(
+            "Retransmitting {} operations to peer {}",
+            resend.len(),
+            peer.addr
+        );
+        match self.send_to_peer(peer, &resend).await {
+            Ok(acked) => {
+                let mut buffer = self.unsent_buffer.write().await;
+                for dot in acked {
+                    self.record_ack(&mut buffer, &peer_id, dot);
+                }
+            }
+            Err(e) => warn!("Failed to retransmit to peer {}: {}", peer.addr, e),
+        }
+    }
+
+    /// How long an operation sent `retries` times waits for an ack before it is resent
+    ///
+    /// The first wait is `ack_timeout_ms`, and each retry adds an exponential backoff
+    /// of `retry_backoff_ms`: 100ms, then 300ms, 700ms, ... on top of the timeout.
+    fn retry_timeout(tunables: &Tunables, retries: u32) -> Duration {
+        let backoff = tunables
+            .retry_backoff_ms
+            .saturating_mul((1u64 << retries.min(32)) - 1);
+        Duration::from_millis(tunables.ack_timeout_ms.saturating_add(backoff))
+    }
+
+    /// How long one step of a send to a peer may take before the send is given up on:
+    /// connecting, writing a batch, or waiting for the next ack
+    fn send_timeout(&self) -> Duration {
+        Duration::from_millis(self.runtime.tunables().ack_timeout_ms.max(1))
+    }
+
+    /// Send operations to a peer and collect its acks
+    ///
+    /// Opens a new connection, sends the operations in batches of up to `max_batch`
+    /// per message, then half-closes it so the peer knows we are done. The peer acks each operation it applied before closing.
+    /// Operations it had to buffer (causality not satisfied) are not acked.
+    /// Each step gets `ack_timeout_ms`, so a long send to a peer that keeps up isn't cut
+    /// short, and one to a stalled peer is. Acks read before the peer stalled or the
+    /// connection failed still count; it is an error only if there were none.
     /// TODO: Connection pooling/reuse for better performance
     async fn send_to_peer(
         &self,
-        addr: &str,
-        operation: &Operation,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // Convert to protobuf
-        let proto_op = crate::proto::operation_to_proto(operation);
-        let mut buf = Vec::new();
-        proto_op.encode(&mut buf)?;
+        peer: &ReplicaInfo,
+        operations: &[Operation],
+    ) -> Result<Vec<Dot>, Box<dyn std::error::Error + Send + Sync>> {
+        let addr = &peer.addr;
+        let step = self.send_timeout();
+        let timed_out = || format!("no reply from {} within {:?}", addr, step);
 
-        // Connect and send (length-prefixed)
-        let mut stream = TcpStream::connect(addr).await?;
+        let mut stream = tokio::time::timeout(step, self.transport.connect(addr))
+            .await
+            .map_err(|_| timed_out())??;
+        for batch in operations.chunks(self.config.max_batch.max(1)) {
+            let message = crate::proto::operations_to_message(batch, self.config.packed_vv);
+            tokio::time::timeout(
+                step,
+                write_frame(&mut stream, &message, self.config.compression),
+            )
+            .await
+            .map_err(|_| timed_out())??;
+        }
+        tokio::time::timeout(step, stream.shutdown())
+            .await
+            .map_err(|_| timed_out())??;
+        self.metrics.record_sent(operations.len());
 
-        // Write length prefix (4 bytes big-endian)
-        stream.write_u32(buf.len() as u32).await?;
+        let mut acked = Vec::with_capacity(operations.len());
+        let ended: Result<(), Box<dyn std::error::Error + Send + Sync>> = loop {
+            let buf = match tokio::time::timeout(step, read_frame(&mut stream)).await {
+                Ok(Ok(Some(buf))) => buf,
+                Ok(Ok(None)) => break Ok(()),
+                Ok(Err(e)) => break Err(e.into()),
+                Err(_) => break Err(timed_out().into()),
+            };
+            match crate::proto::replication::Ack::decode(&buf[..]) {
+                Ok(ack) => match crate::proto::proto_to_ack(&ack) {
+                    Some(dot) => acked.push(dot),
+                    None => warn!("Failed to decode ack from {}", addr),
+                },
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.metrics.record_acks(acked.len());
 
-        // Write message body
-        stream.write_all(&buf).await?;
-        stream.flush().await?;
+        match ended {
+            Ok(()) => {
+                self.last_sent
+                    .write()
+                    .await
+                    .insert(peer.actor_id(), Instant::now());
+            }
+            Err(e) if acked.is_empty() => return Err(e),
+            Err(e) => warn!(
+                "Peer {} acked {} of {} operations, then: {}",
+                addr,
+                acked.len(),
+                operations.len(),
+                e
+            ),
+        }
+        Ok(acked)
+    }
 
-        Ok(())
+    /// Send a heartbeat to every peer every `heartbeat_interval_ms`, until the manager is dropped
+    ///
+    /// Each heartbeat carries the server's version vector, for causal stability.
+    /// Peers that are unreachable are skipped quietly; they notice our absence
+    /// by the heartbeats they miss.
+    pub async fn run_heartbeat(self: Arc<Self>, server: Arc<Server>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(
+            self.config.heartbeat_interval_ms.max(1),
+        ));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            let vv = server.version_vector().read().await.clone();
+            let heartbeat = crate::proto::heartbeat_to_message(
+                server.actor_id(),
+                &vv,
+                &self.subscriptions.read().await,
+                self.config.packed_vv,
+            );
+            for peer in &self.peers().await {
+                let sent = async {
+                    let mut stream = self.transport.connect(&peer.addr).await?;
+                    write_frame(&mut stream, &heartbeat, self.config.compression).await?;
+                    stream.shutdown().await
+                };
+                if let Err(e) = sent.await {
+                    debug!("Heartbeat to peer {} failed: {}", peer.addr, e);
+                }
+            }
+        }
+    }
+
+    /// Record that a peer sent us a message
+    ///
+    /// A peer that was down is up again, and is sent everything it missed.
+    pub async fn mark_seen(self: &Arc<Self>, actor_id: ActorId) {
+        let Some(peer) = self.peer(actor_id).await else {
+            return;
+        };
+
+        let was_up = self.is_up(&peer).await;
+        self.last_seen
+            .write()
+            .await
+            .insert(actor_id, Instant::now());
+
+        if !was_up {
+            info!("Peer {} is up", peer.addr);
+            // On its sender, after anything already queued for it; nothing waits for it
+            let (flushed, _) = oneshot::channel();
+            self.ask_sender(actor_id, Outgoing::Flush(flushed)).await;
+        }
+    }
+
+    /// The set-name patterns this node replicates, empty for every set
+    pub async fn subscriptions(&self) -> Vec<String> {
+        self.subscriptions.read().await.clone()
+    }
+
+    /// Replicate only the sets matching `patterns` from now on, every set if empty
+    /// (SUBSCRIBE SET)
+    ///
+    /// Peers learn of it from our next heartbeat. Sets newly matched are not fetched, only
+    /// kept up to date from then on; SSYNC brings one up to date.
+    pub async fn subscribe(&self, patterns: Vec<String>) {
+        info!("Subscribing to sets {:?}", patterns);
+        *self.subscriptions.write().await = patterns;
+    }
+
+    /// Record the set-name patterns a peer reported in a heartbeat
+    pub async fn record_peer_subscriptions(&self, actor_id: ActorId, patterns: Vec<String>) {
+        if self.peer(actor_id).await.is_none() {
+            return;
+        }
+        self.peer_subscriptions
+            .write()
+            .await
+            .insert(actor_id, patterns);
+    }
+
+    /// Record the version vector a peer reported in a heartbeat
+    pub async fn record_peer_vv(&self, actor_id: ActorId, vv: &VersionVector) {
+        if self.peer(actor_id).await.is_none() {
+            return;
+        }
+        self.peer_vvs
+            .write()
+            .await
+            .entry(actor_id)
+            .or_default()
+            .merge(vv);
+    }
+
+    /// The causally stable version vector: the minimum of `local_vv` and every peer's last report
+    ///
+    /// Empty until every peer has reported, as nothing is known to be seen everywhere before then.
+    pub async fn stable_vv(&self, local_vv: &VersionVector) -> VersionVector {
+        let peers = self.peers.read().await;
+        let peer_vvs = self.peer_vvs.read().await;
+        let mut stable = local_vv.clone();
+        for peer in peers.iter() {
+            match peer_vvs.get(&peer.actor_id()) {
+                Some(vv) => stable.meet(vv),
+                None => return VersionVector::new(),
+            }
+        }
+        stable
+    }
+
+    /// Garbage collect causally stable dots every `gc_interval_ms`, until the manager is dropped
+    ///
+    /// Refreshes the server's stable version vector from the peers' heartbeats first.
+    pub async fn run_dot_gc(self: Arc<Self>, server: Arc<Server>) {
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.gc_interval_ms.max(1)));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            let local_vv = server.version_vector().read().await.clone();
+            server.set_stable_vv(self.stable_vv(&local_vv).await).await;
+            if let Err(e) = server.compact_dots(None).await {
+                error!("Storage error garbage collecting dots: {}", e);
+            }
+        }
+    }
+
+    /// A peer is down once it has missed `heartbeat_miss_limit` heartbeats
+    async fn is_up(&self, peer: &ReplicaInfo) -> bool {
+        let last_seen = self
+            .last_seen
+            .read()
+            .await
+            .get(&peer.actor_id())
+            .copied()
+            .unwrap_or(self.started);
+        last_seen.elapsed() < self.down_after()
+    }
+
+    fn down_after(&self) -> Duration {
+        Duration::from_millis(
+            self.config
+                .heartbeat_interval_ms
+                .saturating_mul(self.config.heartbeat_miss_limit as u64),
+        )
+    }
+
+    /// Liveness of every peer, and how far it has got
+    pub async fn peer_status(&self) -> Vec<PeerStatus> {
+        let peers = self.peers.read().await;
+        let last_seen = self.last_seen.read().await;
+        let last_sent = self.last_sent.read().await;
+        let peer_vvs = self.peer_vvs.read().await;
+        let unacked = self.unsent_buffer.read().await;
+
+        peers
+            .iter()
+            .map(|peer| {
+                let seen = last_seen.get(&peer.actor_id());
+                PeerStatus {
+                    peer: peer.clone(),
+                    up: seen.unwrap_or(&self.started).elapsed() < self.down_after(),
+                    last_seen: seen.map(|at| at.elapsed()),
+                    unacked: unacked.peer_count(&peer.actor_id()),
+                    last_sent: last_sent.get(&peer.actor_id()).map(|at| at.elapsed()),
+                    vv: peer_vvs.get(&peer.actor_id()).cloned(),
+                }
+            })
+            .collect()
+    }
+
+    /// How many operations we have applied that a peer had not at its last heartbeat
+    ///
+    /// None until the peer has reported its version vector.
+    pub async fn peer_lag(&self, peer_id: ActorId, local_vv: &VersionVector) -> Option<u64> {
+        self.peer_vvs
+            .read()
+            .await
+            .get(&peer_id)
+            .map(|vv| local_vv.ahead_of(vv))
+    }
+
+    /// The operations we sent that `vv` has not seen, for a peer that lost some
+    ///
+    /// The flag is false if the log no longer holds all of them.
+    pub async fn sent_since(&self, vv: &VersionVector) -> (Vec<Operation>, bool) {
+        self.sent_log.read().await.since(vv)
+    }
+
+    /// Ask a peer to resend the operations it sent that `from_vv` has not seen
+    ///
+    /// Returns None if the actor is not a peer, or a request to it is already in flight.
+    /// Otherwise the operations come back in causal order, with a flag that is false
+    /// if the peer could not supply them all.
+    pub async fn request_missing_ops(
+        &self,
+        peer_id: ActorId,
+        from_vv: &VersionVector,
+    ) -> Result<Option<(Vec<Operation>, bool)>, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(peer) = self.peer(peer_id).await else {
+            return Ok(None);
+        };
+        if !self.recovering.write().await.insert(peer_id) {
+            return Ok(None);
+        }
+
+        let result = async {
+            let mut stream = self.transport.connect(&peer.addr).await?;
+            write_frame(
+                &mut stream,
+                &crate::proto::missing_ops_to_message(from_vv, self.config.packed_vv),
+                self.config.compression,
+            )
+            .await?;
+            stream.shutdown().await?;
+
+            let buf = read_frame(&mut stream)
+                .await?
+                .ok_or("peer closed the connection without a missing ops response")?;
+            let response = crate::proto::replication::MissingOpsResponse::decode(&buf[..])?;
+            let ops = response
+                .operations
+                .iter()
+                .map(crate::proto::proto_to_operation)
+                .collect::<Option<Vec<_>>>()
+                .ok_or("malformed operation in missing ops response")?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((ops, response.complete))
+        }
+        .await;
+
+        self.recovering.write().await.remove(&peer_id);
+        result.map(Some)
+    }
+
+    /// Ask a peer for the next chunk of operations `vv` has not seen (RBILT startup learning)
+    ///
+    /// Returns the operations in causal order, and whether the peer has nothing more to send.
+    pub async fn rbilt_request(
+        &self,
+        peer: &ReplicaInfo,
+        vv: &VersionVector,
+        max_ops: u32,
+    ) -> Result<(Vec<Operation>, bool), Box<dyn std::error::Error + Send + Sync>> {
+        let mut stream = self.transport.connect(&peer.addr).await?;
+        write_frame(
+            &mut stream,
+            &crate::proto::rbilt_request_to_message(vv, max_ops, self.config.packed_vv),
+            self.config.compression,
+        )
+        .await?;
+        stream.shutdown().await?;
+
+        let buf = read_frame(&mut stream)
+            .await?
+            .ok_or("peer closed the connection without an RBILT response")?;
+        let response = crate::proto::replication::RbiltResponse::decode(&buf[..])?;
+        if !response.complete {
+            warn!(
+                "Peer {} no longer holds every operation we missed, anti-entropy is needed to converge",
+                peer.addr
+            );
+        }
+
+        let ops = response
+            .operations
+            .iter()
+            .map(crate::proto::proto_to_operation)
+            .collect::<Option<Vec<_>>>()
+            .ok_or("malformed operation in RBILT response")?;
+        Ok((ops, response.done))
+    }
+
+    /// The peers to try a startup state transfer from, best first
+    ///
+    /// Every peer if we have no state at all, otherwise those whose last heartbeat shows them
+    /// more than `snapshot_lag_threshold` operations ahead of `local_vv`.
+    pub async fn snapshot_candidates(&self, local_vv: &VersionVector) -> Vec<ReplicaInfo> {
+        let peers = self.peers.read().await;
+        if local_vv.counters.is_empty() {
+            return peers.iter().cloned().collect();
+        }
+
+        let peer_vvs = self.peer_vvs.read().await;
+        let mut lagging: Vec<(u64, ReplicaInfo)> = peers
+            .iter()
+            .filter_map(|peer| {
+                let lag = peer_vvs.get(&peer.actor_id())?.ahead_of(local_vv);
+                (lag > self.config.snapshot_lag_threshold).then(|| (lag, peer.clone()))
+            })
+            .collect();
+        lagging.sort_by_key(|(lag, _)| std::cmp::Reverse(*lag));
+        lagging.into_iter().map(|(_, peer)| peer).collect()
+    }
+
+    /// Ask a peer for a snapshot of everything it has (state transfer)
+    pub async fn request_snapshot(
+        &self,
+        peer: &ReplicaInfo,
+    ) -> Result<(VersionVector, Vec<SetSnapshot>), Box<dyn std::error::Error + Send + Sync>> {
+        let mut stream = self.transport.connect(&peer.addr).await?;
+        write_frame(
+            &mut stream,
+            &crate::proto::snapshot_request_to_message(),
+            self.config.compression,
+        )
+        .await?;
+        stream.shutdown().await?;
+
+        let mut sets = Vec::new();
+        loop {
+            let buf = read_frame(&mut stream)
+                .await?
+                .ok_or("peer closed the connection before the snapshot was complete")?;
+            let chunk = crate::proto::replication::SnapshotChunk::decode(&buf[..])?;
+            match crate::proto::add_snapshot_chunk(&mut sets, &chunk) {
+                Some(Some(vv)) => return Ok((vv, sets)),
+                Some(None) => {}
+                None => return Err("malformed snapshot chunk".into()),
+            }
+        }
+    }
+
+    /// Ask a peer for its version vector (see `NodeBuilder::build`)
+    pub async fn request_vv(
+        &self,
+        peer: &ReplicaInfo,
+    ) -> Result<VersionVector, Box<dyn std::error::Error + Send + Sync>> {
+        let mut stream = self.transport.connect(&peer.addr).await?;
+        write_frame(
+            &mut stream,
+            &crate::proto::vv_request_to_message(),
+            self.config.compression,
+        )
+        .await?;
+        stream.shutdown().await?;
+
+        let buf = read_frame(&mut stream)
+            .await?
+            .ok_or("peer closed the connection without its version vector")?;
+        let heartbeat = crate::proto::replication::Heartbeat::decode(&buf[..])?;
+        let (_, vv) =
+            crate::proto::proto_to_heartbeat(&heartbeat).ok_or("malformed version vector reply")?;
+        Ok(vv)
+    }
+
+    /// A copy of the current peers
+    pub async fn peers(&self) -> BTreeSet<ReplicaInfo> {
+        self.peers.read().await.clone()
+    }
+
+    async fn peer(&self, actor_id: ActorId) -> Option<ReplicaInfo> {
+        self.peers
+            .read()
+            .await
+            .iter()
+            .find(|p| p.actor_id() == actor_id)
+            .cloned()
+    }
+
+    /// Start replicating to a peer (CLUSTER MEET)
+    ///
+    /// A peer already known by the same actor id is replaced, so one that moved can be met at
+    /// its new address. Only operations from now on are sent to it; what came before reaches
+    /// it by state transfer (see `push_snapshot`), or by anti-entropy.
+    pub async fn add_peer(&self, peer: ReplicaInfo) {
+        info!("Adding peer {} at {}", peer.actor_id(), peer.addr);
+        let mut peers = self.peers.write().await;
+        peers.retain(|p| p.actor_id() != peer.actor_id());
+        peers.insert(peer);
+    }
+
+    /// Follow a peer that restarted at a newer epoch, given an actor id it sent us
+    ///
+    /// `(node_id, epoch)` pairs are distinct actors, so the peer is forgotten at its old epoch
+    /// and met at the new one, at the same address. What it had not acked is kept, to be
+    /// retransmitted to it under its new identity. Returns false, changing nothing, unless
+    /// `actor_id` is a newer epoch of a known peer.
+    pub async fn renew_peer(&self, actor_id: ActorId) -> bool {
+        let Some(old) = self
+            .peers
+            .read()
+            .await
+            .iter()
+            .find(|p| {
+                actor_id.version() == 0
+                    && u64::from(p.node_id) == actor_id.node_id()
+                    && p.epoch < actor_id.epoch()
+            })
+            .cloned()
+        else {
+            return false;
+        };
+
+        info!("Peer {} restarted as {}", old.actor_id(), actor_id);
+        let unacked = self.unsent_buffer.write().await.take_peer(&old.actor_id());
+        self.remove_peer(old.node_id).await;
+        self.add_peer(ReplicaInfo {
+            epoch: actor_id.epoch(),
+            ..old
+        })
+        .await;
+        let mut buffer = self.unsent_buffer.write().await;
+        for op in unacked {
+            buffer.add(actor_id, op);
+        }
+        true
+    }
+
+    /// Stop replicating to every epoch of a node (CLUSTER FORGET)
+    ///
+    /// Operations still waiting for the node's acks are dropped rather than retransmitted,
+    /// and it no longer holds back causal stability. Returns the peers removed.
+    pub async fn remove_peer(&self, node_id: u16) -> Vec<ReplicaInfo> {
+        let mut peers = self.peers.write().await;
+        let removed: Vec<ReplicaInfo> = peers
+            .iter()
+            .filter(|p| p.node_id == node_id)
+            .cloned()
+            .collect();
+        peers.retain(|p| p.node_id != node_id);
+
+        // Closing a sender's queue stops its task
+        let mut senders = self.senders.write().await;
+        let mut unacked = self.unsent_buffer.write().await;
+        for peer in &removed {
+            info!("Forgetting peer {} at {}", peer.actor_id(), peer.addr);
+            let actor_id = peer.actor_id();
+            senders.remove(&actor_id);
+            unacked.clear_peer(&actor_id);
+            self.deltas.write().await.clear_peer(&actor_id);
+            self.queued.write().await.remove(&actor_id);
+            self.last_seen.write().await.remove(&actor_id);
+            self.peer_vvs.write().await.remove(&actor_id);
+            self.peer_subscriptions.write().await.remove(&actor_id);
+            self.last_sent.write().await.remove(&actor_id);
+        }
+        removed
+    }
+
+    /// Push a snapshot of everything we have to a peer (state transfer to a node met at runtime)
+    ///
+    /// The peer loads it if it has nothing the snapshot does not cover (see `Server::load_snapshot`).
+    /// Returns the number of sets sent.
+    pub async fn push_snapshot(
+        &self,
+        server: &Server,
+        peer: &ReplicaInfo,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let (vv, sets) = server.snapshot().await?;
+        let mut stream = self.transport.connect(&peer.addr).await?;
+        for chunk in crate::proto::snapshot_to_chunks(
+            &vv,
+            &sets,
+            SNAPSHOT_CHUNK_ELEMENTS,
+            self.config.packed_vv,
+        ) {
+            write_frame(
+                &mut stream,
+                &crate::proto::snapshot_chunk_to_message(chunk),
+                self.config.compression,
+            )
+            .await?;
+        }
+        stream.shutdown().await?;
+        Ok(sets.len())
+    }
+
+    pub fn config(&self) -> &ReplicationConfig {
+        &self.config
+    }
+
+    /// The settings CONFIG GET and CONFIG SET work on
+    pub fn runtime_config(&self) -> &Arc<RuntimeConfig> {
+        &self.runtime
+    }
+
+    pub fn transport(&self) -> Arc<dyn NetworkTransport> {
+        Arc::clone(&self.transport)
+    }
+
+    /// Anti-entropy for one set against every peer
+    ///
+    /// Returns the number of elements changed locally. Unreachable peers are logged and skipped.
+    pub async fn sync_set(&self, server: &Server, set_name: &str) -> usize {
+        let mut changed = 0;
+        for peer in &self.peers().await {
+            match self.sync_with_peer(server, peer, set_name).await {
+                Ok(n) => changed += n,
+                Err(e) => warn!(
+                    "Anti-entropy for set={} with peer {} failed: {}",
+                    set_name, peer.addr, e
+                ),
+            }
+        }
+        changed
+    }
+
+    /// Start anti-entropy for a set with one peer in the background
+    ///
+    /// Does nothing if the actor is not a peer, or an exchange for the same
+    /// peer and set is already running.
+    pub fn spawn_sync(self: &Arc<Self>, server: Arc<Server>, peer_id: ActorId, set_name: String) {
+        let replication = Arc::clone(self);
+        tokio::spawn(async move {
+            let Some(peer) = replication.peer(peer_id).await else {
+                return;
+            };
+
+            let key = (peer_id, set_name);
+            if !replication.syncing.write().await.insert(key.clone()) {
+                return;
+            }
+
+            match replication.sync_with_peer(&server, &peer, &key.1).await {
+                Ok(changed) => debug!(
+                    "Anti-entropy for set={} with peer {} changed {} elements",
+                    key.1, peer.addr, changed
+                ),
+                Err(e) => warn!(
+                    "Anti-entropy for set={} with peer {} failed: {}",
+                    key.1, peer.addr, e
+                ),
+            }
+
+            replication.syncing.write().await.remove(&key);
+        });
+    }
+
+    /// Ask a peer for the part of a set we have not observed, and merge it
+    ///
+    /// First the set's bucket digests are compared, and only buckets that differ
+    /// are synchronised at the element level. Identical sets cost one round trip.
+    /// Returns the number of elements changed locally.
+    async fn sync_with_peer(
+        &self,
+        server: &Server,
+        peer: &ReplicaInfo,
+        set_name: &str,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let bucket_count = self.config.digest_buckets.max(1);
+        let digests = server.bucket_digests(set_name, bucket_count).await?;
+
+        let mut stream = self.transport.connect(&peer.addr).await?;
+        write_frame(
+            &mut stream,
+            &crate::proto::digest_request_to_message(set_name, &digests),
+            self.config.compression,
+        )
+        .await?;
+
+        let buf = read_frame(&mut stream)
+            .await?
+            .ok_or("peer closed the connection without a digest response")?;
+        let differing =
+            crate::proto::replication::DigestResponse::decode(&buf[..])?.differing_buckets;
+        if differing.is_empty() {
+            debug!("Set={} matches peer {}", set_name, peer.addr);
+            return Ok(0);
+        }
+
+        let vv = server.version_vector().read().await.clone();
+        write_frame(
+            &mut stream,
+            &crate::proto::sync_request_to_message(
+                set_name,
+                &vv,
+                &differing,
+                bucket_count,
+                self.config.packed_vv,
+            ),
+            self.config.compression,
+        )
+        .await?;
+        stream.shutdown().await?;
+
+        let buf = read_frame(&mut stream)
+            .await?
+            .ok_or("peer closed the connection without a sync response")?;
+        let response = crate::proto::replication::SyncResponse::decode(&buf[..])?;
+        let (remote_vv, elements) =
+            crate::proto::proto_to_sync_response(&response).ok_or("malformed sync response")?;
+
+        Ok(server.merge_sync(set_name, &remote_vv, &elements).await?)
+    }
+
+    pub fn metrics(&self) -> &ReplicationMetrics {
+        &self.metrics
     }
 
     pub fn pending_buffer(&self) -> Arc<RwLock<PendingBuffer>> {
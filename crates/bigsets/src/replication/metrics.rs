@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Replication counters, cheap enough to bump on every send, ack and apply
+///
+/// Relaxed atomics: each counter is only ever read on its own, for INFO.
+#[derive(Debug, Default)]
+pub struct ReplicationMetrics {
+    ops_sent: AtomicU64,
+    acks_received: AtomicU64,
+    ops_applied: AtomicU64,
+    ops_buffered: AtomicU64,
+    read_repairs: AtomicU64,
+}
+
+/// A point-in-time copy of the counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Operations sent to peers, counting every retransmission
+    pub ops_sent: u64,
+    /// Acks received from peers
+    pub acks_received: u64,
+    /// Operations received from peers and applied (or found already applied)
+    pub ops_applied: u64,
+    /// Operations received from peers that had to wait in the pending buffer
+    pub ops_buffered: u64,
+    /// Read repairs started, by causal reads that found a client ahead of this node
+    pub read_repairs: u64,
+}
+
+impl ReplicationMetrics {
+    pub fn record_sent(&self, ops: usize) {
+        self.ops_sent.fetch_add(ops as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_acks(&self, acks: usize) {
+        self.acks_received.fetch_add(acks as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_applied(&self, ops: usize) {
+        self.ops_applied.fetch_add(ops as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_buffered(&self, ops: usize) {
+        self.ops_buffered.fetch_add(ops as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_read_repair(&self) {
+        self.read_repairs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            ops_sent: self.ops_sent.load(Ordering::Relaxed),
+            acks_received: self.acks_received.load(Ordering::Relaxed),
+            ops_applied: self.ops_applied.load(Ordering::Relaxed),
+            ops_buffered: self.ops_buffered.load(Ordering::Relaxed),
+            read_repairs: self.read_repairs.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,5 +1,8 @@
+mod frame;
 mod manager;
+mod metrics;
 mod server;
 
-pub use manager::ReplicationManager;
+pub use manager::{AckWait, PeerStatus, ReplicationManager};
+pub use metrics::{MetricsSnapshot, ReplicationMetrics};
 pub use server::ReplicationListener;
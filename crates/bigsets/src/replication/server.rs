@@ -1,13 +1,24 @@
+use crate::config::Compression;
+use crate::network::Connection;
 use crate::replication::ReplicationManager;
 use crate::server::Server;
 
+use crate::proto::replication::replication_message::Payload;
+use crate::proto::replication::{
+    Delta, DeltaAck, DigestRequest, MissingOps, RbiltRequest, SyncRequest,
+};
+use crate::replication::frame::{read_frame, write_frame};
+use crate::types::{ActorId, Operation, SetSnapshot, VersionVector};
 use prost::Message;
 use std::sync::Arc;
-use tokio::io::AsyncReadExt;
-use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tracing::{debug, error, info, warn};
 
-/// TCP server that receives operations from peers
+/// Elements per frame when sending a snapshot
+pub(crate) const SNAPSHOT_CHUNK_ELEMENTS: usize = 1000;
+
+/// Server that receives operations from peers
 ///
 /// Listens for incoming operations, applies them via Server,
 /// and manages causality buffering via ReplicationManager.
@@ -15,6 +26,7 @@ pub struct ReplicationListener {
     server: Arc<Server>,
     replication: Arc<ReplicationManager>,
     addr: String,
+    shutdown: CancellationToken,
 }
 
 impl ReplicationListener {
@@ -23,9 +35,16 @@ impl ReplicationListener {
             server,
             replication,
             addr,
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// Stop accepting peers once `shutdown` is cancelled, see `run`
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     /// Try to apply buffered operations
     ///
     /// Iterates through the pending buffer and attempts to apply each operation.
@@ -73,13 +92,14 @@ impl ReplicationListener {
                     }
                     Err(e) => {
                         // Storage error - this is unexpected, log and skip
-                        error!("Storage error applying buffered operation: {}", e);
+                        error!("Error applying buffered operation: {}", e);
                         i += 1;
                     }
                 }
             }
 
             total_applied += applied_this_pass;
+            replication.metrics().record_applied(applied_this_pass);
 
             // If we didn't apply anything this pass, we've reached a fixed point
             if applied_this_pass == 0 {
@@ -94,87 +114,691 @@ impl ReplicationListener {
         total_applied
     }
 
+    /// Accept replication connections until an error occurs or the shutdown token is cancelled
+    ///
+    /// Once listening, startup learning (RBILT) runs in the background, see `rbilt_startup`.
+    /// On shutdown it stops accepting and returns once the connections it has finish, so
+    /// operations a peer has sent are applied (and acked) rather than cut off.
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let listener = TcpListener::bind(&self.addr).await?;
+        let mut listener = self.replication.transport().bind(&self.addr).await?;
         info!("Replication server listening on {}", self.addr);
 
+        tokio::spawn(Self::rbilt_startup(
+            Arc::clone(&self.server),
+            Arc::clone(&self.replication),
+        ));
+
+        let connections = TaskTracker::new();
         loop {
-            let (socket, peer_addr) = listener.accept().await?;
+            let (socket, peer_addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.cancelled() => break,
+            };
             debug!("Replication connection from {}", peer_addr);
 
             let server = Arc::clone(&self.server);
             let replication = Arc::clone(&self.replication);
 
-            tokio::spawn(async move {
+            connections.spawn(async move {
                 if let Err(e) = Self::handle_connection(socket, server, replication).await {
                     error!("Replication connection error from {}: {}", peer_addr, e);
                 }
             });
         }
+
+        connections.close();
+        connections.wait().await;
+        info!("Replication server stopped");
+        Ok(())
     }
 
     async fn handle_connection(
-        mut socket: TcpStream,
+        mut socket: Connection,
         server: Arc<Server>,
         replication: Arc<ReplicationManager>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        // A snapshot pushed to us arrives in several chunks on the one connection
+        let mut pushed: Vec<SetSnapshot> = Vec::new();
         loop {
-            // Read length prefix (4 bytes big-endian)
-            let len = match socket.read_u32().await {
-                Ok(len) => len as usize,
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            let buf = match read_frame(&mut socket).await? {
+                Some(buf) => buf,
+                None => {
                     debug!("Peer closed connection");
                     return Ok(());
                 }
-                Err(e) => return Err(e.into()),
             };
 
-            // Read message body
-            let mut buf = vec![0u8; len];
-            socket.read_exact(&mut buf).await?;
-
-            // Decode protobuf Operation
-            let proto_op = crate::proto::replication::Operation::decode(&buf[..])?;
-            let operation = match crate::proto::proto_to_operation(&proto_op) {
-                Some(op) => op,
+            // Decode the message: a single operation or a batch applied in order
+            let message = crate::proto::replication::ReplicationMessage::decode(&buf[..])?;
+            let proto_ops = match message.payload {
+                Some(Payload::Operation(op)) => vec![op],
+                Some(Payload::Batch(batch)) => batch.operations,
+                Some(Payload::SyncRequest(request)) => {
+                    Self::handle_sync_request(
+                        &mut socket,
+                        &server,
+                        &request,
+                        replication.config().compression,
+                        replication.config().packed_vv,
+                    )
+                    .await?;
+                    continue;
+                }
+                Some(Payload::DigestRequest(request)) => {
+                    Self::handle_digest_request(
+                        &mut socket,
+                        &server,
+                        &request,
+                        replication.config().compression,
+                    )
+                    .await?;
+                    continue;
+                }
+                Some(Payload::MissingOps(request)) => {
+                    Self::handle_missing_ops(&mut socket, &server, &replication, &request).await?;
+                    continue;
+                }
+                Some(Payload::VvRequest(_)) => {
+                    let vv = server.version_vector().read().await.clone();
+                    write_frame(
+                        &mut socket,
+                        &crate::proto::heartbeat_to_proto(
+                            server.actor_id(),
+                            &vv,
+                            replication.config().packed_vv,
+                        ),
+                        replication.config().compression,
+                    )
+                    .await?;
+                    continue;
+                }
+                Some(Payload::SnapshotRequest(_)) => {
+                    Self::handle_snapshot_request(&mut socket, &server, &replication).await?;
+                    continue;
+                }
+                Some(Payload::SnapshotChunk(chunk)) => {
+                    match crate::proto::add_snapshot_chunk(&mut pushed, &chunk) {
+                        Some(Some(vv)) => {
+                            let sets = std::mem::take(&mut pushed);
+                            Self::load_pushed_snapshot(&server, &replication, vv, sets).await;
+                        }
+                        Some(None) => {}
+                        None => return Err("malformed snapshot chunk".into()),
+                    }
+                    continue;
+                }
+                Some(Payload::Delta(delta)) => {
+                    Self::handle_delta(&mut socket, &server, &replication, &delta).await?;
+                    continue;
+                }
+                Some(Payload::RbiltRequest(request)) => {
+                    Self::handle_rbilt_request(&mut socket, &server, &replication, &request)
+                        .await?;
+                    continue;
+                }
+                Some(Payload::Heartbeat(heartbeat)) => {
+                    match crate::proto::proto_to_heartbeat(&heartbeat) {
+                        Some((actor_id, vv)) => {
+                            replication.renew_peer(actor_id).await;
+                            replication.record_peer_vv(actor_id, &vv).await;
+                            replication
+                                .record_peer_subscriptions(actor_id, heartbeat.subscriptions)
+                                .await;
+                            replication.mark_seen(actor_id).await;
+                            if replication.config().fanout > 0
+                                && vv.get(actor_id)
+                                    > server.version_vector().read().await.get(actor_id)
+                            {
+                                Self::spawn_catch_up(
+                                    Arc::clone(&server),
+                                    Arc::clone(&replication),
+                                    actor_id,
+                                );
+                            }
+                        }
+                        None => warn!("Failed to decode heartbeat from protobuf"),
+                    }
+                    continue;
+                }
                 None => {
-                    warn!("Failed to decode operation from protobuf");
+                    warn!("Received replication message with no payload");
                     continue;
                 }
             };
 
-            info!("Received operation for set={}", operation.set_name);
+            let mut applied = 0;
+            for proto_op in &proto_ops {
+                let operation = match crate::proto::proto_to_operation(proto_op) {
+                    Some(op) => op,
+                    None => {
+                        warn!("Failed to decode operation from protobuf");
+                        continue;
+                    }
+                };
 
-            // Try to apply operation
-            match server.apply_remote_operation(operation.clone()).await {
+                if Self::handle_operation(&mut socket, &server, &replication, operation).await? {
+                    applied += 1;
+                }
+            }
+
+            if applied > 0 {
+                // Try to drain the buffer - newly applied operations might unblock others
+                Self::try_apply_buffered(Arc::clone(&server), Arc::clone(&replication)).await;
+            }
+        }
+    }
+
+    /// Reply to an anti-entropy request with everything in the set the requester has not observed
+    async fn handle_sync_request(
+        socket: &mut Connection,
+        server: &Server,
+        request: &SyncRequest,
+        compression: Compression,
+        packed_vv: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((set_name, vv)) = crate::proto::proto_to_sync_request(request) else {
+            warn!("Failed to decode sync request from protobuf");
+            return Ok(());
+        };
+
+        let (local_vv, elements) = server
+            .elements_since(&set_name, &vv, &request.buckets, request.bucket_count)
+            .await?;
+        debug!(
+            "Anti-entropy for set={} sending {} elements",
+            set_name,
+            elements.len()
+        );
+
+        write_frame(
+            socket,
+            &crate::proto::sync_response_to_proto(&set_name, &local_vv, &elements, packed_vv),
+            compression,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Join a peer's delta into our state, and tell it whether we could
+    async fn handle_delta(
+        socket: &mut Connection,
+        server: &Arc<Server>,
+        replication: &Arc<ReplicationManager>,
+        delta: &Delta,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((sender, vv, sets)) = crate::proto::proto_to_delta(delta) else {
+            warn!("Failed to decode delta from protobuf");
+            return Ok(());
+        };
+
+        let applied = match server.apply_delta(sender, vv, sets).await? {
+            Some(_) => true,
+            None => {
+                debug!(
+                    "Delta from {} depends on operations we have not seen",
+                    sender
+                );
+                false
+            }
+        };
+        write_frame(
+            socket,
+            &DeltaAck { applied },
+            replication.config().compression,
+        )
+        .await?;
+
+        replication.mark_seen(sender).await;
+        if applied {
+            // Operations held back for causality may be waiting on what the delta covers
+            Self::try_apply_buffered(Arc::clone(server), Arc::clone(replication)).await;
+        }
+        Ok(())
+    }
+
+    /// RBILT startup learning
+    ///
+    /// Operations sent to us while we were down were lost with the in-memory buffers.
+    /// After `rbilt_startup_delay_ms` (time for peers to come up too) a node with no
+    /// state, or far behind a peer, first takes a state transfer (see `state_transfer`).
+    /// Then we send our VV to each peer and learn what we missed from it, in chunks no
+    /// larger than the pending buffer so everything received can be held until its causal
+    /// dependencies arrive. The server is marked ready once every reachable peer has been
+    /// learned from.
+    async fn rbilt_startup(server: Arc<Server>, replication: Arc<ReplicationManager>) {
+        if replication.peers().await.is_empty() {
+            server.set_ready(true);
+            return;
+        }
+
+        let delay = replication.config().rbilt_startup_delay_ms;
+        tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+
+        Self::state_transfer(&server, &replication).await;
+
+        let max_ops = replication.config().buffer_size.max(1) as u32;
+        for peer in &replication.peers().await {
+            let mut learned = 0;
+            loop {
+                let vv = server.version_vector().read().await.clone();
+                let (ops, done) = match replication.rbilt_request(peer, &vv, max_ops).await {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        warn!("RBILT with peer {} failed: {}", peer.addr, e);
+                        break;
+                    }
+                };
+
+                let received = ops.len();
+                learned += received;
+                Self::apply_or_buffer(&server, &replication, ops).await;
+                Self::try_apply_buffered(Arc::clone(&server), Arc::clone(&replication)).await;
+
+                if done || received == 0 {
+                    break;
+                }
+            }
+            info!(
+                "RBILT learned {} operations from peer {}",
+                learned, peer.addr
+            );
+        }
+
+        server.set_ready(true);
+        info!("RBILT startup learning finished, serving reads");
+    }
+
+    /// Load a snapshot from the first candidate peer that can give us one
+    ///
+    /// Replaying operations cannot give a new node the history from before it joined (or
+    /// from beyond a peer's sent log), so it takes a copy of a peer's state instead.
+    async fn state_transfer(server: &Arc<Server>, replication: &Arc<ReplicationManager>) {
+        let local_vv = server.version_vector().read().await.clone();
+        for peer in replication.snapshot_candidates(&local_vv).await {
+            let (vv, sets) = match replication.request_snapshot(&peer).await {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("State transfer from peer {} failed: {}", peer.addr, e);
+                    continue;
+                }
+            };
+
+            let set_count = sets.len();
+            match server.load_snapshot(vv, sets).await {
                 Ok(true) => {
-                    debug!("Applied operation successfully");
-                    // Try to drain the buffer - newly applied operation might unblock others
-                    Self::try_apply_buffered(Arc::clone(&server), Arc::clone(&replication)).await;
+                    info!(
+                        "Loaded a snapshot of {} sets from peer {}",
+                        set_count, peer.addr
+                    );
+                    Self::try_apply_buffered(Arc::clone(server), Arc::clone(replication)).await;
+                    return;
                 }
                 Ok(false) => {
-                    // Causality not satisfied, buffer it
-                    debug!(
-                        "Operation for set={} needs buffering (causality not satisfied)",
-                        operation.set_name
-                    );
+                    debug!("Snapshot from peer {} has nothing for us", peer.addr)
+                }
+                Err(e) => error!("Storage error loading snapshot: {}", e),
+            }
+        }
+    }
+
+    /// Load a snapshot a peer pushed to us when it met us (see `ReplicationManager::push_snapshot`)
+    async fn load_pushed_snapshot(
+        server: &Arc<Server>,
+        replication: &Arc<ReplicationManager>,
+        vv: VersionVector,
+        sets: Vec<SetSnapshot>,
+    ) {
+        let set_count = sets.len();
+        match server.load_snapshot(vv, sets).await {
+            Ok(true) => {
+                info!("Loaded a pushed snapshot of {} sets", set_count);
+                Self::try_apply_buffered(Arc::clone(server), Arc::clone(replication)).await;
+            }
+            Ok(false) => debug!("Pushed snapshot has nothing for us"),
+            Err(e) => error!("Storage error loading pushed snapshot: {}", e),
+        }
+    }
+
+    /// Reply to a state transfer request with a snapshot of everything we have
+    async fn handle_snapshot_request(
+        socket: &mut Connection,
+        server: &Server,
+        replication: &ReplicationManager,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (vv, sets) = server.snapshot().await?;
+        debug!("Sending snapshot of {} sets", sets.len());
+
+        for chunk in crate::proto::snapshot_to_chunks(
+            &vv,
+            &sets,
+            SNAPSHOT_CHUNK_ELEMENTS,
+            replication.config().packed_vv,
+        ) {
+            write_frame(socket, &chunk, replication.config().compression).await?;
+        }
+        Ok(())
+    }
+
+    /// Reply to an RBILT request with the next chunk of what we sent that the peer has not seen
+    async fn handle_rbilt_request(
+        socket: &mut Connection,
+        server: &Server,
+        replication: &ReplicationManager,
+        request: &RbiltRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some((vv, max_ops)) = crate::proto::proto_to_rbilt_request(request) else {
+            warn!("Failed to decode RBILT request from protobuf");
+            return Ok(());
+        };
+
+        let (mut ops, complete) = Self::ops_since(server, replication, &vv).await;
+        let max_ops = max_ops.max(1) as usize;
+        let done = ops.len() <= max_ops;
+        ops.truncate(max_ops);
+        debug!(
+            "Sending {} operations for RBILT (done={}, complete={})",
+            ops.len(),
+            done,
+            complete
+        );
+
+        write_frame(
+            socket,
+            &crate::proto::rbilt_response_to_proto(
+                &ops,
+                done,
+                complete,
+                replication.config().packed_vv,
+            ),
+            replication.config().compression,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The operations `vv` has not seen, for a peer that lost some, and whether that is all of them
+    ///
+    /// From the storage's operation log if it keeps one, which survives restarts and holds
+    /// the operations we applied as well as those we sent. Otherwise from the sent log.
+    async fn ops_since(
+        server: &Server,
+        replication: &ReplicationManager,
+        vv: &VersionVector,
+    ) -> (Vec<Operation>, bool) {
+        match server.ops_since(vv).await {
+            Ok(Some(logged)) => return logged,
+            Ok(None) => {}
+            Err(e) => error!("Storage error reading the operation log: {}", e),
+        }
+        replication.sent_since(vv).await
+    }
+
+    /// Apply operations in order, buffering those whose causal dependencies are still missing
+    async fn apply_or_buffer(
+        server: &Server,
+        replication: &ReplicationManager,
+        ops: Vec<Operation>,
+    ) {
+        for op in ops {
+            match server.apply_remote_operation(op.clone()).await {
+                Ok(true) => replication.metrics().record_applied(1),
+                Ok(false) => {
                     let pending_buffer = replication.pending_buffer();
                     let mut buffer = pending_buffer.write().await;
-                    if !buffer.add(operation) {
+                    if buffer.contains_dot(op.dot()) {
+                        continue;
+                    }
+                    if buffer.add(op) {
+                        replication.metrics().record_buffered(1);
+                    } else {
+                        warn!("Pending buffer is full, dropping learned operation");
+                    }
+                }
+                Err(e) => error!("Error applying learned operation: {}", e),
+            }
+        }
+    }
+
+    /// Reply to a missing operations request with what we sent that the peer has not seen
+    async fn handle_missing_ops(
+        socket: &mut Connection,
+        server: &Server,
+        replication: &ReplicationManager,
+        request: &MissingOps,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(from_vv) = crate::proto::proto_to_missing_ops(request) else {
+            warn!("Failed to decode missing ops request from protobuf");
+            return Ok(());
+        };
+
+        let (ops, complete) = Self::ops_since(server, replication, &from_vv).await;
+        debug!(
+            "Resending {} missing operations (complete={})",
+            ops.len(),
+            complete
+        );
+
+        write_frame(
+            socket,
+            &crate::proto::missing_ops_response_to_proto(
+                &ops,
+                complete,
+                replication.config().packed_vv,
+            ),
+            replication.config().compression,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Recover from a full pending buffer
+    ///
+    /// Works out the causal gap (which actors the buffered operations are waiting on),
+    /// asks each of those peers to resend what we have not seen, and applies it in order.
+    /// If a peer can no longer supply the whole gap, fall back to anti-entropy for the set.
+    fn spawn_recover(server: Arc<Server>, replication: Arc<ReplicationManager>, set_name: String) {
+        tokio::spawn(async move {
+            let local_vv = server.version_vector().read().await.clone();
+            let missing = replication
+                .pending_buffer()
+                .read()
+                .await
+                .missing_actors(&local_vv);
+
+            for actor_id in missing {
+                let (ops, complete) =
+                    match replication.request_missing_ops(actor_id, &local_vv).await {
+                        Ok(Some(response)) => response,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("Missing ops request to {} failed: {}", actor_id, e);
+                            continue;
+                        }
+                    };
+                debug!("Recovered {} operations from {}", ops.len(), actor_id);
+                Self::apply_or_buffer(&server, &replication, ops).await;
+
+                if !complete {
+                    replication.spawn_sync(Arc::clone(&server), actor_id, set_name.clone());
+                }
+            }
+
+            Self::try_apply_buffered(server, replication).await;
+        });
+    }
+
+    /// Pull the operations a peer wrote that we have not seen, which with a `fanout` it
+    /// may not have pushed to us
+    fn spawn_catch_up(server: Arc<Server>, replication: Arc<ReplicationManager>, peer: ActorId) {
+        tokio::spawn(async move {
+            let local_vv = server.version_vector().read().await.clone();
+            match replication.request_missing_ops(peer, &local_vv).await {
+                Ok(Some((ops, complete))) => {
+                    if !complete {
                         warn!(
-                            "Pending buffer is full! Buffer size: {}/{}",
-                            buffer.len(),
-                            buffer.max_size()
+                            "Peer {} no longer holds every operation we missed, anti-entropy is needed to converge",
+                            peer
                         );
-                        // TODO: Consider triggering anti-entropy here
                     }
+                    debug!("Caught up {} operations from {}", ops.len(), peer);
+                    Self::apply_or_buffer(&server, &replication, ops).await;
+                    Self::try_apply_buffered(server, replication).await;
                 }
-                Err(e) => {
-                    error!(
-                        "Storage error applying operation for set={}: {}",
-                        operation.set_name, e
+                // Not a peer, or a request to it is already in flight
+                Ok(None) => {}
+                Err(e) => warn!("Catch-up request to {} failed: {}", peer, e),
+            }
+        });
+    }
+
+    /// Read repair, after a causal read found that a client has seen operations we have not
+    ///
+    /// The client's version vector came from another replica, so each actor it is ahead on
+    /// is asked for what we are missing, and the operations are applied (or buffered) like
+    /// any others. If an actor can't be asked, because it is not a peer, or no longer has
+    /// them all, the sets read are synced with every peer by anti-entropy instead. It runs
+    /// in the background and is best effort: the reader has its NotReady at once.
+    pub(crate) fn spawn_read_repair(
+        server: Arc<Server>,
+        replication: Arc<ReplicationManager>,
+        client_vv: VersionVector,
+        set_names: Vec<String>,
+    ) {
+        replication.metrics().record_read_repair();
+        tokio::spawn(async move {
+            let local_vv = server.version_vector().read().await.clone();
+            let peers = replication.peers().await;
+            let mut anti_entropy = false;
+
+            for (actor_id, _, _) in client_vv.diff(&local_vv) {
+                if !peers.iter().any(|peer| peer.actor_id() == actor_id) {
+                    anti_entropy = true;
+                    continue;
+                }
+                match replication.request_missing_ops(actor_id, &local_vv).await {
+                    Ok(Some((ops, complete))) => {
+                        debug!("Read repair got {} operations from {}", ops.len(), actor_id);
+                        Self::apply_or_buffer(&server, &replication, ops).await;
+                        anti_entropy |= !complete;
+                    }
+                    // A request to it is already in flight
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Read repair request to {} failed: {}", actor_id, e);
+                        anti_entropy = true;
+                    }
+                }
+            }
+            Self::try_apply_buffered(Arc::clone(&server), Arc::clone(&replication)).await;
+
+            if anti_entropy {
+                for peer in &peers {
+                    for set_name in &set_names {
+                        replication.spawn_sync(
+                            Arc::clone(&server),
+                            peer.actor_id(),
+                            set_name.clone(),
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    /// Reply to a digest comparison with the buckets of the set that differ here
+    async fn handle_digest_request(
+        socket: &mut Connection,
+        server: &Server,
+        request: &DigestRequest,
+        compression: Compression,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let bucket_count = request.bucket_digests.len() as u32;
+        let digests = server
+            .bucket_digests(&request.set_name, bucket_count)
+            .await?;
+        let differing_buckets = crate::proto::differing_buckets(request, &digests);
+        debug!(
+            "Digest for set={} differs in {}/{} buckets",
+            request.set_name,
+            differing_buckets.len(),
+            bucket_count
+        );
+
+        write_frame(
+            socket,
+            &crate::proto::replication::DigestResponse {
+                set_name: request.set_name.clone(),
+                differing_buckets,
+            },
+            compression,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Apply a single received operation, acking it, or buffer it until causality allows
+    ///
+    /// Returns true if the operation was applied.
+    async fn handle_operation(
+        socket: &mut Connection,
+        server: &Arc<Server>,
+        replication: &Arc<ReplicationManager>,
+        operation: Operation,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        info!("Received operation for set={}", operation.set_name);
+        replication.mark_seen(operation.dot().actor_id).await;
+
+        // Try to apply operation
+        match server.apply_remote_operation(operation.clone()).await {
+            Ok(true) => {
+                debug!("Applied operation successfully");
+                replication.metrics().record_applied(1);
+                write_frame(
+                    socket,
+                    &crate::proto::ack_to_proto(&operation),
+                    replication.config().compression,
+                )
+                .await?;
+                Ok(true)
+            }
+            Ok(false) => {
+                // Causality not satisfied, buffer it
+                debug!(
+                    "Operation for set={} needs buffering (causality not satisfied)",
+                    operation.set_name
+                );
+                let pending_buffer = replication.pending_buffer();
+                let mut buffer = pending_buffer.write().await;
+                if buffer.contains_dot(operation.dot()) {
+                    // A retransmission of an op we are already holding
+                    return Ok(false);
+                }
+                let set_names: Vec<String> = operation
+                    .set_names()
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+                if buffer.add(operation) {
+                    replication.metrics().record_buffered(1);
+                } else {
+                    warn!(
+                        "Pending buffer is full! Buffer size: {}/{}",
+                        buffer.len(),
+                        buffer.max_size()
                     );
+                    // The op stays unacked at the sender, meanwhile fetch what is holding up the buffer
+                    for set_name in set_names {
+                        Self::spawn_recover(Arc::clone(server), Arc::clone(replication), set_name);
+                    }
                 }
+                Ok(false)
+            }
+            Err(e) => {
+                error!(
+                    "Error applying operation for set={}: {}",
+                    operation.set_name, e
+                );
+                Ok(false)
             }
         }
     }
@@ -10,8 +10,22 @@ pub enum RespError {
     InvalidProtocol,
     #[error("Incomplete")]
     Incomplete,
+    #[error("Protocol error: request larger than {0} bytes")]
+    TooLarge(usize),
 }
 
+/// The protocol a connection speaks, RESP2 until it asks for RESP3 with HELLO
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// A RESP value
+///
+/// `Map`, `Push`, `Double`, `Boolean` and `BigNumber` only exist in RESP3; on a RESP2
+/// connection they are written as the RESP2 values Redis uses in their place.
 #[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     SimpleString(String),
@@ -20,11 +34,37 @@ pub enum RespValue {
     BulkString(Bytes),
     Array(Vec<RespValue>),
     Null,
+    /// RESP2: an array of the keys and values in turn
+    Map(Vec<(RespValue, RespValue)>),
+    /// Out of band data, such as a pub/sub message. RESP2: an array
+    Push(Vec<RespValue>),
+    /// RESP2: a bulk string of the number
+    Double(f64),
+    /// RESP2: the integer 1 or 0
+    Boolean(bool),
+    /// An integer of any size, as its decimal digits. RESP2: a bulk string
+    BigNumber(String),
 }
 
 impl RespValue {
+    /// Take the first value off the front of a connection's read buffer
+    ///
+    /// Bulk strings are slices of the buffer, not copies, so a 10k member SADD allocates
+    /// its array and nothing per member. Each one keeps the whole buffer's allocation alive
+    /// for as long as it is held, so anything kept past the command, such as the members
+    /// of a replicated Operation, should be copied out. Until a whole value has arrived this
+    /// returns `Incomplete` and leaves `buffer` as it was.
+    ///
+    /// The value is measured in place first and only then split off, so the pipelined
+    /// commands behind it stay where they are rather than being copied down each time.
+    pub fn parse_buffer(buffer: &mut BytesMut) -> Result<RespValue, RespError> {
+        let len = value_len(buffer)?;
+        let mut cursor = Cursor::new(buffer.split_to(len).freeze());
+        RespValue::parse(&mut cursor)
+    }
+
     /// Parse RESP value from buffer
-    pub fn parse(buf: &mut Cursor<&[u8]>) -> Result<RespValue, RespError> {
+    pub fn parse(buf: &mut Cursor<Bytes>) -> Result<RespValue, RespError> {
         if !buf.has_remaining() {
             return Err(RespError::Incomplete);
         }
@@ -62,7 +102,8 @@ impl RespValue {
                     return Err(RespError::Incomplete);
                 }
 
-                let data = Bytes::copy_from_slice(&buf.chunk()[..len]);
+                let start = buf.position() as usize;
+                let data = buf.get_ref().slice(start..start + len);
                 buf.advance(len);
 
                 // Expect \r\n
@@ -82,19 +123,67 @@ impl RespValue {
                     return Ok(RespValue::Null);
                 }
 
-                let mut array = Vec::with_capacity(count as usize);
+                Ok(RespValue::Array(parse_values(buf, count)?))
+            }
+            b'>' => {
+                let count = read_count(buf)?;
+                Ok(RespValue::Push(parse_values(buf, count)?))
+            }
+            b'%' => {
+                let count = read_count(buf)?;
+                let mut map = Vec::with_capacity(count.min(1024) as usize);
                 for _ in 0..count {
-                    array.push(RespValue::parse(buf)?);
+                    let key = RespValue::parse(buf)?;
+                    let value = RespValue::parse(buf)?;
+                    map.push((key, value));
                 }
-
-                Ok(RespValue::Array(array))
+                Ok(RespValue::Map(map))
+            }
+            b'_' => {
+                if !read_line(buf)?.is_empty() {
+                    return Err(RespError::InvalidProtocol);
+                }
+                Ok(RespValue::Null)
+            }
+            b'#' => match read_line(buf)?.as_slice() {
+                b"t" => Ok(RespValue::Boolean(true)),
+                b"f" => Ok(RespValue::Boolean(false)),
+                _ => Err(RespError::InvalidProtocol),
+            },
+            b',' => {
+                let line = read_line(buf)?;
+                // Rust spells these differently: "inf" parses, "nan" doesn't
+                let n = match line.as_slice() {
+                    b"nan" => f64::NAN,
+                    line => String::from_utf8_lossy(line)
+                        .parse::<f64>()
+                        .map_err(|_| RespError::InvalidProtocol)?,
+                };
+                Ok(RespValue::Double(n))
+            }
+            b'(' => {
+                let line = read_line(buf)?;
+                let digits = line.strip_prefix(b"-").unwrap_or(&line);
+                if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                    return Err(RespError::InvalidProtocol);
+                }
+                Ok(RespValue::BigNumber(
+                    String::from_utf8_lossy(&line).to_string(),
+                ))
             }
             _ => Err(RespError::InvalidProtocol),
         }
     }
 
-    /// Serialize RESP value to buffer
-    pub fn serialize(&self, buf: &mut BytesMut) {
+    /// The header of an array of `len` values, for writing the values after it one by one
+    pub fn serialize_array_header(len: usize, buf: &mut BytesMut) {
+        buf.put_u8(b'*');
+        buf.put(len.to_string().as_bytes());
+        buf.put(&b"\r\n"[..]);
+    }
+
+    /// Serialize RESP value to buffer, in RESP2 encodings unless `protocol` is RESP3
+    pub fn serialize(&self, protocol: Protocol, buf: &mut BytesMut) {
         match self {
             RespValue::SimpleString(s) => {
                 buf.put_u8(b'+');
@@ -119,16 +208,65 @@ impl RespValue {
                 buf.put(&b"\r\n"[..]);
             }
             RespValue::Array(arr) => {
-                buf.put_u8(b'*');
-                buf.put(arr.len().to_string().as_bytes());
-                buf.put(&b"\r\n"[..]);
+                Self::serialize_array_header(arr.len(), buf);
                 for val in arr {
-                    val.serialize(buf);
+                    val.serialize(protocol, buf);
+                }
+            }
+            RespValue::Null => match protocol {
+                Protocol::Resp2 => buf.put(&b"$-1\r\n"[..]),
+                Protocol::Resp3 => buf.put(&b"_\r\n"[..]),
+            },
+            RespValue::Map(map) => {
+                match protocol {
+                    Protocol::Resp2 => Self::serialize_array_header(map.len() * 2, buf),
+                    Protocol::Resp3 => put_header(b'%', map.len(), buf),
+                }
+                for (key, value) in map {
+                    key.serialize(protocol, buf);
+                    value.serialize(protocol, buf);
                 }
             }
-            RespValue::Null => {
-                buf.put(&b"$-1\r\n"[..]);
+            RespValue::Push(values) => {
+                match protocol {
+                    Protocol::Resp2 => Self::serialize_array_header(values.len(), buf),
+                    Protocol::Resp3 => put_header(b'>', values.len(), buf),
+                }
+                for value in values {
+                    value.serialize(protocol, buf);
+                }
+            }
+            RespValue::Double(n) => {
+                let n = if n.is_nan() {
+                    "nan".to_string()
+                } else {
+                    n.to_string()
+                };
+                match protocol {
+                    Protocol::Resp2 => {
+                        RespValue::BulkString(Bytes::from(n)).serialize(protocol, buf)
+                    }
+                    Protocol::Resp3 => {
+                        buf.put_u8(b',');
+                        buf.put(n.as_bytes());
+                        buf.put(&b"\r\n"[..]);
+                    }
+                }
             }
+            RespValue::Boolean(b) => match protocol {
+                Protocol::Resp2 => RespValue::Integer(*b as i64).serialize(protocol, buf),
+                Protocol::Resp3 => buf.put(if *b { &b"#t\r\n"[..] } else { &b"#f\r\n"[..] }),
+            },
+            RespValue::BigNumber(digits) => match protocol {
+                Protocol::Resp2 => {
+                    RespValue::BulkString(Bytes::from(digits.clone())).serialize(protocol, buf)
+                }
+                Protocol::Resp3 => {
+                    buf.put_u8(b'(');
+                    buf.put(digits.as_bytes());
+                    buf.put(&b"\r\n"[..]);
+                }
+            },
         }
     }
 
@@ -150,19 +288,111 @@ impl RespValue {
     }
 }
 
-fn read_line(buf: &mut Cursor<&[u8]>) -> Result<Vec<u8>, RespError> {
+/// The length line of an aggregate, which for the RESP3 ones can't be -1
+fn read_count(buf: &mut Cursor<Bytes>) -> Result<i64, RespError> {
+    let line = read_line(buf)?;
+    String::from_utf8_lossy(&line)
+        .parse::<i64>()
+        .ok()
+        .filter(|count| *count >= 0)
+        .ok_or(RespError::InvalidProtocol)
+}
+
+fn parse_values(buf: &mut Cursor<Bytes>, count: i64) -> Result<Vec<RespValue>, RespError> {
+    // A count from the client isn't trusted to size the allocation
+    let mut values = Vec::with_capacity(count.clamp(0, 1024) as usize);
+    for _ in 0..count {
+        values.push(RespValue::parse(buf)?);
+    }
+    Ok(values)
+}
+
+fn put_header(kind: u8, len: usize, buf: &mut BytesMut) {
+    buf.put_u8(kind);
+    buf.put(len.to_string().as_bytes());
+    buf.put(&b"\r\n"[..]);
+}
+
+/// How many bytes the value at the front of `buf` takes, without building it
+///
+/// Only the lengths are checked; whatever else is wrong with the value `parse` finds.
+fn value_len(buf: &[u8]) -> Result<usize, RespError> {
+    let mut cursor = Cursor::new(buf);
+    skip_value(&mut cursor)?;
+    Ok(cursor.position() as usize)
+}
+
+fn skip_value(buf: &mut Cursor<&[u8]>) -> Result<(), RespError> {
+    if !buf.has_remaining() {
+        return Err(RespError::Incomplete);
+    }
+
+    let kind = buf.get_u8();
+    let line = skip_line(buf)?;
+    let len = || {
+        std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.parse::<i64>().ok())
+            .ok_or(RespError::InvalidProtocol)
+    };
+    match kind {
+        b'$' => match len()? {
+            -1 => {}
+            len => {
+                let len = usize::try_from(len).map_err(|_| RespError::InvalidProtocol)?;
+                if buf.remaining() < len.saturating_add(2) {
+                    return Err(RespError::Incomplete);
+                }
+                buf.advance(len + 2);
+            }
+        },
+        b'*' | b'>' => {
+            for _ in 0..len()? {
+                skip_value(buf)?;
+            }
+        }
+        b'%' => {
+            for _ in 0..len()?.saturating_mul(2) {
+                skip_value(buf)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `read_line` without the copy
+fn skip_line<'a>(buf: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], RespError> {
     let start = buf.position() as usize;
     let slice = &buf.get_ref()[start..];
-
-    for i in 0..slice.len() - 1 {
-        if slice[i] == b'\r' && slice[i + 1] == b'\n' {
-            let line = slice[..i].to_vec();
-            buf.advance(i + 2);
-            return Ok(line);
+    let mut i = 0;
+    loop {
+        i += slice[i..]
+            .iter()
+            .position(|&b| b == b'\r')
+            .ok_or(RespError::Incomplete)?;
+        match slice.get(i + 1) {
+            Some(b'\n') => break,
+            Some(_) => i += 1,
+            None => return Err(RespError::Incomplete),
         }
     }
+    buf.advance(i + 2);
+    Ok(&slice[..i])
+}
+
+fn read_line(buf: &mut Cursor<Bytes>) -> Result<Vec<u8>, RespError> {
+    let start = buf.position() as usize;
+    let slice = &buf.get_ref()[start..];
 
-    Err(RespError::Incomplete)
+    // A read can end straight after the type byte, leaving nothing of the line yet
+    let i = slice
+        .windows(2)
+        .position(|pair| pair == b"\r\n")
+        .ok_or(RespError::Incomplete)?;
+    let line = slice[..i].to_vec();
+    buf.advance(i + 2);
+    Ok(line)
 }
 
 #[cfg(test)]
@@ -171,28 +401,28 @@ mod tests {
 
     #[test]
     fn test_parse_simple_string() {
-        let mut buf = Cursor::new(b"+OK\r\n".as_ref());
+        let mut buf = Cursor::new(Bytes::from_static(b"+OK\r\n"));
         let val = RespValue::parse(&mut buf).unwrap();
         assert_eq!(val, RespValue::SimpleString("OK".to_string()));
     }
 
     #[test]
     fn test_parse_integer() {
-        let mut buf = Cursor::new(b":42\r\n".as_ref());
+        let mut buf = Cursor::new(Bytes::from_static(b":42\r\n"));
         let val = RespValue::parse(&mut buf).unwrap();
         assert_eq!(val, RespValue::Integer(42));
     }
 
     #[test]
     fn test_parse_bulk_string() {
-        let mut buf = Cursor::new(b"$5\r\nhello\r\n".as_ref());
+        let mut buf = Cursor::new(Bytes::from_static(b"$5\r\nhello\r\n"));
         let val = RespValue::parse(&mut buf).unwrap();
         assert_eq!(val, RespValue::BulkString(Bytes::from("hello")));
     }
 
     #[test]
     fn test_parse_array() {
-        let mut buf = Cursor::new(b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n".as_ref());
+        let mut buf = Cursor::new(Bytes::from_static(b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n"));
         let val = RespValue::parse(&mut buf).unwrap();
         assert_eq!(
             val,
@@ -207,7 +437,204 @@ mod tests {
     fn test_serialize() {
         let val = RespValue::SimpleString("OK".to_string());
         let mut buf = BytesMut::new();
-        val.serialize(&mut buf);
+        val.serialize(Protocol::Resp2, &mut buf);
         assert_eq!(&buf[..], b"+OK\r\n");
     }
+
+    /// Serialize as RESP3, check the encoding and that it parses back to the same value
+    fn assert_round_trip(val: RespValue, encoded: &[u8]) {
+        let mut buf = BytesMut::new();
+        val.serialize(Protocol::Resp3, &mut buf);
+        assert_eq!(&buf[..], encoded, "{:?}", String::from_utf8_lossy(&buf));
+        let mut cursor = Cursor::new(buf.clone().freeze());
+        assert_eq!(RespValue::parse(&mut cursor).unwrap(), val);
+        assert_eq!(cursor.position() as usize, buf.len());
+    }
+
+    #[test]
+    fn test_round_trip_map() {
+        assert_round_trip(
+            RespValue::Map(vec![
+                (
+                    RespValue::BulkString(Bytes::from("proto")),
+                    RespValue::Integer(3),
+                ),
+                (
+                    RespValue::SimpleString("empty".to_string()),
+                    RespValue::Map(vec![]),
+                ),
+            ]),
+            b"%2\r\n$5\r\nproto\r\n:3\r\n+empty\r\n%0\r\n",
+        );
+    }
+
+    #[test]
+    fn test_round_trip_push() {
+        assert_round_trip(
+            RespValue::Push(vec![
+                RespValue::BulkString(Bytes::from("message")),
+                RespValue::Null,
+            ]),
+            b">2\r\n$7\r\nmessage\r\n_\r\n",
+        );
+    }
+
+    #[test]
+    fn test_round_trip_double() {
+        assert_round_trip(RespValue::Double(1.5), b",1.5\r\n");
+        assert_round_trip(RespValue::Double(-3.0), b",-3\r\n");
+        assert_round_trip(RespValue::Double(f64::INFINITY), b",inf\r\n");
+        assert_round_trip(RespValue::Double(f64::NEG_INFINITY), b",-inf\r\n");
+
+        // NaN isn't equal to itself
+        let mut buf = BytesMut::new();
+        RespValue::Double(f64::NAN).serialize(Protocol::Resp3, &mut buf);
+        assert_eq!(&buf[..], b",nan\r\n");
+        match RespValue::parse(&mut Cursor::new(buf.freeze())).unwrap() {
+            RespValue::Double(n) => assert!(n.is_nan()),
+            other => panic!("expected a double, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_boolean() {
+        assert_round_trip(RespValue::Boolean(true), b"#t\r\n");
+        assert_round_trip(RespValue::Boolean(false), b"#f\r\n");
+    }
+
+    #[test]
+    fn test_round_trip_big_number() {
+        assert_round_trip(
+            RespValue::BigNumber("3492890328409238509324850943850943825024385".to_string()),
+            b"(3492890328409238509324850943850943825024385\r\n",
+        );
+        assert_round_trip(RespValue::BigNumber("-12".to_string()), b"(-12\r\n");
+    }
+
+    #[test]
+    fn test_round_trip_null() {
+        assert_round_trip(RespValue::Null, b"_\r\n");
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_resp3() {
+        for encoded in [
+            &b"#x\r\n"[..],
+            b",one\r\n",
+            b"(12a\r\n",
+            b"(\r\n",
+            b"_x\r\n",
+            b"%-1\r\n",
+            b">-1\r\n",
+        ] {
+            assert!(
+                matches!(
+                    RespValue::parse(&mut Cursor::new(Bytes::from_static(encoded))),
+                    Err(RespError::InvalidProtocol)
+                ),
+                "{:?}",
+                String::from_utf8_lossy(encoded)
+            );
+        }
+    }
+
+    #[test]
+    fn test_serialize_resp3_types_as_resp2() {
+        let val = RespValue::Array(vec![
+            RespValue::Map(vec![(
+                RespValue::BulkString(Bytes::from("a")),
+                RespValue::Boolean(true),
+            )]),
+            RespValue::Push(vec![RespValue::Boolean(false)]),
+            RespValue::Double(2.5),
+            RespValue::BigNumber("12345678901234567890".to_string()),
+            RespValue::Null,
+        ]);
+        let mut buf = BytesMut::new();
+        val.serialize(Protocol::Resp2, &mut buf);
+        assert_eq!(
+            &buf[..],
+            &b"*5\r\n*2\r\n$1\r\na\r\n:1\r\n*1\r\n:0\r\n$3\r\n2.5\r\n\
+               $20\r\n12345678901234567890\r\n$-1\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_parse_buffer_slices_bulk_strings_out_of_the_buffer() {
+        let members: Vec<RespValue> = (0..10_000)
+            .map(|i| RespValue::BulkString(Bytes::from(format!("member-{}", i))))
+            .collect();
+        let sadd = RespValue::Array(
+            [
+                RespValue::BulkString(Bytes::from("SADD")),
+                RespValue::BulkString(Bytes::from("s")),
+            ]
+            .into_iter()
+            .chain(members)
+            .collect(),
+        );
+        let mut encoded = BytesMut::new();
+        sadd.serialize(Protocol::Resp2, &mut encoded);
+
+        // It arrives in two reads, with a pipelined PING behind it
+        let split = encoded.len() / 2;
+        let mut buffer = BytesMut::new();
+        for read in [&encoded[..1], &encoded[1..split]] {
+            buffer.extend_from_slice(read);
+            assert!(matches!(
+                RespValue::parse_buffer(&mut buffer),
+                Err(RespError::Incomplete)
+            ));
+        }
+        assert_eq!(&buffer[..], &encoded[..split]);
+        buffer.extend_from_slice(&encoded[split..]);
+        buffer.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+        let start = buffer.as_ptr() as usize;
+        let end = start + buffer.len();
+
+        let value = RespValue::parse_buffer(&mut buffer).unwrap();
+        assert_eq!(value, sadd);
+        // Every member points into the read buffer rather than a copy of its own
+        for part in value.as_bulk_string_array().unwrap() {
+            let at = part.as_ptr() as usize;
+            assert!(start <= at && at + part.len() <= end);
+        }
+        assert_eq!(&buffer[..], b"*1\r\n$4\r\nPING\r\n");
+        assert_eq!(
+            RespValue::parse_buffer(&mut buffer).unwrap(),
+            RespValue::Array(vec![RespValue::BulkString(Bytes::from("PING"))])
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_parse_buffer_leaves_pipelined_commands_in_place() {
+        let ping = b"*1\r\n$4\r\nPING\r\n";
+        let mut buffer = BytesMut::new();
+        for _ in 0..1000 {
+            buffer.extend_from_slice(ping);
+        }
+
+        // Each command comes off the front, and what is behind it isn't moved
+        for left in (0..1000).rev() {
+            let next = buffer.as_ptr() as usize + ping.len();
+            assert_eq!(
+                RespValue::parse_buffer(&mut buffer).unwrap(),
+                RespValue::Array(vec![RespValue::BulkString(Bytes::from("PING"))])
+            );
+            assert_eq!(buffer.len(), left * ping.len());
+            if left > 0 {
+                assert_eq!(buffer.as_ptr() as usize, next);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_buffer_rejects_a_negative_bulk_length() {
+        let mut buffer = BytesMut::from(&b"*1\r\n$-2\r\nab\r\n"[..]);
+        assert!(matches!(
+            RespValue::parse_buffer(&mut buffer),
+            Err(RespError::InvalidProtocol)
+        ));
+    }
 }
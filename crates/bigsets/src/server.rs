@@ -1,18 +1,34 @@
+use crate::config::{
+    StorageConfig, default_max_element_size, default_max_key_length,
+    default_max_members_per_command,
+};
+use crate::error::{BigsetError, Result};
 use crate::{
     SqliteStorage,
-    types::{ActorId, OpType, Operation, VersionVector},
+    storage::{Checkpoint, Digest, Storage, WriteKind, now_ms, read_snapshot},
+    telemetry,
+    types::{
+        ActorId, Dot, ElementDots, OpType, Operation, SetAdd, SetDelta, SetInfo, SetSnapshot,
+        VersionVector,
+    },
+    writer::{LocalWrite, Writer},
 };
 use bytes::Bytes;
-use rusqlite::Result;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, trace};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{RwLock, mpsc, oneshot};
+use tracing::{Instrument, debug, error, info, info_span, trace};
 
 /// Result type for command execution
 #[derive(Debug, Clone, PartialEq)]
 pub enum CommandResult {
-    /// OK with optional version vector
-    Ok { vv: Option<VersionVector> },
+    /// OK with optional version vector, shared by the writes committed together
+    Ok { vv: Option<Arc<VersionVector>> },
     /// Integer result
     Integer(i64),
     /// Boolean array for multi-membership
@@ -23,6 +39,64 @@ pub enum CommandResult {
     Error(String),
     /// Not ready to serve read (with current VV)
     NotReady(VersionVector),
+    /// A write committed here, but acked by fewer peers than synchronous replication needs
+    Degraded {
+        vv: Option<Arc<VersionVector>>,
+        acked: usize,
+        needed: usize,
+    },
+}
+
+/// Members per batch when streaming SMEMBERS
+pub(crate) const MEMBERS_BATCH: usize = 1000;
+
+/// The members of a set, read from storage as they are taken, see `Server::smembers_stream`
+#[derive(Debug)]
+pub struct MembersStream {
+    /// How many members the batches add up to
+    pub len: u64,
+    batches: mpsc::Receiver<Result<Vec<Bytes>>>,
+}
+
+impl MembersStream {
+    /// The next batch of members, None once they have all been taken
+    pub async fn next_batch(&mut self) -> Option<Result<Vec<Bytes>>> {
+        self.batches.recv().await
+    }
+}
+
+/// Bounds on what clients can write, from the `[storage]` config
+///
+/// Only writes from clients are checked: a remote operation was checked by the replica it
+/// came from, and refusing it here would leave the replicas diverged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest set name, in bytes
+    pub max_key_length: usize,
+    /// Largest member an add can write, in bytes
+    pub max_element_size: usize,
+    /// Most members one add or remove can name
+    pub max_members_per_command: usize,
+}
+
+impl Limits {
+    pub fn new(config: &StorageConfig) -> Self {
+        Self {
+            max_key_length: config.max_key_length,
+            max_element_size: config.max_element_size,
+            max_members_per_command: config.max_members_per_command,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key_length: default_max_key_length(),
+            max_element_size: default_max_element_size(),
+            max_members_per_command: default_max_members_per_command(),
+        }
+    }
 }
 
 /// Core server containing business logic for CRDT operations
@@ -30,24 +104,64 @@ pub enum CommandResult {
 /// This is the heart of the system - manages version vectors, causality,
 /// and coordinates with storage. Generic over Storage to allow testing
 /// with different backends.
-#[derive(Clone, Debug)]
-pub struct Server {
+#[derive(Debug)]
+pub struct Server<S: Storage = SqliteStorage> {
     actor_id: ActorId,
-    storage: Arc<SqliteStorage>,
+    storage: Arc<S>,
+    /// Every storage write goes through the writer, which owns the authoritative version vector
+    writer: Writer<S>,
+    /// The version vector as of the last committed write, published by the writer
     version_vector: Arc<RwLock<VersionVector>>,
+    /// False while startup learning (RBILT) is filling causal gaps; reads are refused meanwhile
+    ready: Arc<AtomicBool>,
+    /// What every replica is known to have seen, maintained by the ReplicationManager
+    stable_vv: Arc<RwLock<VersionVector>>,
+    limits: Limits,
+}
+
+// Not derived, that would needlessly require the storage itself to be Clone
+impl<S: Storage> Clone for Server<S> {
+    fn clone(&self) -> Self {
+        Self {
+            actor_id: self.actor_id,
+            storage: Arc::clone(&self.storage),
+            writer: self.writer.clone(),
+            version_vector: Arc::clone(&self.version_vector),
+            ready: Arc::clone(&self.ready),
+            stable_vv: Arc::clone(&self.stable_vv),
+            limits: self.limits,
+        }
+    }
 }
 
-impl Server {
-    pub async fn new(actor_id: ActorId, storage: Arc<SqliteStorage>) -> Result<Self> {
+impl<S: Storage> Server<S> {
+    pub async fn new(actor_id: ActorId, storage: Arc<S>) -> Result<Self> {
         let vv = storage.load_vv()?;
+        let version_vector = Arc::new(RwLock::new(vv.clone()));
+        let writer = Writer::spawn(
+            Arc::clone(&storage),
+            actor_id,
+            vv,
+            Arc::clone(&version_vector),
+        )?;
 
         Ok(Self {
             actor_id,
             storage,
-            version_vector: Arc::new(RwLock::new(vv)),
+            writer,
+            version_vector,
+            ready: Arc::new(AtomicBool::new(true)),
+            stable_vv: Arc::new(RwLock::new(VersionVector::new())),
+            limits: Limits::default(),
         })
     }
 
+    /// Check client writes against `limits`, rather than the defaults
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Add members to a set
     ///
     /// Returns both the command result and an optional operation for replication.
@@ -65,22 +179,31 @@ impl Server {
                 None,
             ));
         }
+        if let Some(over) = self.over_limits(set_name, members, true) {
+            return Ok((over, None));
+        }
 
-        let context = self.version_vector.read().await.clone();
-
-        let mut vv = self.version_vector.write().await;
-        let dot = vv.increment(self.actor_id);
         trace!("calling storage for SADD");
-        let rem_dots = self.storage.add_elements(set_name, members, dot)?;
+        let LocalWrite {
+            context,
+            dot,
+            result: rem_dots,
+            vv,
+        } = self
+            .writer
+            .grouped(WriteKind::Add, set_name.to_string(), members.to_vec())
+            .await?;
 
         let operation = Operation {
             set_name: set_name.to_string(),
             op_type: OpType::Add {
-                elements: members.to_vec(),
+                elements: copied(members),
                 dot,
-                removed_dots: rem_dots,
+                removed_dots: self.unstable(rem_dots).await,
+                expires_at: None,
             },
             context,
+            trace_context: telemetry::current_context(),
         };
 
         debug!(
@@ -91,12 +214,220 @@ impl Server {
             dot
         );
 
-        Ok((
-            CommandResult::Ok {
-                vv: Some(vv.clone()),
+        self.log_ops(std::slice::from_ref(&operation)).await;
+        Ok((CommandResult::Ok { vv: Some(vv) }, Some(operation)))
+    }
+
+    /// Add members to a set that expire `ttl` from now (SADDEX)
+    ///
+    /// As `sadd`, but the new dot carries its expiry time, and replicates with it. A member
+    /// drops out of reads once every dot supporting it has expired, and `expire` then
+    /// removes it.
+    pub async fn saddex(
+        &self,
+        set_name: &str,
+        members: &[Bytes],
+        ttl: Duration,
+    ) -> Result<(CommandResult, Option<Operation>)> {
+        if members.is_empty() {
+            return Ok((
+                CommandResult::Error(
+                    "ERR wrong number of arguments for 'saddex' command".to_string(),
+                ),
+                None,
+            ));
+        }
+        if let Some(over) = self.over_limits(set_name, members, true) {
+            return Ok((over, None));
+        }
+
+        let expires_at = now_ms().saturating_add(ttl.as_millis() as u64);
+        let (name, elements) = (set_name.to_string(), members.to_vec());
+        let LocalWrite {
+            context,
+            dot,
+            result: rem_dots,
+            vv,
+        } = self
+            .writer
+            .local(move |storage, dot| {
+                storage.add_elements_expiring(&name, &elements, dot, expires_at)
+            })
+            .await?;
+
+        let operation = Operation {
+            set_name: set_name.to_string(),
+            op_type: OpType::Add {
+                elements: copied(members),
+                dot,
+                removed_dots: self.unstable(rem_dots).await,
+                expires_at: Some(expires_at),
+            },
+            context,
+            trace_context: telemetry::current_context(),
+        };
+
+        debug!(
+            "{}: SADDEX {} added {} members expiring at {} with dot {:?}",
+            self.actor_id,
+            set_name,
+            members.len(),
+            expires_at,
+            dot
+        );
+
+        self.log_ops(std::slice::from_ref(&operation)).await;
+        Ok((CommandResult::Ok { vv: Some(vv) }, Some(operation)))
+    }
+
+    /// Add only the members not already in the set (SADDNX)
+    ///
+    /// Membership is checked on the writer, so no local write lands between the check and
+    /// the add. The result is how many members were added; when that's none, no dot is
+    /// taken and nothing replicates. "Not already present" is as far as this node knows:
+    /// another node may add the same member concurrently, and both adds survive.
+    pub async fn saddnx(
+        &self,
+        set_name: &str,
+        members: &[Bytes],
+    ) -> Result<(CommandResult, Option<Operation>)> {
+        if members.is_empty() {
+            return Ok((
+                CommandResult::Error(
+                    "ERR wrong number of arguments for 'saddnx' command".to_string(),
+                ),
+                None,
+            ));
+        }
+        if let Some(over) = self.over_limits(set_name, members, true) {
+            return Ok((over, None));
+        }
+
+        let (name, elements, actor_id) = (set_name.to_string(), members.to_vec(), self.actor_id);
+        let added = self
+            .writer
+            .run(move |storage, vv| {
+                let present = storage.are_members(&name, &elements)?;
+                let mut seen = HashSet::new();
+                let absent: Vec<Bytes> = elements
+                    .into_iter()
+                    .zip(present)
+                    .filter(|(element, present)| !present && seen.insert(element.clone()))
+                    .map(|(element, _)| element)
+                    .collect();
+                if absent.is_empty() {
+                    return Ok(None);
+                }
+                let context = Arc::new(vv.clone());
+                let dot = vv.increment(actor_id);
+                let rem_dots = storage.add_elements(&name, &absent, dot)?;
+                Ok(Some((absent, context, dot, rem_dots)))
+            })
+            .await?;
+
+        let Some((added, context, dot, rem_dots)) = added else {
+            debug!("{}: SADDNX {} added nothing", self.actor_id, set_name);
+            return Ok((CommandResult::Integer(0), None));
+        };
+        debug!(
+            "{}: SADDNX {} added {} members with dot {:?}",
+            self.actor_id,
+            set_name,
+            added.len(),
+            dot
+        );
+
+        let count = added.len() as i64;
+        let operation = Operation {
+            set_name: set_name.to_string(),
+            op_type: OpType::Add {
+                elements: copied(&added),
+                dot,
+                removed_dots: self.unstable(rem_dots).await,
+                expires_at: None,
             },
-            Some(operation),
-        ))
+            context,
+            trace_context: telemetry::current_context(),
+        };
+
+        self.log_ops(std::slice::from_ref(&operation)).await;
+        Ok((CommandResult::Integer(count), Some(operation)))
+    }
+
+    /// Add members to many sets at once, for bulk loading
+    ///
+    /// Every set shares a single dot, so the storage can write them in one transaction
+    /// and replicate them as one `OpType::AddMulti`. Sets with no members are skipped.
+    pub async fn sadd_multi(
+        &self,
+        sets: &[(&str, &[Bytes])],
+    ) -> Result<(CommandResult, Option<Operation>)> {
+        let sets: Vec<(&str, &[Bytes])> = sets
+            .iter()
+            .filter(|(_, members)| !members.is_empty())
+            .copied()
+            .collect();
+        if sets.is_empty() {
+            return Ok((
+                CommandResult::Error(
+                    "ERR wrong number of arguments for 'sadd' command".to_string(),
+                ),
+                None,
+            ));
+        }
+        let member_count = sets.iter().map(|(_, members)| members.len()).sum();
+        if let Some(over) = self.too_many_members(member_count).or_else(|| {
+            sets.iter()
+                .find_map(|(set_name, members)| self.over_limits(set_name, members, true))
+        }) {
+            return Ok((over, None));
+        }
+
+        let owned: Vec<(String, Vec<Bytes>)> = sets
+            .iter()
+            .map(|(set_name, members)| (set_name.to_string(), members.to_vec()))
+            .collect();
+        let LocalWrite {
+            context,
+            dot,
+            result: rem_dots,
+            vv,
+        } = self
+            .writer
+            .local(move |storage, dot| {
+                let sets: Vec<(&str, &[Bytes])> = owned
+                    .iter()
+                    .map(|(set_name, members)| (set_name.as_str(), members.as_slice()))
+                    .collect();
+                storage.add_elements_multi(&sets, dot)
+            })
+            .await?;
+
+        let mut adds = Vec::with_capacity(sets.len());
+        for ((set_name, members), removed_dots) in sets.iter().zip(rem_dots) {
+            adds.push(SetAdd {
+                set_name: set_name.to_string(),
+                elements: copied(members),
+                removed_dots: self.unstable(removed_dots).await,
+            });
+        }
+
+        debug!(
+            "{}: SADD to {} sets with dot {:?}",
+            self.actor_id,
+            adds.len(),
+            dot
+        );
+
+        let operation = Operation {
+            set_name: String::new(),
+            op_type: OpType::AddMulti { sets: adds, dot },
+            context,
+            trace_context: telemetry::current_context(),
+        };
+
+        self.log_ops(std::slice::from_ref(&operation)).await;
+        Ok((CommandResult::Ok { vv: Some(vv) }, Some(operation)))
     }
 
     /// Remove members from a set
@@ -115,25 +446,33 @@ impl Server {
                 None,
             ));
         }
+        if let Some(over) = self.over_limits(set_name, members, false) {
+            return Ok((over, None));
+        }
 
-        let context = self.version_vector.read().await.clone();
-
-        let mut vv = self.version_vector.write().await;
-        let dot = vv.increment(self.actor_id);
-
-        let rem_dots = self.storage.remove_elements(set_name, members, dot)?;
+        let LocalWrite {
+            context,
+            dot,
+            result: rem_dots,
+            vv,
+        } = self
+            .writer
+            .grouped(WriteKind::Remove, set_name.to_string(), members.to_vec())
+            .await?;
 
         // 4. Create operation for replication
         let operation = if !rem_dots.is_empty() {
             let operation = Operation {
                 set_name: set_name.to_string(),
                 op_type: OpType::Remove {
-                    elements: members.to_vec(),
+                    elements: copied(members),
                     dot,
-                    removed_dots: rem_dots,
+                    removed_dots: self.unstable(rem_dots).await,
                 },
                 context,
+                trace_context: telemetry::current_context(),
             };
+            self.log_ops(std::slice::from_ref(&operation)).await;
             Some(operation)
         } else {
             // no-op
@@ -149,15 +488,95 @@ impl Server {
         );
 
         // 5. Return both result and operation
-        Ok((
-            CommandResult::Ok {
-                vv: Some(vv.clone()),
-            },
-            operation,
-        ))
+        Ok((CommandResult::Ok { vv: Some(vv) }, operation))
+    }
+
+    /// Remove up to `max_members_per_command` elements whose every dot has expired
+    ///
+    /// Each set's are removed as SREM would remove them, under a dot of their own, and the
+    /// operations returned replicate the removes. Expired elements are looked for on the
+    /// writer, so an add that lands first keeps its element: add wins.
+    pub async fn expire(&self) -> Result<Vec<Operation>> {
+        let (now, limit, actor_id) = (now_ms(), self.limits.max_members_per_command, self.actor_id);
+        let removes = self
+            .writer
+            .run(move |storage, vv| {
+                let mut removes = Vec::new();
+                for (set_name, elements) in storage.expired_elements(now, limit)? {
+                    // The dot is only taken once the remove has committed, so a failed one leaves no gap
+                    let context = Arc::new(vv.clone());
+                    let dot = Dot::new(actor_id, vv.get(actor_id) + 1);
+                    match storage.remove_elements(&set_name, &elements, dot) {
+                        Ok(rem_dots) => {
+                            vv.update(actor_id, dot.counter);
+                            removes.push((set_name, elements, context, dot, rem_dots));
+                        }
+                        Err(e) if removes.is_empty() => return Err(e),
+                        Err(e) => {
+                            error!("Storage error removing expired elements: {}", e);
+                            break;
+                        }
+                    }
+                }
+                Ok(removes)
+            })
+            .await?;
+
+        let mut operations = Vec::with_capacity(removes.len());
+        for (set_name, elements, context, dot, rem_dots) in removes {
+            debug!(
+                "{}: {} members of {} expired, removed with dot {:?}",
+                self.actor_id,
+                elements.len(),
+                set_name,
+                dot
+            );
+            operations.push(Operation {
+                set_name,
+                op_type: OpType::Remove {
+                    elements,
+                    dot,
+                    removed_dots: self.unstable(rem_dots).await,
+                },
+                context,
+                trace_context: telemetry::current_context(),
+            });
+        }
+        self.log_ops(&operations).await;
+        Ok(operations)
     }
 
-    /// Get cardinality of a set
+    /// Record local operations in the storage's operation log, if it keeps one
+    ///
+    /// The write itself has committed by now, so a failure to log it is only reported: the
+    /// log then lacks the operation, and a peer missing it learns it by anti-entropy.
+    async fn log_ops(&self, ops: &[Operation]) {
+        if !self.storage.logs_ops() || ops.is_empty() {
+            return;
+        }
+        let ops = ops.to_vec();
+        if let Err(e) = self
+            .writer
+            .run(move |storage, _| storage.append_ops(&ops))
+            .await
+        {
+            error!("Storage error logging operations: {}", e);
+        }
+    }
+
+    /// Operations the storage's operation log holds that `vv` has not seen, in causal order,
+    /// with a flag that is false if it does not hold them all; None if no log is kept
+    pub async fn ops_since(&self, vv: &VersionVector) -> Result<Option<(Vec<Operation>, bool)>> {
+        if !self.storage.logs_ops() {
+            return Ok(None);
+        }
+        let vv = vv.clone();
+        self.blocking(move |storage| storage.ops_since(&vv))
+            .await
+            .map(Some)
+    }
+
+    /// Get cardinality of a set: its elements with a surviving dot, see `Storage::count_elements`
     ///
     /// Checks causality if client provides a version vector.
     pub async fn scard(
@@ -165,36 +584,224 @@ impl Server {
         set_name: &str,
         client_vv: Option<&VersionVector>,
     ) -> Result<CommandResult> {
+        if let Some(loading) = self.loading() {
+            return Ok(loading);
+        }
+
         // Check causality
-        let local_vv = self.version_vector.read().await;
-        if let Some(cv) = client_vv {
-            if !local_vv.descends(cv) {
-                return Ok(CommandResult::NotReady(local_vv.clone()));
-            }
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(not_ready);
+        }
+
+        let set_name = set_name.to_string();
+        let count = self
+            .blocking(move |storage| storage.count_elements(&set_name))
+            .await?;
+        Ok(CommandResult::Integer(count))
+    }
+
+    /// How many members the sets have in common (SINTERCARD), counting no further than
+    /// `limit` when there is one
+    pub async fn sintercard(
+        &self,
+        set_names: &[String],
+        limit: Option<u64>,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        if let Some(loading) = self.loading() {
+            return Ok(loading);
+        }
+
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(not_ready);
         }
 
-        let count = self.storage.count_elements(set_name)?;
+        let set_names = set_names.to_vec();
+        let count = self
+            .blocking(move |storage| storage.intersection_count(&set_names, limit))
+            .await?;
         Ok(CommandResult::Integer(count as i64))
     }
 
+    /// The actors with dots in a set (SACTORS): the nodes whose adds its members came
+    /// from. Err with the CommandResult when the read is refused.
+    pub async fn set_actors(
+        &self,
+        set_name: &str,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<std::result::Result<Vec<ActorId>, CommandResult>> {
+        if let Some(loading) = self.loading() {
+            return Ok(Err(loading));
+        }
+
+        // Check causality
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(Err(not_ready));
+        }
+
+        let set_name = set_name.to_string();
+        let actors = self
+            .blocking(move |storage| storage.set_actors(&set_name))
+            .await?;
+        Ok(Ok(actors))
+    }
+
+    /// The dots stored for a set's element (SDOTS), for debugging convergence: which
+    /// actors' adds keep it in the set. Empty for an element the set doesn't have.
+    pub async fn element_dots(&self, set_name: &str, element: &Bytes) -> Result<Vec<Dot>> {
+        let (set_name, element) = (set_name.to_string(), element.clone());
+        self.blocking(move |storage| storage.element_dots(&set_name, &element))
+            .await
+    }
+
+    /// The version vector of a set: for each actor, its newest dot supporting one of the
+    /// set's elements, in `CommandResult::Ok`
+    ///
+    /// A replica whose version vector descends it has seen every add behind the set's
+    /// current elements, so it is a causal context for reads of the set that holds back
+    /// fewer of them than the whole version vector would. A remove leaves no dot, so it
+    /// isn't covered: a client that needs to read its removes uses the vv the SREM returned.
+    pub async fn set_vv(&self, set_name: &str) -> Result<CommandResult> {
+        if let Some(loading) = self.loading() {
+            return Ok(loading);
+        }
+
+        let set_name = set_name.to_string();
+        let vv = self
+            .blocking(move |storage| storage.set_vv(&set_name))
+            .await?;
+        Ok(CommandResult::Ok {
+            vv: Some(Arc::new(vv)),
+        })
+    }
+
+    /// The set's creation time and cardinality, or None if there is no such set
+    pub async fn set_info(&self, set_name: &str) -> Result<Option<SetInfo>> {
+        let set_name = set_name.to_string();
+        self.blocking(move |storage| storage.set_info(&set_name))
+            .await
+    }
+
+    /// Whether the set exists, without creating it: added to and not since deleted,
+    /// here or on a replica whose operations have arrived
+    pub async fn set_exists(&self, set_name: &str) -> Result<bool> {
+        let set_name = set_name.to_string();
+        self.blocking(move |storage| storage.set_exists(&set_name))
+            .await
+    }
+
     /// Get all members of a set
     pub async fn smembers(
         &self,
         set_name: &str,
         client_vv: Option<&VersionVector>,
     ) -> Result<CommandResult> {
+        if let Some(loading) = self.loading() {
+            return Ok(loading);
+        }
+
         // Check causality
-        let local_vv = self.version_vector.read().await;
-        if let Some(cv) = client_vv {
-            if !local_vv.descends(cv) {
-                return Ok(CommandResult::NotReady(local_vv.clone()));
-            }
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(not_ready);
+        }
+
+        let set_name = set_name.to_string();
+        let members = self
+            .blocking(move |storage| storage.get_elements(&set_name))
+            .await?;
+        Ok(CommandResult::BytesArray(members))
+    }
+
+    /// Members picked at random (SRANDMEMBER): with a positive `count`, distinct members
+    /// and at most as many as the set has; with a negative one, exactly `-count` members
+    /// picked with replacement, so they may repeat
+    pub async fn srandmember(
+        &self,
+        set_name: &str,
+        count: i64,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        if let Some(loading) = self.loading() {
+            return Ok(loading);
+        }
+
+        // Check causality
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(not_ready);
         }
 
-        let members = self.storage.get_elements(set_name)?;
+        let wanted = usize::try_from(count.unsigned_abs()).unwrap_or(usize::MAX);
+        if let Some(too_many) = self.too_many_members(wanted) {
+            return Ok(too_many);
+        }
+        let set_name = set_name.to_string();
+        let members = self
+            .blocking(move |storage| storage.random_elements(&set_name, wanted, count < 0))
+            .await?;
         Ok(CommandResult::BytesArray(members))
     }
 
+    /// Get all members of a set, a batch at a time
+    ///
+    /// Like `smembers`, but storage is read as the batches are taken, so memory stays
+    /// bounded however big the set is. With a `pattern` only the members matching that
+    /// glob are read, and the filtering is left to storage. Err with the CommandResult
+    /// when the read is refused.
+    pub async fn smembers_stream(
+        &self,
+        set_name: &str,
+        pattern: Option<&[u8]>,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<std::result::Result<MembersStream, CommandResult>> {
+        if let Some(loading) = self.loading() {
+            return Ok(Err(loading));
+        }
+
+        // Check causality
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(Err(not_ready));
+        }
+
+        let (len_tx, len_rx) = oneshot::channel();
+        let (batch_tx, batches) = mpsc::channel(2);
+        let storage = Arc::clone(&self.storage);
+        let set_name = set_name.to_string();
+        let pattern = pattern.map(<[u8]>::to_vec);
+        let span = info_span!("storage");
+        tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+            let mut len_tx = Some(len_tx);
+            let result = storage.stream_elements(
+                &set_name,
+                pattern.as_deref(),
+                MEMBERS_BATCH,
+                &mut |len| {
+                    if let Some(len_tx) = len_tx.take() {
+                        let _ = len_tx.send(Ok(len));
+                    }
+                },
+                // Stops the scan if the stream is dropped
+                &mut |batch| batch_tx.blocking_send(Ok(batch)).is_ok(),
+            );
+            if let Err(e) = result {
+                match len_tx.take() {
+                    Some(len_tx) => {
+                        let _ = len_tx.send(Err(e));
+                    }
+                    None => {
+                        let _ = batch_tx.blocking_send(Err(e.into()));
+                    }
+                }
+            }
+        });
+
+        let len = len_rx
+            .await
+            .map_err(|_| BigsetError::Internal("the SMEMBERS scan stopped".to_string()))??;
+
+        Ok(Ok(MembersStream { len, batches }))
+    }
+
     /// Check if element is a member of set
     pub async fn sismember(
         &self,
@@ -202,15 +809,19 @@ impl Server {
         member: &Bytes,
         client_vv: Option<&VersionVector>,
     ) -> Result<CommandResult> {
+        if let Some(loading) = self.loading() {
+            return Ok(loading);
+        }
+
         // Check causality
-        let local_vv = self.version_vector.read().await;
-        if let Some(cv) = client_vv {
-            if !local_vv.descends(cv) {
-                return Ok(CommandResult::NotReady(local_vv.clone()));
-            }
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(not_ready);
         }
 
-        let is_member = self.storage.is_member(set_name, member)?;
+        let (set_name, member) = (set_name.to_string(), member.clone());
+        let is_member = self
+            .blocking(move |storage| storage.is_member(&set_name, &member))
+            .await?;
         Ok(CommandResult::Integer(if is_member { 1 } else { 0 }))
     }
 
@@ -227,15 +838,19 @@ impl Server {
             ));
         }
 
+        if let Some(loading) = self.loading() {
+            return Ok(loading);
+        }
+
         // Check causality
-        let local_vv = self.version_vector.read().await;
-        if let Some(cv) = client_vv {
-            if !local_vv.descends(cv) {
-                return Ok(CommandResult::NotReady(local_vv.clone()));
-            }
+        if let Some(not_ready) = self.not_ready(client_vv).await {
+            return Ok(not_ready);
         }
 
-        let membership = self.storage.are_members(set_name, members)?;
+        let (set_name, members) = (set_name.to_string(), members.to_vec());
+        let membership = self
+            .blocking(move |storage| storage.are_members(&set_name, &members))
+            .await?;
         Ok(CommandResult::BoolArray(membership))
     }
 
@@ -243,49 +858,559 @@ impl Server {
     ///
     /// Checks causality and applies the operation atomically.
     /// Returns Ok(true) if applied, Ok(false) if causality not satisfied (needs buffering),
-    /// or Err if there's a storage error.
+    /// or Err if there's a storage error. An operation no replica could have made, see
+    /// `Operation::validate`, is refused with `BigsetError::InvalidOperation`.
+    /// Delivery is at-least-once (retransmission, batching, RBILT), so an operation whose
+    /// dot we have already seen is a duplicate: Ok(true) without touching storage.
+    /// The apply is traced as part of the trace the operation was made in, if it has one.
     pub async fn apply_remote_operation(&self, operation: Operation) -> Result<bool> {
-        let mut vv = self.version_vector.write().await;
+        let dot = operation.dot();
+        let span = info_span!("apply_remote_operation", %dot);
+        telemetry::set_parent(&span, &operation.trace_context);
+        self.apply_remote(operation).instrument(span).await
+    }
+
+    async fn apply_remote(&self, operation: Operation) -> Result<bool> {
+        let dot = operation.dot();
+        let actor_id = self.actor_id;
+        // Refused before the causality check, so a malformed operation is never buffered
+        operation
+            .validate()
+            .map_err(BigsetError::InvalidOperation)?;
+        if dot.actor_id == actor_id && !self.version_vector.read().await.contains_dot(dot) {
+            return Err(BigsetError::InvalidOperation(format!(
+                "{} is this node's, but it hasn't made it",
+                dot
+            )));
+        }
+        let (applied, operation) = self
+            .writer
+            .run(move |storage, vv| {
+                if vv.contains_dot(dot) {
+                    trace!("{}: Skipping duplicate operation {}", actor_id, dot);
+                    return Ok((None, operation)); // we've already done it
+                }
 
-        if !vv.descends(&operation.context) {
-            return Ok(false); // Causality not satisfied, needs buffering
+                if !vv.descends(&operation.context) {
+                    return Ok((Some(false), operation)); // Causality not satisfied, needs buffering
+                }
+
+                vv.update(dot.actor_id, dot.counter);
+                Self::apply_to_storage(storage, &operation)?;
+                // Not logged, or it would be served to peers as the operation itself
+                if !operation.is_elided()
+                    && let Err(e) = storage.append_ops(std::slice::from_ref(&operation))
+                {
+                    error!("Storage error logging operation {}: {}", dot, e);
+                }
+                Ok((Some(true), operation))
+            })
+            .await?;
+
+        match applied {
+            None => return Ok(true),
+            Some(false) => return Ok(false),
+            Some(true) => {}
+        }
+
+        debug!(
+            "{}: Applied remote operation for {:?} with dot {:?}",
+            self.actor_id,
+            operation.set_names(),
+            dot
+        );
+
+        Ok(true)
+    }
+
+    /// Anti-entropy: the local version vector, and every element of the set with dots `vv` has not observed
+    ///
+    /// Limited to the given digest buckets unless `buckets` is empty.
+    pub async fn elements_since(
+        &self,
+        set_name: &str,
+        vv: &VersionVector,
+        buckets: &[u32],
+        bucket_count: u32,
+    ) -> Result<(VersionVector, Vec<ElementDots>)> {
+        // Read first: the storage has at least what it says, so the elements can't claim less
+        let local_vv = self.version_vector.read().await.clone();
+        let (set_name, vv, buckets) = (set_name.to_string(), vv.clone(), buckets.to_vec());
+        let elements = self
+            .blocking(move |storage| storage.elements_since(&set_name, &vv, &buckets, bucket_count))
+            .await?;
+        Ok((local_vv, elements))
+    }
+
+    /// Digests of the set split into `bucket_count` buckets, for cheap divergence detection
+    pub async fn bucket_digests(&self, set_name: &str, bucket_count: u32) -> Result<Vec<Digest>> {
+        let set_name = set_name.to_string();
+        self.blocking(move |storage| storage.bucket_digests(&set_name, bucket_count))
+            .await
+    }
+
+    /// Merge a peer's anti-entropy reply for a set
+    ///
+    /// Runs on the writer, so no other operation interleaves with the merge.
+    /// Returns the number of elements that changed.
+    pub async fn merge_sync(
+        &self,
+        set_name: &str,
+        remote_vv: &VersionVector,
+        elements: &[ElementDots],
+    ) -> Result<usize> {
+        let (set, remote_vv, elements) =
+            (set_name.to_string(), remote_vv.clone(), elements.to_vec());
+        let changed = self
+            .writer
+            .run(move |storage, vv| storage.merge_elements(&set, &elements, &remote_vv, vv))
+            .await?;
+
+        debug!(
+            "{}: Anti-entropy changed {} elements in {}",
+            self.actor_id, changed, set_name
+        );
+
+        Ok(changed)
+    }
+
+    /// Delta-state replication: each set's elements with dots `from` has not seen, plus
+    /// its removed members with whatever dots they have left, keeping only dots `vv` covers
+    ///
+    /// A removed member is absent from the state, so it goes with its current (often no)
+    /// dots for the receiver to drop the ones `vv` says we had seen.
+    pub async fn delta(
+        &self,
+        sets: Vec<(String, Vec<Bytes>)>,
+        from: VersionVector,
+        vv: VersionVector,
+    ) -> Result<Vec<SetDelta>> {
+        self.blocking(move |storage| {
+            let mut delta = Vec::with_capacity(sets.len());
+            for (set_name, removed) in sets {
+                let mut elements = storage.elements_since(&set_name, &from, &[], 1)?;
+                for value in removed {
+                    if !elements.iter().any(|(element, _)| *element == value) {
+                        let dots = storage.element_dots(&set_name, &value)?;
+                        elements.push((value, dots));
+                    }
+                }
+                for (_, dots) in &mut elements {
+                    dots.retain(|dot| vv.contains_dot(*dot));
+                }
+                delta.push((set_name, elements));
+            }
+            Ok(delta)
+        })
+        .await
+    }
+
+    /// Join a peer's delta into our state (delta-state replication)
+    ///
+    /// Each set is merged as anti-entropy merges it, then our version vector is raised to
+    /// the sender's entry in `vv`, the last of its operations the delta covers. Refused
+    /// (None) until we have seen the rest of `vv`: joining the removal of a dot we have
+    /// not received yet would let the dot back in when it arrives.
+    /// Returns the number of elements that changed.
+    pub async fn apply_delta(
+        &self,
+        sender: ActorId,
+        vv: VersionVector,
+        sets: Vec<SetDelta>,
+    ) -> Result<Option<usize>> {
+        let changed = self
+            .writer
+            .run(move |storage, local_vv| {
+                let ready = vv.counters.iter().all(|(actor_id, counter)| {
+                    *actor_id == sender || local_vv.get(*actor_id) >= *counter
+                });
+                if !ready {
+                    return Ok(None);
+                }
+
+                let mut changed = 0;
+                for (set_name, elements) in &sets {
+                    changed += storage.merge_elements(set_name, elements, &vv, local_vv)?;
+                }
+                let through = Dot::new(sender, vv.get(sender));
+                if !local_vv.contains_dot(through) {
+                    storage.observe_dot(through)?;
+                    local_vv.update(sender, through.counter);
+                }
+                Ok(Some(changed))
+            })
+            .await?;
+
+        if let Some(changed) = changed {
+            debug!(
+                "{}: Delta from {} changed {} elements",
+                self.actor_id, sender, changed
+            );
         }
+        Ok(changed)
+    }
 
-        let dot = match &operation.op_type {
-            OpType::Add { dot, .. } | OpType::Remove { dot, .. } => *dot,
+    /// A consistent copy of every set and the version vector, for state transfer
+    pub async fn snapshot(&self) -> Result<(VersionVector, Vec<SetSnapshot>)> {
+        self.blocking(|storage| storage.snapshot()).await
+    }
+
+    /// Replace local state with a peer's snapshot (state transfer)
+    ///
+    /// Only done when the snapshot's version vector descends ours and has something we
+    /// have not seen; returns whether it was loaded.
+    pub async fn load_snapshot(&self, vv: VersionVector, sets: Vec<SetSnapshot>) -> Result<bool> {
+        let set_count = sets.len();
+        let loaded = self
+            .writer
+            .run(move |storage, local_vv| {
+                if !vv.dominates(local_vv) {
+                    return Ok(None);
+                }
+
+                storage.bulk_load_snapshot(&vv, &sets)?;
+                // The operations the snapshot covers never pass through the log
+                storage.truncate_ops(&vv)?;
+                *local_vv = vv.clone();
+                Ok(Some(vv))
+            })
+            .await?;
+        let Some(vv) = loaded else {
+            return Ok(false);
         };
 
-        if vv.contains_dot(dot) {
-            return Ok(true); // we've already done it
+        debug!(
+            "{}: Loaded snapshot of {} sets at {:?}",
+            self.actor_id, set_count, vv
+        );
+
+        Ok(true)
+    }
+
+    /// Write a backup of every set to `path`, see `Storage::export_snapshot`
+    ///
+    /// Written to `<path>.tmp` and renamed over `path`, so a failed save leaves an earlier backup intact.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let dest = path.to_path_buf();
+        self.blocking(move |storage| {
+            let io_error = |e: std::io::Error| rusqlite::Error::ToSqlConversionFailure(Box::new(e));
+            let mut tmp = dest.as_os_str().to_owned();
+            tmp.push(".tmp");
+
+            let mut writer = BufWriter::new(File::create(&tmp).map_err(io_error)?);
+            storage.export_snapshot(&mut writer)?;
+            writer
+                .into_inner()
+                .map_err(|e| io_error(e.into_error()))?
+                .sync_all()
+                .map_err(io_error)?;
+            std::fs::rename(&tmp, &dest).map_err(io_error)
+        })
+        .await?;
+
+        info!("{}: Saved a backup to {:?}", self.actor_id, path);
+        Ok(())
+    }
+
+    /// Replace local state with a backup written by `save`
+    ///
+    /// Follows the state transfer rule of `load_snapshot`: the backup must descend our
+    /// version vector, or loading it would forget operations this replica has seen and
+    /// dots it has issued, so one that doesn't is refused with `BigsetError::Causality`.
+    pub async fn load(&self, path: &Path) -> Result<()> {
+        let source = path.to_path_buf();
+        let (vv, sets) = self
+            .blocking(move |_| {
+                File::open(&source)
+                    .and_then(|file| read_snapshot(&mut BufReader::new(file)))
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+            })
+            .await?;
+
+        if !self.load_snapshot(vv, sets).await? {
+            info!(
+                "{}: Backup {:?} not ahead of local state, not loaded",
+                self.actor_id, path
+            );
+            return Err(BigsetError::Causality(
+                "backup is not ahead of local state".to_string(),
+            ));
+        }
+        info!("{}: Backup {:?} loaded", self.actor_id, path);
+        Ok(())
+    }
+
+    /// Garbage collect dots every replica has seen, see `SqliteStorage::compact_dots`
+    ///
+    /// In every set, or just `set_name` if given. Runs on the writer, so no operation
+    /// interleaves with the compaction. Without a `set_name` the operation log is truncated
+    /// to the same frontier. Returns the number of dots deleted.
+    pub async fn compact_dots(&self, set_name: Option<&str>) -> Result<usize> {
+        let stable = self.stable_vv.read().await.clone();
+        let set = set_name.map(str::to_string);
+        let deleted = self
+            .writer
+            .run(move |storage, _| {
+                // Every replica has seen the operations too, so none will ask for them
+                if set.is_none() {
+                    storage.truncate_ops(&stable)?;
+                }
+                storage.compact_dots(&stable, set.as_deref())
+            })
+            .await?;
+
+        debug!(
+            "{}: Garbage collected {} causally stable dots{}",
+            self.actor_id,
+            deleted,
+            set_name.map(|s| format!(" in {}", s)).unwrap_or_default()
+        );
+
+        Ok(deleted)
+    }
+
+    /// The causally stable version vector: what every replica is known to have seen
+    pub async fn stable_vv(&self) -> VersionVector {
+        self.stable_vv.read().await.clone()
+    }
+
+    /// Give back disk space the storage no longer needs, see `Storage::maintenance`
+    ///
+    /// Returns the number of bytes freed.
+    pub async fn maintenance(&self, vacuum: bool) -> Result<u64> {
+        let freed = self
+            .blocking(move |storage| storage.maintenance(vacuum))
+            .await?;
+
+        info!(
+            "{}: Storage maintenance{} freed {} bytes",
+            self.actor_id,
+            if vacuum { " with vacuum" } else { "" },
+            freed
+        );
+
+        Ok(freed)
+    }
+
+    /// Checkpoint the storage's WAL and truncate it, see `Storage::checkpoint`
+    pub async fn checkpoint(&self) -> Result<Checkpoint> {
+        let checkpoint = self.blocking(|storage| storage.checkpoint()).await?;
+        info!(
+            "{}: Storage checkpoint wrote back {} frames{}",
+            self.actor_id,
+            checkpoint.frames,
+            if checkpoint.truncated {
+                ""
+            } else {
+                ", the WAL was not truncated"
+            }
+        );
+        Ok(checkpoint)
+    }
+
+    /// Checkpoint the storage every `interval_ms`, forever
+    pub async fn run_checkpoints(self: Arc<Self>, interval_ms: u64) {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_millis(interval_ms.max(1)));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.maintenance(false).await {
+                error!("Storage error checkpointing: {}", e);
+            }
         }
+    }
 
-        vv.update(dot.actor_id, dot.counter);
+    /// Checkpoint the storage one last time before the process exits
+    ///
+    /// Call once nothing more is being written: the API and replication listeners have
+    /// drained (see `ApiServer::with_shutdown`). Reads stop being served.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.set_ready(false);
+        self.maintenance(false).await?;
+        info!("{}: Storage checkpointed for shutdown", self.actor_id);
+        Ok(())
+    }
 
+    /// Record a new causally stable version vector
+    pub async fn set_stable_vv(&self, stable: VersionVector) {
+        *self.stable_vv.write().await = stable;
+    }
+
+    /// The storage half of `apply_remote_operation`, run on the writer
+    fn apply_to_storage(storage: &S, operation: &Operation) -> rusqlite::Result<()> {
+        let dot = operation.dot();
+
+        // The sender leaves causally stable dots out of removed_dots, the context still covers them
         match &operation.op_type {
             OpType::Add {
                 elements,
                 removed_dots,
+                expires_at,
                 ..
             } => {
-                self.storage
-                    .replicate_add(&operation.set_name, elements, removed_dots, dot)?;
+                let replaced = Self::replaced_dots(
+                    storage,
+                    &operation.set_name,
+                    elements,
+                    removed_dots,
+                    &operation.context,
+                )?;
+                match expires_at {
+                    Some(at) => storage.replicate_add_expiring(
+                        &operation.set_name,
+                        elements,
+                        &replaced,
+                        dot,
+                        *at,
+                    ),
+                    None => storage.replicate_add(&operation.set_name, elements, &replaced, dot),
+                }
             }
             OpType::Remove {
                 elements,
                 removed_dots,
                 ..
             } => {
-                self.storage
-                    .replicate_remove(&operation.set_name, elements, removed_dots, dot)?;
+                let replaced = Self::replaced_dots(
+                    storage,
+                    &operation.set_name,
+                    elements,
+                    removed_dots,
+                    &operation.context,
+                )?;
+                storage.replicate_remove(&operation.set_name, elements, &replaced, dot)
+            }
+            // Elided for us, as we don't replicate its sets (see `Operation::elided`)
+            OpType::AddMulti { sets, .. } if sets.is_empty() => storage.observe_dot(dot),
+            OpType::AddMulti { sets, .. } => {
+                let mut adds = Vec::with_capacity(sets.len());
+                for set in sets {
+                    adds.push(SetAdd {
+                        removed_dots: Self::replaced_dots(
+                            storage,
+                            &set.set_name,
+                            &set.elements,
+                            &set.removed_dots,
+                            &operation.context,
+                        )?,
+                        ..set.clone()
+                    });
+                }
+                storage.replicate_add_multi(&adds, dot)
             }
         }
+    }
 
-        debug!(
-            "{}: Applied remote operation for {} with dot {:?}",
-            self.actor_id, operation.set_name, dot
-        );
+    /// The local dots a remote operation replaces: those its context covers, plus its removed dots
+    fn replaced_dots(
+        storage: &S,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        context: &VersionVector,
+    ) -> rusqlite::Result<Vec<Dot>> {
+        let mut replaced = storage.covered_dots(set_name, elements, context)?;
+        replaced.extend(removed_dots.iter().copied());
+        Ok(replaced)
+    }
 
-        Ok(true)
+    /// Run a storage call on tokio's blocking pool, so slow disk I/O doesn't hold up the async workers
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let storage = Arc::clone(&self.storage);
+        let span = info_span!("storage");
+        Ok(tokio::task::spawn_blocking(move || span.in_scope(|| f(&storage))).await??)
+    }
+
+    /// Drop the causally stable dots from a new operation's removed dots
+    ///
+    /// Every replica has seen them, so receivers find them through the operation's context.
+    async fn unstable(&self, dots: Vec<Dot>) -> Vec<Dot> {
+        let stable = self.stable_vv.read().await;
+        dots.into_iter()
+            .filter(|dot| !stable.contains_dot(*dot))
+            .collect()
+    }
+
+    /// NotReady with our version vector, if it doesn't descend the client's
+    ///
+    /// The lock is only held for the check. Everything the version vector says has
+    /// already reached storage (see `Writer`), so a read after the check sees it all, and
+    /// a write after it has a context that covers the client's.
+    pub(crate) async fn not_ready(
+        &self,
+        client_vv: Option<&VersionVector>,
+    ) -> Option<CommandResult> {
+        let local_vv = self.version_vector.read().await;
+        match client_vv {
+            Some(cv) if !local_vv.descends(cv) => Some(CommandResult::NotReady(local_vv.clone())),
+            _ => None,
+        }
+    }
+
+    /// Whether reads are being served
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Start or stop serving reads, e.g. around startup learning
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Release);
+    }
+
+    /// The error returned for reads until the server is ready
+    fn loading(&self) -> Option<CommandResult> {
+        (!self.is_ready()).then(|| {
+            CommandResult::Error("LOADING learning missed operations from peers".to_string())
+        })
+    }
+
+    /// The error returned for a client write over `limits`, checked before storage is
+    /// touched: a set name over `max_key_length`, too many members, or when `adding`, a
+    /// member over `max_element_size` (a remove can't write one, so its size is moot)
+    fn over_limits(
+        &self,
+        set_name: &str,
+        members: &[Bytes],
+        adding: bool,
+    ) -> Option<CommandResult> {
+        let limits = &self.limits;
+        if set_name.len() > limits.max_key_length {
+            return Some(CommandResult::Error(format!(
+                "ERR set name is longer than max_key_length ({} bytes)",
+                limits.max_key_length
+            )));
+        }
+        if let Some(too_many) = self.too_many_members(members.len()) {
+            return Some(too_many);
+        }
+        if adding && members.iter().any(|m| m.len() > limits.max_element_size) {
+            return Some(CommandResult::Error(format!(
+                "ERR member is larger than max_element_size ({} bytes)",
+                limits.max_element_size
+            )));
+        }
+        None
+    }
+
+    fn too_many_members(&self, count: usize) -> Option<CommandResult> {
+        let max = self.limits.max_members_per_command;
+        (count > max).then(|| {
+            CommandResult::Error(format!(
+                "ERR too many members, max_members_per_command is {}",
+                max
+            ))
+        })
+    }
+
+    /// Whether storage is answering: takes a connection and reads the version vector
+    pub async fn check_storage(&self) -> Result<()> {
+        self.blocking(|storage| storage.load_vv().map(|_| ())).await
     }
 
     pub fn actor_id(&self) -> ActorId {
@@ -296,3 +1421,12 @@ impl Server {
         Arc::clone(&self.version_vector)
     }
 }
+
+/// Copies of a command's members for its Operation
+///
+/// A client's members are slices of its connection's read buffer, and an Operation can
+/// wait in the replication buffers while a peer is down. Copied, each one holds only its
+/// own bytes rather than keeping a whole read buffer alive.
+fn copied(members: &[Bytes]) -> Vec<Bytes> {
+    members.iter().map(|m| Bytes::copy_from_slice(m)).collect()
+}
@@ -0,0 +1,101 @@
+/// A Bloom filter over a set's element values, for answering "definitely not a member"
+/// without reading the database
+///
+/// Elements can't be taken out of a Bloom filter, so removed elements stay in it as
+/// false positives until it is rebuilt. It is sized for `capacity` elements at the
+/// configured false positive rate; past that the rate climbs and the owner should rebuild it.
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+    capacity: u64,
+    inserted: u64,
+}
+
+impl Bloom {
+    /// An empty filter for `capacity` elements at `fp_rate` false positives
+    pub(crate) fn new(capacity: u64, fp_rate: f64) -> Self {
+        let capacity = capacity.max(1);
+        // The standard sizing: m = -n ln p / (ln 2)^2 bits, and k = (m / n) ln 2 hashes
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity as f64) * fp_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let hashes = ((bits as f64 / capacity as f64) * ln2)
+            .round()
+            .clamp(1.0, 16.0) as u32;
+
+        Self {
+            bits: vec![0; bits.div_ceil(64) as usize],
+            hashes,
+            capacity,
+            inserted: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, value: &[u8]) {
+        for bit in self.bit_indexes(value) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.inserted += 1;
+    }
+
+    /// False only if `value` was never inserted
+    pub(crate) fn may_contain(&self, value: &[u8]) -> bool {
+        self.bit_indexes(value)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// More inserts than the filter was sized for, so its false positive rate is above the target
+    pub(crate) fn is_overfull(&self) -> bool {
+        self.inserted > self.capacity
+    }
+
+    /// The filter's memory, in bytes
+    pub(crate) fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// The `hashes` bits for `value`, from two halves of one blake3 hash (Kirsch-Mitzenmacher)
+    fn bit_indexes(&self, value: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let hash = blake3::hash(value);
+        let h1 = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash.as_bytes()[8..16].try_into().unwrap());
+        let bit_count = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+}
+
+impl std::fmt::Debug for Bloom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Bloom")
+            .field("bytes", &self.size())
+            .field("hashes", &self.hashes)
+            .field("capacity", &self.capacity)
+            .field("inserted", &self.inserted)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives_and_few_false_positives() {
+        let mut bloom = Bloom::new(10_000, 0.01);
+        for i in 0..10_000 {
+            bloom.insert(format!("in{}", i).as_bytes());
+        }
+
+        assert!((0..10_000).all(|i| bloom.may_contain(format!("in{}", i).as_bytes())));
+        let false_positives = (0..10_000)
+            .filter(|i| bloom.may_contain(format!("out{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+        assert!(!bloom.is_overfull());
+
+        bloom.insert(b"one more");
+        assert!(bloom.is_overfull());
+    }
+}
@@ -0,0 +1,605 @@
+use super::{BucketDigests, Digest, Storage, all_seen, digest_bucket, now_ms};
+use crate::types::{ActorId, Dot, ElementDots, SetSnapshot, VersionVector};
+use bytes::Bytes;
+use rusqlite::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+/// In-process implementation of the Storage trait, for tests and embedding
+///
+/// The same add-wins semantics as `SqliteStorage`, method for method, over maps
+/// instead of tables: each set maps an element to the dots supporting it (at most one
+/// per actor), alongside the global version vector. Nothing is persisted.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    vv: VersionVector,
+    sets: BTreeMap<String, BTreeMap<Bytes, Element>>,
+    /// Elements are listed in the order they were created, like SQLite's rowids
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Element {
+    id: u64,
+    dots: BTreeMap<ActorId, u64>,
+    /// When dots expire, in ms since the Unix epoch. May still list dots since replaced.
+    expires: HashMap<Dot, u64>,
+}
+
+impl Element {
+    fn dots(&self) -> Vec<Dot> {
+        self.dots
+            .iter()
+            .map(|(actor_id, counter)| Dot::new(*actor_id, *counter))
+            .collect()
+    }
+
+    /// Whether a dot that hasn't expired by `now` supports the element
+    fn live(&self, now: u64) -> bool {
+        self.dots()
+            .iter()
+            .any(|dot| self.expires.get(dot).is_none_or(|at| *at > now))
+    }
+
+    /// Whether any of the element's dots expires
+    fn expiring(&self) -> bool {
+        self.dots().iter().any(|dot| self.expires.contains_key(dot))
+    }
+
+    fn remove_dots(&mut self, removed: &[Dot]) {
+        for dot in removed {
+            if self.dots.get(&dot.actor_id) == Some(&dot.counter) {
+                self.dots.remove(&dot.actor_id);
+            }
+        }
+    }
+}
+
+impl State {
+    fn element(&mut self, set_name: &str, value: &Bytes) -> &mut Element {
+        let id = &mut self.next_id;
+        self.sets
+            .entry(set_name.to_string())
+            .or_default()
+            .entry(value.clone())
+            .or_insert_with(|| {
+                *id += 1;
+                Element {
+                    id: *id,
+                    dots: BTreeMap::new(),
+                    expires: HashMap::new(),
+                }
+            })
+    }
+
+    fn observe(&mut self, dot: Dot) {
+        if !self.vv.contains_dot(dot) {
+            self.vv.update(dot.actor_id, dot.counter);
+        }
+    }
+}
+
+/// A set's elements in the order they were created
+fn by_id(set: &BTreeMap<Bytes, Element>) -> Vec<(&Bytes, &Element)> {
+    let mut elements: Vec<_> = set.iter().collect();
+    elements.sort_by_key(|(_, element)| element.id);
+    elements
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// add_elements, the new dot expiring at `expires_at` if given
+    fn add(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        dot: Dot,
+        expires_at: Option<u64>,
+    ) -> Result<Vec<Dot>> {
+        if elements.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut state = self.state();
+        let mut deleted = Vec::new();
+        for value in elements {
+            let element = state.element(set_name, value);
+            deleted.extend(element.dots());
+            element.dots = BTreeMap::from([(dot.actor_id, dot.counter)]);
+            element.expires = expires_at.map(|at| (dot, at)).into_iter().collect();
+        }
+        state.observe(dot);
+        Ok(deleted)
+    }
+
+    /// replicate_add, the new dot expiring at `expires_at` if given
+    fn replicate(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.state();
+        for value in elements {
+            let element = state.element(set_name, value);
+            element.remove_dots(removed_dots);
+            let counter = element.dots.entry(dot.actor_id).or_default();
+            *counter = (*counter).max(dot.counter);
+            if let Some(at) = expires_at {
+                element.expires.insert(dot, at);
+            }
+        }
+        state.observe(dot);
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load_vv(&self) -> Result<VersionVector> {
+        Ok(self.state().vv.clone())
+    }
+
+    fn add_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+        self.add(set_name, elements, dot, None)
+    }
+
+    fn add_elements_expiring(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        dot: Dot,
+        expires_at: u64,
+    ) -> Result<Vec<Dot>> {
+        self.add(set_name, elements, dot, Some(expires_at))
+    }
+
+    fn remove_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+        if elements.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut state = self.state();
+        let mut deleted = Vec::new();
+        // Nothing to remove from a set that doesn't exist, but the dot is still seen
+        if let Some(set) = state.sets.get_mut(set_name) {
+            for value in elements {
+                if let Some(element) = set.remove(value) {
+                    deleted.extend(element.dots());
+                }
+            }
+        }
+        state.observe(dot);
+        Ok(deleted)
+    }
+
+    fn delete_set(&self, set_name: &str) -> Result<Vec<Dot>> {
+        let mut state = self.state();
+        Ok(state
+            .sets
+            .remove(set_name)
+            .map(|set| set.values().flat_map(Element::dots).collect())
+            .unwrap_or_default())
+    }
+
+    fn list_sets(&self) -> Result<Vec<String>> {
+        Ok(self.state().sets.keys().cloned().collect())
+    }
+
+    fn get_elements(&self, set_name: &str) -> Result<Vec<Bytes>> {
+        let state = self.state();
+        let now = now_ms();
+        Ok(state
+            .sets
+            .get(set_name)
+            .map(|set| {
+                by_id(set)
+                    .into_iter()
+                    .filter(|(_, element)| element.live(now))
+                    .map(|(value, _)| value.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn count_elements(&self, set_name: &str) -> Result<i64> {
+        let state = self.state();
+        let now = now_ms();
+        Ok(state.sets.get(set_name).map_or(0, |set| {
+            set.values().filter(|element| element.live(now)).count() as i64
+        }))
+    }
+
+    fn set_vv(&self, set_name: &str) -> Result<VersionVector> {
+        let state = self.state();
+        let mut vv = VersionVector::new();
+        let Some(set) = state.sets.get(set_name) else {
+            return Ok(vv);
+        };
+        for element in set.values() {
+            for (actor_id, counter) in &element.dots {
+                vv.update(*actor_id, *counter);
+            }
+        }
+        Ok(vv)
+    }
+
+    fn element_dots(&self, set_name: &str, element: &Bytes) -> Result<Vec<Dot>> {
+        // Element::dots is in actor order already
+        let state = self.state();
+        Ok(state
+            .sets
+            .get(set_name)
+            .and_then(|set| set.get(element))
+            .map_or_else(Vec::new, Element::dots))
+    }
+
+    fn set_exists(&self, set_name: &str) -> Result<bool> {
+        Ok(self.state().sets.contains_key(set_name))
+    }
+
+    fn is_member(&self, set_name: &str, element: &Bytes) -> Result<bool> {
+        let state = self.state();
+        let now = now_ms();
+        Ok(state
+            .sets
+            .get(set_name)
+            .and_then(|set| set.get(element))
+            .is_some_and(|element| element.live(now)))
+    }
+
+    fn are_members(&self, set_name: &str, elements: &[Bytes]) -> Result<Vec<bool>> {
+        let state = self.state();
+        let now = now_ms();
+        let set = state.sets.get(set_name);
+        Ok(elements
+            .iter()
+            .map(|element| {
+                set.and_then(|set| set.get(element))
+                    .is_some_and(|element| element.live(now))
+            })
+            .collect())
+    }
+
+    fn replicate_add(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        self.replicate(set_name, elements, removed_dots, dot, None)
+    }
+
+    fn replicate_add_expiring(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+        expires_at: u64,
+    ) -> Result<()> {
+        self.replicate(set_name, elements, removed_dots, dot, Some(expires_at))
+    }
+
+    fn expired_elements(&self, now: u64, limit: usize) -> Result<Vec<(String, Vec<Bytes>)>> {
+        let state = self.state();
+        let mut expired = Vec::new();
+        let mut left = limit;
+        for (set_name, set) in &state.sets {
+            let values: Vec<Bytes> = by_id(set)
+                .into_iter()
+                .filter(|(_, element)| !element.dots.is_empty() && !element.live(now))
+                .map(|(value, _)| value.clone())
+                .take(left)
+                .collect();
+            if !values.is_empty() {
+                left -= values.len();
+                expired.push((set_name.clone(), values));
+            }
+        }
+        Ok(expired)
+    }
+
+    fn replicate_remove(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.state();
+        // Nothing to remove from a set that doesn't exist, but the dot is still seen
+        if let Some(set) = state.sets.get_mut(set_name) {
+            for value in elements {
+                if let Some(element) = set.get_mut(value) {
+                    element.remove_dots(removed_dots);
+                    if element.dots.is_empty() {
+                        set.remove(value);
+                    }
+                }
+            }
+        }
+        state.observe(dot);
+        Ok(())
+    }
+
+    fn covered_dots(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        vv: &VersionVector,
+    ) -> Result<Vec<Dot>> {
+        let state = self.state();
+        let Some(set) = state.sets.get(set_name) else {
+            return Ok(vec![]);
+        };
+
+        Ok(elements
+            .iter()
+            .filter_map(|value| set.get(value))
+            .flat_map(Element::dots)
+            .filter(|dot| vv.contains_dot(*dot))
+            .collect())
+    }
+
+    fn compact_dots(&self, stable: &VersionVector, set_name: Option<&str>) -> Result<usize> {
+        let mut state = self.state();
+        let mut deleted = 0;
+
+        let sets = state
+            .sets
+            .iter_mut()
+            .filter(|(name, _)| set_name.is_none_or(|set_name| set_name == name.as_str()));
+        for element in sets.flat_map(|(_, set)| set.values_mut()) {
+            let dots = element.dots();
+            if dots.len() < 2
+                || element.expiring()
+                || !dots.iter().all(|dot| stable.contains_dot(*dot))
+            {
+                continue;
+            }
+            let keep = dots
+                .iter()
+                .max_by_key(|dot| (dot.counter, dot.actor_id))
+                .copied()
+                .unwrap();
+            deleted += dots.len() - 1;
+            element.dots = BTreeMap::from([(keep.actor_id, keep.counter)]);
+        }
+
+        Ok(deleted)
+    }
+
+    fn bucket_digests(&self, set_name: &str, bucket_count: u32) -> Result<Vec<Digest>> {
+        let state = self.state();
+        let mut digests = BucketDigests::new(bucket_count);
+
+        // Keyed by value and then actor, so already in the order digests are hashed in
+        for (value, element) in state.sets.get(set_name).into_iter().flatten() {
+            for dot in element.dots() {
+                digests.add(value, dot);
+            }
+        }
+
+        Ok(digests.finish())
+    }
+
+    fn elements_since(
+        &self,
+        set_name: &str,
+        vv: &VersionVector,
+        buckets: &[u32],
+        bucket_count: u32,
+    ) -> Result<Vec<ElementDots>> {
+        let state = self.state();
+        let Some(set) = state.sets.get(set_name) else {
+            return Ok(vec![]);
+        };
+
+        Ok(by_id(set)
+            .into_iter()
+            .map(|(value, element)| (value.clone(), element.dots()))
+            .filter(|(value, dots)| {
+                !dots.is_empty()
+                    && (buckets.is_empty() || buckets.contains(&digest_bucket(value, bucket_count)))
+                    && dots.iter().any(|dot| !vv.contains_dot(*dot))
+            })
+            .collect())
+    }
+
+    fn snapshot(&self) -> Result<(VersionVector, Vec<SetSnapshot>)> {
+        let state = self.state();
+        let sets = state
+            .sets
+            .iter()
+            .map(|(set_name, set)| {
+                let elements: Vec<ElementDots> = by_id(set)
+                    .into_iter()
+                    .filter(|(_, element)| !element.dots.is_empty())
+                    .map(|(value, element)| (value.clone(), element.dots()))
+                    .collect();
+                (set_name.clone(), elements)
+            })
+            .filter(|(_, elements)| !elements.is_empty())
+            .collect();
+
+        Ok((state.vv.clone(), sets))
+    }
+
+    fn bulk_load_snapshot(&self, vv: &VersionVector, sets: &[SetSnapshot]) -> Result<()> {
+        let mut state = self.state();
+        *state = State {
+            vv: vv.clone(),
+            ..State::default()
+        };
+
+        for (set_name, elements) in sets {
+            for (value, dots) in elements {
+                state.element(set_name, value).dots =
+                    dots.iter().map(|dot| (dot.actor_id, dot.counter)).collect();
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_elements(
+        &self,
+        set_name: &str,
+        remote: &[ElementDots],
+        remote_vv: &VersionVector,
+        local_vv: &VersionVector,
+    ) -> Result<usize> {
+        if remote.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.state();
+        // A set we don't have is only created for an element merged in, not for removals
+        if !state.sets.contains_key(set_name) && all_seen(remote, local_vv) {
+            return Ok(0);
+        }
+        let mut changed = 0;
+
+        for (value, remote_dots) in remote {
+            let element = state.element(set_name, value);
+            let local_dots = element.dots();
+
+            // The ORSWOT join, see `SqliteStorage::merge_elements`
+            let mut merged: Vec<Dot> = local_dots
+                .iter()
+                .filter(|dot| remote_dots.contains(dot) || !remote_vv.contains_dot(**dot))
+                .copied()
+                .collect();
+            for dot in remote_dots {
+                if !local_vv.contains_dot(*dot) && !merged.contains(dot) {
+                    merged.push(*dot);
+                }
+            }
+
+            // At most one dot per actor per element: the later one has joined the earlier
+            let mut dots: BTreeMap<ActorId, u64> = BTreeMap::new();
+            for dot in merged {
+                let counter = dots.entry(dot.actor_id).or_default();
+                *counter = (*counter).max(dot.counter);
+            }
+
+            if dots != element.dots {
+                changed += 1;
+                element.dots = dots;
+            }
+            if element.dots.is_empty() {
+                state.sets.get_mut(set_name).map(|set| set.remove(value));
+            }
+        }
+
+        Ok(changed)
+    }
+
+    fn observe_dot(&self, dot: Dot) -> Result<()> {
+        self.state().observe(dot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dot(node_id: u16, counter: u64) -> Dot {
+        Dot::new(ActorId::from_node_id(node_id), counter)
+    }
+
+    #[test]
+    fn test_add_replaces_existing_dots() {
+        let storage = MemoryStorage::new();
+        let x = Bytes::from("x");
+
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], dot(2, 1))
+            .unwrap();
+        let replaced = storage
+            .add_elements("s", std::slice::from_ref(&x), dot(1, 1))
+            .unwrap();
+
+        assert_eq!(replaced, vec![dot(2, 1)]);
+        assert_eq!(
+            storage.snapshot().unwrap().1,
+            vec![("s".to_string(), vec![(x, vec![dot(1, 1)])])]
+        );
+        assert_eq!(storage.load_vv().unwrap().get(ActorId::from_node_id(2)), 1);
+    }
+
+    #[test]
+    fn test_concurrent_add_survives_remove() {
+        let storage = MemoryStorage::new();
+        let x = Bytes::from("x");
+
+        storage
+            .add_elements("s", std::slice::from_ref(&x), dot(1, 1))
+            .unwrap();
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], dot(2, 1))
+            .unwrap();
+
+        // A remove that only saw node 1's add leaves node 2's
+        storage
+            .replicate_remove("s", std::slice::from_ref(&x), &[dot(1, 1)], dot(3, 1))
+            .unwrap();
+        assert_eq!(storage.count_elements("s").unwrap(), 1);
+
+        storage
+            .replicate_remove("s", std::slice::from_ref(&x), &[dot(2, 1)], dot(3, 2))
+            .unwrap();
+        assert_eq!(storage.count_elements("s").unwrap(), 0);
+        assert!(!storage.is_member("s", &x).unwrap());
+    }
+
+    #[test]
+    fn test_set_vv_is_each_actors_newest_dot_in_the_set() {
+        let storage = MemoryStorage::new();
+        let (x, y) = (Bytes::from("x"), Bytes::from("y"));
+
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], dot(1, 1))
+            .unwrap();
+        storage
+            .replicate_add("s", std::slice::from_ref(&y), &[], dot(1, 3))
+            .unwrap();
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], dot(2, 2))
+            .unwrap();
+        storage
+            .replicate_add("t", std::slice::from_ref(&x), &[], dot(2, 9))
+            .unwrap();
+        storage
+            .replicate_remove("s", std::slice::from_ref(&y), &[dot(1, 3)], dot(2, 10))
+            .unwrap();
+
+        let vv = storage.set_vv("s").unwrap();
+        let (a, b) = (ActorId::from_node_id(1), ActorId::from_node_id(2));
+        assert_eq!((vv.get(a), vv.get(b)), (1, 2));
+        assert_eq!(vv.counters.len(), 2);
+    }
+}
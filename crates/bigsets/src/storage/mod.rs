@@ -1,2 +1,505 @@
+mod bloom;
+mod export;
+mod memory;
+#[cfg(feature = "rocksdb")]
+mod rocksdb;
+mod sharded;
 mod sqlite;
+#[cfg(feature = "rocksdb")]
+pub use self::rocksdb::RocksdbStorage;
+pub use export::{read_snapshot, write_snapshot};
+pub use memory::MemoryStorage;
+pub use sharded::ShardedStorage;
 pub use sqlite::SqliteStorage;
+
+use crate::config::glob_match;
+use crate::types::{
+    ActorId, Dot, ElementDots, Operation, SetAdd, SetInfo, SetSnapshot, VersionVector,
+};
+use bytes::Bytes;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use rusqlite::Result;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A blake3 hash summarising (part of) a set, for anti-entropy
+pub type Digest = [u8; 32];
+
+/// The digest bucket an element falls in, the same on every replica
+pub fn digest_bucket(value: &[u8], bucket_count: u32) -> u32 {
+    let hash = blake3::hash(value);
+    let prefix: [u8; 4] = hash.as_bytes()[..4].try_into().unwrap();
+    u32::from_be_bytes(prefix) % bucket_count.max(1)
+}
+
+/// Milliseconds since the Unix epoch, for set creation times and element expiry
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Ranks in `0..len`, in order, for picking `count` of `len` elements at random:
+/// distinct ones and no more than `len`, or with `repeats` exactly `count` drawn with
+/// replacement, so a rank may come up more than once
+pub(crate) fn random_ranks(len: usize, count: usize, repeats: bool) -> Vec<usize> {
+    let mut rng = rand::rng();
+    let mut ranks = if repeats {
+        if len == 0 {
+            return Vec::new();
+        }
+        (0..count).map(|_| rng.random_range(0..len)).collect()
+    } else {
+        rand::seq::index::sample(&mut rng, len, count.min(len)).into_vec()
+    };
+    ranks.sort_unstable();
+    ranks
+}
+
+/// Whether every dot on the remote elements is one `local_vv` has seen, so merging them
+/// can only remove: an element with a dot we haven't seen is one to add
+pub(crate) fn all_seen(remote: &[ElementDots], local_vv: &VersionVector) -> bool {
+    remote
+        .iter()
+        .all(|(_, dots)| dots.iter().all(|dot| local_vv.contains_dot(*dot)))
+}
+
+/// The error a backend gives for a capability it doesn't have
+fn unsupported(what: &str) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(
+        format!("{} is not supported by this storage backend", what).into(),
+    )
+}
+
+/// Whether a `GroupWrite` adds or removes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteKind {
+    Add,
+    Remove,
+}
+
+/// One local write of a `Storage::commit_group`
+#[derive(Debug, Clone, Copy)]
+pub struct GroupWrite<'a> {
+    pub kind: WriteKind,
+    pub set_name: &'a str,
+    pub elements: &'a [Bytes],
+    pub dot: Dot,
+}
+
+/// What a `Storage::checkpoint` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// WAL frames now written back to the database
+    pub frames: u64,
+    /// Whether the WAL was emptied, which a reader of an older snapshot prevents
+    pub truncated: bool,
+}
+
+/// Where a Server keeps its sets, dots and version vector
+///
+/// The add-wins semantics live in the backend, see `SqliteStorage` for what each
+/// method must do. Every backend must hash digests with `BucketDigests`, so replicas
+/// on different backends can still compare sets.
+pub trait Storage: Send + Sync + std::fmt::Debug + 'static {
+    fn load_vv(&self) -> Result<VersionVector>;
+
+    /// Add locally: returns the dots the new `dot` replaced
+    fn add_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>>;
+
+    /// Add locally to many sets under the one `dot`: returns the replaced dots per set, in order.
+    /// Backends that can should do it in a single transaction.
+    fn add_elements_multi(&self, sets: &[(&str, &[Bytes])], dot: Dot) -> Result<Vec<Vec<Dot>>> {
+        sets.iter()
+            .map(|(set_name, elements)| self.add_elements(set_name, elements, dot))
+            .collect()
+    }
+
+    /// Add locally as `add_elements` does, with the new dot expiring at `expires_at`, in ms
+    /// since the Unix epoch
+    ///
+    /// An element is only a member while one of its dots hasn't expired, so a plain add
+    /// concurrent with this one keeps the element. Reads leave out elements whose every
+    /// dot has expired until `expired_elements` finds them and they are removed. Backends
+    /// that don't keep expiry times refuse.
+    fn add_elements_expiring(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        dot: Dot,
+        expires_at: u64,
+    ) -> Result<Vec<Dot>> {
+        let _ = (set_name, elements, dot, expires_at);
+        Err(unsupported("element expiry"))
+    }
+
+    /// Remove locally: returns the dots that supported the removed elements
+    fn remove_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>>;
+
+    /// Apply local adds and removes in order, each as `add_elements` or `remove_elements`
+    /// would, returning each one's dots. Backends that can should commit them all in a
+    /// single transaction, so a burst of writes pays for one commit.
+    fn commit_group(&self, writes: &[GroupWrite]) -> Result<Vec<Vec<Dot>>> {
+        writes
+            .iter()
+            .map(|write| match write.kind {
+                WriteKind::Add => self.add_elements(write.set_name, write.elements, write.dot),
+                WriteKind::Remove => {
+                    self.remove_elements(write.set_name, write.elements, write.dot)
+                }
+            })
+            .collect()
+    }
+
+    /// Drop a set and everything in it: returns the dots that supported its elements,
+    /// nothing (and no error) if there is no such set
+    fn delete_set(&self, set_name: &str) -> Result<Vec<Dot>>;
+
+    /// The name of every set, including sets emptied but not deleted
+    fn list_sets(&self) -> Result<Vec<String>>;
+
+    fn get_elements(&self, set_name: &str) -> Result<Vec<Bytes>>;
+
+    /// The set's elements without holding them all in memory: `on_len` gets the number
+    /// of elements, then `on_batch` gets them up to `batch_size` at a time, and stops the
+    /// scan by returning false. With a `pattern`, only the elements matching that glob
+    /// (see `config::glob_match`) are counted and returned.
+    ///
+    /// By default this is `get_elements`, filtered and cut into batches.
+    fn stream_elements(
+        &self,
+        set_name: &str,
+        pattern: Option<&[u8]>,
+        batch_size: usize,
+        on_len: &mut dyn FnMut(u64),
+        on_batch: &mut dyn FnMut(Vec<Bytes>) -> bool,
+    ) -> Result<()> {
+        let mut elements = self.get_elements(set_name)?;
+        if let Some(pattern) = pattern {
+            elements.retain(|element| glob_match(pattern, element));
+        }
+        on_len(elements.len() as u64);
+        for batch in elements.chunks(batch_size.max(1)) {
+            if !on_batch(batch.to_vec()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// `count` of the set's elements picked at random, in no particular order: distinct
+    /// ones and no more than the set has, or with `repeats` exactly `count`, each picked
+    /// on its own so an element may come up more than once (see `random_ranks`)
+    ///
+    /// By default the picks are made from `get_elements`.
+    fn random_elements(&self, set_name: &str, count: usize, repeats: bool) -> Result<Vec<Bytes>> {
+        let elements = self.get_elements(set_name)?;
+        let mut picked: Vec<Bytes> = random_ranks(elements.len(), count, repeats)
+            .into_iter()
+            .map(|rank| elements[rank].clone())
+            .collect();
+        picked.shuffle(&mut rand::rng());
+        Ok(picked)
+    }
+
+    /// The number of members: elements with at least one dot that hasn't expired. An `i64`,
+    /// as SCARD replies with a RESP integer.
+    fn count_elements(&self, set_name: &str) -> Result<i64>;
+
+    /// The set's creation time and cardinality, or None if there is no such set.
+    /// Backends that don't record creation times report None for it.
+    fn set_info(&self, set_name: &str) -> Result<Option<SetInfo>> {
+        if !self.list_sets()?.iter().any(|name| name == set_name) {
+            return Ok(None);
+        }
+        Ok(Some(SetInfo {
+            created_at: None,
+            cardinality: self.count_elements(set_name)?,
+        }))
+    }
+
+    /// Whether the set exists, without creating it. A set is created by its first add
+    /// and exists until deleted, even when emptied.
+    fn set_exists(&self, set_name: &str) -> Result<bool> {
+        Ok(self.list_sets()?.iter().any(|name| name == set_name))
+    }
+
+    fn is_member(&self, set_name: &str, element: &Bytes) -> Result<bool>;
+
+    /// Whether each of `elements` is a member, in order (SMISMEMBER). Backends look them
+    /// all up at once, e.g. one SQL query, rather than an `is_member` each.
+    fn are_members(&self, set_name: &str, elements: &[Bytes]) -> Result<Vec<bool>>;
+
+    /// Every actor with a dot on one of the set's elements, ordered by id
+    ///
+    /// By default they are collected from `elements_since` everything.
+    fn set_actors(&self, set_name: &str) -> Result<Vec<ActorId>> {
+        let mut actors: Vec<ActorId> = self
+            .elements_since(set_name, &VersionVector::new(), &[], 1)?
+            .into_iter()
+            .flat_map(|(_, dots)| dots)
+            .map(|dot| dot.actor_id)
+            .collect();
+        actors.sort();
+        actors.dedup();
+        Ok(actors)
+    }
+
+    /// The set's version vector (SVV): for each actor, its highest counter among the dots
+    /// on the set's elements. Backends aggregate it where the dots are, e.g. one SQL
+    /// query, rather than reading the whole set out.
+    ///
+    /// By default it is collected from `elements_since` everything.
+    fn set_vv(&self, set_name: &str) -> Result<VersionVector> {
+        let mut vv = VersionVector::new();
+        for (_, dots) in self.elements_since(set_name, &VersionVector::new(), &[], 1)? {
+            for dot in dots {
+                vv.update(dot.actor_id, dot.counter);
+            }
+        }
+        Ok(vv)
+    }
+
+    /// The dots stored for an element, ordered by actor: the adds keeping it in the set.
+    /// Empty when the set has no such element. Expired dots are listed until the element
+    /// is removed.
+    ///
+    /// By default the element is found among `elements_since` everything.
+    fn element_dots(&self, set_name: &str, element: &Bytes) -> Result<Vec<Dot>> {
+        let mut dots = self
+            .elements_since(set_name, &VersionVector::new(), &[], 1)?
+            .into_iter()
+            .find(|(value, _)| value == element)
+            .map_or_else(Vec::new, |(_, dots)| dots);
+        dots.sort();
+        Ok(dots)
+    }
+
+    /// How many elements are in every one of the sets, counting no further than `limit`
+    /// when there is one. A set that doesn't exist makes the intersection empty.
+    ///
+    /// By default the first set's elements are looked up in each of the others.
+    fn intersection_count(&self, set_names: &[String], limit: Option<u64>) -> Result<u64> {
+        let Some((first, rest)) = set_names.split_first() else {
+            return Ok(0);
+        };
+        let mut elements = self.get_elements(first)?;
+        for set_name in rest {
+            if elements.is_empty() {
+                break;
+            }
+            let present = self.are_members(set_name, &elements)?;
+            let mut present = present.into_iter();
+            elements.retain(|_| present.next().unwrap_or(false));
+        }
+        let count = elements.len() as u64;
+        Ok(limit.map_or(count, |limit| count.min(limit)))
+    }
+
+    fn replicate_add(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()>;
+
+    /// Apply a replicated add whose dot expires at `expires_at`, see `add_elements_expiring`.
+    /// Backends that don't keep expiry times add the dot as `replicate_add` does: the
+    /// element stays until the remove its expiry leads to arrives.
+    fn replicate_add_expiring(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+        expires_at: u64,
+    ) -> Result<()> {
+        let _ = expires_at;
+        self.replicate_add(set_name, elements, removed_dots, dot)
+    }
+
+    /// Up to `limit` elements whose every dot had expired by `now`, by set
+    ///
+    /// They are still stored, only hidden from reads, until they are removed like any
+    /// other element. Nothing ever expires by default.
+    fn expired_elements(&self, now: u64, limit: usize) -> Result<Vec<(String, Vec<Bytes>)>> {
+        let _ = (now, limit);
+        Ok(Vec::new())
+    }
+
+    /// Apply an `OpType::AddMulti`, see `add_elements_multi`
+    fn replicate_add_multi(&self, sets: &[SetAdd], dot: Dot) -> Result<()> {
+        for set in sets {
+            self.replicate_add(&set.set_name, &set.elements, &set.removed_dots, dot)?;
+        }
+        Ok(())
+    }
+
+    fn replicate_remove(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()>;
+
+    fn covered_dots(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        vv: &VersionVector,
+    ) -> Result<Vec<Dot>>;
+
+    /// Garbage collect causally stable dots, in just `set_name` if given
+    fn compact_dots(&self, stable: &VersionVector, set_name: Option<&str>) -> Result<usize>;
+
+    /// Whether operations are kept in an operation log (see `append_ops`)
+    fn logs_ops(&self) -> bool {
+        false
+    }
+
+    /// Record operations written or applied in the operation log, if one is kept
+    fn append_ops(&self, _ops: &[Operation]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Every logged operation `vv` has not seen, in causal (per actor counter) order
+    ///
+    /// The flag is false if the log does not hold every operation `vv` has not seen:
+    /// some were truncated as causally stable, or came before the log was kept.
+    fn ops_since(&self, _vv: &VersionVector) -> Result<(Vec<Operation>, bool)> {
+        Ok((Vec::new(), false))
+    }
+
+    /// Drop the logged operations `stable` covers, which every replica has seen
+    ///
+    /// Returns the number dropped.
+    fn truncate_ops(&self, _stable: &VersionVector) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Give back disk space the backend no longer needs, compacting harder with `vacuum`.
+    /// Returns the bytes freed; nothing to do by default.
+    fn maintenance(&self, vacuum: bool) -> Result<u64> {
+        let _ = vacuum;
+        Ok(0)
+    }
+
+    /// Write the write-ahead log back to the database and empty it, without the rest of
+    /// `maintenance`. Nothing to do by default.
+    fn checkpoint(&self) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            frames: 0,
+            truncated: true,
+        })
+    }
+
+    /// Digest of the whole set, a hash over the sorted (element, sorted dots) tuples.
+    /// Two replicas with the same elements supported by the same dots have the same digest.
+    fn set_digest(&self, set_name: &str) -> Result<Digest> {
+        Ok(self.bucket_digests(set_name, 1)?[0])
+    }
+
+    fn bucket_digests(&self, set_name: &str, bucket_count: u32) -> Result<Vec<Digest>>;
+
+    fn elements_since(
+        &self,
+        set_name: &str,
+        vv: &VersionVector,
+        buckets: &[u32],
+        bucket_count: u32,
+    ) -> Result<Vec<ElementDots>>;
+
+    fn snapshot(&self) -> Result<(VersionVector, Vec<SetSnapshot>)>;
+
+    fn bulk_load_snapshot(&self, vv: &VersionVector, sets: &[SetSnapshot]) -> Result<()>;
+
+    /// Write every set, element, dot and the version vector to `writer` as a backup,
+    /// in the engine independent format of `write_snapshot`
+    fn export_snapshot(&self, writer: &mut dyn Write) -> Result<()> {
+        let (vv, sets) = self.snapshot()?;
+        write_snapshot(writer, &vv, &sets)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+    }
+
+    /// Replace all local state with a backup written by `export_snapshot`, returning its
+    /// version vector. The whole stream is read before anything is replaced, so a
+    /// malformed backup leaves the local state alone.
+    fn import_snapshot(&self, reader: &mut dyn Read) -> Result<VersionVector> {
+        let (vv, sets) = read_snapshot(reader)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.bulk_load_snapshot(&vv, &sets)?;
+        Ok(vv)
+    }
+
+    fn merge_elements(
+        &self,
+        set_name: &str,
+        remote: &[ElementDots],
+        remote_vv: &VersionVector,
+        local_vv: &VersionVector,
+    ) -> Result<usize>;
+
+    /// Raise the stored version vector to `dot` without touching any set, for an operation
+    /// whose effect arrived as state (a delta) rather than as the operation itself
+    fn observe_dot(&self, dot: Dot) -> Result<()>;
+
+    /// The epoch this node last chose to write at, if the backend keeps it (see
+    /// `NodeBuilder::build`)
+    fn load_epoch(&self) -> Result<Option<u8>> {
+        Ok(None)
+    }
+
+    /// Keep the epoch this node writes at, so a restart never goes back to an older one
+    fn save_epoch(&self, _epoch: u8) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes a set into digest buckets, fed its (element, dot) pairs in (element, actor, counter) order
+///
+/// Each element is hashed once, followed by each of its dots. Fields are tagged and
+/// length-prefixed so the encoding is unambiguous.
+pub struct BucketDigests {
+    hashers: Vec<blake3::Hasher>,
+    current: Option<(Vec<u8>, usize)>,
+}
+
+impl BucketDigests {
+    pub fn new(bucket_count: u32) -> Self {
+        Self {
+            hashers: vec![blake3::Hasher::new(); bucket_count.max(1) as usize],
+            current: None,
+        }
+    }
+
+    pub fn add(&mut self, value: &[u8], dot: Dot) {
+        let bucket = match &self.current {
+            Some((last, bucket)) if last.as_slice() == value => *bucket,
+            _ => {
+                let bucket = digest_bucket(value, self.hashers.len() as u32) as usize;
+                let hasher = &mut self.hashers[bucket];
+                hasher.update(b"e");
+                hasher.update(&(value.len() as u64).to_be_bytes());
+                hasher.update(value);
+                self.current = Some((value.to_vec(), bucket));
+                bucket
+            }
+        };
+
+        let hasher = &mut self.hashers[bucket];
+        hasher.update(b"d");
+        hasher.update(dot.actor_id.bytes());
+        hasher.update(&dot.counter.to_be_bytes());
+    }
+
+    pub fn finish(self) -> Vec<Digest> {
+        self.hashers
+            .iter()
+            .map(|h| *h.finalize().as_bytes())
+            .collect()
+    }
+}
@@ -0,0 +1,662 @@
+use super::{BucketDigests, Digest, Storage, all_seen, digest_bucket};
+use crate::types::{ActorId, Dot, ElementDots, SetSnapshot, VersionVector};
+use ::rocksdb::{
+    ColumnFamily, DB, Direction, IteratorMode, Options, ReadOptions, Snapshot, WriteBatch,
+};
+use bytes::Bytes;
+use rusqlite::Result;
+use std::path::Path;
+use std::sync::Mutex;
+
+const SETS: &str = "sets";
+const ELEMENTS: &str = "elements";
+const DOTS: &str = "dots";
+const VERSION_VECTOR: &str = "version_vector";
+
+type KeyValue = (Box<[u8]>, Box<[u8]>);
+
+/// RocksDB implementation of the Storage trait
+///
+/// One column family per SQLite table, keyed so every operation is a prefix scan:
+/// - sets: set name
+/// - elements: `len(set) ++ set ++ value`, so a set's elements scan in value order
+/// - dots: `len(set) ++ set ++ len(value) ++ value ++ actor` => counter,
+///   so an element's dots scan in actor order, at most one per actor
+/// - version_vector: actor => counter
+///
+/// Where SQLite deletes and returns the existing dots in one transaction, here the
+/// existing dots are read and the deletes and inserts go in one WriteBatch. Writers
+/// are serialised by `writer`, so nothing can change between the read and the write;
+/// readers never see half a batch.
+#[derive(Debug)]
+pub struct RocksdbStorage {
+    db: DB,
+    writer: Mutex<()>,
+}
+
+fn db_err(e: ::rocksdb::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+}
+
+/// `len(set_name) ++ set_name`, the prefix of all a set's elements and dots
+fn set_prefix(set_name: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(4 + set_name.len());
+    key.extend_from_slice(&(set_name.len() as u32).to_be_bytes());
+    key.extend_from_slice(set_name.as_bytes());
+    key
+}
+
+fn element_key(set_name: &str, value: &[u8]) -> Vec<u8> {
+    let mut key = set_prefix(set_name);
+    key.extend_from_slice(value);
+    key
+}
+
+/// The prefix of one element's dots. The value is length prefixed, as unlike in
+/// an element key it is followed by the actor.
+fn dots_prefix(set_name: &str, value: &[u8]) -> Vec<u8> {
+    let mut key = set_prefix(set_name);
+    key.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    key.extend_from_slice(value);
+    key
+}
+
+fn dot_key(dots_prefix: &[u8], actor_id: ActorId) -> Vec<u8> {
+    let mut key = dots_prefix.to_vec();
+    key.extend_from_slice(actor_id.bytes());
+    key
+}
+
+fn decode_counter(bytes: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = bytes.try_into().map_err(|_| {
+        rusqlite::Error::ToSqlConversionFailure(
+            format!("counter is {} bytes, not 8", bytes.len()).into(),
+        )
+    })?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn decode_dot(actor_id: &[u8], counter: &[u8]) -> Result<Dot> {
+    let actor_id = ActorId::from_bytes(actor_id)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    Ok(Dot::new(actor_id, decode_counter(counter)?))
+}
+
+/// The ORSWOT join of one element's dots, see `SqliteStorage::merge_elements`
+fn join_dots(
+    local_dots: &[Dot],
+    remote_dots: &[Dot],
+    remote_vv: &VersionVector,
+    local_vv: &VersionVector,
+) -> Vec<Dot> {
+    let mut merged: Vec<Dot> = local_dots
+        .iter()
+        .filter(|dot| remote_dots.contains(dot) || !remote_vv.contains_dot(**dot))
+        .copied()
+        .collect();
+    for dot in remote_dots {
+        if !local_vv.contains_dot(*dot) && !merged.contains(dot) {
+            merged.push(*dot);
+        }
+    }
+
+    // At most one dot per actor per element: the later one has joined the earlier
+    merged.sort_by_key(|dot| std::cmp::Reverse(dot.counter));
+    let mut seen_actors = Vec::new();
+    merged.retain(|dot| {
+        let first = !seen_actors.contains(&dot.actor_id);
+        seen_actors.push(dot.actor_id);
+        first
+    });
+    merged
+}
+
+impl RocksdbStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+
+        let db =
+            DB::open_cf(&options, path, [SETS, ELEMENTS, DOTS, VERSION_VECTOR]).map_err(db_err)?;
+
+        Ok(RocksdbStorage {
+            db,
+            writer: Mutex::new(()),
+        })
+    }
+
+    fn cf(&self, name: &str) -> &ColumnFamily {
+        self.db
+            .cf_handle(name)
+            .expect("column families are created on open")
+    }
+
+    /// Every (key, value) in `cf` starting with `prefix`, in key order, read from
+    /// `snapshot` if given
+    fn scan<'a>(
+        &'a self,
+        cf: &str,
+        prefix: Vec<u8>,
+        snapshot: Option<&Snapshot>,
+    ) -> impl Iterator<Item = Result<KeyValue>> + 'a {
+        let mut read_options = ReadOptions::default();
+        if let Some(snapshot) = snapshot {
+            read_options.set_snapshot(snapshot);
+        }
+
+        let iter = self.db.iterator_cf_opt(
+            self.cf(cf),
+            read_options,
+            IteratorMode::From(&prefix, Direction::Forward),
+        );
+        iter.take_while(move |item| {
+            item.as_ref()
+                .map_or(true, |(key, _)| key.starts_with(&prefix))
+        })
+        .map(|item| item.map_err(db_err))
+    }
+
+    /// The values of a set's elements, in value order
+    fn values<'a>(
+        &'a self,
+        set_name: &str,
+        snapshot: Option<&Snapshot>,
+    ) -> impl Iterator<Item = Result<Bytes>> + 'a {
+        let prefix = set_prefix(set_name);
+        let skip = prefix.len();
+        self.scan(ELEMENTS, prefix, snapshot)
+            .map(move |item| item.map(|(key, _)| Bytes::copy_from_slice(&key[skip..])))
+    }
+
+    /// An element's dots, in actor order
+    fn element_dots(&self, dots_prefix: &[u8], snapshot: Option<&Snapshot>) -> Result<Vec<Dot>> {
+        self.scan(DOTS, dots_prefix.to_vec(), snapshot)
+            .map(|item| {
+                let (key, counter) = item?;
+                decode_dot(&key[dots_prefix.len()..], &counter)
+            })
+            .collect()
+    }
+
+    fn read_vv(&self, snapshot: Option<&Snapshot>) -> Result<VersionVector> {
+        let mut vv = VersionVector::new();
+        for item in self.scan(VERSION_VECTOR, vec![], snapshot) {
+            let (actor_id, counter) = item?;
+            let dot = decode_dot(&actor_id, &counter)?;
+            vv.update(dot.actor_id, dot.counter);
+        }
+        Ok(vv)
+    }
+
+    /// Adds raising the version vector to `dot` to the batch, if it is not there already
+    fn observe(&self, batch: &mut WriteBatch, dot: Dot) -> Result<()> {
+        let current = match self
+            .db
+            .get_cf(self.cf(VERSION_VECTOR), dot.actor_id.bytes())
+            .map_err(db_err)?
+        {
+            Some(counter) => decode_counter(&counter)?,
+            None => 0,
+        };
+        if current < dot.counter {
+            batch.put_cf(
+                self.cf(VERSION_VECTOR),
+                dot.actor_id.bytes(),
+                dot.counter.to_be_bytes(),
+            );
+        }
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.db.write(batch).map_err(db_err)
+    }
+}
+
+impl Storage for RocksdbStorage {
+    fn load_vv(&self) -> Result<VersionVector> {
+        self.read_vv(None)
+    }
+
+    fn add_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+        if elements.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let _writer = self.writer.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut deleted = Vec::new();
+
+        batch.put_cf(self.cf(SETS), set_name, b"");
+        for value in elements {
+            let prefix = dots_prefix(set_name, value);
+            for existing in self.element_dots(&prefix, None)? {
+                batch.delete_cf(self.cf(DOTS), dot_key(&prefix, existing.actor_id));
+                deleted.push(existing);
+            }
+            batch.put_cf(self.cf(ELEMENTS), element_key(set_name, value), b"");
+            batch.put_cf(
+                self.cf(DOTS),
+                dot_key(&prefix, dot.actor_id),
+                dot.counter.to_be_bytes(),
+            );
+        }
+        self.observe(&mut batch, dot)?;
+
+        self.write(batch)?;
+        Ok(deleted)
+    }
+
+    fn remove_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+        if elements.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let _writer = self.writer.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut deleted = Vec::new();
+        // Nothing to remove from a set that doesn't exist, but the dot is still seen
+        let elements = if self.set_exists(set_name)? {
+            elements
+        } else {
+            &[]
+        };
+        for value in elements {
+            let prefix = dots_prefix(set_name, value);
+            for existing in self.element_dots(&prefix, None)? {
+                batch.delete_cf(self.cf(DOTS), dot_key(&prefix, existing.actor_id));
+                deleted.push(existing);
+            }
+            batch.delete_cf(self.cf(ELEMENTS), element_key(set_name, value));
+        }
+        self.observe(&mut batch, dot)?;
+
+        self.write(batch)?;
+        Ok(deleted)
+    }
+
+    fn delete_set(&self, set_name: &str) -> Result<Vec<Dot>> {
+        let _writer = self.writer.lock().unwrap();
+        if !self.set_exists(set_name)? {
+            return Ok(vec![]);
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut deleted = Vec::new();
+        for value in self.values(set_name, None) {
+            let value = value?;
+            let prefix = dots_prefix(set_name, &value);
+            for dot in self.element_dots(&prefix, None)? {
+                batch.delete_cf(self.cf(DOTS), dot_key(&prefix, dot.actor_id));
+                deleted.push(dot);
+            }
+            batch.delete_cf(self.cf(ELEMENTS), element_key(set_name, &value));
+        }
+        batch.delete_cf(self.cf(SETS), set_name);
+
+        self.write(batch)?;
+        Ok(deleted)
+    }
+
+    /// In name order
+    fn list_sets(&self) -> Result<Vec<String>> {
+        self.scan(SETS, vec![], None)
+            .map(|set_name| {
+                let (set_name, _) = set_name?;
+                String::from_utf8(set_name.to_vec())
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+            })
+            .collect()
+    }
+
+    fn get_elements(&self, set_name: &str) -> Result<Vec<Bytes>> {
+        self.values(set_name, None).collect()
+    }
+
+    fn count_elements(&self, set_name: &str) -> Result<i64> {
+        // Every element has at least one dot, an element's last dot goes with it
+        let mut count = 0;
+        for value in self.values(set_name, None) {
+            value?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn set_exists(&self, set_name: &str) -> Result<bool> {
+        Ok(self
+            .db
+            .get_cf(self.cf(SETS), set_name)
+            .map_err(db_err)?
+            .is_some())
+    }
+
+    /// One scan of the set's dots, each key's actor after its length prefixed value
+    fn set_vv(&self, set_name: &str) -> Result<VersionVector> {
+        let prefix = set_prefix(set_name);
+        let mut vv = VersionVector::new();
+        for item in self.scan(DOTS, prefix.clone(), None) {
+            let (key, counter) = item?;
+            let rest = &key[prefix.len()..];
+            let value_len = rest
+                .first_chunk::<4>()
+                .map(|len| u32::from_be_bytes(*len) as usize)
+                .filter(|len| 4 + len <= rest.len())
+                .ok_or_else(|| {
+                    rusqlite::Error::ToSqlConversionFailure("truncated dot key".into())
+                })?;
+            let dot = decode_dot(&rest[4 + value_len..], &counter)?;
+            vv.update(dot.actor_id, dot.counter);
+        }
+        Ok(vv)
+    }
+
+    fn is_member(&self, set_name: &str, element: &Bytes) -> Result<bool> {
+        Ok(self
+            .db
+            .get_cf(self.cf(ELEMENTS), element_key(set_name, element))
+            .map_err(db_err)?
+            .is_some())
+    }
+
+    fn are_members(&self, set_name: &str, elements: &[Bytes]) -> Result<Vec<bool>> {
+        let cf = self.cf(ELEMENTS);
+        self.db
+            .multi_get_cf(
+                elements
+                    .iter()
+                    .map(|element| (cf, element_key(set_name, element))),
+            )
+            .into_iter()
+            .map(|value| Ok(value.map_err(db_err)?.is_some()))
+            .collect()
+    }
+
+    fn replicate_add(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        let _writer = self.writer.lock().unwrap();
+        let mut batch = WriteBatch::default();
+
+        batch.put_cf(self.cf(SETS), set_name, b"");
+        for value in elements {
+            let prefix = dots_prefix(set_name, value);
+            let mut counter = dot.counter;
+            for existing in self.element_dots(&prefix, None)? {
+                if removed_dots.contains(&existing) {
+                    batch.delete_cf(self.cf(DOTS), dot_key(&prefix, existing.actor_id));
+                } else if existing.actor_id == dot.actor_id {
+                    counter = counter.max(existing.counter);
+                }
+            }
+            batch.put_cf(self.cf(ELEMENTS), element_key(set_name, value), b"");
+            batch.put_cf(
+                self.cf(DOTS),
+                dot_key(&prefix, dot.actor_id),
+                counter.to_be_bytes(),
+            );
+        }
+        self.observe(&mut batch, dot)?;
+
+        self.write(batch)
+    }
+
+    fn replicate_remove(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        let _writer = self.writer.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        // Nothing to remove from a set that doesn't exist, but the dot is still seen
+        let elements = if self.set_exists(set_name)? {
+            elements
+        } else {
+            &[]
+        };
+        for value in elements {
+            let prefix = dots_prefix(set_name, value);
+            let existing = self.element_dots(&prefix, None)?;
+            if existing.is_empty() {
+                continue;
+            }
+
+            let mut remaining = existing.len();
+            for existing in existing {
+                if removed_dots.contains(&existing) {
+                    batch.delete_cf(self.cf(DOTS), dot_key(&prefix, existing.actor_id));
+                    remaining -= 1;
+                }
+            }
+            if remaining == 0 {
+                batch.delete_cf(self.cf(ELEMENTS), element_key(set_name, value));
+            }
+        }
+        self.observe(&mut batch, dot)?;
+
+        self.write(batch)
+    }
+
+    fn covered_dots(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        vv: &VersionVector,
+    ) -> Result<Vec<Dot>> {
+        let mut covered = Vec::new();
+        for value in elements {
+            let dots = self.element_dots(&dots_prefix(set_name, value), None)?;
+            covered.extend(dots.into_iter().filter(|dot| vv.contains_dot(*dot)));
+        }
+        Ok(covered)
+    }
+
+    fn compact_dots(&self, stable: &VersionVector, set_name: Option<&str>) -> Result<usize> {
+        let _writer = self.writer.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut deleted = 0;
+
+        let set_names = match set_name {
+            Some(set_name) => vec![set_name.to_string()],
+            None => self.list_sets()?,
+        };
+
+        for set_name in &set_names {
+            for value in self.values(set_name, None) {
+                let prefix = dots_prefix(set_name, &value?);
+                let mut dots = self.element_dots(&prefix, None)?;
+                if dots.len() < 2 || !dots.iter().all(|dot| stable.contains_dot(*dot)) {
+                    continue;
+                }
+                dots.sort_by_key(|dot| (dot.counter, dot.actor_id));
+                dots.pop();
+                for dot in dots {
+                    batch.delete_cf(self.cf(DOTS), dot_key(&prefix, dot.actor_id));
+                    deleted += 1;
+                }
+            }
+        }
+
+        self.write(batch)?;
+        Ok(deleted)
+    }
+
+    /// Elements are scanned in value order and each element's dots in actor order,
+    /// the order `BucketDigests` hashes in, so digests match replicas on other backends
+    fn bucket_digests(&self, set_name: &str, bucket_count: u32) -> Result<Vec<Digest>> {
+        let snapshot = self.db.snapshot();
+        let mut digests = BucketDigests::new(bucket_count);
+
+        for value in self.values(set_name, Some(&snapshot)) {
+            let value = value?;
+            for dot in self.element_dots(&dots_prefix(set_name, &value), Some(&snapshot))? {
+                digests.add(&value, dot);
+            }
+        }
+
+        Ok(digests.finish())
+    }
+
+    fn elements_since(
+        &self,
+        set_name: &str,
+        vv: &VersionVector,
+        buckets: &[u32],
+        bucket_count: u32,
+    ) -> Result<Vec<ElementDots>> {
+        let snapshot = self.db.snapshot();
+        let mut elements = Vec::new();
+
+        for value in self.values(set_name, Some(&snapshot)) {
+            let value = value?;
+            if !buckets.is_empty() && !buckets.contains(&digest_bucket(&value, bucket_count)) {
+                continue;
+            }
+            let dots = self.element_dots(&dots_prefix(set_name, &value), Some(&snapshot))?;
+            if dots.iter().any(|dot| !vv.contains_dot(*dot)) {
+                elements.push((value, dots));
+            }
+        }
+
+        Ok(elements)
+    }
+
+    /// Read from one RocksDB snapshot, so the version vector covers exactly the dots returned
+    fn snapshot(&self) -> Result<(VersionVector, Vec<SetSnapshot>)> {
+        let snapshot = self.db.snapshot();
+        let vv = self.read_vv(Some(&snapshot))?;
+
+        let mut sets = Vec::new();
+        for set_name in self.scan(SETS, vec![], Some(&snapshot)) {
+            let (set_name, _) = set_name?;
+            let set_name = String::from_utf8(set_name.into_vec())
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+            let mut elements = Vec::new();
+            for value in self.values(&set_name, Some(&snapshot)) {
+                let value = value?;
+                let dots = self.element_dots(&dots_prefix(&set_name, &value), Some(&snapshot))?;
+                if !dots.is_empty() {
+                    elements.push((value, dots));
+                }
+            }
+            if !elements.is_empty() {
+                sets.push((set_name, elements));
+            }
+        }
+
+        Ok((vv, sets))
+    }
+
+    fn bulk_load_snapshot(&self, vv: &VersionVector, sets: &[SetSnapshot]) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut batch = WriteBatch::default();
+
+        // Replaces everything: later puts of the same keys win over these deletes
+        for cf in [SETS, ELEMENTS, DOTS, VERSION_VECTOR] {
+            for item in self.scan(cf, vec![], None) {
+                let (key, _) = item?;
+                batch.delete_cf(self.cf(cf), key);
+            }
+        }
+
+        for (actor_id, counter) in &vv.counters {
+            batch.put_cf(
+                self.cf(VERSION_VECTOR),
+                actor_id.bytes(),
+                counter.to_be_bytes(),
+            );
+        }
+        for (set_name, elements) in sets {
+            batch.put_cf(self.cf(SETS), set_name, b"");
+            for (value, dots) in elements {
+                let prefix = dots_prefix(set_name, value);
+                batch.put_cf(self.cf(ELEMENTS), element_key(set_name, value), b"");
+                for dot in dots {
+                    batch.put_cf(
+                        self.cf(DOTS),
+                        dot_key(&prefix, dot.actor_id),
+                        dot.counter.to_be_bytes(),
+                    );
+                }
+            }
+        }
+
+        self.write(batch)
+    }
+
+    fn merge_elements(
+        &self,
+        set_name: &str,
+        remote: &[ElementDots],
+        remote_vv: &VersionVector,
+        local_vv: &VersionVector,
+    ) -> Result<usize> {
+        if remote.is_empty() {
+            return Ok(0);
+        }
+
+        let _writer = self.writer.lock().unwrap();
+        // A set we don't have is only created for an element merged in, not for removals
+        if !self.set_exists(set_name)? && all_seen(remote, local_vv) {
+            return Ok(0);
+        }
+        let mut batch = WriteBatch::default();
+        let mut changed = 0;
+
+        batch.put_cf(self.cf(SETS), set_name, b"");
+        for (value, remote_dots) in remote {
+            let prefix = dots_prefix(set_name, value);
+            let local_dots = self.element_dots(&prefix, None)?;
+            let merged = join_dots(&local_dots, remote_dots, remote_vv, local_vv);
+
+            let unchanged = merged.len() == local_dots.len()
+                && merged.iter().all(|dot| local_dots.contains(dot));
+            if unchanged {
+                continue;
+            }
+            changed += 1;
+
+            for dot in &local_dots {
+                batch.delete_cf(self.cf(DOTS), dot_key(&prefix, dot.actor_id));
+            }
+            if merged.is_empty() {
+                batch.delete_cf(self.cf(ELEMENTS), element_key(set_name, value));
+                continue;
+            }
+            batch.put_cf(self.cf(ELEMENTS), element_key(set_name, value), b"");
+            for dot in &merged {
+                batch.put_cf(
+                    self.cf(DOTS),
+                    dot_key(&prefix, dot.actor_id),
+                    dot.counter.to_be_bytes(),
+                );
+            }
+        }
+
+        self.write(batch)?;
+        Ok(changed)
+    }
+
+    fn observe_dot(&self, dot: Dot) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        self.observe(&mut batch, dot)?;
+        self.write(batch)
+    }
+}
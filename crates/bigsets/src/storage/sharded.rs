@@ -0,0 +1,564 @@
+use super::{Checkpoint, Digest, GroupWrite, SqliteStorage, Storage, digest_bucket};
+use crate::config::StorageConfig;
+use crate::types::{ActorId, Dot, ElementDots, SetAdd, SetInfo, SetSnapshot, VersionVector};
+use bytes::Bytes;
+use rusqlite::Result;
+use std::path::Path;
+
+/// The `meta` key shard 0 keeps the number of shards under
+const SHARDS_KEY: &str = "shards";
+
+/// Sets spread over several SQLite databases, for write heavy workloads over many sets
+///
+/// A set lives in the shard its name hashes to, each shard a `SqliteStorage` in a file
+/// of its own with its own read pool and writer. Methods on one set go to its shard.
+/// `commit_group` and the multi-set adds split their writes by shard and commit the
+/// shards in parallel, so writes to sets in different shards don't queue for one
+/// database file.
+///
+/// Each shard's version vector only counts the dots written to it, and the node's is
+/// their merge: an actor's counter is the highest any shard has. A multi-set add that
+/// spans shards is committed shard by shard, so a crash between them leaves it in some
+/// shards only, until anti-entropy repairs the sets it missed.
+///
+/// The number of shards is kept in shard 0 and can't change, as the sets would all
+/// move. Shard 0 also keeps the epoch. There is no operation log: one shard's version
+/// vector can't say which operations a log started on it would be missing.
+#[derive(Clone, Debug)]
+pub struct ShardedStorage {
+    shards: Vec<SqliteStorage>,
+}
+
+impl ShardedStorage {
+    /// Open (or create) `shards` databases, `shard-0.db` onwards, in the directory `dir`
+    pub fn open<P: AsRef<Path>>(dir: P, shards: u32, config: &StorageConfig) -> Result<Self> {
+        let invalid = |message: String| rusqlite::Error::ToSqlConversionFailure(message.into());
+        if shards < 1 {
+            return Err(invalid(
+                "sharded storage needs at least 1 shard".to_string(),
+            ));
+        }
+        if config.op_log {
+            return Err(invalid(
+                "sharded storage can't keep an operation log".to_string(),
+            ));
+        }
+
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let path = |shard: u32| dir.join(format!("shard-{}.db", shard));
+
+        let first = SqliteStorage::open(path(0), config)?;
+        match first.load_meta(SHARDS_KEY)? {
+            Some(stored) if stored != i64::from(shards) => {
+                return Err(invalid(format!(
+                    "{} has {} shards, not {}",
+                    dir.display(),
+                    stored,
+                    shards
+                )));
+            }
+            Some(_) => {}
+            None => first.save_meta(SHARDS_KEY, shards.into())?,
+        }
+
+        let mut opened = vec![first];
+        for shard in 1..shards {
+            opened.push(SqliteStorage::open(path(shard), config)?);
+        }
+        Ok(Self { shards: opened })
+    }
+
+    /// The shard a set lives in, by the same stable hash digests bucket elements by
+    fn shard_index(&self, set_name: &str) -> usize {
+        digest_bucket(set_name.as_bytes(), self.shards.len() as u32) as usize
+    }
+
+    fn shard(&self, set_name: &str) -> &SqliteStorage {
+        &self.shards[self.shard_index(set_name)]
+    }
+
+    /// Hand each shard the items (in order) on its sets, all shards at once, and return
+    /// each item's result in the order of `items`
+    ///
+    /// `write` gets a shard and its items, and returns a result per item.
+    fn by_shard<'a, I, T, F>(
+        &self,
+        items: &'a [I],
+        set_name: fn(&I) -> &str,
+        write: F,
+    ) -> Result<Vec<T>>
+    where
+        I: Sync,
+        T: Send,
+        F: Fn(&SqliteStorage, Vec<&'a I>) -> Result<Vec<T>> + Sync,
+    {
+        let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            let shard = self.shard_index(set_name(item));
+            match groups.iter_mut().find(|(s, _)| *s == shard) {
+                Some((_, group)) => group.push(index),
+                None => groups.push((shard, vec![index])),
+            }
+        }
+
+        let run = |(shard, group): &(usize, Vec<usize>)| {
+            write(
+                &self.shards[*shard],
+                group.iter().map(|&i| &items[i]).collect(),
+            )
+        };
+        let results: Vec<Result<Vec<T>>> = if groups.len() == 1 {
+            vec![run(&groups[0])]
+        } else {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = groups
+                    .iter()
+                    .map(|group| scope.spawn(move || run(group)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("shard write panicked"))
+                    .collect()
+            })
+        };
+
+        let mut ordered: Vec<Option<T>> = items.iter().map(|_| None).collect();
+        for ((_, group), result) in groups.iter().zip(results) {
+            for (&index, value) in group.iter().zip(result?) {
+                ordered[index] = Some(value);
+            }
+        }
+        Ok(ordered
+            .into_iter()
+            .map(|value| value.expect("a result for every item"))
+            .collect())
+    }
+}
+
+impl Storage for ShardedStorage {
+    fn load_vv(&self) -> Result<VersionVector> {
+        let mut vv = VersionVector::new();
+        for shard in &self.shards {
+            vv.merge(&shard.load_vv()?);
+        }
+        Ok(vv)
+    }
+
+    fn add_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+        self.shard(set_name).add_elements(set_name, elements, dot)
+    }
+
+    fn add_elements_multi(&self, sets: &[(&str, &[Bytes])], dot: Dot) -> Result<Vec<Vec<Dot>>> {
+        self.by_shard(
+            sets,
+            |(set_name, _)| set_name,
+            |shard, sets| {
+                let sets: Vec<(&str, &[Bytes])> = sets.into_iter().copied().collect();
+                shard.add_elements_multi(&sets, dot)
+            },
+        )
+    }
+
+    fn add_elements_expiring(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        dot: Dot,
+        expires_at: u64,
+    ) -> Result<Vec<Dot>> {
+        self.shard(set_name)
+            .add_elements_expiring(set_name, elements, dot, expires_at)
+    }
+
+    fn remove_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+        self.shard(set_name)
+            .remove_elements(set_name, elements, dot)
+    }
+
+    fn commit_group(&self, writes: &[GroupWrite]) -> Result<Vec<Vec<Dot>>> {
+        self.by_shard(
+            writes,
+            |write| write.set_name,
+            |shard, writes| {
+                let writes: Vec<GroupWrite> = writes.into_iter().copied().collect();
+                shard.commit_group(&writes)
+            },
+        )
+    }
+
+    fn delete_set(&self, set_name: &str) -> Result<Vec<Dot>> {
+        self.shard(set_name).delete_set(set_name)
+    }
+
+    /// Shard by shard, each in creation order
+    fn list_sets(&self) -> Result<Vec<String>> {
+        let mut sets = Vec::new();
+        for shard in &self.shards {
+            sets.extend(shard.list_sets()?);
+        }
+        Ok(sets)
+    }
+
+    fn get_elements(&self, set_name: &str) -> Result<Vec<Bytes>> {
+        self.shard(set_name).get_elements(set_name)
+    }
+
+    fn stream_elements(
+        &self,
+        set_name: &str,
+        pattern: Option<&[u8]>,
+        batch_size: usize,
+        on_len: &mut dyn FnMut(u64),
+        on_batch: &mut dyn FnMut(Vec<Bytes>) -> bool,
+    ) -> Result<()> {
+        self.shard(set_name)
+            .stream_elements(set_name, pattern, batch_size, on_len, on_batch)
+    }
+
+    fn random_elements(&self, set_name: &str, count: usize, repeats: bool) -> Result<Vec<Bytes>> {
+        self.shard(set_name)
+            .random_elements(set_name, count, repeats)
+    }
+
+    fn count_elements(&self, set_name: &str) -> Result<i64> {
+        self.shard(set_name).count_elements(set_name)
+    }
+
+    fn set_info(&self, set_name: &str) -> Result<Option<SetInfo>> {
+        self.shard(set_name).set_info(set_name)
+    }
+
+    fn set_exists(&self, set_name: &str) -> Result<bool> {
+        self.shard(set_name).set_exists(set_name)
+    }
+
+    fn is_member(&self, set_name: &str, element: &Bytes) -> Result<bool> {
+        self.shard(set_name).is_member(set_name, element)
+    }
+
+    fn are_members(&self, set_name: &str, elements: &[Bytes]) -> Result<Vec<bool>> {
+        self.shard(set_name).are_members(set_name, elements)
+    }
+
+    fn set_actors(&self, set_name: &str) -> Result<Vec<ActorId>> {
+        self.shard(set_name).set_actors(set_name)
+    }
+
+    fn set_vv(&self, set_name: &str) -> Result<VersionVector> {
+        self.shard(set_name).set_vv(set_name)
+    }
+
+    fn element_dots(&self, set_name: &str, element: &Bytes) -> Result<Vec<Dot>> {
+        self.shard(set_name).element_dots(set_name, element)
+    }
+
+    fn replicate_add(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        self.shard(set_name)
+            .replicate_add(set_name, elements, removed_dots, dot)
+    }
+
+    fn replicate_add_expiring(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+        expires_at: u64,
+    ) -> Result<()> {
+        self.shard(set_name).replicate_add_expiring(
+            set_name,
+            elements,
+            removed_dots,
+            dot,
+            expires_at,
+        )
+    }
+
+    /// Up to `limit` elements, from the first shards to have any
+    fn expired_elements(&self, now: u64, limit: usize) -> Result<Vec<(String, Vec<Bytes>)>> {
+        let mut expired = Vec::new();
+        let mut left = limit;
+        for shard in &self.shards {
+            if left == 0 {
+                break;
+            }
+            for (set_name, elements) in shard.expired_elements(now, left)? {
+                left = left.saturating_sub(elements.len());
+                expired.push((set_name, elements));
+            }
+        }
+        Ok(expired)
+    }
+
+    fn replicate_add_multi(&self, sets: &[SetAdd], dot: Dot) -> Result<()> {
+        self.by_shard(
+            sets,
+            |set| &set.set_name,
+            |shard, sets| {
+                let count = sets.len();
+                let sets: Vec<SetAdd> = sets.into_iter().cloned().collect();
+                shard.replicate_add_multi(&sets, dot)?;
+                Ok(vec![(); count])
+            },
+        )?;
+        Ok(())
+    }
+
+    fn replicate_remove(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        self.shard(set_name)
+            .replicate_remove(set_name, elements, removed_dots, dot)
+    }
+
+    fn covered_dots(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        vv: &VersionVector,
+    ) -> Result<Vec<Dot>> {
+        self.shard(set_name).covered_dots(set_name, elements, vv)
+    }
+
+    fn compact_dots(&self, stable: &VersionVector, set_name: Option<&str>) -> Result<usize> {
+        if let Some(set_name) = set_name {
+            return self.shard(set_name).compact_dots(stable, Some(set_name));
+        }
+        let mut compacted = 0;
+        for shard in &self.shards {
+            compacted += shard.compact_dots(stable, None)?;
+        }
+        Ok(compacted)
+    }
+
+    fn maintenance(&self, vacuum: bool) -> Result<u64> {
+        let mut freed = 0;
+        for shard in &self.shards {
+            freed += shard.maintenance(vacuum)?;
+        }
+        Ok(freed)
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint> {
+        let mut checkpoint = Checkpoint {
+            frames: 0,
+            truncated: true,
+        };
+        for shard in &self.shards {
+            let shard = shard.checkpoint()?;
+            checkpoint.frames += shard.frames;
+            checkpoint.truncated &= shard.truncated;
+        }
+        Ok(checkpoint)
+    }
+
+    fn set_digest(&self, set_name: &str) -> Result<Digest> {
+        self.shard(set_name).set_digest(set_name)
+    }
+
+    fn bucket_digests(&self, set_name: &str, bucket_count: u32) -> Result<Vec<Digest>> {
+        self.shard(set_name).bucket_digests(set_name, bucket_count)
+    }
+
+    fn elements_since(
+        &self,
+        set_name: &str,
+        vv: &VersionVector,
+        buckets: &[u32],
+        bucket_count: u32,
+    ) -> Result<Vec<ElementDots>> {
+        self.shard(set_name)
+            .elements_since(set_name, vv, buckets, bucket_count)
+    }
+
+    /// Each shard's snapshot, with the merge of their version vectors. A shard only
+    /// counts the dots it holds, so the merge claims no dot a shard left out.
+    fn snapshot(&self) -> Result<(VersionVector, Vec<SetSnapshot>)> {
+        let mut vv = VersionVector::new();
+        let mut sets = Vec::new();
+        for shard in &self.shards {
+            let (shard_vv, shard_sets) = shard.snapshot()?;
+            vv.merge(&shard_vv);
+            sets.extend(shard_sets);
+        }
+        Ok((vv, sets))
+    }
+
+    /// Every shard is replaced, those with none of the sets left empty
+    fn bulk_load_snapshot(&self, vv: &VersionVector, sets: &[SetSnapshot]) -> Result<()> {
+        let mut by_shard: Vec<Vec<SetSnapshot>> = vec![Vec::new(); self.shards.len()];
+        for set in sets {
+            by_shard[self.shard_index(&set.0)].push(set.clone());
+        }
+        for (shard, sets) in self.shards.iter().zip(by_shard) {
+            shard.bulk_load_snapshot(vv, &sets)?;
+        }
+        Ok(())
+    }
+
+    fn merge_elements(
+        &self,
+        set_name: &str,
+        remote: &[ElementDots],
+        remote_vv: &VersionVector,
+        local_vv: &VersionVector,
+    ) -> Result<usize> {
+        self.shard(set_name)
+            .merge_elements(set_name, remote, remote_vv, local_vv)
+    }
+
+    fn observe_dot(&self, dot: Dot) -> Result<()> {
+        self.shards[0].observe_dot(dot)
+    }
+
+    fn load_epoch(&self) -> Result<Option<u8>> {
+        self.shards[0].load_epoch()
+    }
+
+    fn save_epoch(&self, epoch: u8) -> Result<()> {
+        self.shards[0].save_epoch(epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::WriteKind;
+    use tempfile::TempDir;
+
+    fn config() -> StorageConfig {
+        StorageConfig {
+            sqlite_cache_size: 1000,
+            sqlite_busy_timeout: 5000,
+            read_pool_size: 5,
+            pool_min_idle: 1,
+            checkpoint_interval_ms: 0,
+            bloom_min_elements: 0,
+            bloom_fp_rate: 0.01,
+            max_key_length: 1024,
+            max_element_size: 65536,
+            max_members_per_command: 100_000,
+            expiry_interval_ms: 1000,
+            op_log: false,
+            mmap_size: 0,
+            page_size: 0,
+            encryption: None,
+        }
+    }
+
+    fn dot(counter: u64) -> Dot {
+        Dot::new(ActorId::from_node_id(1), counter)
+    }
+
+    /// Set names that hash to every shard
+    fn spread(storage: &ShardedStorage) -> Vec<String> {
+        let mut names: Vec<Option<String>> = vec![None; storage.shards.len()];
+        for i in 0.. {
+            let name = format!("set-{}", i);
+            let slot = &mut names[storage.shard_index(&name)];
+            if slot.is_none() {
+                *slot = Some(name);
+            }
+            if names.iter().all(Option::is_some) {
+                break;
+            }
+        }
+        names.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn test_shard_count_is_kept() {
+        let temp = TempDir::new().unwrap();
+        drop(ShardedStorage::open(temp.path(), 4, &config()).unwrap());
+
+        assert!(ShardedStorage::open(temp.path(), 4, &config()).is_ok());
+        assert!(ShardedStorage::open(temp.path(), 2, &config()).is_err());
+        assert!(ShardedStorage::open(temp.path(), 0, &config()).is_err());
+        let logged = StorageConfig {
+            op_log: true,
+            ..config()
+        };
+        assert!(ShardedStorage::open(temp.path(), 4, &logged).is_err());
+    }
+
+    #[test]
+    fn test_group_commits_across_shards_in_order() {
+        let temp = TempDir::new().unwrap();
+        let storage = ShardedStorage::open(temp.path(), 4, &config()).unwrap();
+        let names = spread(&storage);
+        let (a, b) = (Bytes::from("a"), Bytes::from("b"));
+
+        let mut writes = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let i = i as u64 * 3;
+            writes.push(GroupWrite {
+                kind: WriteKind::Add,
+                set_name: name,
+                elements: std::slice::from_ref(&a),
+                dot: dot(i + 1),
+            });
+            writes.push(GroupWrite {
+                kind: WriteKind::Add,
+                set_name: name,
+                elements: std::slice::from_ref(&a),
+                dot: dot(i + 2),
+            });
+            writes.push(GroupWrite {
+                kind: WriteKind::Add,
+                set_name: name,
+                elements: std::slice::from_ref(&b),
+                dot: dot(i + 3),
+            });
+        }
+        let results = storage.commit_group(&writes).unwrap();
+
+        // Each set's second add replaced its first, wherever the set lives
+        for (i, name) in names.iter().enumerate() {
+            let i = i as u64 * 3;
+            assert_eq!(results[i as usize + 1], vec![dot(i + 1)]);
+            assert_eq!(storage.element_dots(name, &a).unwrap(), vec![dot(i + 2)]);
+            assert_eq!(storage.count_elements(name).unwrap(), 2);
+        }
+        assert_eq!(
+            storage.load_vv().unwrap().get(ActorId::from_node_id(1)),
+            names.len() as u64 * 3
+        );
+        assert_eq!(storage.list_sets().unwrap().len(), names.len());
+    }
+
+    #[test]
+    fn test_snapshot_round_trips_across_shards() {
+        let temp = TempDir::new().unwrap();
+        let storage = ShardedStorage::open(temp.path().join("from"), 3, &config()).unwrap();
+        let names = spread(&storage);
+        let members: Vec<Bytes> = vec![Bytes::from("x")];
+        let sets: Vec<(&str, &[Bytes])> = names
+            .iter()
+            .map(|name| (name.as_str(), members.as_slice()))
+            .collect();
+        storage.add_elements_multi(&sets, dot(1)).unwrap();
+
+        let (vv, snapshot) = storage.snapshot().unwrap();
+        assert_eq!(vv.get(ActorId::from_node_id(1)), 1);
+        assert_eq!(snapshot.len(), names.len());
+
+        let copy = ShardedStorage::open(temp.path().join("to"), 3, &config()).unwrap();
+        copy.bulk_load_snapshot(&vv, &snapshot).unwrap();
+        assert_eq!(copy.load_vv().unwrap(), vv);
+        for name in &names {
+            assert_eq!(copy.get_elements(name).unwrap(), members);
+        }
+    }
+}
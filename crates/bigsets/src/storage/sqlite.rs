@@ -1,57 +1,260 @@
-use crate::config::StorageConfig;
-use crate::types::{ActorId, Dot, VersionVector};
+use super::bloom::Bloom;
+use super::{
+    BucketDigests, Checkpoint, Digest, GroupWrite, Storage, WriteKind, all_seen, digest_bucket,
+    now_ms, random_ranks,
+};
+use crate::config::{GlobToken, StorageConfig, glob_token};
+use crate::proto;
+use crate::types::{
+    ActorId, Dot, ElementDots, Operation, SetAdd, SetInfo, SetSnapshot, VersionVector,
+};
 use bytes::Bytes;
+use prost::Message;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{Connection, OptionalExtension, Result, ToSql};
-use std::collections::HashMap;
-use std::path::Path;
-use tracing::trace;
+use rand::seq::SliceRandom;
+use rusqlite::{Connection, OptionalExtension, Result, ToSql, Transaction};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info, trace, warn};
 
 pub type DbPool = Pool<SqliteConnectionManager>;
 
+/// One change to the schema, applied in a transaction of its own
+struct Migration {
+    description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+}
+
+/// Every change to the schema, in order: applying the first `n` brings a database to
+/// schema version `n`. Only ever append to it; a shipped migration may already have run.
+///
+/// Databases from before `schema_version` are at version 0 with some of the first four
+/// already made, so those check for what is there.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "sets, elements, dots and the version vector",
+        apply: SqliteStorage::create_schema,
+    },
+    Migration {
+        description: "dots.expires_at",
+        apply: SqliteStorage::add_dot_expiry,
+    },
+    Migration {
+        description: "sets.created_at and sets.element_count",
+        apply: SqliteStorage::add_set_metadata,
+    },
+    Migration {
+        description: "meta",
+        apply: SqliteStorage::add_meta,
+    },
+];
+
+/// The schema version this build reads and writes
+const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Compiled statements each connection keeps, room for the fixed write path statements
+/// and a power of two bucket per replicated remove set size
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// Most removed dots one replicated add's DELETE names: with two variables each, the
+/// largest bucket stays well under SQLite's limit of 32766 per statement
+const MAX_REMOVED_DOTS_PER_DELETE: usize = 8192;
+
 /// SQLite implementation of the Storage trait
 /// All the AddWinsSet logic is in the sql.
 /// The purpose of bigsets is to not pay the price
 /// - of reading an entire set from disk and deserialising it before mutatating
 /// - nor after of reserialising it and writing it all back to disk
 /// See the add/remove_[remote]_elements methods for how the AddWins semantics are maintained.
+///
+/// Reads share a pool of connections; writes all go through `writer`, a pool of one,
+/// as the Server serialises them anyway and SQLite only has one writer at a time.
+///
+/// Sets of at least `bloom_min_elements` elements have a Bloom filter in `blooms`, so
+/// membership checks for absent elements skip the database. A filter always holds every
+/// committed element of its set: elements go in before the add commits, and filters are
+/// only built or rebuilt from the writer connection after a commit. A set without a
+/// filter is always checked in the database.
 #[derive(Clone, Debug)]
 pub struct SqliteStorage {
+    /// Read-only connections (`query_only`), up to `read_pool_size` of them
     pool: DbPool,
+    /// The one connection every write goes through
+    writer: DbPool,
+    path: PathBuf,
+    blooms: Arc<RwLock<HashMap<String, Bloom>>>,
+    bloom_min_elements: u64,
+    bloom_fp_rate: f64,
+    /// Operations are kept in `op_log` (see `prepare_op_log`)
+    op_log: bool,
 }
 
 impl SqliteStorage {
     pub fn open<P: AsRef<Path>>(path: P, config: &StorageConfig) -> Result<Self> {
+        config
+            .validate()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+
         let cache_size = config.sqlite_cache_size;
         let busy_timeout = config.sqlite_busy_timeout;
+        let mmap_size = config.mmap_size;
         let path_ref = path.as_ref();
+        let key = match &config.encryption {
+            Some(encryption) => Some(
+                encryption
+                    .passphrase()
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
+            ),
+            None => None,
+        };
 
         {
-            let conn = rusqlite::Connection::open(path_ref)?;
+            let mut conn = rusqlite::Connection::open(path_ref)?;
+            if let Some(key) = &key {
+                conn.pragma_update(None, "key", key)?;
+                // SQLCipher only finds out the key is wrong on the first read
+                conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+                    .map_err(|e| {
+                        rusqlite::Error::ToSqlConversionFailure(
+                            format!(
+                                "can't decrypt {} (wrong key, or not encrypted): {}",
+                                path_ref.display(),
+                                e
+                            )
+                            .into(),
+                        )
+                    })?;
+            }
+            // Before WAL mode, which fixes the page size of a new database for good
+            if config.page_size != 0 {
+                conn.pragma_update(None, "page_size", config.page_size)?;
+                let page_size: u32 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+                if page_size != config.page_size {
+                    warn!(
+                        "{} keeps its {} byte pages, page_size only applies to a new database",
+                        path_ref.display(),
+                        page_size
+                    );
+                }
+            }
             conn.pragma_update(None, "cache_size", cache_size)?;
             conn.pragma_update(None, "busy_timeout", busy_timeout)?;
             conn.pragma_update(None, "journal_mode", "WAL")?;
             conn.pragma_update(None, "synchronous", "NORMAL")?;
 
-            Self::create_schema(&conn)?;
+            Self::migrate(&mut conn, path_ref)?;
+            Self::prepare_op_log(&conn, config.op_log)?;
         }
 
-        let manager = SqliteConnectionManager::file(path_ref).with_init(move |conn| {
-            conn.pragma_update(None, "cache_size", cache_size)?;
-            conn.pragma_update(None, "busy_timeout", busy_timeout)?;
-            conn.pragma_update(None, "journal_mode", "WAL")?;
-            conn.pragma_update(None, "synchronous", "NORMAL")?;
-            Ok(())
-        });
+        let manager = |query_only: bool| {
+            let key = key.clone();
+            SqliteConnectionManager::file(path_ref).with_init(move |conn| {
+                // First, before anything reads the database
+                if let Some(key) = &key {
+                    conn.pragma_update(None, "key", key)?;
+                }
+                conn.pragma_update(None, "cache_size", cache_size)?;
+                conn.pragma_update(None, "busy_timeout", busy_timeout)?;
+                conn.pragma_update(None, "journal_mode", "WAL")?;
+                conn.pragma_update(None, "synchronous", "NORMAL")?;
+                if mmap_size != 0 {
+                    conn.pragma_update(None, "mmap_size", mmap_size)?;
+                }
+                conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+                // Last, once the connection is set up
+                conn.pragma_update(None, "query_only", query_only)?;
+                Ok(())
+            })
+        };
 
         let pool = Pool::builder()
-            .max_size(5) // Sized for concurrent reads only (writes are serialized)
+            .max_size(config.read_pool_size)
+            .min_idle(Some(config.pool_min_idle))
+            .build(manager(true))
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let writer = Pool::builder()
+            .max_size(1)
             .min_idle(Some(1))
-            .build(manager)
+            .build(manager(false))
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let storage = SqliteStorage {
+            pool,
+            writer,
+            path: path_ref.to_path_buf(),
+            blooms: Arc::new(RwLock::new(HashMap::new())),
+            bloom_min_elements: config.bloom_min_elements,
+            bloom_fp_rate: config.bloom_fp_rate,
+            op_log: config.op_log,
+        };
+
+        let conn = storage
+            .writer
+            .get()
             .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        storage.rebuild_blooms(&conn)?;
+        drop(conn);
+
+        Ok(storage)
+    }
+
+    /// Bring the database up to `SCHEMA_VERSION`, one migration at a time, recording each
+    /// in `schema_version`. A database newer than this build is refused rather than written
+    /// to by code that doesn't know its schema.
+    ///
+    /// Each migration commits in an immediate transaction that first reads the version, so
+    /// two processes opening the same database can't both apply one.
+    fn migrate(conn: &mut Connection, path: &Path) -> Result<()> {
+        conn.execute_batch(
+            r#"
+            -- One row per migration applied, the highest version being the schema's
+            CREATE TABLE IF NOT EXISTS schema_version (
+                version INTEGER PRIMARY KEY,
+                description TEXT NOT NULL,
+                applied_at INTEGER NOT NULL  -- ms since the Unix epoch
+            );
+            "#,
+        )?;
+
+        loop {
+            let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            let version: u32 = tx.query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+                [],
+                |row| row.get(0),
+            )?;
+            if version > SCHEMA_VERSION {
+                return Err(rusqlite::Error::ToSqlConversionFailure(
+                    format!(
+                        "{} is at schema version {}, newer than this build's {}",
+                        path.display(),
+                        version,
+                        SCHEMA_VERSION
+                    )
+                    .into(),
+                ));
+            }
+            let Some(migration) = MIGRATIONS.get(version as usize) else {
+                return Ok(());
+            };
 
-        Ok(SqliteStorage { pool })
+            (migration.apply)(&tx)?;
+            tx.execute(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+                rusqlite::params![version + 1, migration.description, now_ms()],
+            )?;
+            tx.commit()?;
+            info!(
+                "{}: schema migrated to version {} ({})",
+                path.display(),
+                version + 1,
+                migration.description
+            );
+        }
     }
 
     /// The schema is the AddWinsSet design.
@@ -60,6 +263,8 @@ impl SqliteStorage {
     /// - Every dot counter will be <= the counter in the version_vector table for that actor
     /// - There will be at most one dot per actor per element
     /// - Every element has at least one dot
+    ///
+    /// `IF NOT EXISTS`, as databases from before `schema_version` already have it.
     fn create_schema(conn: &Connection) -> Result<()> {
         conn.execute_batch(
             r#"
@@ -71,7 +276,7 @@ impl SqliteStorage {
 
             -- version vector
             CREATE TABLE IF NOT EXISTS version_vector (
-                actor_id BLOB NOT NULL,  -- 4-byte ActorId
+                actor_id BLOB NOT NULL,  -- ActorId, 4 bytes (8 for v2)
                 counter INTEGER NOT NULL,
                 PRIMARY KEY (actor_id)
             );
@@ -88,7 +293,7 @@ impl SqliteStorage {
             -- Dots pointing to elements (at most one dot per element per actor)
             CREATE TABLE IF NOT EXISTS dots (
                 element_id INTEGER NOT NULL,
-                actor_id BLOB NOT NULL,  -- 4-byte ActorId
+                actor_id BLOB NOT NULL,  -- ActorId, 4 bytes (8 for v2)
                 counter INTEGER NOT NULL,
                 PRIMARY KEY (element_id, actor_id),
                 FOREIGN KEY (element_id) REFERENCES elements(id) ON DELETE CASCADE
@@ -98,264 +303,470 @@ impl SqliteStorage {
             CREATE INDEX IF NOT EXISTS idx_elements_set_value ON elements(set_id, value);
             CREATE INDEX IF NOT EXISTS idx_dots_element ON dots(element_id);
             "#,
+        )
+    }
+
+    /// Dots that expire (SADDEX): an element is a member while at least one of its dots
+    /// hasn't expired. Existing dots never expire.
+    fn add_dot_expiry(conn: &Connection) -> Result<()> {
+        if !has_column(conn, "dots", "expires_at")? {
+            conn.execute_batch(
+                "ALTER TABLE dots ADD COLUMN expires_at INTEGER;  -- ms since the Unix epoch, NULL for a dot that never expires",
+            )?;
+        }
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_dots_expires_at ON dots(expires_at) WHERE expires_at IS NOT NULL;",
+        )
+    }
+
+    /// When each set was created, and its element count. The counts are backfilled from
+    /// the dots; the creation times of existing sets are unknown and stay NULL.
+    fn add_set_metadata(conn: &Connection) -> Result<()> {
+        if has_column(conn, "sets", "element_count")? {
+            return Ok(());
+        }
+        conn.execute_batch(
+            r#"
+            ALTER TABLE sets ADD COLUMN created_at INTEGER;  -- ms since the Unix epoch
+            ALTER TABLE sets ADD COLUMN element_count INTEGER NOT NULL DEFAULT 0;  -- elements with at least one dot
+            UPDATE sets SET element_count = (
+                SELECT COUNT(DISTINCT e.id)
+                FROM elements e
+                JOIN dots d ON d.element_id = e.id
+                WHERE e.set_id = sets.id
+            );
+            "#,
+        )
+    }
+
+    /// Facts about this node rather than its sets, e.g. the epoch it writes at
+    fn add_meta(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS meta (
+                key TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );
+            "#,
+        )
+    }
+
+    /// Create the operation log when it is kept, and drop it when it is not
+    ///
+    /// A log started on a database that already has data holds nothing from before, so its
+    /// floor starts at the version vector. A log that was dropped and is kept again starts
+    /// afresh the same way, rather than claiming to hold what was written meanwhile.
+    fn prepare_op_log(conn: &Connection, enabled: bool) -> Result<()> {
+        if !enabled {
+            return conn
+                .execute_batch("DROP TABLE IF EXISTS op_log; DROP TABLE IF EXISTS op_log_floor;");
+        }
+
+        let exists: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'op_log'",
+            [],
+            |row| row.get(0),
         )?;
+        if exists {
+            return Ok(());
+        }
 
-        Ok(())
+        conn.execute_batch(
+            r#"
+            BEGIN;
+            -- Operations written or applied, each the protobuf encoding of its Operation
+            CREATE TABLE op_log (
+                actor_id BLOB NOT NULL,  -- ActorId of the operation's dot, 4 bytes (8 for v2)
+                counter INTEGER NOT NULL,
+                operation BLOB NOT NULL,
+                PRIMARY KEY (actor_id, counter)
+            ) WITHOUT ROWID;
+
+            -- Per actor, the counter up to which operations are not in op_log
+            CREATE TABLE op_log_floor (
+                actor_id BLOB PRIMARY KEY,  -- ActorId, 4 bytes (8 for v2)
+                counter INTEGER NOT NULL
+            );
+            INSERT INTO op_log_floor (actor_id, counter) SELECT actor_id, counter FROM version_vector;
+            COMMIT;
+            "#,
+        )
     }
 
+    /// The read pool
     pub fn pool(&self) -> &DbPool {
         &self.pool
     }
 
-    pub fn load_vv(&self) -> Result<VersionVector> {
+    /// A fact about this node kept in the `meta` table, None if it was never saved
+    pub(crate) fn load_meta(&self, key: &str) -> Result<Option<i64>> {
         let conn = self
             .pool
             .get()
             .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        conn.query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()
+    }
 
-        let mut stmt = conn.prepare("SELECT actor_id, counter FROM version_vector")?;
+    /// Keep a fact about this node in the `meta` table, replacing what it was
+    pub(crate) fn save_meta(&self, key: &str, value: i64) -> Result<()> {
+        let conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        conn.execute(
+            "INSERT INTO meta (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            rusqlite::params![key, value],
+        )?;
+        Ok(())
+    }
 
-        let rows = stmt.query_map([], |row| {
-            let actor_bytes: Vec<u8> = row.get(0)?;
-            let counter: u64 = row.get(1)?;
-            Ok((actor_bytes, counter))
-        })?;
+    /// A page of up to `limit` elements, in `elements.id` order after `after_id` (0 for the first page)
+    ///
+    /// Returns the id to resume after, or None once the set is exhausted. An element added
+    /// meanwhile gets a higher id, so paging never skips one that was there throughout.
+    pub fn get_elements_page(
+        &self,
+        set_name: &str,
+        after_id: i64,
+        limit: usize,
+    ) -> Result<(Vec<Bytes>, Option<i64>)> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
-        let mut counters = HashMap::new();
-        for row in rows {
-            let (actor_bytes, counter) = row?;
-            if let Ok(actor_id) = ActorId::from_bytes(&actor_bytes) {
-                counters.insert(actor_id, counter);
-            }
-        }
+        let limit = limit.max(1);
+        let mut stmt = conn.prepare(&format!(
+            r#"
+                SELECT e.id, e.value
+                FROM elements e
+                JOIN sets s ON s.id = e.set_id
+                WHERE s.name = ?1 AND e.id > ?2 AND e.id NOT IN ({})
+                ORDER BY e.id
+                LIMIT ?3;
+                "#,
+            expired_ids(4)
+        ))?;
+        // One more than the page, to know whether there is another page after it
+        let rows = stmt.query_map(
+            rusqlite::params![set_name, after_id, limit as i64 + 1, now_ms()],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    Bytes::from(row.get::<_, Vec<u8>>(1)?),
+                ))
+            },
+        )?;
+        let mut page = rows.collect::<Result<Vec<(i64, Bytes)>>>()?;
 
-        Ok(VersionVector { counters })
+        let next = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|(id, _)| *id)
+        } else {
+            None
+        };
+
+        Ok((page.into_iter().map(|(_, value)| value).collect(), next))
     }
 
-    /// Adding an element to an AddWinsSet "joins" all the observed concurrent writes for that element (if any).
-    /// The process is:
-    /// - generate a new dot for this add
-    /// - if the set does not exist, create it
-    /// - insert the element into the elements table
-    /// - delete and return every existing dot for this element
-    /// - insert the new element
-    /// - return the set of dots, as these must be replicated to peers as part of the context of the operation.
-    /// Adding an element results in single dot for that element,
-    /// a dot that has replaced (joined) the previously observed concurrent adds.
-    pub fn add_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+    /// Bytes on disk: the database file plus its WAL
+    fn disk_usage(&self) -> u64 {
+        let mut wal = self.path.clone().into_os_string();
+        wal.push("-wal");
+        [self.path.as_os_str(), wal.as_os_str()]
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// The id of the set, creating it (stamped with the current time) if needed
+    fn set_id_or_create(tx: &Transaction, set_name: &str) -> Result<i64> {
+        tx.prepare_cached(
+            "INSERT INTO sets (name, created_at) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET name=name RETURNING id",
+        )?
+        .query_row(rusqlite::params![set_name, now_ms()], |row| row.get(0))
+    }
+
+    /// Add `delta` to the set's maintained element count
+    fn adjust_element_count(tx: &Transaction, set_id: i64, delta: i64) -> Result<()> {
+        if delta != 0 {
+            tx.prepare_cached("UPDATE sets SET element_count = element_count + ?2 WHERE id = ?1")?
+                .execute(rusqlite::params![set_id, delta])?;
+        }
+        Ok(())
+    }
+
+    /// The body of add_elements, without the version vector update, the new dot expiring
+    /// at `expires_at` if given
+    fn add_in_tx(
+        tx: &Transaction,
+        set_name: &str,
+        elements: &[Bytes],
+        dot: Dot,
+        expires_at: Option<u64>,
+    ) -> Result<Vec<Dot>> {
         if elements.is_empty() {
             return Ok(vec![]);
         }
 
-        let mut conn = self
-            .pool
-            .get()
-            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
-
-        let tx = conn.transaction()?;
-
         // Get the set_id (creating if needed)
-        let set_id: i64 = tx.query_row(
-            "INSERT INTO sets (name) VALUES (?1) ON CONFLICT(name) DO UPDATE SET name=name RETURNING id",
-            [set_name],
-            |row| row.get(0),
-        )?;
+        let set_id = Self::set_id_or_create(tx, set_name)?;
 
         let mut deleted = Vec::new();
+        let mut added = 0;
         let actor_id = dot.actor_id.bytes();
 
+        // Cached, so a SADD of a member or two doesn't compile its statements afresh
+        let mut insert_element = tx.prepare_cached(
+            "INSERT INTO elements (set_id, value) VALUES (?1, ?2) ON CONFLICT(set_id, value) DO UPDATE SET value=value RETURNING id",
+        )?;
+        let mut delete_dots = tx
+            .prepare_cached("DELETE FROM dots WHERE element_id = ?1 RETURNING actor_id, counter")?;
+        let mut insert_dot = tx.prepare_cached(
+            "INSERT INTO dots (element_id, actor_id, counter, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+
         for element in elements {
             // Insert element (or get existing element_id)
-            let element_id: i64 = tx.query_row(
-                "INSERT INTO elements (set_id, value) VALUES (?1, ?2) ON CONFLICT(set_id, value) DO UPDATE SET value=value RETURNING id",
-                rusqlite::params![set_id, element.as_ref()],
-                |row| row.get(0),
-            )?;
+            let element_id: i64 = insert_element
+                .query_row(rusqlite::params![set_id, element.as_ref()], |row| {
+                    row.get(0)
+                })?;
 
             // Remove and return each existing dot for this element_id
-            let mut stmt =
-                tx.prepare("DELETE FROM dots WHERE element_id = ?1 RETURNING actor_id, counter")?;
-            let rows = stmt.query_map([element_id], |row| {
+            let rows = delete_dots.query_map([element_id], |row| {
                 Ok(Dot::from_parts(row.get(0)?, row.get(1)?)
                     .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?)
             })?;
 
+            let deleted_before = deleted.len();
             for r in rows {
                 deleted.push(r?);
             }
-            drop(stmt);
+            if deleted.len() == deleted_before {
+                added += 1;
+            }
 
             // Insert the new dot for this element_id
-            tx.execute(
-                "INSERT INTO dots (element_id, actor_id, counter) VALUES (?1, ?2, ?3)",
-                rusqlite::params![element_id, actor_id, dot.counter],
-            )?;
+            insert_dot.execute(rusqlite::params![
+                element_id,
+                actor_id,
+                dot.counter,
+                expires_at
+            ])?;
         }
 
-        // Update version vector with the new dot
-        tx.execute(
-            "INSERT INTO version_vector (actor_id, counter) VALUES (?1, ?2) ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
-            rusqlite::params![actor_id, dot.counter],
-        )?;
-
-        tx.commit()?;
+        Self::adjust_element_count(tx, set_id, added)?;
         Ok(deleted)
     }
 
-    /// Removing an element is much like adding one, in that it returns the set of dots currently supporting that element.
-    /// The main difference is that it doesn't insert a new dot, and it actually _removes_ the element.
-    /// The removed dots are returned to be replicated.
-    pub fn remove_elements(
-        &self,
+    /// The body of replicate_add, without the version vector update, the new dot expiring
+    /// at `expires_at` if given
+    fn replicate_add_in_tx(
+        tx: &Transaction,
         set_name: &str,
         elements: &[Bytes],
+        removed_dots: &[Dot],
         dot: Dot,
-    ) -> Result<Vec<Dot>> {
+        expires_at: Option<u64>,
+    ) -> Result<()> {
         if elements.is_empty() {
-            return Ok(vec![]);
+            return Ok(());
         }
 
-        let mut conn = self
-            .pool
-            .get()
-            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        // Get the set_id (creating if needed)
+        let set_id = Self::set_id_or_create(tx, set_name)?;
 
-        let tx = conn.transaction()?;
+        let actor_id = dot.actor_id.bytes();
+        let mut added = 0;
 
-        // Get the set_id (exit if it doesn't exist)
-        let set_id: Option<i64> = tx
-            .query_row("SELECT id FROM sets WHERE name = ?1", [set_name], |row| {
-                row.get(0)
-            })
-            .optional()?;
+        let mut insert_element = tx.prepare_cached(
+            "INSERT INTO elements (set_id, value) VALUES (?1, ?2) ON CONFLICT(set_id, value) DO UPDATE SET value=value RETURNING id",
+        )?;
+        let mut had_dots =
+            tx.prepare_cached("SELECT EXISTS(SELECT 1 FROM dots WHERE element_id = ?1)")?;
+        // Anti-entropy may already have installed this dot, or a later one from the same actor,
+        // whose expiry (none, as anti-entropy doesn't carry them) is kept.
+        let mut insert_dot = tx.prepare_cached(
+            "INSERT INTO dots (element_id, actor_id, counter, expires_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(element_id, actor_id) DO UPDATE SET
+                 counter = MAX(counter, excluded.counter),
+                 expires_at = CASE WHEN excluded.counter > counter THEN excluded.expires_at ELSE expires_at END",
+        )?;
+
+        // The remove set's DELETEs, one per chunk of MAX_REMOVED_DOTS_PER_DELETE, each shaped
+        // for its chunk's length rounded up to a power of two so that the cache holds a few
+        // statements rather than one per length. The padding repeats the chunk's last dot,
+        // which the IN list doesn't mind. The element_id comes first, given per element.
+        let mut delete_removed = Vec::new();
+        for chunk in removed_dots.chunks(MAX_REMOVED_DOTS_PER_DELETE) {
+            let bucket = chunk.len().next_power_of_two();
+            let placeholders = vec!["(?, ?)"; bucket].join(", ");
+            let sql = format!(
+                "DELETE FROM dots WHERE element_id = ? AND (actor_id, counter) IN ({placeholders})"
+            );
+            let padded: Vec<(&[u8], u64)> = chunk
+                .iter()
+                .chain(std::iter::repeat(&chunk[chunk.len() - 1]))
+                .take(bucket)
+                .map(|d| (d.actor_id.bytes(), d.counter))
+                .collect();
+            delete_removed.push((tx.prepare_cached(&sql)?, padded));
+        }
 
-        let set_id = match set_id {
-            Some(id) => id,
-            None => {
-                // Set doesn't exist, nothing to remove
-                println!("Set {} doesn't exist", set_name);
-                return Ok(vec![]);
+        for element in elements {
+            // Insert element (or get existing element_id)
+            let element_id: i64 = insert_element
+                .query_row(rusqlite::params![set_id, element.as_ref()], |row| {
+                    row.get(0)
+                })?;
+
+            // The element counts towards the cardinality once it has a dot, which it always does after this add
+            if !had_dots.query_row([element_id], |row| row.get::<_, bool>(0))? {
+                added += 1;
             }
-        };
 
-        let mut deleted = Vec::new();
-        let actor_id = dot.actor_id.bytes();
+            // remove each dot from the remove set for this element
+            for (stmt, padded) in delete_removed.iter_mut() {
+                let mut params: Vec<&dyn ToSql> = vec![&element_id];
+                for (actor_id, counter) in padded.iter() {
+                    params.push(actor_id);
+                    params.push(counter);
+                }
+                stmt.execute(rusqlite::params_from_iter(params))?;
+            }
 
-        for element in elements {
-            let mut stmt = tx.prepare(
-                "DELETE FROM dots
-                        WHERE element_id IN (
-                            SELECT id FROM elements
-                            WHERE set_id =  ?1
-                            AND value = ?2
-                        )
-                        RETURNING actor_id, counter",
-            )?;
+            // Insert the new dot for this element_id
+            insert_dot.execute(rusqlite::params![
+                element_id,
+                actor_id,
+                dot.counter,
+                expires_at
+            ])?;
+        }
 
-            let rows = stmt.query_map(rusqlite::params![set_id, element.as_ref()], |row| {
-                Ok(Dot::from_parts(row.get(0)?, row.get(1)?)
-                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?)
-            })?;
+        Self::adjust_element_count(tx, set_id, added)
+    }
 
-            for r in rows {
-                trace!("Deleted {:?} dots for element {:?}", r, element);
-                deleted.push(r?);
+    /// Add `elements` to the set's Bloom filter, if it has one.
+    /// Called inside the transaction adding them, so the filter has them before any reader can see them.
+    fn bloom_insert(&self, set_name: &str, elements: &[Bytes]) {
+        if let Some(bloom) = self.blooms.write().unwrap().get_mut(set_name) {
+            for element in elements {
+                bloom.insert(element);
             }
-            drop(stmt);
+        }
+    }
 
-            // Only delete the element if we found dots for it (meaning it existed)
-            if !deleted.is_empty() {
-                tx.execute(
-                    "DELETE FROM elements
-                                WHERE set_id = (SELECT id FROM sets WHERE name = ?1)
-                                AND value = ?2",
-                    rusqlite::params![set_name, element.as_ref()],
-                )?;
-            }
+    /// After a commit adding to the set, build its Bloom filter if it has reached
+    /// `bloom_min_elements`, or rebuild it if it has outgrown its capacity.
+    /// `conn` is the writer, so nothing is written while the filter is built. The add has
+    /// committed, so a failure here only leaves the set unfiltered, and is logged rather than returned.
+    fn refresh_bloom(&self, conn: &Connection, set_name: &str) {
+        if self.bloom_min_elements == 0 {
+            return;
         }
 
-        // Update version vector with the new dot
-        tx.execute(
-            "INSERT INTO version_vector (actor_id, counter) VALUES (?1, ?2) ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
-            rusqlite::params![actor_id, dot.counter],
-        )?;
+        let stale = match self.blooms.read().unwrap().get(set_name) {
+            Some(bloom) => bloom.is_overfull(),
+            None => true,
+        };
+        if !stale {
+            return;
+        }
 
-        tx.commit()?;
-        Ok(deleted)
+        let built = Self::cardinality(conn, set_name).and_then(|count| {
+            if count < self.bloom_min_elements {
+                return Ok(None);
+            }
+            self.build_bloom(conn, set_name, count).map(Some)
+        });
+        match built {
+            Ok(Some(bloom)) => {
+                self.blooms
+                    .write()
+                    .unwrap()
+                    .insert(set_name.to_string(), bloom);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.blooms.write().unwrap().remove(set_name);
+                warn!("Failed to build a Bloom filter for set {}: {}", set_name, e);
+            }
+        }
     }
 
-    /// Since we don't have tombstones this is simply the set of elements for the given set.
-    pub fn get_elements(&self, set_name: &str) -> Result<Vec<Bytes>> {
-        let conn = self
-            .pool
-            .get()
-            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    /// Replace every Bloom filter from what `conn` sees, one for each set of at least `bloom_min_elements`
+    fn rebuild_blooms(&self, conn: &Connection) -> Result<()> {
+        if self.bloom_min_elements == 0 {
+            return Ok(());
+        }
 
-        let mut stmt = conn.prepare(
-            r#"
-                SELECT e.value
-                FROM elements e
-                JOIN sets s ON s.id = e.set_id
-                WHERE s.name = ?1
-                ORDER BY e.id;
-                "#,
-        )?;
-        let rows = stmt.query_map([set_name], |row| {
-            let value: Vec<u8> = row.get(0)?;
-            Ok(Bytes::from(value))
-        })?;
+        let sets = {
+            let mut stmt =
+                conn.prepare("SELECT name, element_count FROM sets WHERE element_count >= ?1")?;
+            stmt.query_map([self.bloom_min_elements], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?
+        };
 
-        rows.collect::<Result<Vec<Bytes>>>()
+        let mut blooms = HashMap::with_capacity(sets.len());
+        for (set_name, count) in sets {
+            let bloom = self.build_bloom(conn, &set_name, count)?;
+            blooms.insert(set_name, bloom);
+        }
+        *self.blooms.write().unwrap() = blooms;
+        Ok(())
     }
 
-    /// Return the count of elements in the set
-    pub fn count_elements(&self, set_name: &str) -> Result<u64> {
-        let conn = self
-            .pool
-            .get()
-            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
-
-        // Get cardinality
-        let count: u64 = conn.query_row(
-            r#"
-                SELECT COUNT(e.id)
-                FROM elements e
-                JOIN sets s ON s.id = e.set_id
-                WHERE s.name = ?1;
-                "#,
-            [set_name],
-            |row| row.get(0),
+    /// A Bloom filter over every element of the set, with room for it to double before it needs rebuilding
+    fn build_bloom(&self, conn: &Connection, set_name: &str, count: u64) -> Result<Bloom> {
+        let mut bloom = Bloom::new(count.max(self.bloom_min_elements) * 2, self.bloom_fp_rate);
+        let mut stmt = conn.prepare(
+            "SELECT e.value FROM elements e JOIN sets s ON s.id = e.set_id WHERE s.name = ?1",
         )?;
+        let mut rows = stmt.query([set_name])?;
+        while let Some(row) = rows.next()? {
+            bloom.insert(row.get_ref(0)?.as_blob()?);
+        }
 
-        Ok(count)
+        debug!(
+            "Built a {} byte Bloom filter for set {} of {} elements",
+            bloom.size(),
+            set_name,
+            count
+        );
+        Ok(bloom)
     }
 
-    // given an element, true if it is present in the set at this replica
-    pub fn is_member(&self, set_name: &str, element: &Bytes) -> Result<bool> {
-        let conn = self
-            .pool
-            .get()
-            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    /// False if the set's Bloom filter rules `element` out, true if it may be a member
+    fn bloom_may_contain(&self, set_name: &str, element: &[u8]) -> bool {
+        self.blooms
+            .read()
+            .unwrap()
+            .get(set_name)
+            .is_none_or(|bloom| bloom.may_contain(element))
+    }
 
-        let exists: i64 = conn.query_row(
-            r#"
-                SELECT EXISTS (
-                  SELECT 1
-                  FROM elements e
-                  JOIN sets s ON s.id = e.set_id
-                  WHERE s.name = ?1
-                    AND e.value = ?2
-                );
-                "#,
-            rusqlite::params![set_name, element.as_ref()],
-            |row| row.get(0),
-        )?;
-        Ok(exists != 0)
+    /// The maintained element count of the set, 0 if there is no such set
+    fn cardinality(conn: &Connection, set_name: &str) -> Result<u64> {
+        let count: Option<u64> = conn
+            .query_row(
+                "SELECT element_count FROM sets WHERE name = ?1",
+                [set_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(count.unwrap_or(0))
     }
 
-    // Given elements, returns a vec of bool, positionally matching the elements where
-    // true is in the set, and false is not.
-    pub fn are_members(&self, set_name: &str, elements: &[Bytes]) -> Result<Vec<bool>> {
+    /// are_members, answered by the database alone in one query, however many elements
+    fn are_members_in_db(&self, set_name: &str, elements: &[Bytes]) -> Result<Vec<bool>> {
         if elements.is_empty() {
             return Ok(Vec::new());
         }
@@ -365,9 +776,9 @@ impl SqliteStorage {
             .get()
             .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
-        // Build "(?),(?),(?)" for vals(value)
-        let vals_placeholders = std::iter::repeat("(?)")
-            .take(elements.len())
+        // Build "(0, ?2), (1, ?3), ..." for vals(i, value), so the rows come back in order
+        let vals_placeholders = (0..elements.len())
+            .map(|i| format!("({}, ?{})", i, i + 2))
             .collect::<Vec<_>>()
             .join(", ");
 
@@ -377,24 +788,25 @@ impl SqliteStorage {
                 s AS (
                   SELECT id AS set_id FROM sets WHERE name = ?1
                 ),
-                vals(value) AS (VALUES {vals}),
-                joined AS (
-                  SELECT v.value, e.value AS present
-                  FROM vals v
-                  LEFT JOIN elements e
-                    ON e.value = v.value
-                   AND e.set_id = (SELECT set_id FROM s)
-                )
-                SELECT CASE WHEN present IS NOT NULL THEN 1 ELSE 0 END
-                FROM joined;
+                vals(i, value) AS (VALUES {vals})
+                SELECT e.id IS NOT NULL
+                FROM vals v
+                LEFT JOIN elements e
+                  ON e.value = v.value
+                 AND e.set_id = (SELECT set_id FROM s)
+                 AND e.id NOT IN ({expired})
+                ORDER BY v.i;
                 "#,
-            vals = vals_placeholders
+            vals = vals_placeholders,
+            expired = expired_ids(elements.len() + 2)
         );
         let element_slices: Vec<&[u8]> = elements.iter().map(|e| e.as_ref()).collect();
+        let now = now_ms();
 
-        // Bind params: ?1 = set_name, then the element values
+        // Bind params: ?1 = set_name, then the element values, then the time
         let mut params: Vec<&dyn ToSql> = vec![&set_name];
         params.extend(element_slices.iter().map(|s| s as &dyn ToSql));
+        params.push(&now);
 
         let mut stmt = conn.prepare(&sql)?;
         let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
@@ -409,194 +821,2925 @@ impl SqliteStorage {
         Ok(out)
     }
 
-    /// A replication received add event.
-    /// Assumption is that if the `Dot` of the event has already been observed this method will not be called.
-    ///
-    /// Much like add_elements above, here the given dot is added for each of the elements,
-    /// and all the dots on removed_dots are removed from the set of supporting dots for each added element.
-    /// Another way to implement this would be to use the remote actors version vector to remove all dots for the given
-    /// elements (and that is (maybe?) a better idea, but demands causal consistency)
-    pub fn replicate_add(
+    /// The body of remove_elements, without the version vector update, see there
+    fn remove_in_tx(tx: &Transaction, set_name: &str, elements: &[Bytes]) -> Result<Vec<Dot>> {
+        let set_id: Option<i64> = tx
+            .query_row("SELECT id FROM sets WHERE name = ?1", [set_name], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(set_id) = set_id else {
+            // Nothing to remove from a set that doesn't exist, which isn't created for it
+            return Ok(vec![]);
+        };
+
+        let mut deleted = Vec::new();
+        let mut removed = 0;
+
+        for element in elements {
+            let mut stmt = tx.prepare(
+                "DELETE FROM dots
+                        WHERE element_id IN (
+                            SELECT id FROM elements
+                            WHERE set_id =  ?1
+                            AND value = ?2
+                        )
+                        RETURNING actor_id, counter",
+            )?;
+
+            let rows = stmt.query_map(rusqlite::params![set_id, element.as_ref()], |row| {
+                Ok(Dot::from_parts(row.get(0)?, row.get(1)?)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?)
+            })?;
+
+            let mut found = false;
+            for r in rows {
+                trace!("Deleted {:?} dots for element {:?}", r, element);
+                deleted.push(r?);
+                found = true;
+            }
+            drop(stmt);
+
+            // Only delete the element if we found dots for it (meaning it existed)
+            if found {
+                tx.execute(
+                    "DELETE FROM elements
+                                WHERE set_id = (SELECT id FROM sets WHERE name = ?1)
+                                AND value = ?2",
+                    rusqlite::params![set_name, element.as_ref()],
+                )?;
+                removed += 1;
+            }
+        }
+
+        Self::adjust_element_count(tx, set_id, -removed)?;
+        Ok(deleted)
+    }
+
+    /// add_elements, the new dot expiring at `expires_at` if given
+    fn add(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        dot: Dot,
+        expires_at: Option<u64>,
+    ) -> Result<Vec<Dot>> {
+        if elements.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+
+        let deleted = Self::add_in_tx(&tx, set_name, elements, dot, expires_at)?;
+        Self::observe_dot(&tx, dot)?;
+        self.bloom_insert(set_name, elements);
+
+        tx.commit()?;
+        self.refresh_bloom(&conn, set_name);
+        Ok(deleted)
+    }
+
+    /// replicate_add, the new dot expiring at `expires_at` if given
+    fn replicate(
         &self,
         set_name: &str,
         elements: &[Bytes],
         removed_dots: &[Dot],
         dot: Dot,
+        expires_at: Option<u64>,
     ) -> Result<()> {
         if elements.is_empty() {
             return Ok(());
         }
 
         let mut conn = self
-            .pool
+            .writer
             .get()
             .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
         let tx = conn.transaction()?;
 
-        // Get the set_id (creating if needed)
-        let set_id: i64 = tx.query_row(
-            "INSERT INTO sets (name) VALUES (?1) ON CONFLICT(name) DO UPDATE SET name=name RETURNING id",
-            [set_name],
-            |row| row.get(0),
-        )?;
+        Self::replicate_add_in_tx(&tx, set_name, elements, removed_dots, dot, expires_at)?;
+        Self::observe_dot(&tx, dot)?;
+        self.bloom_insert(set_name, elements);
 
-        let actor_id = dot.actor_id.bytes();
+        tx.commit()?;
+        self.refresh_bloom(&conn, set_name);
+        Ok(())
+    }
 
-        // For each element
-        for element in elements {
-            // Insert element (or get existing element_id)
-            let element_id: i64 = tx.query_row(
-                "INSERT INTO elements (set_id, value) VALUES (?1, ?2) ON CONFLICT(set_id, value) DO UPDATE SET value=value RETURNING id",
-                rusqlite::params![set_id, element.as_ref()],
-                |row| row.get(0),
-            )?;
+    /// Record `dot` in the version vector
+    fn observe_dot(tx: &Transaction, dot: Dot) -> Result<()> {
+        tx.prepare_cached(
+            "INSERT INTO version_vector (actor_id, counter) VALUES (?1, ?2) ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
+        )?
+        .execute(rusqlite::params![dot.actor_id.bytes(), dot.counter])?;
+        Ok(())
+    }
 
-            // remove each dot from the remove set for this element
-            if !removed_dots.is_empty() {
-                let placeholders = std::iter::repeat("(?, ?)")
-                    .take(removed_dots.len())
-                    .collect::<Vec<_>>()
-                    .join(", ");
-
-                let sql = format!(
-                    "DELETE FROM dots WHERE element_id = ?1 AND (actor_id, counter) IN ({})",
-                    placeholders
-                );
+    /// Record every entry of `vv` in the version vector, in one statement however many
+    /// actors it has
+    fn observe_vv(tx: &Transaction, vv: &VersionVector) -> Result<()> {
+        if vv.counters.is_empty() {
+            return Ok(());
+        }
+        let sql = format!(
+            "INSERT INTO version_vector (actor_id, counter) VALUES {} ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
+            vec!["(?, ?)"; vv.counters.len()].join(", ")
+        );
+        let entries: Vec<(&[u8], u64)> = vv
+            .counters
+            .iter()
+            .map(|(actor_id, counter)| (actor_id.bytes(), *counter))
+            .collect();
+        let mut params: Vec<&dyn ToSql> = Vec::with_capacity(2 * entries.len());
+        for (actor_id, counter) in &entries {
+            params.push(actor_id);
+            params.push(counter);
+        }
+        tx.execute(&sql, rusqlite::params_from_iter(params))?;
+        Ok(())
+    }
+}
 
-                // Collect actor_id bytes first to ensure stable lifetimes
-                let removed_dots_params: Vec<(&[u8], u64)> = removed_dots
-                    .iter()
-                    .map(|d| (d.actor_id.bytes(), d.counter))
-                    .collect();
+impl Storage for SqliteStorage {
+    fn load_vv(&self) -> Result<VersionVector> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
-                let mut params: Vec<&dyn ToSql> = vec![&element_id];
-                for i in 0..removed_dots.len() {
-                    params.push(&removed_dots_params[i].0);
-                    params.push(&removed_dots_params[i].1);
-                }
+        let mut stmt = conn.prepare("SELECT actor_id, counter FROM version_vector")?;
 
-                tx.execute(&sql, rusqlite::params_from_iter(params))?;
-            }
+        let rows = stmt.query_map([], |row| {
+            let actor_bytes: Vec<u8> = row.get(0)?;
+            let counter: u64 = row.get(1)?;
+            Ok((actor_bytes, counter))
+        })?;
 
-            // Insert the new dot for this element_id
-            tx.execute(
-                "INSERT INTO dots (element_id, actor_id, counter) VALUES (?1, ?2, ?3)",
-                rusqlite::params![element_id, actor_id, dot.counter],
-            )?;
+        let mut counters = HashMap::new();
+        for row in rows {
+            let (actor_bytes, counter) = row?;
+            if let Ok(actor_id) = ActorId::from_bytes(&actor_bytes) {
+                counters.insert(actor_id, counter);
+            }
         }
 
-        // Update version vector with the new dot
-        tx.execute(
-            "INSERT INTO version_vector (actor_id, counter) VALUES (?1, ?2) ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
-            rusqlite::params![actor_id, dot.counter],
-        )?;
+        Ok(VersionVector { counters })
+    }
 
-        tx.commit()?;
-        Ok(())
+    /// Adding an element to an AddWinsSet "joins" all the observed concurrent writes for that element (if any).
+    /// The process is:
+    /// - generate a new dot for this add
+    /// - if the set does not exist, create it
+    /// - insert the element into the elements table
+    /// - delete and return every existing dot for this element
+    /// - insert the new element
+    /// - return the set of dots, as these must be replicated to peers as part of the context of the operation.
+    /// Adding an element results in single dot for that element,
+    /// a dot that has replaced (joined) the previously observed concurrent adds.
+    fn add_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
+        self.add(set_name, elements, dot, None)
     }
 
-    /// A replication received remove event.
-    /// Assumption is that if the `Dot` of the event has already been observed this method will not be called.
-    ///
-    /// Much like replicated_add aboce, all the dots in removed_dots are removed from the set of supporting dots for each added element.
-    /// Another way to implement this would be to use the remote actors version vector to remove all dots for the given
-    /// elements (and that is maybe a better idea, but demands causal consistency).
-    /// If any element has no dots left, it is removed from the set.
-    pub fn replicate_remove(
+    fn add_elements_expiring(
         &self,
         set_name: &str,
         elements: &[Bytes],
-        removed_dots: &[Dot],
         dot: Dot,
-    ) -> Result<()> {
+        expires_at: u64,
+    ) -> Result<Vec<Dot>> {
+        self.add(set_name, elements, dot, Some(expires_at))
+    }
+
+    /// Removing an element is much like adding one, in that it returns the set of dots currently supporting that element.
+    /// The main difference is that it doesn't insert a new dot, and it actually _removes_ the element.
+    /// The removed dots are returned to be replicated.
+    fn remove_elements(&self, set_name: &str, elements: &[Bytes], dot: Dot) -> Result<Vec<Dot>> {
         if elements.is_empty() {
-            return Ok(());
+            return Ok(vec![]);
         }
 
         let mut conn = self
-            .pool
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+
+        let deleted = Self::remove_in_tx(&tx, set_name, elements)?;
+        // Seen even when the set doesn't exist
+        Self::observe_dot(&tx, dot)?;
+
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Every write in one transaction, so the group shares a single commit, and one
+    /// version vector update for all their dots
+    fn commit_group(&self, writes: &[GroupWrite]) -> Result<Vec<Vec<Dot>>> {
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+
+        let mut results = Vec::with_capacity(writes.len());
+        let mut observed = VersionVector::new();
+        for write in writes {
+            if write.elements.is_empty() {
+                results.push(vec![]);
+                continue;
+            }
+            let dots = match write.kind {
+                WriteKind::Add => {
+                    let deleted =
+                        Self::add_in_tx(&tx, write.set_name, write.elements, write.dot, None)?;
+                    self.bloom_insert(write.set_name, write.elements);
+                    deleted
+                }
+                WriteKind::Remove => Self::remove_in_tx(&tx, write.set_name, write.elements)?,
+            };
+            observed.update(write.dot.actor_id, write.dot.counter);
+            results.push(dots);
+        }
+        Self::observe_vv(&tx, &observed)?;
+
+        tx.commit()?;
+        let added: HashSet<&str> = writes
+            .iter()
+            .filter(|w| w.kind == WriteKind::Add)
+            .map(|w| w.set_name)
+            .collect();
+        for set_name in added {
+            self.refresh_bloom(&conn, set_name);
+        }
+        Ok(results)
+    }
+
+    /// Drop a whole set, returning every dot that supported its elements so the caller
+    /// can replicate the delete as a remove of all of them.
+    /// Foreign keys are not enforced, so dots and elements are deleted explicitly rather than by cascade.
+    fn delete_set(&self, set_name: &str) -> Result<Vec<Dot>> {
+        let mut conn = self
+            .writer
             .get()
             .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
         let tx = conn.transaction()?;
 
-        // Get the set_id (exit if it doesn't exist)
         let set_id: Option<i64> = tx
             .query_row("SELECT id FROM sets WHERE name = ?1", [set_name], |row| {
                 row.get(0)
             })
             .optional()?;
+        let Some(set_id) = set_id else {
+            return Ok(vec![]);
+        };
 
-        let set_id = match set_id {
-            Some(id) => id,
-            None => {
-                // Set doesn't exist, nothing to remove, it would be an error to be here
-                return Ok(());
+        let mut deleted = Vec::new();
+        {
+            let mut stmt = tx.prepare(
+                "DELETE FROM dots
+                 WHERE element_id IN (SELECT id FROM elements WHERE set_id = ?1)
+                 RETURNING actor_id, counter",
+            )?;
+            let rows = stmt.query_map([set_id], |row| {
+                Dot::from_parts(row.get(0)?, row.get(1)?)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+            })?;
+            for row in rows {
+                deleted.push(row?);
             }
-        };
+        }
 
-        let actor_id = dot.actor_id.bytes();
+        tx.execute("DELETE FROM elements WHERE set_id = ?1", [set_id])?;
+        tx.execute("DELETE FROM sets WHERE id = ?1", [set_id])?;
 
-        // For each element
-        for element in elements {
-            // Get existing element_id (skip this element if no such element)
-            let element_id: Option<i64> = tx
-                .query_row(
-                    "SELECT id FROM elements WHERE set_id = ?1 AND value = ?2",
-                    rusqlite::params![set_id, element.as_ref()],
-                    |row| row.get(0),
-                )
+        tx.commit()?;
+        self.blooms.write().unwrap().remove(set_name);
+        Ok(deleted)
+    }
+
+    /// In the order the sets were created
+    fn list_sets(&self) -> Result<Vec<String>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare("SELECT name FROM sets ORDER BY id")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Since we don't have tombstones this is simply the set of elements for the given set,
+    /// less any that have expired.
+    fn get_elements(&self, set_name: &str) -> Result<Vec<Bytes>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare(&format!(
+            r#"
+                SELECT e.value
+                FROM elements e
+                JOIN sets s ON s.id = e.set_id
+                WHERE s.name = ?1 AND e.id NOT IN ({})
+                ORDER BY e.id;
+                "#,
+            expired_ids(2)
+        ))?;
+        let rows = stmt.query_map(rusqlite::params![set_name, now_ms()], |row| {
+            let value: Vec<u8> = row.get(0)?;
+            Ok(Bytes::from(value))
+        })?;
+
+        rows.collect::<Result<Vec<Bytes>>>()
+    }
+
+    /// Read in one transaction, so the length and the elements agree however long the
+    /// caller takes over each batch. The read connection is held until the scan ends.
+    ///
+    /// A pattern is matched by SQLite's GLOB (see `sql_glob`), so only matching elements
+    /// leave the database. GLOB reads elements as UTF-8 text: `?` and classes match one
+    /// character rather than one byte, and elements holding a NUL byte never match.
+    fn stream_elements(
+        &self,
+        set_name: &str,
+        pattern: Option<&[u8]>,
+        batch_size: usize,
+        on_len: &mut dyn FnMut(u64),
+        on_batch: &mut dyn FnMut(Vec<Bytes>) -> bool,
+    ) -> Result<()> {
+        let glob = match pattern.map(sql_glob) {
+            Some(None) => {
+                on_len(0);
+                return Ok(());
+            }
+            Some(glob) => glob,
+            None => None,
+        };
+        let matching = if glob.is_some() {
+            "AND e.value GLOB ?3 AND instr(e.value, x'00') = 0"
+        } else {
+            ""
+        };
+
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+        let now = now_ms();
+        let mut params: Vec<&dyn ToSql> = vec![&set_name, &now];
+        if let Some(glob) = &glob {
+            params.push(glob);
+        }
+
+        let len: u64 = tx.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM elements e JOIN sets s ON s.id = e.set_id WHERE s.name = ?1 AND e.id NOT IN ({}) {}",
+                expired_ids(2),
+                matching
+            ),
+            rusqlite::params_from_iter(&params),
+            |row| row.get(0),
+        )?;
+        on_len(len);
+
+        let mut stmt = tx.prepare(&format!(
+            r#"
+                SELECT e.value
+                FROM elements e
+                JOIN sets s ON s.id = e.set_id
+                WHERE s.name = ?1 AND e.id NOT IN ({}) {}
+                ORDER BY e.id;
+                "#,
+            expired_ids(2),
+            matching
+        ))?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(&params))?;
+
+        let batch_size = batch_size.max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+        while let Some(row) = rows.next()? {
+            let value: Vec<u8> = row.get(0)?;
+            batch.push(Bytes::from(value));
+            if batch.len() == batch_size && !on_batch(std::mem::take(&mut batch)) {
+                return Ok(());
+            }
+        }
+        if !batch.is_empty() {
+            on_batch(batch);
+        }
+
+        Ok(())
+    }
+
+    /// Counts the set, picks the ranks, then walks the set's ids in order for the ones at
+    /// those ranks, all in one read transaction. Only the picked elements' values are read.
+    fn random_elements(&self, set_name: &str, count: usize, repeats: bool) -> Result<Vec<Bytes>> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+        let now = now_ms();
+        let live = format!(
+            "FROM elements e JOIN sets s ON s.id = e.set_id WHERE s.name = ?1 AND e.id NOT IN ({})",
+            expired_ids(2)
+        );
+
+        let len: u64 = tx.query_row(
+            &format!("SELECT COUNT(*) {}", live),
+            rusqlite::params![set_name, now],
+            |row| row.get(0),
+        )?;
+        let ranks = random_ranks(len as usize, count, repeats);
+        if ranks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut ids = Vec::with_capacity(ranks.len());
+        {
+            let mut stmt = tx.prepare(&format!("SELECT e.id {} ORDER BY e.id", live))?;
+            let mut rows = stmt.query(rusqlite::params![set_name, now])?;
+            let mut ranks = ranks.into_iter().peekable();
+            let mut rank = 0;
+            while let Some(row) = rows.next()? {
+                let id: i64 = row.get(0)?;
+                while ranks.next_if_eq(&rank).is_some() {
+                    ids.push(id);
+                }
+                if ranks.peek().is_none() {
+                    break;
+                }
+                rank += 1;
+            }
+        }
+
+        let mut stmt = tx.prepare("SELECT value FROM elements WHERE id = ?1")?;
+        let mut values: HashMap<i64, Bytes> = HashMap::with_capacity(ids.len());
+        let mut picked = Vec::with_capacity(ids.len());
+        for id in ids {
+            let value = match values.entry(id) {
+                Entry::Occupied(value) => value.get().clone(),
+                Entry::Vacant(entry) => {
+                    let value: Vec<u8> = stmt.query_row([id], |row| row.get(0))?;
+                    entry.insert(Bytes::from(value)).clone()
+                }
+            };
+            picked.push(value);
+        }
+        picked.shuffle(&mut rand::rng());
+        Ok(picked)
+    }
+
+    /// Return the count of elements in the set.
+    /// An element is only a member while at least one dot supports it. Every write keeps
+    /// `sets.element_count` equal to the number of elements with surviving dots, so this is a
+    /// single row read, less the expired elements not yet removed.
+    fn count_elements(&self, set_name: &str) -> Result<i64> {
+        Ok(self.set_info(set_name)?.map_or(0, |info| info.cardinality))
+    }
+
+    fn set_info(&self, set_name: &str) -> Result<Option<SetInfo>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        conn.query_row(
+            &format!(
+                "SELECT created_at, element_count - (
+                     SELECT COUNT(*) FROM elements e WHERE e.set_id = sets.id AND e.id IN ({})
+                 )
+                 FROM sets WHERE name = ?1",
+                expired_ids(2)
+            ),
+            rusqlite::params![set_name, now_ms()],
+            |row| {
+                Ok(SetInfo {
+                    created_at: row.get(0)?,
+                    cardinality: row.get(1)?,
+                })
+            },
+        )
+        .optional()
+    }
+
+    fn set_actors(&self, set_name: &str) -> Result<Vec<ActorId>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare(
+            r#"
+                SELECT DISTINCT d.actor_id
+                FROM dots d
+                JOIN elements e ON e.id = d.element_id
+                JOIN sets s ON s.id = e.set_id
+                WHERE s.name = ?1
+                ORDER BY d.actor_id;
+                "#,
+        )?;
+        let rows = stmt.query_map([set_name], |row| {
+            ActorId::from_bytes(&row.get::<_, Vec<u8>>(0)?)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+        })?;
+        rows.collect()
+    }
+
+    fn set_vv(&self, set_name: &str) -> Result<VersionVector> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare_cached(
+            r#"
+                SELECT d.actor_id, MAX(d.counter)
+                FROM dots d
+                JOIN elements e ON e.id = d.element_id
+                JOIN sets s ON s.id = e.set_id
+                WHERE s.name = ?1
+                GROUP BY d.actor_id;
+                "#,
+        )?;
+        let rows = stmt.query_map([set_name], |row| {
+            Dot::from_parts(row.get(0)?, row.get(1)?)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+        })?;
+
+        let mut vv = VersionVector::new();
+        for row in rows {
+            let dot = row?;
+            vv.update(dot.actor_id, dot.counter);
+        }
+        Ok(vv)
+    }
+
+    fn element_dots(&self, set_name: &str, element: &Bytes) -> Result<Vec<Dot>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare(
+            r#"
+                SELECT d.actor_id, d.counter
+                FROM dots d
+                JOIN elements e ON e.id = d.element_id
+                JOIN sets s ON s.id = e.set_id
+                WHERE s.name = ?1 AND e.value = ?2
+                ORDER BY d.actor_id;
+                "#,
+        )?;
+        let rows = stmt.query_map(rusqlite::params![set_name, element.as_ref()], |row| {
+            Dot::from_parts(row.get(0)?, row.get(1)?)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+        })?;
+        rows.collect()
+    }
+
+    /// One indexed lookup of the set's row in `sets`
+    fn set_exists(&self, set_name: &str) -> Result<bool> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sets WHERE name = ?1)",
+            [set_name],
+            |row| row.get(0),
+        )
+    }
+
+    // given an element, true if it is present in the set at this replica
+    fn is_member(&self, set_name: &str, element: &Bytes) -> Result<bool> {
+        if !self.bloom_may_contain(set_name, element) {
+            return Ok(false);
+        }
+
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let exists: i64 = conn.query_row(
+            &format!(
+                r#"
+                SELECT EXISTS (
+                  SELECT 1
+                  FROM elements e
+                  JOIN sets s ON s.id = e.set_id
+                  WHERE s.name = ?1
+                    AND e.value = ?2
+                    AND e.id NOT IN ({})
+                );
+                "#,
+                expired_ids(3)
+            ),
+            rusqlite::params![set_name, element.as_ref(), now_ms()],
+            |row| row.get(0),
+        )?;
+        Ok(exists != 0)
+    }
+
+    // Given elements, returns a vec of bool, positionally matching the elements where
+    // true is in the set, and false is not. Only the elements the set's Bloom filter
+    // doesn't rule out are looked up.
+    fn are_members(&self, set_name: &str, elements: &[Bytes]) -> Result<Vec<bool>> {
+        let maybe: Vec<bool> = elements
+            .iter()
+            .map(|element| self.bloom_may_contain(set_name, element))
+            .collect();
+        if maybe.iter().all(|m| *m) {
+            return self.are_members_in_db(set_name, elements);
+        }
+
+        let candidates: Vec<Bytes> = elements
+            .iter()
+            .zip(&maybe)
+            .filter(|(_, m)| **m)
+            .map(|(element, _)| element.clone())
+            .collect();
+        let mut found = self.are_members_in_db(set_name, &candidates)?.into_iter();
+        Ok(maybe
+            .into_iter()
+            .map(|m| m && found.next().unwrap_or(false))
+            .collect())
+    }
+
+    // Counted in SQL from the smallest set, probing the others for each of its elements,
+    // and the count stops at `limit` rather than working out the whole intersection
+    fn intersection_count(&self, set_names: &[String], limit: Option<u64>) -> Result<u64> {
+        if set_names.is_empty() {
+            return Ok(0);
+        }
+
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut sets = Vec::with_capacity(set_names.len());
+        for set_name in set_names {
+            let set = conn
+                .query_row(
+                    "SELECT id, element_count FROM sets WHERE name = ?1",
+                    [set_name],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+                )
                 .optional()?;
+            match set {
+                Some(set) => sets.push(set),
+                None => return Ok(0),
+            }
+        }
+        sets.sort_by_key(|&(_, element_count)| element_count);
+
+        // ?1 = the smallest set, ?2 = the time, then the other sets, then the limit
+        let probes: String = (0..sets.len() - 1)
+            .map(|i| {
+                format!(
+                    " AND EXISTS (
+                        SELECT 1 FROM elements o
+                        WHERE o.set_id = ?{} AND o.value = e.value AND o.id NOT IN ({})
+                    )",
+                    i + 3,
+                    expired_ids(2)
+                )
+            })
+            .collect();
+        let sql = format!(
+            "SELECT COUNT(*) FROM (
+                 SELECT 1 FROM elements e
+                 WHERE e.set_id = ?1 AND e.id NOT IN ({expired}){probes}
+                 LIMIT ?{limit}
+             )",
+            expired = expired_ids(2),
+            limit = sets.len() + 2
+        );
+
+        let now = now_ms();
+        // SQLite takes a negative LIMIT as no limit
+        let limit = limit.map_or(-1, |limit| limit.min(i64::MAX as u64) as i64);
+        let mut params: Vec<&dyn ToSql> = vec![&sets[0].0, &now];
+        params.extend(sets[1..].iter().map(|(id, _)| id as &dyn ToSql));
+        params.push(&limit);
+
+        conn.query_row(&sql, rusqlite::params_from_iter(params), |row| row.get(0))
+    }
+
+    /// A replication received add event.
+    /// Assumption is that if the `Dot` of the event has already been observed this method will not be called.
+    ///
+    /// Much like add_elements above, here the given dot is added for each of the elements,
+    /// and all the dots on removed_dots are removed from the set of supporting dots for each added element.
+    /// The Server also passes every local dot on the elements that the operation's context covers
+    /// (see `covered_dots`), so dots dropped from removed_dots as causally stable are still removed.
+    fn replicate_add(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        self.replicate(set_name, elements, removed_dots, dot, None)
+    }
+
+    fn replicate_add_expiring(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+        expires_at: u64,
+    ) -> Result<()> {
+        self.replicate(set_name, elements, removed_dots, dot, Some(expires_at))
+    }
+
+    fn expired_elements(&self, now: u64, limit: usize) -> Result<Vec<(String, Vec<Bytes>)>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT s.name, e.value
+             FROM elements e
+             JOIN sets s ON s.id = e.set_id
+             WHERE e.id IN ({})
+             ORDER BY s.name
+             LIMIT ?2",
+            expired_ids(1)
+        ))?;
+        let rows = stmt.query_map(rusqlite::params![now, limit as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                Bytes::from(row.get::<_, Vec<u8>>(1)?),
+            ))
+        })?;
+
+        let mut expired: Vec<(String, Vec<Bytes>)> = Vec::new();
+        for row in rows {
+            let (set_name, value) = row?;
+            match expired.last_mut() {
+                Some((name, values)) if *name == set_name => values.push(value),
+                _ => expired.push((set_name, vec![value])),
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Like add_elements, for many sets in one transaction: every element gets the one
+    /// `dot`, and the version vector is bumped once.
+    fn add_elements_multi(&self, sets: &[(&str, &[Bytes])], dot: Dot) -> Result<Vec<Vec<Dot>>> {
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+
+        let mut deleted = Vec::with_capacity(sets.len());
+        for (set_name, elements) in sets {
+            deleted.push(Self::add_in_tx(&tx, set_name, elements, dot, None)?);
+            self.bloom_insert(set_name, elements);
+        }
+
+        if sets.iter().any(|(_, elements)| !elements.is_empty()) {
+            Self::observe_dot(&tx, dot)?;
+        }
+
+        tx.commit()?;
+        for (set_name, _) in sets {
+            self.refresh_bloom(&conn, set_name);
+        }
+        Ok(deleted)
+    }
+
+    fn replicate_add_multi(&self, sets: &[SetAdd], dot: Dot) -> Result<()> {
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+
+        for set in sets {
+            Self::replicate_add_in_tx(
+                &tx,
+                &set.set_name,
+                &set.elements,
+                &set.removed_dots,
+                dot,
+                None,
+            )?;
+            self.bloom_insert(&set.set_name, &set.elements);
+        }
+
+        if sets.iter().any(|set| !set.elements.is_empty()) {
+            Self::observe_dot(&tx, dot)?;
+        }
+
+        tx.commit()?;
+        for set in sets {
+            self.refresh_bloom(&conn, &set.set_name);
+        }
+        Ok(())
+    }
+
+    /// A replication received remove event.
+    /// Assumption is that if the `Dot` of the event has already been observed this method will not be called.
+    ///
+    /// Much like replicated_add aboce, all the dots in removed_dots are removed from the set of supporting dots for each added element.
+    /// If any element has no dots left, it is removed from the set.
+    fn replicate_remove(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        removed_dots: &[Dot],
+        dot: Dot,
+    ) -> Result<()> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+
+        let set_id: Option<i64> = tx
+            .query_row("SELECT id FROM sets WHERE name = ?1", [set_name], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(set_id) = set_id else {
+            // Nothing to remove from a set we don't have, but the dot is still seen,
+            // without creating the set
+            Self::observe_dot(&tx, dot)?;
+            tx.commit()?;
+            return Ok(());
+        };
+
+        let actor_id = dot.actor_id.bytes();
+        let mut removed = 0;
+
+        // For each element
+        for element in elements {
+            // Get existing element_id (skip this element if no such element)
+            let element_id: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM elements WHERE set_id = ?1 AND value = ?2",
+                    rusqlite::params![set_id, element.as_ref()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            if let Some(element_id) = element_id {
+                let had_dots: bool = tx.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM dots WHERE element_id = ?1)",
+                    [element_id],
+                    |row| row.get(0),
+                )?;
+
+                // Remove each of the removed_dots for this element
+                if !removed_dots.is_empty() {
+                    let placeholders = std::iter::repeat("(?, ?)")
+                        .take(removed_dots.len())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+
+                    let sql = format!(
+                        "DELETE FROM dots WHERE element_id = ?1 AND (actor_id, counter) IN ({})",
+                        placeholders
+                    );
+
+                    // Collect actor_id bytes first to ensure stable lifetimes
+                    let removed_dots_params: Vec<(&[u8], u64)> = removed_dots
+                        .iter()
+                        .map(|d| (d.actor_id.bytes(), d.counter))
+                        .collect();
+
+                    let mut params: Vec<&dyn ToSql> = vec![&element_id];
+                    for i in 0..removed_dots.len() {
+                        params.push(&removed_dots_params[i].0);
+                        params.push(&removed_dots_params[i].1);
+                    }
+
+                    tx.execute(&sql, rusqlite::params_from_iter(params))?;
+                }
+
+                // If there are no dots left for this element, remove the element
+                let dot_count: i64 = tx.query_row(
+                    "SELECT COUNT(*) FROM dots WHERE element_id = ?1",
+                    [element_id],
+                    |row| row.get(0),
+                )?;
+
+                if dot_count == 0 {
+                    tx.execute("DELETE FROM elements WHERE id = ?1", [element_id])?;
+                    if had_dots {
+                        removed += 1;
+                    }
+                }
+            }
+        }
+
+        Self::adjust_element_count(&tx, set_id, -removed)?;
+
+        // Update version vector with the new dot
+        tx.execute(
+            "INSERT INTO version_vector (actor_id, counter) VALUES (?1, ?2) ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
+            rusqlite::params![actor_id, dot.counter],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The dots on the given elements that `vv` has seen.
+    /// An operation's context covers every dot its sender had seen, so these are the dots the
+    /// operation replaces, including any the sender left out of removed_dots.
+    fn covered_dots(
+        &self,
+        set_name: &str,
+        elements: &[Bytes],
+        vv: &VersionVector,
+    ) -> Result<Vec<Dot>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare(
+            "SELECT d.actor_id, d.counter FROM dots d
+             JOIN elements e ON d.element_id = e.id
+             JOIN sets s ON e.set_id = s.id
+             WHERE s.name = ?1 AND e.value = ?2",
+        )?;
+
+        let mut covered = Vec::new();
+        for element in elements {
+            let rows = stmt.query_map(rusqlite::params![set_name, element.as_ref()], |row| {
+                Dot::from_parts(row.get(0)?, row.get(1)?)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+            })?;
+            for dot in rows {
+                let dot = dot?;
+                if vv.contains_dot(dot) {
+                    covered.push(dot);
+                }
+            }
+        }
+        Ok(covered)
+    }
+
+    /// Garbage collect dots below the causally stable frontier.
+    /// Every replica has seen every dot `stable` covers, so any future operation on an element
+    /// replaces all of its stable dots together. An element supported only by stable dots
+    /// needs just one of them: the rest are deleted, keeping the highest. Elements with an
+    /// expiring dot are left alone, as which dot is kept decides when the element expires.
+    /// Returns the number of dots deleted.
+    fn compact_dots(&self, stable: &VersionVector, set_name: Option<&str>) -> Result<usize> {
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+
+        let mut by_element: Vec<(i64, Vec<Dot>)> = Vec::new();
+        {
+            let mut stmt = tx.prepare(
+                "SELECT element_id, actor_id, counter FROM dots
+                 WHERE element_id IN (
+                     SELECT element_id FROM dots GROUP BY element_id HAVING COUNT(*) > 1
+                 )
+                 AND element_id NOT IN (SELECT element_id FROM dots WHERE expires_at IS NOT NULL)
+                 AND (?1 IS NULL OR element_id IN (
+                     SELECT e.id FROM elements e JOIN sets s ON s.id = e.set_id WHERE s.name = ?1
+                 ))
+                 ORDER BY element_id",
+            )?;
+            let rows = stmt.query_map([set_name], |row| {
+                let dot = Dot::from_parts(row.get(1)?, row.get(2)?)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                Ok((row.get::<_, i64>(0)?, dot))
+            })?;
+            for row in rows {
+                let (element_id, dot) = row?;
+                match by_element.last_mut() {
+                    Some((id, dots)) if *id == element_id => dots.push(dot),
+                    _ => by_element.push((element_id, vec![dot])),
+                }
+            }
+        }
+
+        let mut deleted = 0;
+        {
+            let mut delete =
+                tx.prepare("DELETE FROM dots WHERE element_id = ?1 AND actor_id = ?2")?;
+            for (element_id, mut dots) in by_element {
+                if !dots.iter().all(|dot| stable.contains_dot(*dot)) {
+                    continue;
+                }
+                dots.sort_by_key(|dot| (dot.counter, dot.actor_id));
+                dots.pop();
+                for dot in dots {
+                    deleted +=
+                        delete.execute(rusqlite::params![element_id, dot.actor_id.bytes()])?;
+                }
+            }
+        }
+
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn logs_ops(&self) -> bool {
+        self.op_log
+    }
+
+    fn append_ops(&self, ops: &[Operation]) -> Result<()> {
+        if !self.op_log || ops.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR IGNORE INTO op_log (actor_id, counter, operation) VALUES (?1, ?2, ?3)",
+            )?;
+            for op in ops {
+                let dot = op.dot();
+                insert.execute(rusqlite::params![
+                    dot.actor_id.bytes(),
+                    dot.counter,
+                    proto::operation_to_proto(op, false).encode_to_vec()
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Scans the whole log, which causal stability keeps short
+    fn ops_since(&self, vv: &VersionVector) -> Result<(Vec<Operation>, bool)> {
+        if !self.op_log {
+            return Ok((Vec::new(), false));
+        }
+
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut complete = true;
+        let mut stmt = conn.prepare("SELECT actor_id, counter FROM op_log_floor")?;
+        for row in stmt.query_map([], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, u64>(1)?))
+        })? {
+            let (actor_id, counter) = row?;
+            let actor_id = ActorId::from_bytes(&actor_id)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            complete &= vv.get(actor_id) >= counter;
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT actor_id, counter, operation FROM op_log ORDER BY actor_id, counter",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let dot = Dot::from_parts(row.get(0)?, row.get(1)?)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            Ok((dot, row.get::<_, Vec<u8>>(2)?))
+        })?;
+
+        let mut ops = Vec::new();
+        for row in rows {
+            let (dot, encoded) = row?;
+            if vv.contains_dot(dot) {
+                continue;
+            }
+            let op = proto::replication::Operation::decode(&encoded[..])
+                .ok()
+                .and_then(|op| proto::proto_to_operation(&op))
+                .ok_or_else(|| {
+                    rusqlite::Error::ToSqlConversionFailure(
+                        format!("malformed operation {} in op_log", dot).into(),
+                    )
+                })?;
+            ops.push(op);
+        }
+        Ok((ops, complete))
+    }
+
+    fn truncate_ops(&self, stable: &VersionVector) -> Result<usize> {
+        if !self.op_log {
+            return Ok(0);
+        }
+
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let tx = conn.transaction()?;
+        let mut deleted = 0;
+        {
+            let mut delete =
+                tx.prepare("DELETE FROM op_log WHERE actor_id = ?1 AND counter <= ?2")?;
+            let mut floor = tx.prepare(
+                "INSERT INTO op_log_floor (actor_id, counter) VALUES (?1, ?2) ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
+            )?;
+            for (actor_id, counter) in &stable.counters {
+                deleted += delete.execute(rusqlite::params![actor_id.bytes(), counter])?;
+                floor.execute(rusqlite::params![actor_id.bytes(), counter])?;
+            }
+        }
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Checkpoint the WAL into the database and truncate it, after a VACUUM if asked.
+    /// Under steady writes SQLite's own (passive) checkpoints never get to reset the WAL.
+    /// VACUUM rewrites the whole database, and holds the writer while it does.
+    fn maintenance(&self, vacuum: bool) -> Result<u64> {
+        let conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let before = self.disk_usage();
+
+        if vacuum {
+            conn.execute_batch("VACUUM")?;
+        }
+
+        // VACUUM goes through the WAL too, so checkpoint after it
+        let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+        if busy != 0 {
+            warn!("WAL checkpoint was blocked by readers, the WAL was not truncated");
+        }
+
+        Ok(before.saturating_sub(self.disk_usage()))
+    }
+
+    /// Checkpoint the WAL and truncate it, holding the writer as briefly as it can.
+    ///
+    /// A passive checkpoint on a read connection first copies what it can while writes
+    /// carry on. The writer is then held only to copy what was written meanwhile and
+    /// truncate, and doesn't wait on readers: if one still reads an older snapshot, the
+    /// WAL is left as it is, to be truncated by a later checkpoint.
+    fn checkpoint(&self) -> Result<Checkpoint> {
+        let pool_error = |e: r2d2::Error| rusqlite::Error::ToSqlConversionFailure(Box::new(e));
+        let wal_checkpoint = |conn: &Connection, mode: &str| -> Result<(bool, i64, i64)> {
+            conn.query_row(&format!("PRAGMA wal_checkpoint({})", mode), [], |row| {
+                Ok((row.get::<_, i64>(0)? != 0, row.get(1)?, row.get(2)?))
+            })
+        };
+
+        let reader = self.pool.get().map_err(pool_error)?;
+        wal_checkpoint(&reader, "PASSIVE")?;
+        drop(reader);
+
+        let conn = self.writer.get().map_err(pool_error)?;
+        // With no writes now, the frames in the WAL are those a truncation writes back
+        let (_, frames, _) = wal_checkpoint(&conn, "PASSIVE")?;
+        let busy_timeout: i64 = conn.query_row("PRAGMA busy_timeout", [], |row| row.get(0))?;
+        conn.pragma_update(None, "busy_timeout", 0)?;
+        let truncate = wal_checkpoint(&conn, "TRUNCATE");
+        conn.pragma_update(None, "busy_timeout", busy_timeout)?;
+        let (busy, _, checkpointed) = truncate?;
+
+        if busy {
+            warn!(
+                "WAL checkpoint wrote back {} of {} frames, readers kept the WAL from being truncated",
+                checkpointed, frames
+            );
+            return Ok(Checkpoint {
+                frames: checkpointed as u64,
+                truncated: false,
+            });
+        }
+        Ok(Checkpoint {
+            frames: frames as u64,
+            truncated: true,
+        })
+    }
+
+    /// Digests of the set split into `bucket_count` buckets, so a difference can be narrowed down
+    /// to the buckets that disagree. Elements are bucketed by a hash of their value (see `digest_bucket`),
+    /// not by element id, as ids are local to each replica.
+    /// Each bucket is hashed in (element, actor, counter) order, streaming over the set.
+    fn bucket_digests(&self, set_name: &str, bucket_count: u32) -> Result<Vec<Digest>> {
+        let bucket_count = bucket_count.max(1);
+
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare(
+            r#"
+                SELECT e.value, d.actor_id, d.counter
+                FROM elements e
+                JOIN sets s ON s.id = e.set_id
+                JOIN dots d ON d.element_id = e.id
+                WHERE s.name = ?1
+                ORDER BY e.value, d.actor_id, d.counter;
+                "#,
+        )?;
+        let mut rows = stmt.query([set_name])?;
+
+        let mut digests = BucketDigests::new(bucket_count);
+        while let Some(row) = rows.next()? {
+            let value: Vec<u8> = row.get(0)?;
+            let dot = Dot::from_parts(row.get(1)?, row.get(2)?)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            digests.add(&value, dot);
+        }
+
+        Ok(digests.finish())
+    }
+
+    /// Anti-entropy delta: every element with at least one dot that `vv` has not observed,
+    /// along with all of that element's dots.
+    /// If `buckets` is not empty only elements in those digest buckets (out of `bucket_count`) are considered.
+    /// Elements the requester has fully seen are left out, so this says nothing about removes
+    /// of elements outside the delta (there are no tombstones to send).
+    fn elements_since(
+        &self,
+        set_name: &str,
+        vv: &VersionVector,
+        buckets: &[u32],
+        bucket_count: u32,
+    ) -> Result<Vec<ElementDots>> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let mut stmt = conn.prepare(
+            r#"
+                SELECT e.value, d.actor_id, d.counter
+                FROM elements e
+                JOIN sets s ON s.id = e.set_id
+                JOIN dots d ON d.element_id = e.id
+                WHERE s.name = ?1
+                ORDER BY e.id;
+                "#,
+        )?;
+        let rows = stmt.query_map([set_name], |row| {
+            let value: Vec<u8> = row.get(0)?;
+            let dot = Dot::from_parts(row.get(1)?, row.get(2)?)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            Ok((value, dot))
+        })?;
+
+        // Rows are ordered by element, so group consecutive rows
+        let mut elements: Vec<ElementDots> = Vec::new();
+        for row in rows {
+            let (value, dot) = row?;
+            match elements.last_mut() {
+                Some((last, dots)) if last.as_ref() == value.as_slice() => dots.push(dot),
+                _ => elements.push((Bytes::from(value), vec![dot])),
+            }
+        }
+
+        elements.retain(|(value, dots)| {
+            (buckets.is_empty() || buckets.contains(&digest_bucket(value, bucket_count)))
+                && dots.iter().any(|dot| !vv.contains_dot(*dot))
+        });
+        Ok(elements)
+    }
+
+    /// A consistent copy of every set and the version vector, for state transfer to a new replica.
+    /// Read in one transaction, so the version vector covers exactly the dots returned.
+    fn snapshot(&self) -> Result<(VersionVector, Vec<SetSnapshot>)> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction()?;
+
+        let mut vv = VersionVector::new();
+        {
+            let mut stmt = tx.prepare("SELECT actor_id, counter FROM version_vector")?;
+            let rows = stmt.query_map([], |row| {
+                Dot::from_parts(row.get(0)?, row.get(1)?)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+            })?;
+            for row in rows {
+                let entry = row?;
+                vv.update(entry.actor_id, entry.counter);
+            }
+        }
+
+        let mut sets: Vec<SetSnapshot> = Vec::new();
+        {
+            let mut stmt = tx.prepare(
+                r#"
+                    SELECT s.name, e.id, e.value, d.actor_id, d.counter
+                    FROM sets s
+                    JOIN elements e ON e.set_id = s.id
+                    JOIN dots d ON d.element_id = e.id
+                    ORDER BY s.name, e.id;
+                    "#,
+            )?;
+            let rows = stmt.query_map([], |row| {
+                let dot = Dot::from_parts(row.get(3)?, row.get(4)?)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                    dot,
+                ))
+            })?;
+
+            // Rows are ordered by set then element, so group consecutive rows
+            let mut last_element = None;
+            for row in rows {
+                let (set_name, element_id, value, dot) = row?;
+                if sets.last().is_none_or(|(name, _)| *name != set_name) {
+                    sets.push((set_name, Vec::new()));
+                    last_element = None;
+                }
+                let elements = &mut sets.last_mut().unwrap().1;
+                if last_element == Some(element_id) {
+                    elements.last_mut().unwrap().1.push(dot);
+                } else {
+                    elements.push((Bytes::from(value), vec![dot]));
+                    last_element = Some(element_id);
+                }
+            }
+        }
+
+        tx.commit()?;
+        Ok((vv, sets))
+    }
+
+    /// Replace all local state with a snapshot from a peer (state transfer).
+    /// Only correct when `vv` descends the local version vector: the peer has then seen
+    /// everything we have, so its state supersedes ours. Done in one transaction.
+    fn bulk_load_snapshot(&self, vv: &VersionVector, sets: &[SetSnapshot]) -> Result<()> {
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+
+        tx.execute_batch(
+            "DELETE FROM dots; DELETE FROM elements; DELETE FROM sets; DELETE FROM version_vector;",
+        )?;
+
+        {
+            let mut insert_set = tx.prepare(
+                "INSERT INTO sets (name, created_at, element_count) VALUES (?1, ?2, ?3) RETURNING id",
+            )?;
+            let mut insert_element =
+                tx.prepare("INSERT INTO elements (set_id, value) VALUES (?1, ?2) RETURNING id")?;
+            let mut insert_dot =
+                tx.prepare("INSERT INTO dots (element_id, actor_id, counter) VALUES (?1, ?2, ?3)")?;
+
+            // The peer's creation times aren't in the snapshot, so the sets are stamped as created now
+            let now_ms = now_ms();
+            for (set_name, elements) in sets {
+                let element_count = elements.iter().filter(|(_, dots)| !dots.is_empty()).count();
+                let set_id: i64 = insert_set.query_row(
+                    rusqlite::params![set_name, now_ms, element_count as i64],
+                    |row| row.get(0),
+                )?;
+                for (value, dots) in elements {
+                    let element_id: i64 = insert_element
+                        .query_row(rusqlite::params![set_id, value.as_ref()], |row| row.get(0))?;
+                    for dot in dots {
+                        insert_dot.execute(rusqlite::params![
+                            element_id,
+                            dot.actor_id.bytes(),
+                            dot.counter
+                        ])?;
+                    }
+                }
+            }
+        }
+        Self::observe_vv(&tx, vv)?;
+
+        // Unfiltered sets are checked in the database, so this is safe until the new filters are built
+        self.blooms.write().unwrap().clear();
+        tx.commit()?;
+        if let Err(e) = self.rebuild_blooms(&conn) {
+            warn!(
+                "Failed to rebuild Bloom filters after loading a snapshot: {}",
+                e
+            );
+        }
+        Ok(())
+    }
 
-            if let Some(element_id) = element_id {
-                // Remove each of the removed_dots for this element
-                if !removed_dots.is_empty() {
-                    let placeholders = std::iter::repeat("(?, ?)")
-                        .take(removed_dots.len())
-                        .collect::<Vec<_>>()
-                        .join(", ");
+    /// Merge an anti-entropy delta from a peer into the local state of the set.
+    /// This is the ORSWOT join, per element in the delta:
+    /// - keep a local dot if the peer has it too, or the peer has not seen it
+    /// - take a remote dot if we have not seen it
+    /// - a dot seen by one side but missing from it was removed there, so it is dropped
+    ///
+    /// An element left with no dots is removed.
+    /// The version vector is not changed: the delta covers one set only, and the vector
+    /// summarises every set. The ops for the installed dots still arrive through replication.
+    /// Returns the number of elements whose dots changed.
+    fn merge_elements(
+        &self,
+        set_name: &str,
+        remote: &[ElementDots],
+        remote_vv: &VersionVector,
+        local_vv: &VersionVector,
+    ) -> Result<usize> {
+        if remote.is_empty() {
+            return Ok(0);
+        }
 
-                    let sql = format!(
-                        "DELETE FROM dots WHERE element_id = ?1 AND (actor_id, counter) IN ({})",
-                        placeholders
-                    );
+        let mut conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
-                    // Collect actor_id bytes first to ensure stable lifetimes
-                    let removed_dots_params: Vec<(&[u8], u64)> = removed_dots
-                        .iter()
-                        .map(|d| (d.actor_id.bytes(), d.counter))
-                        .collect();
+        let tx = conn.transaction()?;
 
-                    let mut params: Vec<&dyn ToSql> = vec![&element_id];
-                    for i in 0..removed_dots.len() {
-                        params.push(&removed_dots_params[i].0);
-                        params.push(&removed_dots_params[i].1);
-                    }
+        // A set we don't have is only created for an element merged in, not for removals
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sets WHERE name = ?1)",
+            [set_name],
+            |row| row.get(0),
+        )?;
+        if !exists && all_seen(remote, local_vv) {
+            return Ok(0);
+        }
+        let set_id = Self::set_id_or_create(&tx, set_name)?;
 
-                    tx.execute(&sql, rusqlite::params_from_iter(params))?;
-                }
+        let mut changed = 0;
+        let mut delta = 0;
 
-                // If there are no dots left for this element, remove the element
-                let dot_count: i64 = tx.query_row(
-                    "SELECT COUNT(*) FROM dots WHERE element_id = ?1",
-                    [element_id],
-                    |row| row.get(0),
-                )?;
+        for (element, remote_dots) in remote {
+            let element_id: i64 = tx.query_row(
+                "INSERT INTO elements (set_id, value) VALUES (?1, ?2) ON CONFLICT(set_id, value) DO UPDATE SET value=value RETURNING id",
+                rusqlite::params![set_id, element.as_ref()],
+                |row| row.get(0),
+            )?;
 
-                if dot_count == 0 {
+            let local_dots = {
+                let mut stmt =
+                    tx.prepare("SELECT actor_id, counter FROM dots WHERE element_id = ?1")?;
+                let rows = stmt.query_map([element_id], |row| {
+                    Dot::from_parts(row.get(0)?, row.get(1)?)
+                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+                })?;
+                rows.collect::<Result<Vec<Dot>>>()?
+            };
+
+            let mut merged: Vec<Dot> = local_dots
+                .iter()
+                .filter(|dot| remote_dots.contains(dot) || !remote_vv.contains_dot(**dot))
+                .copied()
+                .collect();
+            for dot in remote_dots {
+                if !local_vv.contains_dot(*dot) && !merged.contains(dot) {
+                    merged.push(*dot);
+                }
+            }
+
+            // At most one dot per actor per element: the later one has joined the earlier
+            merged.sort_by_key(|dot| std::cmp::Reverse(dot.counter));
+            let mut seen_actors = Vec::new();
+            merged.retain(|dot| {
+                let first = !seen_actors.contains(&dot.actor_id);
+                seen_actors.push(dot.actor_id);
+                first
+            });
+
+            let unchanged = merged.len() == local_dots.len()
+                && merged.iter().all(|dot| local_dots.contains(dot));
+            if unchanged {
+                // A remote element with no dots (a removal in a delta) we never had
+                if local_dots.is_empty() {
                     tx.execute("DELETE FROM elements WHERE id = ?1", [element_id])?;
                 }
+                continue;
+            }
+            changed += 1;
+            match (local_dots.is_empty(), merged.is_empty()) {
+                (true, false) => delta += 1,
+                (false, true) => delta -= 1,
+                _ => {}
+            }
+
+            tx.execute("DELETE FROM dots WHERE element_id = ?1", [element_id])?;
+            if merged.is_empty() {
+                tx.execute("DELETE FROM elements WHERE id = ?1", [element_id])?;
+                continue;
+            }
+            for dot in &merged {
+                tx.execute(
+                    "INSERT INTO dots (element_id, actor_id, counter) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![element_id, dot.actor_id.bytes(), dot.counter],
+                )?;
             }
         }
 
-        // Update version vector with the new dot
-        tx.execute(
+        Self::adjust_element_count(&tx, set_id, delta)?;
+        if let Some(bloom) = self.blooms.write().unwrap().get_mut(set_name) {
+            for (element, _) in remote {
+                bloom.insert(element);
+            }
+        }
+        tx.commit()?;
+        self.refresh_bloom(&conn, set_name);
+        Ok(changed)
+    }
+
+    fn observe_dot(&self, dot: Dot) -> Result<()> {
+        let conn = self
+            .writer
+            .get()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        conn.execute(
             "INSERT INTO version_vector (actor_id, counter) VALUES (?1, ?2) ON CONFLICT(actor_id) DO UPDATE SET counter = MAX(counter, excluded.counter)",
-            rusqlite::params![actor_id, dot.counter],
+            rusqlite::params![dot.actor_id.bytes(), dot.counter],
         )?;
-
-        tx.commit()?;
         Ok(())
     }
+
+    fn load_epoch(&self) -> Result<Option<u8>> {
+        self.load_meta("epoch")?
+            .map(|epoch| {
+                u8::try_from(epoch)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+            })
+            .transpose()
+    }
+
+    fn save_epoch(&self, epoch: u8) -> Result<()> {
+        self.save_meta("epoch", epoch.into())
+    }
+}
+
+/// Whether `table` has a `column`
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )
+}
+
+/// The ids of the elements whose every dot had expired by the parameter `?{now}`.
+/// Only expiring dots are scanned, through `idx_dots_expires_at`.
+fn expired_ids(now: usize) -> String {
+    format!(
+        "SELECT x.element_id FROM dots x
+         WHERE x.expires_at <= ?{now}
+         AND NOT EXISTS (
+             SELECT 1 FROM dots l
+             WHERE l.element_id = x.element_id AND (l.expires_at IS NULL OR l.expires_at > ?{now})
+         )"
+    )
+}
+
+/// A glob as `config::glob_match` takes it, rewritten for SQLite's GLOB; None when
+/// nothing can match it, as with an empty class
+///
+/// GLOB has no `\` escape, so a literal `*`, `?` or `[` becomes a class of just that
+/// byte. Inside a class GLOB only reads `]` as a member when it comes first, `^` when it
+/// doesn't, and `-` when it comes last, so those are taken out of ranges and put there.
+fn sql_glob(pattern: &[u8]) -> Option<Vec<u8>> {
+    fn literal(glob: &mut Vec<u8>, c: u8) {
+        if matches!(c, b'*' | b'?' | b'[') {
+            glob.extend_from_slice(&[b'[', c, b']']);
+        } else {
+            glob.push(c);
+        }
+    }
+
+    let mut glob = Vec::with_capacity(pattern.len());
+    let mut p = 0;
+    while p < pattern.len() {
+        if pattern[p] == b'*' {
+            glob.push(b'*');
+            p += 1;
+            continue;
+        }
+        let (token, len) = glob_token(&pattern[p..])?;
+        p += len;
+        let (negated, ranges) = match token {
+            GlobToken::Any => {
+                glob.push(b'?');
+                continue;
+            }
+            GlobToken::Byte(c) => {
+                literal(&mut glob, c);
+                continue;
+            }
+            GlobToken::Class { negated, ranges } => (negated, ranges),
+        };
+
+        let special = |c: u16| matches!(c as u8, b']' | b'^' | b'-');
+        let mut specials = Vec::new();
+        let mut middle = Vec::new();
+        for (lo, hi) in ranges {
+            let (mut lo, mut hi) = (u16::from(lo), u16::from(hi));
+            while lo <= hi && special(lo) {
+                specials.push(lo as u8);
+                lo += 1;
+            }
+            while lo <= hi && special(hi) {
+                specials.push(hi as u8);
+                hi -= 1;
+            }
+            if lo < hi {
+                middle.extend_from_slice(&[lo as u8, b'-', hi as u8]);
+            } else if lo == hi {
+                middle.push(lo as u8);
+            }
+        }
+        specials.sort_unstable();
+        specials.dedup();
+        let has = |c: u8| specials.contains(&c);
+
+        match (negated, specials.is_empty() && middle.is_empty()) {
+            (false, true) => return None,
+            (true, true) => glob.push(b'?'),
+            // A lone `^` would read as negation, and needs no class anyway
+            (false, false) if middle.is_empty() && specials == [b'^'] => glob.push(b'^'),
+            (negated, false) => {
+                glob.push(b'[');
+                if negated {
+                    glob.push(b'^');
+                }
+                if has(b']') {
+                    glob.push(b']');
+                }
+                // `-` first is as literal as `-` last, and keeps `^` off the front
+                let dash_first = !negated && !has(b']') && middle.is_empty() && has(b'^');
+                if dash_first {
+                    glob.push(b'-');
+                }
+                glob.extend_from_slice(&middle);
+                if has(b'^') {
+                    glob.push(b'^');
+                }
+                if has(b'-') && !dash_first {
+                    glob.push(b'-');
+                }
+                glob.push(b']');
+            }
+        }
+    }
+    Some(glob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::glob_match;
+    use tempfile::TempDir;
+
+    fn config() -> StorageConfig {
+        StorageConfig {
+            sqlite_cache_size: 1000,
+            sqlite_busy_timeout: 5000,
+            read_pool_size: 5,
+            pool_min_idle: 1,
+            checkpoint_interval_ms: 0,
+            bloom_min_elements: 0,
+            bloom_fp_rate: 0.01,
+            max_key_length: 1024,
+            max_element_size: 65536,
+            max_members_per_command: 100_000,
+            expiry_interval_ms: 1000,
+            op_log: false,
+            mmap_size: 0,
+            page_size: 0,
+            encryption: None,
+        }
+    }
+
+    fn open(temp: &TempDir) -> SqliteStorage {
+        SqliteStorage::open(temp.path().join("test.db"), &config()).unwrap()
+    }
+
+    fn element_rows(storage: &SqliteStorage, value: &[u8]) -> i64 {
+        let conn = storage.pool().get().unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) FROM elements WHERE value = ?1",
+            [value],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_remove_only_deletes_elements_that_had_dots() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let actor_id = ActorId::from_node_id(1);
+        let present = Bytes::from("present");
+        let absent = Bytes::from("absent");
+
+        storage
+            .add_elements("s", std::slice::from_ref(&present), Dot::new(actor_id, 1))
+            .unwrap();
+        // A row for `absent` with no dots of its own to delete
+        storage
+            .writer
+            .get()
+            .unwrap()
+            .execute(
+                "INSERT INTO elements (set_id, value) SELECT id, ?1 FROM sets WHERE name = 's'",
+                [absent.as_ref()],
+            )
+            .unwrap();
+
+        let deleted = storage
+            .remove_elements(
+                "s",
+                &[present.clone(), absent.clone()],
+                Dot::new(actor_id, 2),
+            )
+            .unwrap();
+
+        assert_eq!(deleted, vec![Dot::new(actor_id, 1)]);
+        assert_eq!(element_rows(&storage, &present), 0);
+        assert_eq!(
+            element_rows(&storage, &absent),
+            1,
+            "absent element was touched"
+        );
+    }
+
+    #[test]
+    fn test_delete_set_returns_its_dots_and_leaves_other_sets() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let (a, b) = (ActorId::from_node_id(1), ActorId::from_node_id(2));
+        let (x, y) = (Bytes::from("x"), Bytes::from("y"));
+
+        storage
+            .add_elements("s", &[x.clone(), y.clone()], Dot::new(a, 1))
+            .unwrap();
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], Dot::new(b, 1))
+            .unwrap();
+        storage
+            .add_elements("t", std::slice::from_ref(&x), Dot::new(a, 2))
+            .unwrap();
+
+        let mut deleted = storage.delete_set("s").unwrap();
+        deleted.sort();
+        assert_eq!(
+            deleted,
+            vec![Dot::new(a, 1), Dot::new(a, 1), Dot::new(b, 1)]
+        );
+
+        assert!(storage.get_elements("s").unwrap().is_empty());
+        assert_eq!(element_rows(&storage, &y), 0);
+        assert_eq!(storage.get_elements("t").unwrap(), vec![x]);
+        assert!(storage.delete_set("s").unwrap().is_empty());
+        assert!(storage.delete_set("missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_add_elements_multi_groups_replaced_dots_by_set() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let (a, b) = (ActorId::from_node_id(1), ActorId::from_node_id(2));
+        let (x, y) = (Bytes::from("x"), Bytes::from("y"));
+
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], Dot::new(b, 1))
+            .unwrap();
+        storage
+            .replicate_add("t", std::slice::from_ref(&y), &[], Dot::new(b, 2))
+            .unwrap();
+
+        let dot = Dot::new(a, 1);
+        let replaced = storage
+            .add_elements_multi(
+                &[
+                    ("s", std::slice::from_ref(&x)),
+                    ("t", &[x.clone(), y.clone()]),
+                    ("u", &[]),
+                ],
+                dot,
+            )
+            .unwrap();
+
+        assert_eq!(
+            replaced,
+            vec![vec![Dot::new(b, 1)], vec![Dot::new(b, 2)], vec![]]
+        );
+        assert_eq!(storage.get_elements("s").unwrap(), vec![x.clone()]);
+        assert_eq!(storage.count_elements("t").unwrap(), 2);
+        assert_eq!(storage.load_vv().unwrap().get(a), 1);
+        assert_eq!(
+            storage
+                .covered_dots("t", &[x, y], &storage.load_vv().unwrap())
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_commit_group_applies_writes_in_order() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let a = ActorId::from_node_id(1);
+        let (x, y) = (Bytes::from("x"), Bytes::from("y"));
+
+        let write = |kind, elements, counter| GroupWrite {
+            kind,
+            set_name: "s",
+            elements,
+            dot: Dot::new(a, counter),
+        };
+        let results = storage
+            .commit_group(&[
+                write(WriteKind::Add, std::slice::from_ref(&x), 1),
+                write(WriteKind::Add, &[x.clone(), y.clone()], 2),
+                write(WriteKind::Remove, std::slice::from_ref(&y), 3),
+                write(WriteKind::Remove, std::slice::from_ref(&y), 4),
+            ])
+            .unwrap();
+
+        // Each write sees the ones before it
+        assert_eq!(
+            results,
+            vec![vec![], vec![Dot::new(a, 1)], vec![Dot::new(a, 2)], vec![]]
+        );
+        assert_eq!(storage.get_elements("s").unwrap(), vec![x]);
+        assert_eq!(storage.count_elements("s").unwrap(), 1);
+        assert_eq!(storage.load_vv().unwrap().get(a), 4);
+    }
+
+    #[test]
+    fn test_open_rejects_bad_pool_sizes() {
+        let temp = TempDir::new().unwrap();
+        let config = |read_pool_size, pool_min_idle| StorageConfig {
+            sqlite_cache_size: 1000,
+            sqlite_busy_timeout: 5000,
+            read_pool_size,
+            pool_min_idle,
+            checkpoint_interval_ms: 0,
+            bloom_min_elements: 0,
+            bloom_fp_rate: 0.01,
+            max_key_length: 1024,
+            max_element_size: 65536,
+            max_members_per_command: 100_000,
+            expiry_interval_ms: 1000,
+            op_log: false,
+            mmap_size: 0,
+            page_size: 0,
+            encryption: None,
+        };
+        let path = temp.path().join("test.db");
+
+        assert!(SqliteStorage::open(&path, &config(0, 1)).is_err());
+        assert!(SqliteStorage::open(&path, &config(2, 0)).is_err());
+        assert!(SqliteStorage::open(&path, &config(2, 3)).is_err());
+        assert!(SqliteStorage::open(&path, &config(1, 1)).is_ok());
+    }
+
+    #[test]
+    fn test_maintenance_truncates_the_wal() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let actor_id = ActorId::from_node_id(1);
+        let elements: Vec<Bytes> = (0..1000)
+            .map(|i| Bytes::from(format!("element-{}", i)))
+            .collect();
+
+        storage
+            .add_elements("s", &elements, Dot::new(actor_id, 1))
+            .unwrap();
+        storage.delete_set("s").unwrap();
+
+        assert!(storage.maintenance(true).unwrap() > 0);
+        let wal = temp.path().join("test.db-wal");
+        assert_eq!(std::fs::metadata(wal).unwrap().len(), 0);
+        assert_eq!(storage.maintenance(false).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_checkpoint_reports_frames_and_waits_for_no_reader() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let wal = temp.path().join("test.db-wal");
+        let add = |counter| {
+            storage
+                .add_elements(
+                    "s",
+                    &[Bytes::from(format!("element-{}", counter))],
+                    Dot::new(ActorId::from_node_id(1), counter),
+                )
+                .unwrap();
+        };
+        add(1);
+
+        let checkpoint = storage.checkpoint().unwrap();
+        assert!(checkpoint.truncated);
+        assert!(checkpoint.frames > 0);
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+
+        // A reader in the middle of a read holds on to the WAL it started with
+        add(2);
+        let reader = storage.pool().get().unwrap();
+        reader
+            .execute_batch("BEGIN; SELECT count(*) FROM sets;")
+            .unwrap();
+        add(3);
+        let checkpoint = storage.checkpoint().unwrap();
+        assert!(!checkpoint.truncated);
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+
+        reader.execute_batch("COMMIT").unwrap();
+        drop(reader);
+        assert!(storage.checkpoint().unwrap().truncated);
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+        assert_eq!(storage.count_elements("s").unwrap(), 3);
+    }
+
+    thread_local! {
+        /// Statements run on this thread by traced connections
+        static STATEMENTS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn count_statement(_sql: &str) {
+        STATEMENTS.with(|statements| statements.set(statements.get() + 1));
+    }
+
+    /// Statements that write the version vector, run on this thread by traced connections
+    fn count_vv_statement(sql: &str) {
+        if sql.contains("INTO version_vector") {
+            count_statement(sql);
+        }
+    }
+
+    #[test]
+    fn test_replicated_removes_padded_to_a_bucket_remove_only_their_dots() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let x = Bytes::from("x");
+        let elements = std::slice::from_ref(&x);
+        let actors: Vec<ActorId> = (1..=5).map(ActorId::from_node_id).collect();
+        for &actor_id in &actors {
+            storage
+                .replicate_add("s", elements, &[], Dot::new(actor_id, 1))
+                .unwrap();
+        }
+
+        // Three removed dots, padded out to a statement for four, leave the other two
+        let removed: Vec<Dot> = actors[..3].iter().map(|&a| Dot::new(a, 1)).collect();
+        let new_dot = Dot::new(actors[0], 2);
+        storage
+            .replicate_add("s", elements, &removed, new_dot)
+            .unwrap();
+
+        let mut dots = storage.element_dots("s", &x).unwrap();
+        dots.sort();
+        let mut expected = vec![new_dot, Dot::new(actors[3], 1), Dot::new(actors[4], 1)];
+        expected.sort();
+        assert_eq!(dots, expected);
+        assert_eq!(storage.count_elements("s").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_remove_sets_past_the_variable_limit_are_split() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let x = Bytes::from("x");
+        let elements = std::slice::from_ref(&x);
+        let (a, b, c) = (
+            ActorId::from_node_id(1),
+            ActorId::from_node_id(2),
+            ActorId::from_node_id(3),
+        );
+        storage
+            .replicate_add("s", elements, &[], Dot::new(a, 1))
+            .unwrap();
+        storage
+            .replicate_add("s", elements, &[], Dot::new(b, 1))
+            .unwrap();
+
+        // One more than a whole chunk: a's dot in the first, b's alone in the second. As one
+        // power of two bucket it would need 32769 variables
+        let mut removed: Vec<Dot> = (2..=MAX_REMOVED_DOTS_PER_DELETE as u64)
+            .map(|counter| Dot::new(c, counter))
+            .collect();
+        removed.insert(0, Dot::new(a, 1));
+        removed.push(Dot::new(b, 1));
+        assert_eq!(removed.len(), MAX_REMOVED_DOTS_PER_DELETE + 1);
+
+        let new_dot = Dot::new(c, 1);
+        storage
+            .replicate_add("s", elements, &removed, new_dot)
+            .unwrap();
+        assert_eq!(storage.element_dots("s", &x).unwrap(), vec![new_dot]);
+    }
+
+    #[test]
+    fn test_commit_group_updates_the_vv_in_one_statement() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let (a, b) = (ActorId::from_node_id(1), ActorId::from_node_id(2));
+        let x = Bytes::from("x");
+        let elements = std::slice::from_ref(&x);
+        let writes: Vec<GroupWrite> = [(a, 1, "s"), (b, 7, "t"), (a, 2, "u"), (b, 5, "missing")]
+            .into_iter()
+            .map(|(actor_id, counter, set_name)| GroupWrite {
+                kind: if set_name == "missing" {
+                    WriteKind::Remove
+                } else {
+                    WriteKind::Add
+                },
+                set_name,
+                elements,
+                dot: Dot::new(actor_id, counter),
+            })
+            .collect();
+
+        storage
+            .writer
+            .get()
+            .unwrap()
+            .trace(Some(count_vv_statement));
+        STATEMENTS.with(|statements| statements.set(0));
+        storage.commit_group(&writes).unwrap();
+        assert_eq!(STATEMENTS.with(|statements| statements.get()), 1);
+        storage.writer.get().unwrap().trace(None);
+
+        // Each actor at its highest counter, a remove from a missing set seen too
+        let vv = storage.load_vv().unwrap();
+        assert_eq!((vv.get(a), vv.get(b)), (2, 7));
+        assert!(!storage.set_exists("missing").unwrap());
+    }
+
+    #[test]
+    fn test_set_vv_is_each_actors_newest_dot_in_the_set() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let (a, b) = (ActorId::from_node_id(1), ActorId::from_node_id(2));
+        let (x, y) = (Bytes::from("x"), Bytes::from("y"));
+
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], Dot::new(a, 1))
+            .unwrap();
+        storage
+            .replicate_add("s", std::slice::from_ref(&y), &[], Dot::new(a, 3))
+            .unwrap();
+        storage
+            .replicate_add("s", std::slice::from_ref(&x), &[], Dot::new(b, 2))
+            .unwrap();
+        // Another set's dots, and a removed one, aren't the set's
+        storage
+            .replicate_add("t", std::slice::from_ref(&x), &[], Dot::new(b, 9))
+            .unwrap();
+        storage
+            .replicate_remove(
+                "s",
+                std::slice::from_ref(&y),
+                &[Dot::new(a, 3)],
+                Dot::new(b, 10),
+            )
+            .unwrap();
+
+        let vv = storage.set_vv("s").unwrap();
+        assert_eq!((vv.get(a), vv.get(b)), (1, 2));
+        assert_eq!(vv.counters.len(), 2);
+        let mut whole_set = VersionVector::new();
+        for (_, dots) in storage
+            .elements_since("s", &VersionVector::new(), &[], 1)
+            .unwrap()
+        {
+            for dot in dots {
+                whole_set.update(dot.actor_id, dot.counter);
+            }
+        }
+        assert_eq!(vv, whole_set);
+        assert_eq!(storage.set_vv("missing").unwrap(), VersionVector::new());
+    }
+
+    #[test]
+    fn test_are_members_is_one_query() {
+        let temp = TempDir::new().unwrap();
+        // One read connection, so the traced one is the one the read uses
+        let config = StorageConfig {
+            read_pool_size: 1,
+            ..config()
+        };
+        let storage = SqliteStorage::open(temp.path().join("test.db"), &config).unwrap();
+        let element = |i: usize| Bytes::from(format!("element-{}", i));
+        let added: Vec<Bytes> = (0..500).step_by(2).map(element).collect();
+        storage
+            .add_elements("s", &added, Dot::new(ActorId::from_node_id(1), 1))
+            .unwrap();
+
+        storage.pool().get().unwrap().trace(Some(count_statement));
+        let asked: Vec<Bytes> = (0..500).rev().map(element).collect();
+        STATEMENTS.with(|statements| statements.set(0));
+        let members = storage.are_members("s", &asked).unwrap();
+        assert_eq!(STATEMENTS.with(|statements| statements.get()), 1);
+        storage.pool().get().unwrap().trace(None);
+
+        let expected: Vec<bool> = (0..500).rev().map(|i| i % 2 == 0).collect();
+        assert_eq!(members, expected);
+        assert_eq!(
+            storage.are_members("missing", &asked[..3]).unwrap(),
+            vec![false; 3]
+        );
+    }
+
+    #[test]
+    fn test_read_pool_sees_committed_writes_and_cannot_write() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let actor_id = ActorId::from_node_id(1);
+
+        // Opened before the write, so it has to pick it up from the WAL
+        let reader = storage.pool().get().unwrap();
+        storage
+            .add_elements("s", &[Bytes::from("a")], Dot::new(actor_id, 1))
+            .unwrap();
+        let count: i64 = reader
+            .query_row("SELECT COUNT(*) FROM elements", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(storage.is_member("s", &Bytes::from("a")).unwrap());
+
+        assert!(
+            reader
+                .execute("DELETE FROM elements", [])
+                .is_err_and(|e| e.to_string().contains("readonly"))
+        );
+        drop(reader);
+        assert_eq!(storage.get_elements("s").unwrap(), vec![Bytes::from("a")]);
+    }
+
+    #[test]
+    fn test_page_size_and_mmap_size_are_applied() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("test.db");
+        let sized = |page_size| StorageConfig {
+            mmap_size: 1 << 20,
+            page_size,
+            ..config()
+        };
+        let pragma = |storage: &SqliteStorage, name: &str| -> i64 {
+            let conn = storage.pool().get().unwrap();
+            conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+                .unwrap()
+        };
+
+        let storage = SqliteStorage::open(&path, &sized(8192)).unwrap();
+        storage
+            .add_elements(
+                "s",
+                &[Bytes::from("a")],
+                Dot::new(ActorId::from_node_id(1), 1),
+            )
+            .unwrap();
+        assert_eq!(pragma(&storage, "page_size"), 8192);
+        assert_eq!(pragma(&storage, "mmap_size"), 1 << 20);
+        drop(storage);
+
+        // WAL mode keeps an existing database at the page size it was created with
+        let storage = SqliteStorage::open(&path, &sized(16384)).unwrap();
+        assert_eq!(pragma(&storage, "page_size"), 8192);
+        storage.maintenance(true).unwrap();
+        assert_eq!(pragma(&storage, "page_size"), 8192);
+        assert_eq!(storage.get_elements("s").unwrap(), vec![Bytes::from("a")]);
+
+        assert!(SqliteStorage::open(&path, &sized(1000)).is_err());
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_encrypted_database_needs_its_key() {
+        use crate::config::EncryptionConfig;
+
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("test.db");
+        let keyed = |key: &str| StorageConfig {
+            encryption: Some(EncryptionConfig {
+                key: Some(key.to_string()),
+                key_file: None,
+            }),
+            ..config()
+        };
+
+        let storage = SqliteStorage::open(&path, &keyed("secret")).unwrap();
+        storage
+            .add_elements(
+                "s",
+                &[Bytes::from("plaintext-member")],
+                Dot::new(ActorId::from_node_id(1), 1),
+            )
+            .unwrap();
+        drop(storage);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.starts_with(b"SQLite format 3"));
+        let wal = std::fs::read(temp.path().join("test.db-wal")).unwrap_or_default();
+        assert!(
+            !(bytes.windows(16).any(|w| w == b"plaintext-member")
+                || wal.windows(16).any(|w| w == b"plaintext-member"))
+        );
+
+        assert!(SqliteStorage::open(&path, &keyed("wrong")).is_err());
+        assert!(SqliteStorage::open(&path, &config()).is_err());
+        let storage = SqliteStorage::open(&path, &keyed("secret")).unwrap();
+        assert_eq!(
+            storage.get_elements("s").unwrap(),
+            vec![Bytes::from("plaintext-member")]
+        );
+    }
+
+    #[test]
+    fn test_compact_dots_in_one_set() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let (a, b) = (ActorId::from_node_id(1), ActorId::from_node_id(2));
+        let x = Bytes::from("x");
+
+        // Concurrent adds of x, in two sets
+        for set_name in ["s", "t"] {
+            storage
+                .replicate_add(set_name, std::slice::from_ref(&x), &[], Dot::new(a, 1))
+                .unwrap();
+            storage
+                .replicate_add(set_name, std::slice::from_ref(&x), &[], Dot::new(b, 1))
+                .unwrap();
+        }
+        let stable = storage.load_vv().unwrap();
+        let dots = |set_name| {
+            storage
+                .elements_since(set_name, &VersionVector::new(), &[], 1)
+                .unwrap()[0]
+                .1
+                .clone()
+        };
+
+        assert_eq!(storage.compact_dots(&stable, Some("s")).unwrap(), 1);
+        assert_eq!(dots("s"), vec![Dot::new(b, 1)]);
+        assert_eq!(dots("t").len(), 2);
+
+        assert_eq!(storage.compact_dots(&stable, None).unwrap(), 1);
+        assert_eq!(dots("t"), vec![Dot::new(b, 1)]);
+    }
+
+    #[test]
+    fn test_get_elements_page_resumes_after_the_last_id() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let actor_id = ActorId::from_node_id(1);
+        let elements: Vec<Bytes> = (0..25).map(|i| Bytes::from(format!("e{}", i))).collect();
+        storage
+            .add_elements("s", &elements, Dot::new(actor_id, 1))
+            .unwrap();
+
+        let (first, next) = storage.get_elements_page("s", 0, 10).unwrap();
+        assert_eq!(first, elements[..10]);
+        let next = next.unwrap();
+
+        // Changes between pages: nothing that was there throughout is skipped
+        storage
+            .remove_elements("s", &elements[..5], Dot::new(actor_id, 2))
+            .unwrap();
+        let late = Bytes::from("late");
+        storage
+            .add_elements("s", std::slice::from_ref(&late), Dot::new(actor_id, 3))
+            .unwrap();
+
+        let (second, next) = storage.get_elements_page("s", next, 10).unwrap();
+        assert_eq!(second, elements[10..20]);
+        let (third, next) = storage.get_elements_page("s", next.unwrap(), 10).unwrap();
+        assert_eq!(third[..5], elements[20..]);
+        assert_eq!(third[5], late);
+        assert_eq!(next, None);
+
+        assert_eq!(
+            storage.get_elements_page("missing", 0, 10).unwrap(),
+            (vec![], None)
+        );
+    }
+
+    #[test]
+    fn test_list_sets_in_creation_order() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let actor_id = ActorId::from_node_id(1);
+        let x = Bytes::from("x");
+
+        for (counter, set_name) in (1..).zip(["b", "a", "c"]) {
+            storage
+                .add_elements(
+                    set_name,
+                    std::slice::from_ref(&x),
+                    Dot::new(actor_id, counter),
+                )
+                .unwrap();
+        }
+        storage
+            .remove_elements("a", std::slice::from_ref(&x), Dot::new(actor_id, 4))
+            .unwrap();
+        storage.delete_set("c").unwrap();
+
+        // An emptied set is still listed, a deleted one is not
+        assert_eq!(storage.list_sets().unwrap(), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_element_count_follows_local_and_remote_writes() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let local = ActorId::from_node_id(1);
+        let remote = ActorId::from_node_id(2);
+        let (a, b, c, d) = (
+            Bytes::from("a"),
+            Bytes::from("b"),
+            Bytes::from("c"),
+            Bytes::from("d"),
+        );
+        let check = |expected: i64| {
+            assert_eq!(storage.count_elements("s").unwrap(), expected);
+            assert_eq!(storage.get_elements("s").unwrap().len() as i64, expected);
+        };
+
+        storage
+            .add_elements("s", &[a.clone(), b.clone()], Dot::new(local, 1))
+            .unwrap();
+        storage
+            .add_elements("s", std::slice::from_ref(&a), Dot::new(local, 2))
+            .unwrap();
+        check(2);
+
+        // A concurrent add of `a` gives it a second dot, not a second count
+        storage
+            .replicate_add("s", &[a.clone(), c.clone()], &[], Dot::new(remote, 1))
+            .unwrap();
+        storage
+            .replicate_add("s", std::slice::from_ref(&a), &[], Dot::new(remote, 2))
+            .unwrap();
+        check(3);
+
+        // `a` keeps the local dot, `c` loses its only one
+        storage
+            .replicate_remove(
+                "s",
+                &[a.clone(), c.clone()],
+                &[Dot::new(remote, 1), Dot::new(remote, 2)],
+                Dot::new(remote, 3),
+            )
+            .unwrap();
+        check(2);
+
+        storage
+            .remove_elements("s", std::slice::from_ref(&b), Dot::new(local, 3))
+            .unwrap();
+        check(1);
+
+        // The peer has seen `a`'s dot and dropped it, and added `d`
+        let mut remote_vv = VersionVector::new();
+        remote_vv.update(local, 2);
+        remote_vv.update(ActorId::from_node_id(3), 1);
+        let local_vv = storage.load_vv().unwrap();
+        storage
+            .merge_elements(
+                "s",
+                &[
+                    (a.clone(), vec![]),
+                    (d.clone(), vec![Dot::new(ActorId::from_node_id(3), 1)]),
+                ],
+                &remote_vv,
+                &local_vv,
+            )
+            .unwrap();
+        check(1);
+
+        let info = storage.set_info("s").unwrap().unwrap();
+        assert!(info.created_at.is_some());
+        assert_eq!(info.cardinality, 1);
+        assert_eq!(storage.set_info("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_open_backfills_element_counts() {
+        let temp = TempDir::new().unwrap();
+        let actor_id = ActorId::from_node_id(1);
+        {
+            // A database from before `sets` had metadata columns
+            let conn = Connection::open(temp.path().join("test.db")).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE sets (id INTEGER PRIMARY KEY, name TEXT UNIQUE NOT NULL);
+                 CREATE TABLE elements (id INTEGER PRIMARY KEY, set_id INTEGER NOT NULL, value BLOB NOT NULL, UNIQUE (set_id, value));
+                 CREATE TABLE dots (element_id INTEGER NOT NULL, actor_id BLOB NOT NULL, counter INTEGER NOT NULL, PRIMARY KEY (element_id, actor_id)) WITHOUT ROWID;
+                 INSERT INTO sets (id, name) VALUES (1, 's'), (2, 'empty');
+                 INSERT INTO elements (id, set_id, value) VALUES (1, 1, 'a'), (2, 1, 'b'), (3, 1, 'no dots');",
+            )
+            .unwrap();
+            for (element_id, counter) in [(1, 1), (2, 2)] {
+                conn.execute(
+                    "INSERT INTO dots (element_id, actor_id, counter) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![element_id, actor_id.bytes(), counter],
+                )
+                .unwrap();
+            }
+        }
+
+        let storage = open(&temp);
+
+        assert_eq!(storage.count_elements("s").unwrap(), 2);
+        assert_eq!(
+            storage.set_info("empty").unwrap(),
+            Some(SetInfo {
+                created_at: None,
+                cardinality: 0
+            })
+        );
+        storage
+            .add_elements("s", &[Bytes::from("c")], Dot::new(actor_id, 3))
+            .unwrap();
+        assert_eq!(storage.count_elements("s").unwrap(), 3);
+        assert_eq!(schema_version(&storage), SCHEMA_VERSION);
+    }
+
+    fn schema_version(storage: &SqliteStorage) -> u32 {
+        let conn = storage.pool().get().unwrap();
+        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_migrations_run_once_and_refuse_a_newer_schema() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        assert_eq!(schema_version(&storage), SCHEMA_VERSION);
+        let applied = |storage: &SqliteStorage| -> u32 {
+            let conn = storage.pool().get().unwrap();
+            conn.query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(applied(&storage), SCHEMA_VERSION);
+        drop(storage);
+
+        let storage = open(&temp);
+        assert_eq!(applied(&storage), SCHEMA_VERSION);
+        storage
+            .writer
+            .get()
+            .unwrap()
+            .execute(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'from the future', 0)",
+                [SCHEMA_VERSION + 1],
+            )
+            .unwrap();
+        drop(storage);
+
+        let error = SqliteStorage::open(temp.path().join("test.db"), &config()).unwrap_err();
+        assert!(
+            error.to_string().contains("newer than this build"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_export_snapshot_round_trips_into_any_backend() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let local = ActorId::from_node_id(1);
+        let remote = ActorId::from_node_id(2);
+
+        let many: Vec<Bytes> = (0..2500).map(|i| Bytes::from(format!("e{}", i))).collect();
+        storage
+            .add_elements("big", &many, Dot::new(local, 1))
+            .unwrap();
+        storage
+            .add_elements(
+                "small",
+                &[Bytes::from("a"), Bytes::from("b")],
+                Dot::new(local, 2),
+            )
+            .unwrap();
+        storage
+            .replicate_add("small", &[Bytes::from("a")], &[], Dot::new(remote, 1))
+            .unwrap();
+        storage
+            .remove_elements("big", &many[..10], Dot::new(local, 3))
+            .unwrap();
+
+        let mut backup = Vec::new();
+        storage.export_snapshot(&mut backup).unwrap();
+
+        let restored_dir = TempDir::new().unwrap();
+        let restored = open(&restored_dir);
+        let vv = restored.import_snapshot(&mut &backup[..]).unwrap();
+        assert_eq!(vv, storage.load_vv().unwrap());
+        assert_eq!(restored.snapshot().unwrap(), storage.snapshot().unwrap());
+        assert_eq!(restored.count_elements("big").unwrap(), 2490);
+        assert_eq!(restored.count_elements("small").unwrap(), 2);
+
+        let memory = crate::storage::MemoryStorage::new();
+        memory.import_snapshot(&mut &backup[..]).unwrap();
+        assert_eq!(memory.snapshot().unwrap(), storage.snapshot().unwrap());
+    }
+
+    #[test]
+    fn test_bloom_filter_answers_misses_and_follows_adds() {
+        let temp = TempDir::new().unwrap();
+        let config = StorageConfig {
+            bloom_min_elements: 10,
+            ..config()
+        };
+        let storage = SqliteStorage::open(temp.path().join("test.db"), &config).unwrap();
+        let local = ActorId::from_node_id(1);
+        let remote = ActorId::from_node_id(2);
+        let elements: Vec<Bytes> = (0..10).map(|i| Bytes::from(format!("e{}", i))).collect();
+
+        storage
+            .add_elements("small", &elements[..9], Dot::new(local, 1))
+            .unwrap();
+        storage
+            .add_elements("big", &elements, Dot::new(local, 2))
+            .unwrap();
+        assert!(!storage.blooms.read().unwrap().contains_key("small"));
+        assert!(storage.blooms.read().unwrap().contains_key("big"));
+
+        // Added behind the filter's back: only a lookup that skips the database misses it
+        let sneak = |set_name: &str, value: &str| {
+            storage
+                .writer
+                .get()
+                .unwrap()
+                .execute(
+                    "INSERT INTO elements (set_id, value) SELECT id, ?2 FROM sets WHERE name = ?1",
+                    rusqlite::params![set_name, value.as_bytes()],
+                )
+                .unwrap();
+        };
+        sneak("big", "hidden");
+        sneak("small", "hidden");
+        let hidden = Bytes::from("hidden");
+        assert!(!storage.is_member("big", &hidden).unwrap());
+        assert!(storage.is_member("small", &hidden).unwrap());
+
+        // Replicated and merged elements go into the filter too
+        let replicated = Bytes::from("replicated");
+        let merged = Bytes::from("merged");
+        storage
+            .replicate_add(
+                "big",
+                std::slice::from_ref(&replicated),
+                &[],
+                Dot::new(remote, 1),
+            )
+            .unwrap();
+        let mut remote_vv = VersionVector::new();
+        remote_vv.update(remote, 2);
+        storage
+            .merge_elements(
+                "big",
+                &[(merged.clone(), vec![Dot::new(remote, 2)])],
+                &remote_vv,
+                &storage.load_vv().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            storage
+                .are_members(
+                    "big",
+                    &[
+                        elements[0].clone(),
+                        replicated,
+                        merged,
+                        Bytes::from("absent")
+                    ]
+                )
+                .unwrap(),
+            vec![true, true, true, false]
+        );
+
+        // Crossing the threshold builds the filter, and reopening rebuilds them all
+        storage
+            .add_elements("small", &elements[9..], Dot::new(local, 3))
+            .unwrap();
+        assert!(storage.blooms.read().unwrap().contains_key("small"));
+        drop(storage);
+        let storage = SqliteStorage::open(temp.path().join("test.db"), &config).unwrap();
+        assert_eq!(storage.blooms.read().unwrap().len(), 2);
+        assert!(storage.is_member("big", &hidden).unwrap());
+    }
+
+    #[test]
+    fn test_expired_elements_are_hidden_until_every_dot_expires() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let local = ActorId::from_node_id(1);
+        let remote = ActorId::from_node_id(2);
+        let (gone, kept, later) = (
+            Bytes::from("gone"),
+            Bytes::from("kept"),
+            Bytes::from("later"),
+        );
+        let now = now_ms();
+
+        storage
+            .add_elements_expiring(
+                "s",
+                &[gone.clone(), kept.clone()],
+                Dot::new(local, 1),
+                now - 1,
+            )
+            .unwrap();
+        storage
+            .add_elements_expiring(
+                "s",
+                std::slice::from_ref(&later),
+                Dot::new(local, 2),
+                now + 60_000,
+            )
+            .unwrap();
+        // A concurrent plain add keeps `kept` alive after the expiring dot runs out
+        storage
+            .replicate_add("s", std::slice::from_ref(&kept), &[], Dot::new(remote, 1))
+            .unwrap();
+
+        assert!(!storage.is_member("s", &gone).unwrap());
+        assert!(storage.is_member("s", &kept).unwrap());
+        assert!(storage.is_member("s", &later).unwrap());
+        assert_eq!(storage.count_elements("s").unwrap(), 2);
+        assert_eq!(
+            storage.expired_elements(now, 100).unwrap(),
+            vec![("s".to_string(), vec![gone.clone()])]
+        );
+        assert_eq!(
+            storage.expired_elements(now + 60_000, 100).unwrap(),
+            vec![("s".to_string(), vec![gone, later])]
+        );
+    }
+
+    #[test]
+    fn test_intersection_count_stops_at_the_limit() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let actor_id = ActorId::from_node_id(1);
+        let shared: Vec<Bytes> = (0..20_000)
+            .map(|i| Bytes::from(format!("e{}", i)))
+            .collect();
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        storage
+            .add_elements("big", &shared, Dot::new(actor_id, 1))
+            .unwrap();
+        storage
+            .add_elements("also big", &shared, Dot::new(actor_id, 2))
+            .unwrap();
+        storage
+            .add_elements(
+                "small",
+                &[shared[7].clone(), Bytes::from("only small")],
+                Dot::new(actor_id, 3),
+            )
+            .unwrap();
+
+        let started = std::time::Instant::now();
+        assert_eq!(
+            storage
+                .intersection_count(&names(&["big", "also big"]), Some(10))
+                .unwrap(),
+            10
+        );
+        let limited = started.elapsed();
+        let started = std::time::Instant::now();
+        assert_eq!(
+            storage
+                .intersection_count(&names(&["big", "also big"]), None)
+                .unwrap(),
+            20_000
+        );
+        assert!(
+            limited < started.elapsed(),
+            "the limited count scanned as much"
+        );
+
+        assert_eq!(
+            storage
+                .intersection_count(&names(&["big", "also big", "small"]), None)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            storage
+                .intersection_count(&names(&["big", "missing"]), Some(10))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_sql_glob_escapes_what_glob_would_read_as_syntax() {
+        assert_eq!(sql_glob(b"a*b?c").unwrap(), b"a*b?c");
+        assert_eq!(sql_glob(br"\*\?\[x\]").unwrap(), b"[*][?][[]x]");
+        assert_eq!(sql_glob(br"[^\]a-c-]").unwrap(), b"[^]a-c-]");
+        assert_eq!(sql_glob(br"[a\]-c]").unwrap(), b"[]a_-c^]");
+        assert_eq!(sql_glob(b"[-^]").unwrap(), b"[-^]");
+        assert_eq!(sql_glob(br"[\^]").unwrap(), b"^");
+        assert_eq!(sql_glob(b"[Z-a]").unwrap(), b"[Z-a]");
+        assert_eq!(sql_glob(b"x[]"), None);
+        assert_eq!(sql_glob(b"x[^]]").unwrap(), b"x?]");
+    }
+
+    #[test]
+    fn test_stream_elements_matches_in_sql_as_glob_match_does() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let elements: Vec<Bytes> = [
+            "apple", "apricot", "banana", "a*b", "a?b", "a[b", "a]b", "a^b", "a-b", "a\\b", "ab",
+            "", "Zoo", "_x", "%y",
+        ]
+        .iter()
+        .map(|e| Bytes::from(*e))
+        .collect();
+        storage
+            .add_elements("s", &elements, Dot::new(ActorId::from_node_id(1), 1))
+            .unwrap();
+
+        let patterns: [&[u8]; 20] = [
+            b"*",
+            b"a*",
+            b"a?b",
+            br"a\*b",
+            br"a\?b",
+            br"a\[b",
+            b"a]b",
+            b"a[*?]b",
+            br"a[\]^-]b",
+            b"a[^a-z]b",
+            b"[A-Z]*",
+            b"[z-a]*",
+            b"*[-]*",
+            b"a[^]b",
+            b"a[]b",
+            b"?",
+            b"_*",
+            b"%*",
+            br"a\\b",
+            b"a[",
+        ];
+        for pattern in patterns {
+            let mut len = 0;
+            let mut matched = Vec::new();
+            storage
+                .stream_elements("s", Some(pattern), 2, &mut |l| len = l, &mut |batch| {
+                    matched.extend(batch);
+                    true
+                })
+                .unwrap();
+            let expected: Vec<Bytes> = elements
+                .iter()
+                .filter(|e| glob_match(pattern, e))
+                .cloned()
+                .collect();
+            let pattern = String::from_utf8_lossy(pattern);
+            assert_eq!(matched, expected, "pattern {}", pattern);
+            assert_eq!(len, expected.len() as u64, "pattern {}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_random_elements_with_and_without_repeats() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        let elements: Vec<Bytes> = (0..5).map(|i| Bytes::from(format!("e{}", i))).collect();
+        storage
+            .add_elements("s", &elements, Dot::new(ActorId::from_node_id(1), 1))
+            .unwrap();
+        let distinct = |picked: &[Bytes]| picked.iter().collect::<HashSet<_>>().len();
+
+        // Distinct, and capped at the set's size however many are asked for
+        let picked = storage.random_elements("s", 3, false).unwrap();
+        assert_eq!((picked.len(), distinct(&picked)), (3, 3));
+        let picked = storage.random_elements("s", 10, false).unwrap();
+        assert_eq!(
+            picked.iter().collect::<HashSet<_>>(),
+            elements.iter().collect()
+        );
+
+        // With repeats, exactly as many as asked for, beyond the set's size
+        let picked = storage.random_elements("s", 50, true).unwrap();
+        assert_eq!(picked.len(), 50);
+        assert!(distinct(&picked) <= 5);
+        assert!(picked.iter().all(|e| elements.contains(e)));
+
+        assert!(
+            storage
+                .random_elements("missing", 10, true)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(storage.random_elements("s", 0, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_epoch_is_kept_across_opens() {
+        let temp = TempDir::new().unwrap();
+        let storage = open(&temp);
+        assert_eq!(storage.load_epoch().unwrap(), None);
+        storage.save_epoch(3).unwrap();
+        storage.save_epoch(4).unwrap();
+        drop(storage);
+
+        assert_eq!(open(&temp).load_epoch().unwrap(), Some(4));
+    }
+
+    #[test]
+    fn test_op_log_serves_ops_since_a_vv_until_truncated() {
+        let temp = TempDir::new().unwrap();
+        let logged = StorageConfig {
+            op_log: true,
+            ..config()
+        };
+        let actor = ActorId::from_node_id(1);
+
+        // A log started on a database with data holds nothing from before
+        let storage = open(&temp);
+        storage
+            .add_elements("s", &[Bytes::from("old")], Dot::new(actor, 1))
+            .unwrap();
+        drop(storage);
+        let storage = SqliteStorage::open(temp.path().join("test.db"), &logged).unwrap();
+        assert!(storage.logs_ops());
+
+        let ops: Vec<Operation> = (2..=4)
+            .map(|counter| Operation {
+                set_name: "s".to_string(),
+                op_type: crate::types::OpType::Add {
+                    elements: vec![Bytes::from(format!("e{}", counter))],
+                    dot: Dot::new(actor, counter),
+                    removed_dots: vec![],
+                    expires_at: None,
+                },
+                context: Arc::new(VersionVector::new()),
+                trace_context: Default::default(),
+            })
+            .collect();
+        storage.append_ops(&ops).unwrap();
+        storage.append_ops(&ops[..1]).unwrap();
+
+        let mut vv = VersionVector::new();
+        vv.update(actor, 2);
+        assert_eq!(storage.ops_since(&vv).unwrap(), (ops[1..].to_vec(), true));
+        let (all, complete) = storage.ops_since(&VersionVector::new()).unwrap();
+        assert_eq!((all, complete), (ops.clone(), false));
+
+        // Truncating raises the floor: a vv below it can no longer be fully served
+        assert_eq!(storage.truncate_ops(&vv).unwrap(), 1);
+        assert_eq!(storage.ops_since(&vv).unwrap(), (ops[1..].to_vec(), true));
+        let mut behind = VersionVector::new();
+        behind.update(actor, 1);
+        assert!(!storage.ops_since(&behind).unwrap().1);
+
+        // Not kept: dropped, and started afresh if kept again
+        drop(storage);
+        let storage = open(&temp);
+        assert!(!storage.logs_ops());
+        assert_eq!(storage.ops_since(&vv).unwrap(), (vec![], false));
+        drop(storage);
+        let storage = SqliteStorage::open(temp.path().join("test.db"), &logged).unwrap();
+        assert_eq!(
+            storage.ops_since(&VersionVector::new()).unwrap(),
+            (vec![], false)
+        );
+    }
 }
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use tracing::Span;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{Registry, reload};
+
+/// Changes the level the subscriber `init` installed logs at
+static LOG_LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Trace context carried by a replicated operation, as W3C Trace Context headers
+/// (`traceparent`, `tracestate`)
+///
+/// Lets the span that applies an operation on a peer join the trace of the client
+/// command that made it. Empty when the write wasn't traced, and always without the
+/// `otel` feature.
+pub type TraceContext = BTreeMap<String, String>;
+
+/// The trace context of the current span, for an operation made in it
+#[cfg(feature = "otel")]
+pub fn current_context() -> TraceContext {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let mut context = TraceContext::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut Injector(&mut context))
+    });
+    context
+}
+
+/// Without the `otel` feature spans carry no trace context
+#[cfg(not(feature = "otel"))]
+pub fn current_context() -> TraceContext {
+    TraceContext::new()
+}
+
+/// Make `span` part of the trace an operation was made in, if it carries one
+#[cfg(feature = "otel")]
+pub fn set_parent(span: &Span, context: &TraceContext) {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    if context.is_empty() {
+        return;
+    }
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&Extractor(context))
+    });
+    // Only fails for a span that is already closed or not recorded by OpenTelemetry
+    let _ = span.set_parent(parent);
+}
+
+/// Without the `otel` feature there is no trace to join
+#[cfg(not(feature = "otel"))]
+pub fn set_parent(_span: &Span, _context: &TraceContext) {}
+
+/// Keeps span export running, see `init`
+///
+/// Dropping it flushes the spans not yet exported.
+#[must_use]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// Install the global subscriber, logging at info and above until `set_log_level`
+///
+/// With the `otel` feature, spans are also exported over OTLP/HTTP when
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set (the other standard `OTEL_*` variables apply too),
+/// and the W3C trace context is propagated with replicated operations.
+#[cfg(feature = "otel")]
+pub fn init() -> Telemetry {
+    use opentelemetry::trace::TracerProvider;
+    use tracing_subscriber::prelude::*;
+
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    );
+
+    let provider = std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").and_then(|_| {
+        // The exporter's blocking HTTP client can't be created or dropped on an async
+        // worker, so it is built on a thread of its own; afterwards it only runs on the
+        // batch processor's thread
+        let exporter = std::thread::spawn(|| {
+            opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .build()
+        })
+        .join()
+        .expect("building the OTLP exporter panicked");
+        match exporter {
+            Ok(exporter) => Some(
+                opentelemetry_sdk::trace::SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(
+                        opentelemetry_sdk::Resource::builder()
+                            .with_service_name("bigsets")
+                            .build(),
+                    )
+                    .build(),
+            ),
+            Err(e) => {
+                eprintln!("Not exporting spans, OTLP exporter failed: {}", e);
+                None
+            }
+        }
+    });
+
+    let otel = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("bigsets")));
+    let (level, handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(level)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+    let _ = LOG_LEVEL.set(handle);
+
+    Telemetry { provider }
+}
+
+/// Install the global subscriber, logging at info and above until `set_log_level`
+#[cfg(not(feature = "otel"))]
+pub fn init() -> Telemetry {
+    use tracing_subscriber::prelude::*;
+
+    let (level, handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(level)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    let _ = LOG_LEVEL.set(handle);
+    Telemetry {}
+}
+
+/// Log at `level` and above from now on (CONFIG SET server.log_level)
+///
+/// Does nothing unless `init` installed the subscriber, as in tests.
+pub fn set_log_level(level: LevelFilter) {
+    if let Some(handle) = LOG_LEVEL.get()
+        && let Err(e) = handle.reload(level)
+    {
+        eprintln!("Failed to change the log level: {}", e);
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush spans: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+struct Injector<'a>(&'a mut TraceContext);
+
+#[cfg(feature = "otel")]
+impl opentelemetry::propagation::Injector for Injector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+#[cfg(feature = "otel")]
+struct Extractor<'a>(&'a TraceContext);
+
+#[cfg(feature = "otel")]
+impl opentelemetry::propagation::Extractor for Extractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_trace_context_joins_the_remote_trace() {
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let command = tracing::info_span!("command");
+            let context = command.in_scope(current_context);
+            assert!(context.contains_key("traceparent"));
+
+            let apply = tracing::info_span!("apply_remote_operation");
+            set_parent(&apply, &context);
+            let trace_id = |span: &Span| span.context().span().span_context().trace_id();
+            assert_eq!(trace_id(&apply), trace_id(&command));
+        });
+
+        assert!(
+            tracing::info_span!("outside")
+                .in_scope(current_context)
+                .is_empty()
+        );
+    }
+}
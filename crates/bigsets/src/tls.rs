@@ -0,0 +1,80 @@
+use crate::config::ReplicationTlsConfig;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// A TLS acceptor presenting the certificate chain and private key in these PEM files
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)
+        .map_err(|e| invalid(key_path, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The two ends of replication over TLS: connecting to peers and accepting them
+///
+/// Both present this node's certificate and only trust certificates signed by the CA.
+pub fn replication(config: &ReplicationTlsConfig) -> io::Result<(TlsConnector, TlsAcceptor)> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&config.ca_cert_path)? {
+        roots
+            .add(cert)
+            .map_err(|e| invalid(&config.ca_cert_path, e))?;
+    }
+    let roots = Arc::new(roots);
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+
+    let client = ClientConfig::builder()
+        .with_root_certificates(Arc::clone(&roots))
+        .with_client_auth_cert(certs.clone(), key.clone_key())
+        .map_err(|e| invalid(&config.key_path, e))?;
+
+    // Without require_client_cert a peer may connect with no certificate, but one it
+    // does present must still be signed by the CA
+    let verifier = WebPkiClientVerifier::builder(roots);
+    let verifier = if config.require_client_cert {
+        verifier.build()
+    } else {
+        verifier.allow_unauthenticated().build()
+    }
+    .map_err(|e| invalid(&config.ca_cert_path, e))?;
+    let server = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(&config.key_path, e))?;
+
+    Ok((
+        TlsConnector::from(Arc::new(client)),
+        TlsAcceptor::from(Arc::new(server)),
+    ))
+}
+
+/// Every certificate in a PEM file, leaf first for a chain
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+/// The first private key in a PEM file
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| invalid(path, e))
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", path.display(), e),
+    )
+}
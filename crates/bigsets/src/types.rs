@@ -1,79 +1,133 @@
+use crate::telemetry::TraceContext;
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::str::FromStr;
+use std::sync::Arc;
 
 /// Fixed-size actor identifier
 ///
-/// Binary layout (4 bytes): [version: u8][node_id: u16][epoch: u8]
-/// - version: Protocol version (currently 0)
-/// - node_id: Node identifier (0-65535)
-/// - epoch: Restart/generation counter (0-255, defaults to 0)
+/// Binary layout, by the version in the first byte:
+/// - v0 (4 bytes): [version: u8][node_id: u16][epoch: u8]
+///   - node_id: Node identifier (0-65535)
+///   - epoch: Restart/generation counter (0-255), raised when a node restarts without its data
+/// - v1 (4 bytes): [version: u8][node_id: u24], for clusters past 65535 nodes; there is no epoch
+/// - v2 (8 bytes): [version: u8][node_id: u56], for node ids made from hostnames or
+///   hashes rather than counted out; there is no epoch
 ///
-/// Human-readable format: "v0:1234:5" (version:node:epoch)
-#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+/// Human-readable format: "v0:1234:5" (version:node:epoch), or "v1:1234" / "v2:1234"
+/// (version:node). Serde uses the human-readable format.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ActorId {
-    bytes: [u8; 4],
+    /// The layout above, zero past its width
+    bytes: [u8; 8],
 }
 
 impl ActorId {
     pub fn version(&self) -> u8 {
         self.bytes[0]
     }
-    pub fn node_id(&self) -> u16 {
-        ((self.bytes[1] as u16) << 8) | (self.bytes[2] as u16)
+    pub fn node_id(&self) -> u64 {
+        match self.version() {
+            1 => u64::from_be_bytes([0, 0, 0, 0, 0, self.bytes[1], self.bytes[2], self.bytes[3]]),
+            2 => u64::from_be_bytes(self.bytes) & Self::MAX_V2_NODE_ID,
+            _ => ((self.bytes[1] as u64) << 8) | (self.bytes[2] as u64),
+        }
     }
+    /// Always 0 for v1 and v2, which have no epoch
     pub fn epoch(&self) -> u8 {
-        self.bytes[3]
+        match self.version() {
+            0 => self.bytes[3],
+            _ => 0,
+        }
     }
+    /// The binary layout, 4 or 8 bytes by version
     pub fn bytes(&self) -> &[u8] {
-        &self.bytes
+        &self.bytes[..Self::width(self.version()).unwrap_or(4)]
+    }
+
+    /// The width of a version's binary layout, None for a version this build doesn't know
+    fn width(version: u8) -> Option<usize> {
+        match version {
+            0 | 1 => Some(4),
+            2 => Some(8),
+            _ => None,
+        }
     }
 }
 
 impl ActorId {
+    /// Largest node_id a v1 ActorId holds
+    pub const MAX_V1_NODE_ID: u32 = (1 << 24) - 1;
+    /// Largest node_id a v2 ActorId holds
+    pub const MAX_V2_NODE_ID: u64 = (1 << 56) - 1;
+
     pub fn new(node_id: u16, epoch: u8) -> Self {
+        let [high, low] = node_id.to_be_bytes();
         Self {
-            bytes: [
-                0,                      // version (default 0)
-                (node_id >> 8) as u8,   // node_id high byte
-                (node_id & 0xFF) as u8, // node_id low byte
-                epoch,
-            ],
+            bytes: [0, high, low, epoch, 0, 0, 0, 0],
         }
     }
 
-    fn new_with_version(version: u8, node_id: u16, epoch: u8) -> Self {
-        Self {
-            bytes: [
-                version,                // version
-                (node_id >> 8) as u8,   // node_id high byte
-                (node_id & 0xFF) as u8, // node_id low byte
-                epoch,
-            ],
+    /// A v1 ActorId, None if `node_id` is past `MAX_V1_NODE_ID`
+    pub fn new_v1(node_id: u32) -> Option<Self> {
+        if node_id > Self::MAX_V1_NODE_ID {
+            return None;
         }
+        let [_, high, mid, low] = node_id.to_be_bytes();
+        Some(Self {
+            bytes: [1, high, mid, low, 0, 0, 0, 0],
+        })
+    }
+
+    /// A v2 ActorId, None if `node_id` is past `MAX_V2_NODE_ID`: a 64 bit hash is
+    /// masked or shifted to 56 bits first
+    pub fn from_u64(node_id: u64) -> Option<Self> {
+        if node_id > Self::MAX_V2_NODE_ID {
+            return None;
+        }
+        let mut bytes = node_id.to_be_bytes();
+        bytes[0] = 2;
+        Some(Self { bytes })
     }
 
     pub fn from_node_id(node_id: u16) -> Self {
         Self::new(node_id, 0)
     }
 
-    /// Deserialize from fixed 4-byte format
+    /// Deserialize from the binary layout, its width given by the version byte
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, ActorIdError> {
-        if bytes.len() != 4 {
+        let Some(&version) = bytes.first() else {
+            return Err(ActorIdError::InvalidLength(0));
+        };
+        let width = Self::width(version).ok_or(ActorIdError::UnsupportedVersion(version))?;
+        if bytes.len() != width {
             return Err(ActorIdError::InvalidLength(bytes.len()));
         }
 
-        Ok(Self {
-            bytes: [bytes[0], bytes[1], bytes[2], bytes[3]],
-        })
+        let mut actor_id = Self { bytes: [0; 8] };
+        actor_id.bytes[..width].copy_from_slice(bytes);
+        Ok(actor_id)
+    }
+
+    /// The ActorId at the start of `bytes` and the bytes after it
+    pub(crate) fn split_from(bytes: &[u8]) -> Result<(Self, &[u8]), ActorIdError> {
+        let version = *bytes.first().ok_or(ActorIdError::InvalidLength(0))?;
+        let width = Self::width(version).ok_or(ActorIdError::UnsupportedVersion(version))?;
+        let (actor_bytes, rest) = bytes
+            .split_at_checked(width)
+            .ok_or(ActorIdError::InvalidLength(bytes.len()))?;
+        Ok((Self::from_bytes(actor_bytes)?, rest))
     }
 }
 
 impl fmt::Display for ActorId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "v{}:{}:{}", self.version(), self.node_id(), self.epoch())
+        match self.version() {
+            0 => write!(f, "v0:{}:{}", self.node_id(), self.epoch()),
+            version => write!(f, "v{}:{}", version, self.node_id()),
+        }
     }
 }
 
@@ -88,23 +142,44 @@ impl FromStr for ActorId {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() != 3 {
-            return Err(ActorIdError::InvalidFormat);
-        }
 
-        // Parse version (expect "v0" format)
-        let version_str = parts[0];
-        if !version_str.starts_with('v') {
-            return Err(ActorIdError::InvalidFormat);
-        }
-        let version: u8 = version_str[1..]
+        // Parse version ("v0" format), which decides the fields that follow
+        let version: u8 = parts[0]
+            .strip_prefix('v')
+            .ok_or(ActorIdError::InvalidFormat)?
             .parse()
             .map_err(|_| ActorIdError::InvalidFormat)?;
 
-        let node_id: u16 = parts[1].parse().map_err(|_| ActorIdError::InvalidFormat)?;
-        let epoch: u8 = parts[2].parse().map_err(|_| ActorIdError::InvalidFormat)?;
+        match (version, &parts[1..]) {
+            (0, [node_id, epoch]) => {
+                let node_id: u16 = node_id.parse().map_err(|_| ActorIdError::InvalidFormat)?;
+                let epoch: u8 = epoch.parse().map_err(|_| ActorIdError::InvalidFormat)?;
+                Ok(Self::new(node_id, epoch))
+            }
+            (1, [node_id]) => {
+                let node_id: u32 = node_id.parse().map_err(|_| ActorIdError::InvalidFormat)?;
+                Self::new_v1(node_id).ok_or(ActorIdError::InvalidFormat)
+            }
+            (2, [node_id]) => {
+                let node_id: u64 = node_id.parse().map_err(|_| ActorIdError::InvalidFormat)?;
+                Self::from_u64(node_id).ok_or(ActorIdError::InvalidFormat)
+            }
+            (0..=2, _) => Err(ActorIdError::InvalidFormat),
+            (version, _) => Err(ActorIdError::UnsupportedVersion(version)),
+        }
+    }
+}
+
+impl Serialize for ActorId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
-        Ok(Self::new_with_version(version, node_id, epoch))
+impl<'de> Deserialize<'de> for ActorId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -113,15 +188,23 @@ impl FromStr for ActorId {
 pub enum ActorIdError {
     InvalidLength(usize),
     InvalidFormat,
+    UnsupportedVersion(u8),
 }
 
 impl fmt::Display for ActorIdError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ActorIdError::InvalidLength(len) => {
-                write!(f, "Invalid ActorId length: {} (expected 4)", len)
+                write!(
+                    f,
+                    "Invalid ActorId length: {} (expected 4, or 8 for v2)",
+                    len
+                )
             }
             ActorIdError::InvalidFormat => write!(f, "Invalid ActorId format"),
+            ActorIdError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported ActorId version: {}", version)
+            }
         }
     }
 }
@@ -129,7 +212,10 @@ impl fmt::Display for ActorIdError {
 impl std::error::Error for ActorIdError {}
 
 /// A logical timestamp representing an actor and counter pair
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+///
+/// Ordered by actor, then counter, so each actor's dots sort together, oldest first.
+/// Only dots of the same actor are causally ordered, see `dominates`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Dot {
     pub actor_id: ActorId,
     pub counter: u64,
@@ -156,6 +242,12 @@ impl Dot {
         let actor_id = ActorId::from_bytes(&actor_id)?;
         Ok(Self { actor_id, counter })
     }
+
+    /// The same actor's dot, at or after `other`: it has seen `other`, so `other` is
+    /// superseded. Dots of different actors never dominate each other.
+    pub fn dominates(&self, other: &Dot) -> bool {
+        self.actor_id == other.actor_id && self.counter >= other.counter
+    }
 }
 
 /// Version vector for causal consistency
@@ -189,11 +281,43 @@ impl VersionVector {
         *current = (*current).max(counter);
     }
 
-    /// Merge another version vector (take maximum of each counter)
-    pub fn merge(&mut self, other: &VersionVector) {
+    /// Merge another version vector (take maximum of each counter). True if a counter
+    /// rose, false if this VV had already seen everything in `other`.
+    pub fn merge(&mut self, other: &VersionVector) -> bool {
+        let mut changed = false;
         for (actor_id, &counter) in &other.counters {
-            self.update(*actor_id, counter);
+            let current = self.counters.entry(*actor_id).or_insert(0);
+            if counter > *current {
+                *current = counter;
+                changed = true;
+            }
         }
+        changed
+    }
+
+    /// Keep only what both have seen (take minimum of each counter)
+    pub fn meet(&mut self, other: &VersionVector) {
+        self.counters.retain(|actor_id, counter| {
+            *counter = (*counter).min(other.get(*actor_id));
+            *counter > 0
+        });
+    }
+
+    /// How many events this VV has seen that `other` has not
+    pub fn ahead_of(&self, other: &VersionVector) -> u64 {
+        self.counters
+            .iter()
+            .map(|(actor_id, counter)| counter.saturating_sub(other.get(*actor_id)))
+            .sum()
+    }
+
+    /// The newest epoch of v0 node `node_id` this VV has seen events from
+    pub fn latest_epoch(&self, node_id: u16) -> Option<u8> {
+        self.counters
+            .keys()
+            .filter(|actor_id| actor_id.version() == 0 && actor_id.node_id() == u64::from(node_id))
+            .map(|actor_id| actor_id.epoch())
+            .max()
     }
 
     /// Check if this VV descends from another (has seen all events in other)
@@ -207,6 +331,47 @@ impl VersionVector {
         true
     }
 
+    /// Strictly ahead: has seen everything in `other` and something `other` hasn't
+    pub fn dominates(&self, other: &VersionVector) -> bool {
+        self.descends(other) && !other.descends(self)
+    }
+
+    /// Neither has seen everything the other has: the events behind them are causally
+    /// concurrent. With `descends` both ways this tells every relationship apart.
+    pub fn concurrent_with(&self, other: &VersionVector) -> bool {
+        !self.descends(other) && !other.descends(self)
+    }
+
+    /// What this VV has seen that `other` has not: per actor it is ahead on, the counter
+    /// range `(other_counter, self_counter]` as `(actor, other_counter, self_counter)`,
+    /// sorted by actor
+    pub fn diff(&self, other: &VersionVector) -> Vec<(ActorId, u64, u64)> {
+        let mut ranges: Vec<_> = self
+            .counters
+            .iter()
+            .filter_map(|(actor_id, &counter)| {
+                let theirs = other.get(*actor_id);
+                (counter > theirs).then_some((*actor_id, theirs, counter))
+            })
+            .collect();
+        ranges.sort_by_key(|(actor_id, _, _)| *actor_id);
+        ranges
+    }
+
+    /// The counters by which this VV is ahead of `other`, only for the actors it is ahead on.
+    /// `diff` as a VV: each counter is how many events of that actor `other` is missing.
+    pub fn subtract(&self, other: &VersionVector) -> VersionVector {
+        let counters = self
+            .counters
+            .iter()
+            .filter_map(|(actor_id, &counter)| {
+                let ahead = counter.saturating_sub(other.get(*actor_id));
+                (ahead > 0).then_some((*actor_id, ahead))
+            })
+            .collect();
+        VersionVector { counters }
+    }
+
     /// If we've already seen this dot, true
     pub fn contains_dot(&self, dot: Dot) -> bool {
         self.get(dot.actor_id) >= dot.counter
@@ -244,6 +409,74 @@ impl VersionVector {
             .collect::<Vec<_>>()
             .join(",")
     }
+
+    /// Compact binary form: a varint count of actors, then for each, sorted by actor,
+    /// its actor id's binary layout and a varint counter
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.encode(false)
+    }
+
+    /// Parse the format written by `to_bytes`, None if it is malformed or has trailing bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::decode(bytes, false)
+    }
+
+    /// `to_bytes` with each counter as a zigzag varint difference from the previous
+    /// actor's (from 0 for the first), as the proto `packed` field carries it
+    ///
+    /// The nodes of a cluster write at similar rates, so their counters are close and
+    /// the differences take a byte or two where the counters would take several.
+    pub fn to_packed_bytes(&self) -> Vec<u8> {
+        self.encode(true)
+    }
+
+    /// Parse the format written by `to_packed_bytes`, None if it is malformed or has
+    /// trailing bytes
+    pub fn from_packed_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::decode(bytes, true)
+    }
+
+    fn encode(&self, packed: bool) -> Vec<u8> {
+        let mut pairs: Vec<_> = self.counters.iter().collect();
+        pairs.sort_by_key(|(actor_id, _)| *actor_id);
+
+        let mut buf = Vec::with_capacity(1 + pairs.len() * 6);
+        prost::encoding::encode_varint(pairs.len() as u64, &mut buf);
+        let mut previous = 0u64;
+        for (actor_id, &counter) in pairs {
+            buf.extend_from_slice(actor_id.bytes());
+            if packed {
+                let delta = counter.wrapping_sub(previous) as i64;
+                prost::encoding::encode_varint(((delta << 1) ^ (delta >> 63)) as u64, &mut buf);
+                previous = counter;
+            } else {
+                prost::encoding::encode_varint(counter, &mut buf);
+            }
+        }
+        buf
+    }
+
+    fn decode(mut bytes: &[u8], packed: bool) -> Option<Self> {
+        let count = prost::encoding::decode_varint(&mut bytes).ok()?;
+
+        let mut counters = HashMap::new();
+        let mut previous = 0u64;
+        for _ in 0..count {
+            let (actor_id, rest) = ActorId::split_from(bytes).ok()?;
+            bytes = rest;
+            let varint = prost::encoding::decode_varint(&mut bytes).ok()?;
+            let counter = if packed {
+                let delta = (varint >> 1) as i64 ^ -((varint & 1) as i64);
+                previous = previous.wrapping_add(delta as u64);
+                previous
+            } else {
+                varint
+            };
+            counters.insert(actor_id, counter);
+        }
+
+        bytes.is_empty().then_some(Self { counters })
+    }
 }
 
 impl Default for VersionVector {
@@ -252,28 +485,271 @@ impl Default for VersionVector {
     }
 }
 
+/// A run of one actor's consecutive dots, counters `start` to `end` inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DotRange {
+    pub actor_id: ActorId,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl DotRange {
+    pub fn new(actor_id: ActorId, start: u64, end: u64) -> Self {
+        Self {
+            actor_id,
+            start,
+            end,
+        }
+    }
+
+    pub fn contains(&self, dot: Dot) -> bool {
+        dot.actor_id == self.actor_id && self.start <= dot.counter && dot.counter <= self.end
+    }
+
+    /// Expand back into the dots, in counter order
+    pub fn dots(&self) -> impl Iterator<Item = Dot> + use<> {
+        let actor_id = self.actor_id;
+        (self.start..=self.end).map(move |counter| Dot::new(actor_id, counter))
+    }
+
+    /// The fewest ranges covering exactly `dots`, by actor then counter
+    pub fn from_dots(dots: &[Dot]) -> Vec<DotRange> {
+        dots.iter().copied().collect::<DotCloud>().runs().collect()
+    }
+}
+
+/// A set of dots, held per actor as sorted, disjoint runs of consecutive counters
+///
+/// Where a `VersionVector` only covers an unbroken prefix of each actor's counters, this
+/// covers any dots at all, and costs one run however many consecutive dots it holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DotCloud {
+    runs: BTreeMap<ActorId, Vec<(u64, u64)>>,
+}
+
+impl DotCloud {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a dot, extending or joining the runs either side of it
+    pub fn insert(&mut self, dot: Dot) {
+        let runs = self.runs.entry(dot.actor_id).or_default();
+        let counter = dot.counter;
+        // The first run that ends at, or just before, the counter or later
+        let i = runs.partition_point(|&(_, last)| last.saturating_add(1) < counter);
+
+        match runs.get(i).copied() {
+            Some((first, last)) if first <= counter && counter <= last => {}
+            Some((first, _)) if first <= counter => {
+                // Just past the end of this run, and maybe just before the next
+                runs[i].1 = counter;
+                if runs.get(i + 1).is_some_and(|next| next.0 == counter + 1) {
+                    runs[i].1 = runs.remove(i + 1).1;
+                }
+            }
+            Some((first, _)) if first == counter + 1 => runs[i].0 = counter,
+            _ => runs.insert(i, (counter, counter)),
+        }
+    }
+
+    pub fn contains(&self, dot: Dot) -> bool {
+        self.runs.get(&dot.actor_id).is_some_and(|runs| {
+            let i = runs.partition_point(|&(_, last)| last < dot.counter);
+            runs.get(i).is_some_and(|&(first, _)| first <= dot.counter)
+        })
+    }
+
+    /// Every dot, by actor then counter
+    pub fn to_dots(&self) -> Vec<Dot> {
+        self.runs().flat_map(|range| range.dots()).collect()
+    }
+
+    /// The runs, by actor then counter
+    pub fn runs(&self) -> impl Iterator<Item = DotRange> + '_ {
+        self.runs.iter().flat_map(|(actor_id, runs)| {
+            runs.iter()
+                .map(move |&(start, end)| DotRange::new(*actor_id, start, end))
+        })
+    }
+
+    /// Add every dot in `range`, merging it with the runs it overlaps or touches
+    pub fn insert_range(&mut self, range: DotRange) {
+        if range.start > range.end {
+            return;
+        }
+        let runs = self.runs.entry(range.actor_id).or_default();
+        // The runs from the first that reaches the range to the last that starts by its end
+        let first = runs.partition_point(|&(_, last)| last.saturating_add(1) < range.start);
+        let past = runs.partition_point(|&(start, _)| start <= range.end.saturating_add(1));
+
+        let (mut start, mut end) = (range.start, range.end);
+        if first < past {
+            start = start.min(runs[first].0);
+            end = end.max(runs[past - 1].1);
+        }
+        runs.splice(first..past, [(start, end)]);
+    }
+
+    /// How many dots, without expanding the runs
+    pub fn len(&self) -> u64 {
+        self.runs.values().flatten().fold(0u64, |n, &(start, end)| {
+            n.saturating_add(end - start).saturating_add(1)
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+}
+
+impl FromIterator<Dot> for DotCloud {
+    fn from_iter<I: IntoIterator<Item = Dot>>(dots: I) -> Self {
+        let mut cloud = Self::new();
+        for dot in dots {
+            cloud.insert(dot);
+        }
+        cloud
+    }
+}
+
 /// Operation type for replication
+///
+/// `set_name` is empty for an `AddMulti`, which names its sets itself.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Operation {
     pub set_name: String,
     pub op_type: OpType,
-    pub context: VersionVector,
+    /// Shared, as the operation is copied for every peer it is queued for
+    pub context: Arc<VersionVector>,
+    /// The trace of the command that made it, so applying it on a peer joins that trace
+    #[serde(default)]
+    pub trace_context: TraceContext,
+}
+
+impl Operation {
+    /// The dot that identifies this operation
+    pub fn dot(&self) -> Dot {
+        match &self.op_type {
+            OpType::Add { dot, .. } | OpType::Remove { dot, .. } | OpType::AddMulti { dot, .. } => {
+                *dot
+            }
+        }
+    }
+
+    /// Every set this operation touches
+    pub fn set_names(&self) -> Vec<&str> {
+        match &self.op_type {
+            OpType::AddMulti { sets, .. } => sets.iter().map(|s| s.set_name.as_str()).collect(),
+            _ => vec![self.set_name.as_str()],
+        }
+    }
+
+    /// This operation with its effect left out, for a peer that replicates none of the sets
+    /// it touches: an `AddMulti` to no sets, which only moves the peer's version vector on
+    /// so the operations after it stay causally ready
+    pub fn elided(&self) -> Operation {
+        Operation {
+            set_name: String::new(),
+            op_type: OpType::AddMulti {
+                sets: Vec::new(),
+                dot: self.dot(),
+            },
+            context: Arc::clone(&self.context),
+            trace_context: self.trace_context.clone(),
+        }
+    }
+
+    /// Check this is an operation a replica could have made, the reason if not
+    ///
+    /// Its dot is an event (counters start at 1) that comes straight after its context,
+    /// as the context is the sender's version vector just before the dot: a counter that
+    /// jumps ahead would move our version vector on past dots that were never made. None
+    /// of its removed dots is the dot itself or a later one of its actor: an operation
+    /// only replaces what came before it.
+    pub fn validate(&self) -> Result<(), String> {
+        let dot = self.dot();
+        if dot.counter == 0 {
+            return Err(format!("{} has counter 0", dot));
+        }
+        let seen = self.context.get(dot.actor_id);
+        if seen != dot.counter - 1 {
+            return Err(format!(
+                "{} doesn't follow its context, which has {} of its actor's",
+                dot, seen
+            ));
+        }
+
+        let removed_dots: Vec<&Dot> = match &self.op_type {
+            OpType::Add { removed_dots, .. } | OpType::Remove { removed_dots, .. } => {
+                removed_dots.iter().collect()
+            }
+            OpType::AddMulti { sets, .. } => {
+                sets.iter().flat_map(|set| &set.removed_dots).collect()
+            }
+        };
+        match removed_dots
+            .into_iter()
+            .find(|removed| removed.dominates(&dot))
+        {
+            Some(removed) => Err(format!("{} removes {}, not before it", dot, removed)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this is an operation a sender elided, see `elided`
+    ///
+    /// A local `AddMulti` always touches at least one set.
+    pub fn is_elided(&self) -> bool {
+        matches!(&self.op_type, OpType::AddMulti { sets, .. } if sets.is_empty())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum OpType {
     Add {
-        elements: Vec<Bytes>,   // Multiple elements, single dot
-        dot: Dot,               // Single dot for this add operation
-        removed_dots: Vec<Dot>, // Concurrent removes observed
+        elements: Vec<Bytes>,    // Multiple elements, single dot
+        dot: Dot,                // Single dot for this add operation
+        removed_dots: Vec<Dot>,  // Concurrent removes observed
+        expires_at: Option<u64>, // When the dot expires, in ms since the Unix epoch (SADDEX)
     },
     Remove {
         elements: Vec<Bytes>,   // Multiple elements being removed
         dot: Dot,               // New dot for this remove (causality only, VV only)
         removed_dots: Vec<Dot>, // Dots that were on these elements
     },
+    AddMulti {
+        sets: Vec<SetAdd>, // Adds to many sets...
+        dot: Dot,          // ...sharing a single dot
+    },
 }
 
+/// One set's part of an `OpType::AddMulti`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SetAdd {
+    pub set_name: String,
+    pub elements: Vec<Bytes>,
+    pub removed_dots: Vec<Dot>,
+}
+
+/// What a replica records about one set
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetInfo {
+    /// When this replica created the set, in ms since the Unix epoch (None if not recorded)
+    pub created_at: Option<u64>,
+    /// The number of elements in the set
+    pub cardinality: i64,
+}
+
+/// An element with the dots that support it, as exchanged by anti-entropy
+pub type ElementDots = (Bytes, Vec<Dot>);
+
+/// A set name with every element of the set, as sent in a state transfer
+pub type SetSnapshot = (String, Vec<ElementDots>);
+
+/// A set name with the elements of it a delta carries (delta-state replication)
+pub type SetDelta = (String, Vec<ElementDots>);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -321,7 +797,7 @@ mod tests {
     fn test_actor_id_roundtrip_bytes() {
         let actor1 = ActorId::new(12345, 7);
         let bytes = actor1.bytes();
-        let actor2 = ActorId::from_bytes(&bytes).unwrap();
+        let actor2 = ActorId::from_bytes(bytes).unwrap();
         assert_eq!(actor1, actor2);
     }
 
@@ -366,6 +842,74 @@ mod tests {
         assert!(a3 < a2); // version:0, node:1, epoch:1 < version:0, node:2, epoch:0
     }
 
+    #[test]
+    fn test_actor_id_v1() {
+        let actor = ActorId::new_v1(0x123456).unwrap();
+        assert_eq!(actor.version(), 1);
+        assert_eq!(actor.node_id(), 0x123456);
+        assert_eq!(actor.epoch(), 0);
+        assert_eq!(actor.bytes(), [0x01, 0x12, 0x34, 0x56]);
+        assert_eq!(ActorId::from_bytes(actor.bytes()).unwrap(), actor);
+
+        assert_eq!(actor.to_string(), "v1:1193046");
+        assert_eq!(ActorId::from_str("v1:1193046").unwrap(), actor);
+        assert!(ActorId::from_str("v1:1193046:0").is_err());
+        assert!(ActorId::from_str("v1:16777216").is_err());
+
+        assert!(ActorId::new_v1(ActorId::MAX_V1_NODE_ID).is_some());
+        assert!(ActorId::new_v1(ActorId::MAX_V1_NODE_ID + 1).is_none());
+        // Never equal to the v0 id with the same bytes after the version
+        assert_ne!(actor, ActorId::new(0x1234, 0x56));
+    }
+
+    #[test]
+    fn test_actor_id_v2() {
+        let actor = ActorId::from_u64(0x12_3456_789a_bcde).unwrap();
+        assert_eq!(actor.version(), 2);
+        assert_eq!(actor.node_id(), 0x12_3456_789a_bcde);
+        assert_eq!(actor.epoch(), 0);
+        assert_eq!(
+            actor.bytes(),
+            [0x02, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde]
+        );
+        assert_eq!(ActorId::from_bytes(actor.bytes()).unwrap(), actor);
+        // Each version only in its own width
+        assert_eq!(
+            ActorId::from_bytes(&actor.bytes()[..4]),
+            Err(ActorIdError::InvalidLength(4))
+        );
+        assert_eq!(
+            ActorId::from_bytes(&[0, 0, 1, 0, 0, 0, 0, 0]),
+            Err(ActorIdError::InvalidLength(8))
+        );
+
+        assert_eq!(actor.to_string(), "v2:5124095576030430");
+        assert_eq!(ActorId::from_str("v2:5124095576030430").unwrap(), actor);
+        assert!(ActorId::from_str("v2:1:0").is_err());
+        assert!(ActorId::from_str("v2:72057594037927936").is_err());
+
+        assert!(ActorId::from_u64(ActorId::MAX_V2_NODE_ID).is_some());
+        assert!(ActorId::from_u64(ActorId::MAX_V2_NODE_ID + 1).is_none());
+        assert_ne!(actor, ActorId::new_v1(0x123456).unwrap());
+
+        // Serde uses the human-readable form
+        let json = serde_json::to_string(&actor).unwrap();
+        assert_eq!(json, "\"v2:5124095576030430\"");
+        assert_eq!(serde_json::from_str::<ActorId>(&json).unwrap(), actor);
+    }
+
+    #[test]
+    fn test_actor_id_unsupported_version() {
+        assert_eq!(
+            ActorId::from_bytes(&[0x03, 0x12, 0x34, 0x56]),
+            Err(ActorIdError::UnsupportedVersion(3))
+        );
+        assert_eq!(
+            ActorId::from_str("v7:1234:5"),
+            Err(ActorIdError::UnsupportedVersion(7))
+        );
+    }
+
     // Dot tests
     #[test]
     fn test_dot_creation() {
@@ -375,6 +919,23 @@ mod tests {
         assert_eq!(dot.counter, 5);
     }
 
+    #[test]
+    fn test_dot_ordering_and_dominance() {
+        let (a, b) = (ActorId::from_node_id(1), ActorId::from_node_id(2));
+        let mut dots = vec![Dot::new(b, 1), Dot::new(a, 3), Dot::new(a, 1)];
+        dots.sort();
+        assert_eq!(dots, vec![Dot::new(a, 1), Dot::new(a, 3), Dot::new(b, 1)]);
+
+        // Same actor: the later dot has seen the earlier
+        assert!(Dot::new(a, 3).dominates(&Dot::new(a, 1)));
+        assert!(!Dot::new(a, 1).dominates(&Dot::new(a, 3)));
+        assert!(Dot::new(a, 1).dominates(&Dot::new(a, 1)));
+
+        // Different actors are incomparable, whatever their counters
+        assert!(!Dot::new(a, 3).dominates(&Dot::new(b, 1)));
+        assert!(!Dot::new(b, 1).dominates(&Dot::new(a, 3)));
+    }
+
     // VersionVector tests
     #[test]
     fn test_version_vector_new() {
@@ -445,11 +1006,75 @@ mod tests {
         vv2.increment(actor_c);
         vv2.increment(actor_c);
 
-        vv1.merge(&vv2);
+        assert!(vv1.merge(&vv2));
 
         assert_eq!(vv1.get(actor_a), 2); // max(2, 1)
         assert_eq!(vv1.get(actor_b), 1); // only in vv1
         assert_eq!(vv1.get(actor_c), 2); // only in vv2
+
+        // Nothing new: merging again, an ancestor, or an empty VV changes nothing
+        let merged = vv1.clone();
+        assert!(!vv1.merge(&vv2));
+        let mut ancestor = VersionVector::new();
+        ancestor.increment(actor_a);
+        assert!(!vv1.merge(&ancestor));
+        assert!(!vv1.merge(&VersionVector::new()));
+        assert_eq!(vv1, merged);
+    }
+
+    #[test]
+    fn test_version_vector_meet() {
+        let mut vv1 = VersionVector::new();
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+        let actor_c = ActorId::from_node_id(3);
+
+        vv1.update(actor_a, 2);
+        vv1.update(actor_b, 1);
+
+        let mut vv2 = VersionVector::new();
+        vv2.update(actor_a, 1);
+        vv2.update(actor_c, 2);
+
+        vv1.meet(&vv2);
+
+        assert_eq!(vv1.get(actor_a), 1); // min(2, 1)
+        assert_eq!(vv1.get(actor_b), 0); // only in vv1
+        assert_eq!(vv1.get(actor_c), 0); // only in vv2
+        assert_eq!(vv1.counters.len(), 1);
+    }
+
+    #[test]
+    fn test_version_vector_ahead_of() {
+        let mut vv1 = VersionVector::new();
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+
+        vv1.update(actor_a, 5);
+        vv1.update(actor_b, 1);
+
+        let mut vv2 = VersionVector::new();
+        vv2.update(actor_a, 2);
+        vv2.update(actor_b, 3);
+
+        assert_eq!(vv1.ahead_of(&vv2), 3); // a: 5 - 2, b: nothing vv2 lacks
+        assert_eq!(vv2.ahead_of(&vv1), 2);
+        assert_eq!(vv1.ahead_of(&vv1), 0);
+        assert_eq!(vv1.ahead_of(&VersionVector::new()), 6);
+    }
+
+    #[test]
+    fn test_version_vector_latest_epoch() {
+        let mut vv = VersionVector::new();
+        assert_eq!(vv.latest_epoch(1), None);
+
+        vv.update(ActorId::new(1, 0), 5);
+        vv.update(ActorId::new(1, 2), 1);
+        vv.update(ActorId::new(2, 7), 1);
+        vv.update(ActorId::new_v1(1).unwrap(), 1);
+        assert_eq!(vv.latest_epoch(1), Some(2));
+        assert_eq!(vv.latest_epoch(2), Some(7));
+        assert_eq!(vv.latest_epoch(3), None);
     }
 
     #[test]
@@ -476,6 +1101,25 @@ mod tests {
         assert!(!vv3.descends(&vv1)); // Concurrent - vv3 doesn't have A or B
     }
 
+    #[test]
+    fn test_version_vector_concurrent_with() {
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+        let mut ancestor = VersionVector::new();
+        ancestor.increment(actor_a);
+        let mut descendant = ancestor.clone();
+        descendant.increment(actor_a);
+        let mut concurrent = ancestor.clone();
+        concurrent.increment(actor_b);
+
+        assert!(!ancestor.concurrent_with(&descendant));
+        assert!(!descendant.concurrent_with(&ancestor));
+        assert!(descendant.concurrent_with(&concurrent));
+        assert!(concurrent.concurrent_with(&descendant));
+        assert!(!ancestor.concurrent_with(&ancestor.clone()));
+        assert!(!VersionVector::new().concurrent_with(&VersionVector::new()));
+    }
+
     #[test]
     fn test_version_vector_descends_self() {
         let mut vv = VersionVector::new();
@@ -486,6 +1130,85 @@ mod tests {
         assert!(vv.descends(&vv)); // Should descend itself (reflexive)
     }
 
+    #[test]
+    fn test_version_vector_diff() {
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+        let actor_c = ActorId::from_node_id(3);
+
+        let mut ancestor = VersionVector::new();
+        ancestor.update(actor_a, 2);
+        let mut vv1 = ancestor.clone();
+        vv1.update(actor_a, 5);
+        vv1.update(actor_b, 1);
+        let mut vv2 = ancestor.clone();
+        vv2.update(actor_c, 3);
+
+        // Ancestor
+        assert_eq!(vv1.diff(&ancestor), vec![(actor_a, 2, 5), (actor_b, 0, 1)]);
+        assert!(ancestor.diff(&vv1).is_empty());
+        assert!(vv1.dominates(&ancestor));
+        assert!(!ancestor.dominates(&vv1));
+        assert!(!vv1.concurrent_with(&ancestor));
+
+        // Concurrent
+        assert_eq!(vv1.diff(&vv2), vec![(actor_a, 2, 5), (actor_b, 0, 1)]);
+        assert_eq!(vv2.diff(&vv1), vec![(actor_c, 0, 3)]);
+        assert!(vv1.concurrent_with(&vv2));
+        assert!(vv2.concurrent_with(&vv1));
+        assert!(!vv1.dominates(&vv2));
+        assert!(!vv2.dominates(&vv1));
+
+        // Identical, a zero counter is the same as no entry
+        let mut zeroed = vv1.clone();
+        zeroed.update(actor_c, 0);
+        assert!(vv1.diff(&zeroed).is_empty());
+        assert!(zeroed.diff(&vv1).is_empty());
+        assert!(!vv1.dominates(&zeroed));
+        assert!(!vv1.concurrent_with(&zeroed));
+    }
+
+    #[test]
+    fn test_version_vector_subtract() {
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+        let actor_c = ActorId::from_node_id(3);
+
+        // Disjoint
+        let mut vv1 = VersionVector::new();
+        vv1.update(actor_a, 4);
+        let mut vv2 = VersionVector::new();
+        vv2.update(actor_b, 2);
+        assert_eq!(vv1.subtract(&vv2), vv1);
+        assert_eq!(vv2.subtract(&vv1), vv2);
+
+        // Overlapping
+        let mut vv1 = VersionVector::new();
+        vv1.update(actor_a, 5);
+        vv1.update(actor_b, 1);
+        let mut vv2 = VersionVector::new();
+        vv2.update(actor_a, 2);
+        vv2.update(actor_b, 3);
+        vv2.update(actor_c, 1);
+        let delta = vv1.subtract(&vv2);
+        assert_eq!(delta.counters.len(), 1);
+        assert_eq!(delta.get(actor_a), 3);
+        let delta = vv2.subtract(&vv1);
+        assert_eq!(delta.counters.len(), 2);
+        assert_eq!((delta.get(actor_b), delta.get(actor_c)), (2, 1));
+        assert_eq!(
+            vv1.subtract(&vv2).ahead_of(&VersionVector::new()),
+            vv1.ahead_of(&vv2)
+        );
+
+        // Dominating
+        let mut vv3 = vv2.clone();
+        vv3.merge(&vv1);
+        assert!(vv2.subtract(&vv3).counters.is_empty());
+        assert_eq!(vv3.subtract(&vv2).counters.len(), 1);
+        assert!(vv3.subtract(&vv3).counters.is_empty());
+    }
+
     #[test]
     fn test_version_vector_from_str() {
         let vv = VersionVector::from_str("v0:1:0:5,v0:2:0:3,v0:3:0:2").unwrap();
@@ -535,4 +1258,170 @@ mod tests {
 
         assert_eq!(vv1, vv2);
     }
+
+    #[test]
+    fn test_dot_cloud() {
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+
+        let mut cloud = DotCloud::new();
+        assert!(cloud.is_empty());
+        for c in [5, 1, 3, 2, 7, 9, 8, 2] {
+            cloud.insert(Dot::new(actor_a, c));
+        }
+        cloud.insert(Dot::new(actor_b, 4));
+
+        // 1-3 run together, 7-9 were joined up by 8
+        assert_eq!(
+            cloud.runs().collect::<Vec<_>>(),
+            vec![
+                DotRange::new(actor_a, 1, 3),
+                DotRange::new(actor_a, 5, 5),
+                DotRange::new(actor_a, 7, 9),
+                DotRange::new(actor_b, 4, 4)
+            ]
+        );
+        assert!(cloud.contains(Dot::new(actor_a, 2)));
+        assert!(cloud.contains(Dot::new(actor_a, 9)));
+        assert!(!cloud.contains(Dot::new(actor_a, 4)));
+        assert!(!cloud.contains(Dot::new(actor_a, 10)));
+        assert!(!cloud.contains(Dot::new(actor_b, 2)));
+
+        cloud.insert(Dot::new(actor_a, 4));
+        cloud.insert(Dot::new(actor_a, 6));
+        assert_eq!(cloud.runs().next(), Some(DotRange::new(actor_a, 1, 9)));
+
+        let dots = cloud.to_dots();
+        assert_eq!(dots.len(), 10);
+        assert_eq!(dots[0], Dot::new(actor_a, 1));
+        assert_eq!(dots.iter().copied().collect::<DotCloud>(), cloud);
+    }
+
+    #[test]
+    fn test_dot_cloud_insert_range() {
+        let actor_a = ActorId::from_node_id(1);
+
+        let mut cloud = DotCloud::new();
+        for c in [2, 5, 6, 12, 20] {
+            cloud.insert(Dot::new(actor_a, c));
+        }
+        // Swallows 5-6, joins 12 at its end, and leaves 2 and 20 apart
+        cloud.insert_range(DotRange::new(actor_a, 4, 11));
+        assert_eq!(
+            cloud.runs().collect::<Vec<_>>(),
+            vec![
+                DotRange::new(actor_a, 2, 2),
+                DotRange::new(actor_a, 4, 12),
+                DotRange::new(actor_a, 20, 20)
+            ]
+        );
+        assert_eq!(cloud.len(), 11);
+
+        cloud.insert_range(DotRange::new(actor_a, 3, 3));
+        assert_eq!(cloud.runs().next(), Some(DotRange::new(actor_a, 2, 12)));
+
+        // A huge run is still one run, and nothing is expanded to count it
+        cloud.insert_range(DotRange::new(actor_a, 1, u64::MAX));
+        assert_eq!(
+            cloud.runs().collect::<Vec<_>>(),
+            vec![DotRange::new(actor_a, 1, u64::MAX)]
+        );
+        assert_eq!(cloud.len(), u64::MAX);
+
+        let mut inverted = DotCloud::new();
+        inverted.insert_range(DotRange::new(actor_a, 5, 4));
+        assert!(inverted.is_empty());
+    }
+
+    #[test]
+    fn test_dot_range() {
+        let actor_a = ActorId::from_node_id(1);
+        let actor_b = ActorId::from_node_id(2);
+
+        let dots = [
+            Dot::new(actor_b, 3),
+            Dot::new(actor_a, 2),
+            Dot::new(actor_a, 1),
+            Dot::new(actor_a, 3),
+            Dot::new(actor_b, 5),
+        ];
+        let ranges = DotRange::from_dots(&dots);
+        assert_eq!(
+            ranges,
+            vec![
+                DotRange::new(actor_a, 1, 3),
+                DotRange::new(actor_b, 3, 3),
+                DotRange::new(actor_b, 5, 5)
+            ]
+        );
+        assert!(ranges[0].contains(Dot::new(actor_a, 2)));
+        assert!(!ranges[0].contains(Dot::new(actor_b, 2)));
+        assert!(!ranges[0].contains(Dot::new(actor_a, 4)));
+
+        let mut expanded: Vec<Dot> = ranges.iter().flat_map(|range| range.dots()).collect();
+        let mut expected = dots.to_vec();
+        expanded.sort();
+        expected.sort();
+        assert_eq!(expanded, expected);
+    }
+
+    #[test]
+    fn test_version_vector_bytes_roundtrip() {
+        let mut vv = VersionVector::new();
+        vv.update(ActorId::from_node_id(1), 5);
+        vv.update(ActorId::new(300, 2), 1 << 40);
+        vv.update(ActorId::from_node_id(3), 0);
+        vv.update(ActorId::from_u64(1 << 40).unwrap(), 7);
+
+        let bytes = vv.to_bytes();
+        let decoded = VersionVector::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, vv);
+        // The same VV as the string format gives
+        assert_eq!(decoded, VersionVector::from_str(&vv.to_string()).unwrap());
+        assert_eq!(decoded.to_string(), vv.to_string());
+        assert!(bytes.len() < vv.to_string().len());
+
+        let empty = VersionVector::new();
+        assert_eq!(empty.to_bytes(), vec![0]);
+        assert_eq!(VersionVector::from_bytes(&[0]).unwrap(), empty);
+
+        assert!(VersionVector::from_bytes(&[]).is_none());
+        assert!(VersionVector::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(VersionVector::from_bytes(&trailing).is_none());
+    }
+
+    #[test]
+    fn test_version_vector_packed_bytes() {
+        // A dozen nodes that have each written about a million times
+        let mut vv = VersionVector::new();
+        for node_id in 1..=12u16 {
+            vv.update(
+                ActorId::from_node_id(node_id),
+                1_000_000 + u64::from(node_id) * 37,
+            );
+        }
+        vv.update(ActorId::from_u64(1 << 40).unwrap(), 3);
+
+        let packed = vv.to_packed_bytes();
+        assert_eq!(VersionVector::from_packed_bytes(&packed).unwrap(), vv);
+        assert!(packed.len() < vv.to_bytes().len());
+
+        // Each counter on its own in `to_bytes`, the formats only agree on the first
+        let actor = ActorId::from_node_id(1);
+        let mut one = VersionVector::new();
+        one.update(actor, 300);
+        let mut plain = vec![1];
+        plain.extend_from_slice(actor.bytes());
+        plain.extend_from_slice(&[0xac, 0x02]);
+        assert_eq!(one.to_bytes(), plain);
+        assert_ne!(vv.to_packed_bytes(), vv.to_bytes());
+
+        assert_eq!(VersionVector::new().to_packed_bytes(), vec![0]);
+        assert!(VersionVector::from_packed_bytes(&packed[..packed.len() - 1]).is_none());
+        let mut trailing = packed.clone();
+        trailing.push(0);
+        assert!(VersionVector::from_packed_bytes(&trailing).is_none());
+    }
 }
@@ -1,12 +1,20 @@
-use crate::replication::ReplicationManager;
-use crate::server::{CommandResult, Server};
+use crate::config::{ReplicaInfo, RuntimeConfig};
+use crate::replication::{AckWait, PeerStatus, ReplicationListener, ReplicationManager};
+use crate::server::{CommandResult, MembersStream, Server};
+use crate::storage::Checkpoint;
 
-use crate::types::VersionVector;
+use crate::error::{BigsetError, Result};
+use crate::types::{ActorId, Dot, OpType, VersionVector};
 use bytes::Bytes;
-use rusqlite::Result;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
 use tracing::{error, trace};
 
+/// How long HEALTH waits for storage before calling it degraded
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Wrapper that coordinates Server and ReplicationManager
 ///
 /// This is the glue layer that:
@@ -19,6 +27,9 @@ use tracing::{error, trace};
 pub struct ServerWrapper {
     server: Arc<Server>,
     replication: Arc<ReplicationManager>,
+    /// Held from producing an operation until it is queued for replication, so
+    /// operations are queued (and leave for each peer) in the order they were produced
+    dispatch: Mutex<()>,
 }
 
 impl ServerWrapper {
@@ -26,91 +37,514 @@ impl ServerWrapper {
         Self {
             server,
             replication,
+            dispatch: Mutex::new(()),
         }
     }
 
+    /// The settings CONFIG GET and CONFIG SET work on, shared with replication
+    pub fn runtime_config(&self) -> &Arc<RuntimeConfig> {
+        self.replication.runtime_config()
+    }
+
     /// Add members to a set
     ///
-    /// Calls server, spawns replication task, returns result
-    pub async fn sadd(&self, set_name: &str, members: &[Bytes]) -> Result<CommandResult> {
+    /// Calls server, queues the operation for replication, returns result. With a
+    /// `client_vv` the add waits, as NotReady, until the server has seen everything in it,
+    /// so the operation's context carries the client's causal history to every replica.
+    /// With synchronous replication the reply waits for peers' acks too, see `synced`.
+    pub async fn sadd(
+        &self,
+        set_name: &str,
+        members: &[Bytes],
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        if let Some(not_ready) = self.server.not_ready(client_vv).await {
+            return Ok(not_ready);
+        }
+        let dispatch = self.dispatch.lock().await;
         trace!("Calling the server SADD");
         let (result, operation) = self.server.sadd(set_name, members).await?;
 
-        // Send operation to replication (fire and forget)
+        // Queue operation for replication
         trace!("Replication op from SADD");
-        if let Some(op) = operation {
-            tracing::info!(
-                "SADD wrapper spawning replication task for set={}",
-                set_name
-            );
-            let replication = Arc::clone(&self.replication);
-            tokio::spawn(async move {
-                tracing::info!("Replication task started, calling send()");
-                if let Err(e) = replication.send(op).await {
-                    error!("Failed to replicate SADD: {}", e);
-                } else {
-                    tracing::info!("Replication send() completed successfully");
-                }
-            });
+        let wait = if let Some(op) = operation {
+            tracing::info!("SADD wrapper queueing replication for set={}", set_name);
+            self.replication.enqueue_synced(op).await
         } else {
             tracing::warn!("SADD produced no operation to replicate");
+            None
+        };
+        drop(dispatch);
+
+        Ok(self.synced(result, wait).await)
+    }
+
+    /// Add members to a set that expire `ttl` from now, NotReady until the server has seen
+    /// `client_vv` and synchronous as for SADD
+    pub async fn saddex(
+        &self,
+        set_name: &str,
+        members: &[Bytes],
+        ttl: Duration,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        if let Some(not_ready) = self.server.not_ready(client_vv).await {
+            return Ok(not_ready);
         }
+        let dispatch = self.dispatch.lock().await;
+        let (result, operation) = self.server.saddex(set_name, members, ttl).await?;
+        let wait = match operation {
+            Some(op) => self.replication.enqueue_synced(op).await,
+            None => None,
+        };
+        drop(dispatch);
+        Ok(self.synced(result, wait).await)
+    }
 
+    /// Add the members not already in a set, NotReady until the server has seen
+    /// `client_vv` as for SADD; only replicates when something was added
+    pub async fn saddnx(
+        &self,
+        set_name: &str,
+        members: &[Bytes],
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        if let Some(not_ready) = self.server.not_ready(client_vv).await {
+            return Ok(not_ready);
+        }
+        let _dispatch = self.dispatch.lock().await;
+        let (result, operation) = self.server.saddnx(set_name, members).await?;
+        if let Some(op) = operation {
+            self.replication.enqueue(op).await;
+        }
         Ok(result)
     }
 
-    /// Remove members from a set
-    pub async fn srem(&self, set_name: &str, members: &[Bytes]) -> Result<CommandResult> {
+    /// Remove members from a set, NotReady until the server has seen `client_vv` and
+    /// synchronous as for SADD
+    pub async fn srem(
+        &self,
+        set_name: &str,
+        members: &[Bytes],
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        if let Some(not_ready) = self.server.not_ready(client_vv).await {
+            return Ok(not_ready);
+        }
+        let dispatch = self.dispatch.lock().await;
         let (result, operation) = self.server.srem(set_name, members).await?;
 
-        // Send operation to replication (fire and forget)
-        if let Some(op) = operation {
+        // Queue operation for replication
+        let wait = match operation {
+            Some(op) => self.replication.enqueue_synced(op).await,
+            None => None,
+        };
+        drop(dispatch);
+
+        Ok(self.synced(result, wait).await)
+    }
+
+    /// Hold a write's reply until peers ack its operation, when replication is synchronous
+    ///
+    /// Waited for once the dispatch lock is released, so other writes are not held up.
+    /// Too few acks in time turn OK into Degraded: the write is committed here, and still
+    /// reaches the peers as any other write does.
+    async fn synced(&self, result: CommandResult, wait: Option<AckWait>) -> CommandResult {
+        let Some(wait) = wait else {
+            return result;
+        };
+        let (acked, needed) = self.replication.wait_for_acks(wait).await;
+        match result {
+            CommandResult::Ok { vv } if acked < needed => {
+                CommandResult::Degraded { vv, acked, needed }
+            }
+            result => result,
+        }
+    }
+
+    /// Remove the elements whose every dot has expired, replicating the removes
+    ///
+    /// Returns the number of elements removed.
+    pub async fn expire(&self) -> Result<usize> {
+        let _dispatch = self.dispatch.lock().await;
+        let mut removed = 0;
+        for op in self.server.expire().await? {
+            if let OpType::Remove { elements, .. } = &op.op_type {
+                removed += elements.len();
+            }
+            self.replication.enqueue(op).await;
+        }
+        Ok(removed)
+    }
+
+    /// Remove expired elements every `interval_ms`, forever
+    pub async fn run_expiry(self: Arc<Self>, interval_ms: u64) {
+        let mut interval = tokio::time::interval(Duration::from_millis(interval_ms.max(1)));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            match self.expire().await {
+                Ok(0) => {}
+                Ok(removed) => trace!("Removed {} expired elements", removed),
+                Err(e) => error!("Storage error removing expired elements: {}", e),
+            }
+        }
+    }
+
+    /// Force anti-entropy for a set against every peer
+    ///
+    /// Returns the number of elements changed locally.
+    pub async fn ssync(&self, set_name: &str) -> Result<CommandResult> {
+        let changed = self.replication.sync_set(&self.server, set_name).await;
+        Ok(CommandResult::Integer(changed as i64))
+    }
+
+    /// Start replicating to the node `node_id` listening on `addr` (CLUSTER MEET)
+    ///
+    /// With `transfer` the node is also pushed a snapshot of everything we have, for a
+    /// new node that missed the history.
+    pub async fn cluster_meet(
+        &self,
+        addr: &str,
+        node_id: u16,
+        transfer: bool,
+    ) -> Result<CommandResult> {
+        if u64::from(node_id) == self.server.actor_id().node_id() {
+            return Ok(CommandResult::Error("ERR can't meet myself".to_string()));
+        }
+
+        let peer = ReplicaInfo {
+            node_id,
+            epoch: 0,
+            addr: addr.to_string(),
+        };
+        self.replication.add_peer(peer.clone()).await;
+
+        if transfer {
+            let server = Arc::clone(&self.server);
             let replication = Arc::clone(&self.replication);
             tokio::spawn(async move {
-                if let Err(e) = replication.send(op).await {
-                    error!("Failed to replicate SREM: {}", e);
+                match replication.push_snapshot(&server, &peer).await {
+                    Ok(sets) => tracing::info!("Pushed {} sets to peer {}", sets, peer.addr),
+                    Err(e) => error!("State transfer to peer {} failed: {}", peer.addr, e),
                 }
             });
         }
 
-        Ok(result)
+        Ok(CommandResult::Ok { vv: None })
+    }
+
+    /// Stop replicating to the node `node_id` (CLUSTER FORGET)
+    pub async fn cluster_forget(&self, node_id: u16) -> Result<CommandResult> {
+        if self.replication.remove_peer(node_id).await.is_empty() {
+            return Ok(CommandResult::Error(format!(
+                "ERR unknown node {}",
+                node_id
+            )));
+        }
+        Ok(CommandResult::Ok { vv: None })
     }
 
-    /// Get cardinality of a set (read-only, pass through)
+    /// Replicate only the sets matching `patterns` from now on (SUBSCRIBE SET)
+    pub async fn subscribe(&self, patterns: Vec<String>) -> Result<CommandResult> {
+        self.replication.subscribe(patterns).await;
+        Ok(CommandResult::Ok { vv: None })
+    }
+
+    /// The set-name patterns this node replicates, none for every set (SUBSCRIBE LIST)
+    pub async fn subscriptions(&self) -> Result<CommandResult> {
+        let patterns = self.replication.subscriptions().await;
+        Ok(CommandResult::BytesArray(
+            patterns.into_iter().map(Bytes::from).collect(),
+        ))
+    }
+
+    /// Checkpoint the storage, and VACUUM it with `vacuum` (MAINTENANCE [VACUUM])
+    pub async fn maintenance(&self, vacuum: bool) -> Result<CommandResult> {
+        self.server.maintenance(vacuum).await?;
+        Ok(CommandResult::Ok { vv: None })
+    }
+
+    /// Checkpoint the WAL and truncate it, without the rest of MAINTENANCE (CHECKPOINT)
+    pub async fn checkpoint(&self) -> Result<Checkpoint> {
+        self.server.checkpoint().await
+    }
+
+    /// Garbage collect causally stable dots now, in one set or all of them (MAINTENANCE COMPACT [key])
+    pub async fn compact_dots(&self, set_name: Option<&str>) -> Result<CommandResult> {
+        let deleted = self.server.compact_dots(set_name).await?;
+        Ok(CommandResult::Integer(deleted as i64))
+    }
+
+    /// Write a backup of every set to `path` on the server (SAVE path)
+    pub async fn save(&self, path: &str) -> Result<CommandResult> {
+        self.server.save(Path::new(path)).await?;
+        Ok(CommandResult::Ok { vv: None })
+    }
+
+    /// Replace local state with a backup at `path` on the server (LOAD path)
+    pub async fn load(&self, path: &str) -> Result<CommandResult> {
+        match self.server.load(Path::new(path)).await {
+            Ok(()) => Ok(CommandResult::Ok { vv: None }),
+            Err(BigsetError::Causality(_)) => Ok(CommandResult::Error(
+                "ERR backup is not ahead of local state, not loaded".to_string(),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Server information for INFO, in the Redis `# Section` / `field:value` layout
+    ///
+    /// Returns every section if `section` is None, and nothing for an unknown section.
+    pub async fn info(&self, section: Option<&str>) -> String {
+        let wanted = |name: &str| section.is_none_or(|s| s.eq_ignore_ascii_case(name));
+        let mut out = String::new();
+
+        if wanted("replication") {
+            let peers = self.replication_status().await;
+            let pending = self.replication.pending_buffer().read().await.len();
+
+            out.push_str("# Replication\r\n");
+            out.push_str(&format!("actor_id:{}\r\n", self.server.actor_id()));
+            out.push_str(&format!(
+                "connected_peers:{}\r\n",
+                peers.iter().filter(|(p, _)| p.up).count()
+            ));
+            out.push_str(&format!("pending_ops:{}\r\n", pending));
+            let metrics = self.replication.metrics().snapshot();
+            out.push_str(&format!("ops_sent:{}\r\n", metrics.ops_sent));
+            out.push_str(&format!("acks_received:{}\r\n", metrics.acks_received));
+            out.push_str(&format!("ops_applied:{}\r\n", metrics.ops_applied));
+            out.push_str(&format!("ops_buffered:{}\r\n", metrics.ops_buffered));
+            out.push_str(&format!("read_repairs:{}\r\n", metrics.read_repairs));
+            let local_vv = self.server.version_vector().read().await.clone();
+            out.push_str(&format!(
+                "stable_vv:{}\r\n",
+                self.replication.stable_vv(&local_vv).await.to_string()
+            ));
+            for (i, (status, lag)) in peers.iter().enumerate() {
+                out.push_str(&format!(
+                    "peer{}:node_id={},epoch={},addr={},status={},last_seen_ms={},unacked={},lag={}\r\n",
+                    i,
+                    status.peer.node_id,
+                    status.peer.epoch,
+                    status.peer.addr,
+                    if status.up { "up" } else { "down" },
+                    status.last_seen.map_or(-1, |ago| ago.as_millis() as i64),
+                    status.unacked,
+                    lag.map_or(-1, |lag| lag as i64)
+                ));
+            }
+        }
+
+        out
+    }
+
+    /// Every peer's status, with how many of our operations it is behind if it has
+    /// reported its version vector (REPLICATION)
+    pub async fn replication_status(&self) -> Vec<(PeerStatus, Option<u64>)> {
+        let local_vv = self.server.version_vector().read().await.clone();
+        let mut statuses = Vec::new();
+        for status in self.replication.peer_status().await {
+            let lag = self
+                .replication
+                .peer_lag(status.peer.actor_id(), &local_vv)
+                .await;
+            statuses.push((status, lag));
+        }
+        statuses
+    }
+
+    /// Liveness and readiness, for load balancers (HEALTH)
+    ///
+    /// OK when storage answers within `HEALTH_TIMEOUT` and the server is serving reads.
+    /// LOADING until startup learning or a state transfer is done, and DEGRADED when
+    /// storage fails or the pending buffer is nearly full, so remote operations are
+    /// about to be dropped.
+    pub async fn health(&self) -> CommandResult {
+        match tokio::time::timeout(HEALTH_TIMEOUT, self.server.check_storage()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return CommandResult::Error(format!("DEGRADED storage: {}", e)),
+            Err(_) => return CommandResult::Error("DEGRADED storage: timed out".to_string()),
+        }
+
+        let (pending, max_size) = {
+            let buffer = self.replication.pending_buffer();
+            let buffer = buffer.read().await;
+            (buffer.len(), buffer.max_size())
+        };
+        if max_size > 0 && pending * 10 >= max_size * 9 {
+            return CommandResult::Error(format!(
+                "DEGRADED pending buffer {}/{} full",
+                pending, max_size
+            ));
+        }
+
+        if !self.server.is_ready() {
+            return CommandResult::Error(
+                "LOADING learning missed operations from peers".to_string(),
+            );
+        }
+
+        CommandResult::Ok { vv: None }
+    }
+
+    /// Get cardinality of a set (read-only, pass through, with read repair)
     pub async fn scard(
         &self,
         set_name: &str,
         client_vv: Option<&VersionVector>,
     ) -> Result<CommandResult> {
-        self.server.scard(set_name, client_vv).await
+        let result = self.server.scard(set_name, client_vv).await;
+        self.read_repair(
+            matches!(result, Ok(CommandResult::NotReady(_))),
+            client_vv,
+            &[set_name],
+        );
+        result
+    }
+
+    /// Count the members sets have in common (read-only, pass through, with read repair)
+    pub async fn sintercard(
+        &self,
+        set_names: &[String],
+        limit: Option<u64>,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        let result = self.server.sintercard(set_names, limit, client_vv).await;
+        let sets: Vec<&str> = set_names.iter().map(String::as_str).collect();
+        self.read_repair(
+            matches!(result, Ok(CommandResult::NotReady(_))),
+            client_vv,
+            &sets,
+        );
+        result
+    }
+
+    /// The node's version vector, a causal context for reads of any set (VV)
+    pub async fn vv(&self) -> VersionVector {
+        self.server.version_vector().read().await.clone()
     }
 
-    /// Get all members of a set (read-only, pass through)
+    /// The actors with dots in a set (read-only, pass through, with read repair)
+    pub async fn set_actors(
+        &self,
+        set_name: &str,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<std::result::Result<Vec<ActorId>, CommandResult>> {
+        let result = self.server.set_actors(set_name, client_vv).await;
+        self.read_repair(
+            matches!(result, Ok(Err(CommandResult::NotReady(_)))),
+            client_vv,
+            &[set_name],
+        );
+        result
+    }
+
+    /// The dots on a set's element, for debugging (read-only, pass through)
+    pub async fn element_dots(&self, set_name: &str, element: &Bytes) -> Result<Vec<Dot>> {
+        self.server.element_dots(set_name, element).await
+    }
+
+    /// A set's version vector, a causal context for reads of it (read-only, pass through)
+    pub async fn set_vv(&self, set_name: &str) -> Result<CommandResult> {
+        self.server.set_vv(set_name).await
+    }
+
+    /// Get all members of a set (read-only, pass through, with read repair)
     pub async fn smembers(
         &self,
         set_name: &str,
         client_vv: Option<&VersionVector>,
     ) -> Result<CommandResult> {
-        self.server.smembers(set_name, client_vv).await
+        let result = self.server.smembers(set_name, client_vv).await;
+        self.read_repair(
+            matches!(result, Ok(CommandResult::NotReady(_))),
+            client_vv,
+            &[set_name],
+        );
+        result
+    }
+
+    /// Get members of a set at random (read-only, pass through, with read repair)
+    pub async fn srandmember(
+        &self,
+        set_name: &str,
+        count: i64,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<CommandResult> {
+        let result = self.server.srandmember(set_name, count, client_vv).await;
+        self.read_repair(
+            matches!(result, Ok(CommandResult::NotReady(_))),
+            client_vv,
+            &[set_name],
+        );
+        result
+    }
+
+    /// Get all members of a set a batch at a time (read-only, pass through, with read repair)
+    pub async fn smembers_stream(
+        &self,
+        set_name: &str,
+        pattern: Option<&[u8]>,
+        client_vv: Option<&VersionVector>,
+    ) -> Result<std::result::Result<MembersStream, CommandResult>> {
+        let result = self
+            .server
+            .smembers_stream(set_name, pattern, client_vv)
+            .await;
+        self.read_repair(
+            matches!(result, Ok(Err(CommandResult::NotReady(_)))),
+            client_vv,
+            &[set_name],
+        );
+        result
     }
 
-    /// Check if element is member (read-only, pass through)
+    /// Check if element is member (read-only, pass through, with read repair)
     pub async fn sismember(
         &self,
         set_name: &str,
         member: &Bytes,
         client_vv: Option<&VersionVector>,
     ) -> Result<CommandResult> {
-        self.server.sismember(set_name, member, client_vv).await
+        let result = self.server.sismember(set_name, member, client_vv).await;
+        self.read_repair(
+            matches!(result, Ok(CommandResult::NotReady(_))),
+            client_vv,
+            &[set_name],
+        );
+        result
     }
 
-    /// Check membership for multiple elements (read-only, pass through)
+    /// Check membership for multiple elements (read-only, pass through, with read repair)
     pub async fn smismember(
         &self,
         set_name: &str,
         members: &[Bytes],
         client_vv: Option<&VersionVector>,
     ) -> Result<CommandResult> {
-        self.server.smismember(set_name, members, client_vv).await
+        let result = self.server.smismember(set_name, members, client_vv).await;
+        self.read_repair(
+            matches!(result, Ok(CommandResult::NotReady(_))),
+            client_vv,
+            &[set_name],
+        );
+        result
+    }
+
+    /// A causal read came back NotReady when `not_ready`: the client has seen operations,
+    /// from another replica, that this node is missing. Start a read repair of them in the
+    /// background (see `ReplicationListener::spawn_read_repair`); the read isn't held up.
+    fn read_repair(&self, not_ready: bool, client_vv: Option<&VersionVector>, set_names: &[&str]) {
+        if let (true, Some(client_vv)) = (not_ready, client_vv) {
+            ReplicationListener::spawn_read_repair(
+                Arc::clone(&self.server),
+                Arc::clone(&self.replication),
+                client_vv.clone(),
+                set_names.iter().map(|name| name.to_string()).collect(),
+            );
+        }
     }
 }
@@ -0,0 +1,289 @@
+use crate::storage::{GroupWrite, Storage, WriteKind};
+use crate::types::{ActorId, Dot, VersionVector};
+use bytes::Bytes;
+use rusqlite::Result;
+use std::sync::Arc;
+use tokio::sync::{RwLock, mpsc, oneshot};
+use tracing::{Span, info_span};
+
+/// Writes waiting for the writer thread before senders have to wait
+const QUEUE_LEN: usize = 1024;
+
+/// Most local adds and removes committed together in one group
+const MAX_GROUP: usize = 256;
+
+/// A write, run on the writer thread with the storage and the version vector. It hands
+/// back its reply, sent once the version vector it left behind has been published, and
+/// whether it succeeded.
+type RunJob<S> = Box<dyn FnOnce(&S, &mut VersionVector) -> (Reply, bool) + Send>;
+
+type Reply = Box<dyn FnOnce() + Send>;
+
+enum Job<S> {
+    Run(RunJob<S>),
+    /// A local add or remove, which can share a commit with the ones queued after it
+    Grouped(Grouped),
+}
+
+struct Grouped {
+    kind: WriteKind,
+    set_name: String,
+    elements: Vec<Bytes>,
+    reply: oneshot::Sender<Result<LocalWrite<Vec<Dot>>>>,
+    /// The sender's span, for the storage span of the commit
+    span: Span,
+}
+
+/// A write done with a new local dot, see `Writer::local`
+pub(crate) struct LocalWrite<T> {
+    /// The version vector before the write's dot, the context of its operation
+    pub(crate) context: Arc<VersionVector>,
+    pub(crate) dot: Dot,
+    /// What the storage call returned
+    pub(crate) result: T,
+    /// The version vector after the write
+    pub(crate) vv: Arc<VersionVector>,
+}
+
+/// The single point every storage write of a Server goes through
+///
+/// A dedicated thread takes writes off a channel one at a time. It owns the authoritative
+/// version vector, so taking a dot and writing it to storage happen together with nothing
+/// in between. After each write has committed, the thread publishes the version vector to
+/// the Server's shared copy. Readers only hold that copy briefly, and what it says has
+/// always reached storage, so a read that checks it first sees at least that much. A write that
+/// fails leaves the version vector as it was, so no dot is spent on it.
+///
+/// Local adds and removes sent with `grouped` are committed in groups: the thread takes
+/// every one already queued behind the first, up to `MAX_GROUP`, gives each the next dot
+/// in turn and commits them with one `Storage::commit_group`. Nothing waits for a group
+/// to fill, a group is whatever queued up during the previous commit.
+///
+/// Each storage call runs in a `storage` span, a child of the span the write was sent from.
+///
+/// The thread stops once every handle is dropped.
+pub(crate) struct Writer<S: Storage> {
+    jobs: mpsc::Sender<Job<S>>,
+    actor_id: ActorId,
+}
+
+impl<S: Storage> Writer<S> {
+    /// Start the writer thread from `vv`, the version vector `published` already holds
+    pub(crate) fn spawn(
+        storage: Arc<S>,
+        actor_id: ActorId,
+        mut vv: VersionVector,
+        published: Arc<RwLock<VersionVector>>,
+    ) -> Result<Self> {
+        let (jobs, mut queue) = mpsc::channel::<Job<S>>(QUEUE_LEN);
+
+        std::thread::Builder::new()
+            .name("bigsets-writer".to_string())
+            .spawn(move || {
+                let mut next = None;
+                while let Some(job) = next.take().or_else(|| queue.blocking_recv()) {
+                    let before = vv.clone();
+                    let (replies, ok) = match job {
+                        Job::Run(job) => {
+                            let (reply, ok) = job(&storage, &mut vv);
+                            (vec![reply], ok)
+                        }
+                        Job::Grouped(first) => {
+                            let mut group = vec![first];
+                            while group.len() < MAX_GROUP {
+                                match queue.try_recv() {
+                                    Ok(Job::Grouped(write)) => group.push(write),
+                                    Ok(job) => {
+                                        next = Some(job);
+                                        break;
+                                    }
+                                    Err(_) => break,
+                                }
+                            }
+                            commit_group(storage.as_ref(), actor_id, &mut vv, group)
+                        }
+                    };
+                    if !ok {
+                        // Nothing reached storage, so neither do any dots the write took
+                        vv = before;
+                    } else if vv != before {
+                        *published.blocking_write() = vv.clone();
+                    }
+                    for reply in replies {
+                        reply();
+                    }
+                }
+            })
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+        Ok(Self { jobs, actor_id })
+    }
+
+    /// Run `write` on the writer thread, after every write queued before it
+    ///
+    /// It gets the storage and the version vector; any change it makes to the version
+    /// vector is published before the result comes back, or undone if it fails. The write
+    /// runs to the end even if the caller stops waiting for it.
+    pub(crate) async fn run<T, F>(&self, write: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S, &mut VersionVector) -> Result<T> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let parent = Span::current();
+        self.send(Job::Run(Box::new(move |storage, vv| {
+            let result = info_span!(parent: &parent, "storage").in_scope(|| write(storage, vv));
+            let ok = result.is_ok();
+            let reply: Reply = Box::new(move || {
+                let _ = reply.send(result);
+            });
+            (reply, ok)
+        })))
+        .await?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    /// Take the next local dot and write with it, on the writer thread
+    pub(crate) async fn local<T, F>(&self, write: F) -> Result<LocalWrite<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S, Dot) -> Result<T> + Send + 'static,
+    {
+        let actor_id = self.actor_id;
+        self.run(move |storage, vv| {
+            // Taken with the write, so the context covers every dot the storage call can observe
+            let context = Arc::new(vv.clone());
+            let dot = vv.increment(actor_id);
+            let result = write(storage, dot)?;
+            Ok(LocalWrite {
+                context,
+                dot,
+                result,
+                vv: Arc::new(vv.clone()),
+            })
+        })
+        .await
+    }
+
+    /// A local add or remove with the next local dot, committed along with any others
+    /// queued with it; the result is the dots `Storage::add_elements` or
+    /// `Storage::remove_elements` would return
+    pub(crate) async fn grouped(
+        &self,
+        kind: WriteKind,
+        set_name: String,
+        elements: Vec<Bytes>,
+    ) -> Result<LocalWrite<Vec<Dot>>> {
+        let (reply, result) = oneshot::channel();
+        self.send(Job::Grouped(Grouped {
+            kind,
+            set_name,
+            elements,
+            reply,
+            span: Span::current(),
+        }))
+        .await?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    async fn send(&self, job: Job<S>) -> Result<()> {
+        self.jobs.send(job).await.map_err(|_| stopped())
+    }
+}
+
+/// Give each write of the group the next dot, then commit them all at once, returning
+/// the replies and whether the commit succeeded
+///
+/// Each write's context is the version vector just before its own dot, so it covers the
+/// earlier writes of the group, whose dots it may have replaced.
+fn commit_group<S: Storage>(
+    storage: &S,
+    actor_id: ActorId,
+    vv: &mut VersionVector,
+    group: Vec<Grouped>,
+) -> (Vec<Reply>, bool) {
+    let mut contexts = Vec::with_capacity(group.len());
+    let mut dots = Vec::with_capacity(group.len());
+    for _ in &group {
+        contexts.push(Arc::new(vv.clone()));
+        dots.push(vv.increment(actor_id));
+    }
+
+    let writes: Vec<GroupWrite> = group
+        .iter()
+        .zip(&dots)
+        .map(|(write, &dot)| GroupWrite {
+            kind: write.kind,
+            set_name: &write.set_name,
+            elements: &write.elements,
+            dot,
+        })
+        .collect();
+    // One commit for the whole group: a child of the first write's span, linked to the rest
+    let span = info_span!(parent: &group[0].span, "storage", writes = group.len());
+    for write in &group[1..] {
+        span.follows_from(&write.span);
+    }
+    let results = span.in_scope(|| storage.commit_group(&writes));
+    drop(writes);
+
+    match results {
+        Ok(results) => {
+            // One copy of the version vector after the group, for all of it
+            let after = Arc::new(vv.clone());
+            let replies = group
+                .into_iter()
+                .zip(contexts.into_iter().zip(dots))
+                .zip(results)
+                .map(|((write, (context, dot)), result)| {
+                    let local = LocalWrite {
+                        context,
+                        dot,
+                        result,
+                        vv: Arc::clone(&after),
+                    };
+                    Box::new(move || {
+                        let _ = write.reply.send(Ok(local));
+                    }) as Reply
+                })
+                .collect();
+            (replies, true)
+        }
+        Err(e) => {
+            let msg = e.to_string();
+            let replies = group
+                .into_iter()
+                .map(|write| {
+                    let e = rusqlite::Error::ToSqlConversionFailure(msg.clone().into());
+                    Box::new(move || {
+                        let _ = write.reply.send(Err(e));
+                    }) as Reply
+                })
+                .collect();
+            (replies, false)
+        }
+    }
+}
+
+// Not derived, that would needlessly require the storage itself to be Clone
+impl<S: Storage> Clone for Writer<S> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+            actor_id: self.actor_id,
+        }
+    }
+}
+
+impl<S: Storage> std::fmt::Debug for Writer<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Writer")
+            .field("actor_id", &self.actor_id)
+            .field("queued", &(QUEUE_LEN - self.jobs.capacity()))
+            .finish()
+    }
+}
+
+fn stopped() -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure("the writer thread has stopped".into())
+}
@@ -0,0 +1,1151 @@
+use bigsets::config::{
+    ClusterConfig, Compression, Config, ReplicaInfo, ReplicationConfig, ReplicationMode,
+    ServerConfig, StorageConfig,
+};
+use bigsets::{ApiServer, NodeBuilder, ServerWrapper};
+use rustls_pki_types::{CertificateDer, ServerName};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_util::sync::CancellationToken;
+
+/// Reserve an ephemeral port on localhost for the API listener
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+async fn start_wrapper(temp: &TempDir) -> Arc<ServerWrapper> {
+    let replication_addr = free_addr();
+    let config = Config {
+        server: ServerConfig {
+            node_id: 1,
+            epoch: 0,
+            api_addr: free_addr(),
+            replication_addr: replication_addr.clone(),
+            db_path: temp.path().join("node.db"),
+            slowlog_threshold_ms: 0,
+            slowlog_max_len: 128,
+            log_level: "info".to_string(),
+            tls: None,
+            http_addr: None,
+            read_buffer_size: 4096,
+            max_request_size: 64 * 1024 * 1024,
+            max_connections: 10_000,
+        },
+        cluster: ClusterConfig {
+            replicas: vec![ReplicaInfo {
+                node_id: 1,
+                epoch: 0,
+                addr: replication_addr,
+            }],
+        },
+        replication: ReplicationConfig {
+            max_retries: 5,
+            retry_backoff_ms: 10,
+            buffer_size: 100,
+            ack_timeout_ms: 50,
+            rbilt_startup_delay_ms: 60_000,
+            max_batch: 1,
+            flush_interval_ms: 10,
+            digest_buckets: 16,
+            heartbeat_interval_ms: 1000,
+            heartbeat_miss_limit: 3,
+            sent_log_size: 1000,
+            gc_interval_ms: 60_000,
+            snapshot_lag_threshold: 10_000,
+            mode: ReplicationMode::Op,
+            delta_interval_ms: 100,
+            sync: false,
+            write_quorum: 2,
+            fanout: 0,
+            sync_timeout_ms: 1000,
+            subscriptions: Vec::new(),
+            compression: Compression::None,
+            packed_vv: false,
+            tls: None,
+        },
+        storage: StorageConfig {
+            sqlite_cache_size: 1000,
+            sqlite_busy_timeout: 5000,
+            read_pool_size: 5,
+            pool_min_idle: 1,
+            checkpoint_interval_ms: 0,
+            bloom_min_elements: 0,
+            bloom_fp_rate: 0.01,
+            max_key_length: 1024,
+            max_element_size: 65536,
+            max_members_per_command: 100_000,
+            expiry_interval_ms: 1000,
+            op_log: false,
+            mmap_size: 0,
+            page_size: 0,
+            encryption: None,
+        },
+    };
+    let node = NodeBuilder::new(config).build().await.unwrap();
+    Arc::clone(node.wrapper())
+}
+
+async fn wait_for_listener(addr: &str) {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("listener on {} never came up", addr);
+}
+
+/// Send one command and read until its whole reply, which must end with `ends_with`, is in
+async fn command(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    request: &[u8],
+    ends_with: &[u8],
+) -> Vec<u8> {
+    stream.write_all(request).await.unwrap();
+    let mut reply = Vec::new();
+    while !reply.ends_with(ends_with) {
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "connection closed after {:?}", reply);
+        reply.extend_from_slice(&buf[..n]);
+    }
+    reply
+}
+
+#[tokio::test]
+async fn test_api_over_tls() {
+    let temp = TempDir::new().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_path = temp.path().join("cert.pem");
+    let key_path = temp.path().join("key.pem");
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone())
+        .with_tls(bigsets::tls::acceptor(&cert_path, &key_path).unwrap());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from(certified.cert.der().to_vec()))
+        .unwrap();
+    let connector = TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ));
+    let tcp = TcpStream::connect(&addr).await.unwrap();
+    let mut tls = connector
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        command(&mut tls, b"*1\r\n$4\r\nPING\r\n", b"\r\n").await,
+        b"+PONG\r\n"
+    );
+    assert!(
+        command(
+            &mut tls,
+            b"*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n",
+            b"\r\n"
+        )
+        .await
+        .starts_with(b"+OK")
+    );
+    // Streamed straight to the connection rather than sent as one response
+    assert_eq!(
+        command(
+            &mut tls,
+            b"*2\r\n$8\r\nSMEMBERS\r\n$1\r\ns\r\n",
+            b"$1\r\na\r\n"
+        )
+        .await,
+        b"*1\r\n$1\r\na\r\n"
+    );
+
+    // A plaintext client gets no answer, the connection is dropped at the handshake
+    let mut plain = TcpStream::connect(&addr).await.unwrap();
+    plain.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut reply = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), plain.read_to_end(&mut reply))
+        .await
+        .unwrap()
+        .ok();
+    assert!(!reply.starts_with(b"+PONG"), "{:?}", reply);
+}
+
+#[tokio::test]
+async fn test_hello_negotiates_resp3() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    // Every command is slow, to see a null in SLOWLOG GET
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone())
+        .with_slowlog(Duration::from_nanos(1), 10);
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+
+    assert_eq!(
+        command(&mut client, b"*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n", b"\r\n").await,
+        b"-NOPROTO unsupported protocol version\r\n"
+    );
+    // Still RESP2, so the map comes as a flat array
+    assert!(
+        command(&mut client, b"*1\r\n$5\r\nHELLO\r\n", b":2\r\n")
+            .await
+            .starts_with(b"*6\r\n$6\r\nserver\r\n")
+    );
+    assert!(
+        command(&mut client, b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n", b":3\r\n")
+            .await
+            .starts_with(b"%3\r\n$6\r\nserver\r\n")
+    );
+    // PING has no key, which the slow log now sends as a RESP3 null
+    command(&mut client, b"*1\r\n$4\r\nPING\r\n", b"\r\n").await;
+    command(
+        &mut client,
+        b"*3\r\n$7\r\nSLOWLOG\r\n$3\r\nGET\r\n$1\r\n1\r\n",
+        b"$4\r\nPING\r\n_\r\n:0\r\n",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_config_get_and_set() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let get = b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$26\r\nreplication.ack_timeout_ms\r\n";
+
+    assert_eq!(
+        command(&mut client, get, b"\r\n50\r\n").await,
+        b"*2\r\n$26\r\nreplication.ack_timeout_ms\r\n$2\r\n50\r\n"
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*4\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$26\r\nreplication.ack_timeout_ms\r\n$2\r\n75\r\n",
+            b"\r\n",
+        )
+        .await,
+        b"+OK\r\n"
+    );
+    assert!(
+        command(&mut client, get, b"\r\n")
+            .await
+            .ends_with(b"$2\r\n75\r\n")
+    );
+
+    // Patterns match settings that need a restart too, which can't be set
+    assert!(
+        command(
+            &mut client,
+            b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$8\r\nserver.*\r\n",
+            b"$5\r\n10000\r\n",
+        )
+        .await
+        .starts_with(b"*24\r\n$16\r\nserver.log_level\r\n$4\r\ninfo\r\n")
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*4\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$14\r\nserver.node_id\r\n$1\r\n2\r\n",
+            b"\r\n",
+        )
+        .await,
+        b"-ERR CONFIG SET failed: server.node_id: can't be changed while running, only in the config file (with a restart)\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_set_names_are_utf8_and_limited_in_length() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let sadd = |key: &[u8]| {
+        let mut request = format!("*3\r\n$4\r\nSADD\r\n${}\r\n", key.len()).into_bytes();
+        request.extend_from_slice(key);
+        request.extend_from_slice(b"\r\n$1\r\na\r\n");
+        request
+    };
+
+    assert_eq!(
+        command(&mut client, &sadd(b"caf\xe9"), b"\r\n").await,
+        b"-ERR set names must be UTF-8\r\n"
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*2\r\n$8\r\nSMEMBERS\r\n$4\r\ncaf\xe9\r\n",
+            b"\r\n"
+        )
+        .await,
+        b"-ERR set names must be UTF-8\r\n"
+    );
+
+    // The config's max_key_length is the default, 1024 bytes
+    assert!(
+        command(&mut client, &sadd(&[b'k'; 1024]), b"\r\n")
+            .await
+            .starts_with(b"+OK")
+    );
+    assert_eq!(
+        command(&mut client, &sadd(&[b'k'; 1025]), b"\r\n").await,
+        b"-ERR set name is longer than max_key_length (1024 bytes)\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_command_table_checks_arity() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+
+    for (request, error) in [
+        (&b"*2\r\n$4\r\nsadd\r\n$1\r\ns\r\n"[..], "sadd"),
+        (
+            b"*4\r\n$5\r\nSCARD\r\n$1\r\ns\r\n$1\r\na\r\n$1\r\nb\r\n",
+            "scard",
+        ),
+        (b"*1\r\n$4\r\nSAVE\r\n", "save"),
+        (b"*1\r\n$7\r\nCLUSTER\r\n", "cluster"),
+    ] {
+        assert_eq!(
+            command(&mut client, request, b"\r\n").await,
+            format!("-ERR wrong number of arguments for '{}' command\r\n", error).as_bytes()
+        );
+    }
+    assert_eq!(
+        command(&mut client, b"*1\r\n$8\r\nFLUSHALL\r\n", b"\r\n").await,
+        b"-ERR unknown command 'FLUSHALL'\r\n"
+    );
+
+    assert_eq!(
+        command(
+            &mut client,
+            b"*2\r\n$7\r\nCOMMAND\r\n$5\r\nCOUNT\r\n",
+            b"\r\n"
+        )
+        .await,
+        format!(":{}\r\n", bigsets::commands::COMMANDS.len()).as_bytes()
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*4\r\n$7\r\nCOMMAND\r\n$4\r\nINFO\r\n$4\r\nsadd\r\n$4\r\nnope\r\n",
+            b"$-1\r\n"
+        )
+        .await,
+        b"*2\r\n*6\r\n$4\r\nsadd\r\n:-3\r\n*2\r\n+write\r\n+fast\r\n:1\r\n:1\r\n:1\r\n$-1\r\n"
+    );
+    assert_eq!(
+        command(&mut client, b"*2\r\n$4\r\nPING\r\n$2\r\nhi\r\n", b"\r\n").await,
+        b"$2\r\nhi\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_vv_and_svv_return_causal_contexts() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let bulk = |s: &str| format!("${}\r\n{}\r\n", s.len(), s).into_bytes();
+    let vv = b"*1\r\n$2\r\nVV\r\n";
+    let svv = |key: &str| format!("*2\r\n$3\r\nSVV\r\n${}\r\n{}\r\n", key.len(), key);
+
+    assert_eq!(command(&mut client, vv, b"\r\n\r\n").await, bulk(""));
+    let added = command(
+        &mut client,
+        b"*3\r\n$4\r\nSADD\r\n$1\r\na\r\n$1\r\nx\r\n",
+        b"\r\n",
+    )
+    .await;
+    let added = String::from_utf8(added).unwrap();
+    let first = added
+        .trim_end()
+        .strip_prefix("+OK vv:")
+        .unwrap()
+        .to_string();
+    command(
+        &mut client,
+        b"*3\r\n$4\r\nSADD\r\n$1\r\nb\r\n$1\r\ny\r\n",
+        b"\r\n",
+    )
+    .await;
+
+    // The node's has both writes, each set's only the add behind its element
+    let node_vv = command(&mut client, vv, b"\r\n").await;
+    let node_vv = String::from_utf8(node_vv).unwrap();
+    assert_ne!(node_vv.as_bytes(), bulk(&first));
+    assert_eq!(
+        command(&mut client, svv("a").as_bytes(), b"\r\n").await,
+        bulk(&first)
+    );
+    assert_eq!(
+        command(&mut client, svv("missing").as_bytes(), b"\r\n\r\n").await,
+        bulk("")
+    );
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_connections_between_commands() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let shutdown = CancellationToken::new();
+    let api =
+        ApiServer::new(start_wrapper(&temp).await, addr.clone()).with_shutdown(shutdown.clone());
+    let running = tokio::spawn(async move { api.run().await.is_ok() });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    command(
+        &mut client,
+        b"*3\r\n$4\r\nSADD\r\n$5\r\nmyset\r\n$1\r\na\r\n",
+        b"\r\n",
+    )
+    .await;
+
+    shutdown.cancel();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+    assert!(
+        stopped.unwrap().unwrap(),
+        "run should return Ok once drained"
+    );
+
+    // The idle connection was closed, and nothing new is accepted
+    let mut buf = [0; 16];
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    assert!(TcpStream::connect(&addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_pipelined_commands_are_answered_in_order() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+
+    // Sent in one write, the last command split across two
+    let batch = b"*4\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n$1\r\nb\r\n\
+                  *2\r\n$5\r\nSCARD\r\n$1\r\ns\r\n\
+                  *2\r\n$8\r\nSMEMBERS\r\n$1\r\ns\r\n\
+                  *1\r\n$4\r\nPI";
+    client.write_all(batch).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let reply = String::from_utf8(command(&mut client, b"NG\r\n", b"+PONG\r\n").await).unwrap();
+    let (sadd, rest) = reply.split_once("\r\n").unwrap();
+    assert!(sadd.starts_with("+OK"), "{:?}", reply);
+    assert!(
+        rest == ":2\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n+PONG\r\n"
+            || rest == ":2\r\n*2\r\n$1\r\nb\r\n$1\r\na\r\n+PONG\r\n",
+        "{:?}",
+        reply
+    );
+}
+
+#[tokio::test]
+async fn test_oversized_requests_close_the_connection() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api =
+        ApiServer::new(start_wrapper(&temp).await, addr.clone()).with_request_limits(16, 1024);
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+
+    // Read 16 bytes at a time, a request near the limit still gets through
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let member = "m".repeat(900);
+    let sadd = format!(
+        "*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n${}\r\n{}\r\n",
+        member.len(),
+        member
+    );
+    assert!(
+        command(&mut client, sadd.as_bytes(), b"\r\n")
+            .await
+            .starts_with(b"+OK")
+    );
+
+    // One that is still coming past the limit is refused before it is all in
+    let member = "m".repeat(4000);
+    let sadd = format!(
+        "*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n${}\r\n{}",
+        member.len(),
+        &member[..2000]
+    );
+    assert_eq!(
+        command(&mut client, sadd.as_bytes(), b"\r\n").await,
+        b"-ERR Protocol error: request larger than 1024 bytes\r\n"
+    );
+    let mut buf = [0; 16];
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_connections_over_the_limit_are_refused() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone()).with_max_connections(2);
+    let clients = Arc::clone(api.clients());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let ping = b"*1\r\n$4\r\nPING\r\n";
+    let info = b"*2\r\n$4\r\nINFO\r\n$7\r\nclients\r\n";
+
+    let mut first = TcpStream::connect(&addr).await.unwrap();
+    let mut second = TcpStream::connect(&addr).await.unwrap();
+    assert_eq!(command(&mut first, ping, b"\r\n").await, b"+PONG\r\n");
+    assert_eq!(command(&mut second, ping, b"\r\n").await, b"+PONG\r\n");
+    assert!(
+        String::from_utf8(command(&mut first, info, b"maxclients:2\r\n\r\n").await)
+            .unwrap()
+            .contains("connected_clients:2\r\n")
+    );
+
+    // The third is told why before it is closed
+    let mut third = TcpStream::connect(&addr).await.unwrap();
+    let mut reply = Vec::new();
+    third.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, b"-ERR max number of clients reached\r\n");
+
+    // Closing one makes room again
+    drop(first);
+    for _ in 0..100 {
+        if clients.open() < 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(clients.open(), 1);
+    let mut fourth = TcpStream::connect(&addr).await.unwrap();
+    assert_eq!(command(&mut fourth, ping, b"\r\n").await, b"+PONG\r\n");
+    assert_eq!(command(&mut second, ping, b"\r\n").await, b"+PONG\r\n");
+}
+
+/// Send one HTTP/1.1 request and read the response, returning its status, headers
+/// (lower cased) and body, de-chunked
+#[cfg(feature = "http")]
+async fn http(
+    addr: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> (u16, Vec<(String, String)>, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nhost: {}\r\nconnection: close\r\ncontent-type: application/json\r\ncontent-length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, mut body) = response.split_once("\r\n\r\n").unwrap();
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .unwrap()
+        .split(' ')
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+    let headers: Vec<(String, String)> = lines
+        .map(|line| {
+            let (name, value) = line.split_once(':').unwrap();
+            (name.to_lowercase(), value.trim().to_string())
+        })
+        .collect();
+
+    let mut dechunked = String::new();
+    if headers.contains(&("transfer-encoding".to_string(), "chunked".to_string())) {
+        loop {
+            let (len, rest) = body.split_once("\r\n").unwrap();
+            let len = usize::from_str_radix(len, 16).unwrap();
+            if len == 0 {
+                break;
+            }
+            dechunked.push_str(&rest[..len]);
+            body = &rest[len + 2..];
+        }
+    } else {
+        dechunked.push_str(body);
+    }
+    (status, headers, dechunked)
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn test_http_api() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let http_server = bigsets::HttpServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(http_server.run());
+    wait_for_listener(&addr).await;
+    let header = |headers: &[(String, String)], name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let (status, headers, _) = http(
+        &addr,
+        "POST",
+        "/sets/s/members",
+        &[],
+        r#"{"members": ["a", "b", "c"]}"#,
+    )
+    .await;
+    assert_eq!(status, 204);
+    let vv = header(&headers, "x-bigsets-vv").unwrap();
+
+    let (status, _, body) = http(&addr, "DELETE", "/sets/s/members/c", &[], "").await;
+    assert_eq!((status, body.as_str()), (204, ""));
+
+    assert_eq!(
+        http(&addr, "GET", "/sets/s", &[], "").await.2,
+        r#"{"cardinality":2}"#
+    );
+    let (status, headers, body) = http(&addr, "GET", "/sets/s/members", &[], "").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        header(&headers, "content-type").as_deref(),
+        Some("application/json")
+    );
+    let mut members: Vec<String> =
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["members"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|member| member.as_str().unwrap().to_string())
+            .collect();
+    members.sort();
+    assert_eq!(members, ["a", "b"]);
+
+    // A causal read, as of the first write
+    assert_eq!(
+        http(
+            &addr,
+            "GET",
+            "/sets/s/members/a",
+            &[("x-bigsets-vv", &vv)],
+            ""
+        )
+        .await
+        .2,
+        r#"{"member":true}"#
+    );
+    assert_eq!(
+        http(
+            &addr,
+            "POST",
+            "/sets/s/contains",
+            &[],
+            r#"{"members": ["a", "c"]}"#
+        )
+        .await
+        .2,
+        r#"{"members":[true,false]}"#
+    );
+    assert_eq!(
+        http(&addr, "GET", "/sets/empty/members", &[], "").await.2,
+        r#"{"members":[]}"#
+    );
+
+    // Not ready for a read as of a write the server hasn't seen
+    let (status, headers, body) = http(
+        &addr,
+        "GET",
+        "/sets/s/members",
+        &[("x-bigsets-vv", "v0:2:0:1")],
+        "",
+    )
+    .await;
+    assert_eq!((status, body.as_str()), (503, r#"{"error":"NOTREADY"}"#));
+    assert!(header(&headers, "x-bigsets-vv").is_some());
+
+    let (status, _, body) = http(&addr, "GET", "/sets/s", &[("x-bigsets-vv", "nope")], "").await;
+    assert_eq!(
+        (status, body.as_str()),
+        (400, r#"{"error":"invalid version vector"}"#)
+    );
+    let (status, _, body) = http(&addr, "POST", "/sets/s/members", &[], r#"{"members": []}"#).await;
+    assert_eq!(
+        (status, body.as_str()),
+        (400, r#"{"error":"no members given"}"#)
+    );
+}
+
+#[tokio::test]
+async fn test_replication_reports_each_peer() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let replication = b"*1\r\n$11\r\nREPLICATION\r\n";
+
+    // Nothing listens on the new peer's address, so nothing is ever sent to it
+    let peer = free_addr();
+    let meet = format!(
+        "*4\r\n$7\r\nCLUSTER\r\n$4\r\nMEET\r\n${}\r\n{}\r\n$1\r\n2\r\n",
+        peer.len(),
+        peer
+    );
+    assert_eq!(
+        command(&mut client, meet.as_bytes(), b"\r\n").await,
+        b"+OK\r\n"
+    );
+
+    let text = String::from_utf8(command(&mut client, replication, b"vv=\r\n\r\n").await).unwrap();
+    let (head, fields) = text.split_once("\r\n").unwrap();
+    assert_eq!(head, format!("${}", fields.len() - 2));
+    assert!(
+        fields.starts_with(&format!("v0:2:0:node_id=2,epoch=0,addr={},status=", peer)),
+        "{}",
+        text
+    );
+    assert!(
+        fields.ends_with(",last_seen_ms=-1,last_sent_ms=-1,unacked=0,lag=-1,vv=\r\n\r\n"),
+        "{}",
+        text
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*2\r\n$7\r\nCLUSTER\r\n$4\r\nINFO\r\n",
+            b"vv=\r\n\r\n"
+        )
+        .await,
+        text.as_bytes()
+    );
+
+    command(&mut client, b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n", b":3\r\n").await;
+    let reply = command(&mut client, replication, b"$2\r\nvv\r\n$0\r\n\r\n").await;
+    let reply = String::from_utf8(reply).unwrap();
+    assert!(
+        reply.starts_with("%1\r\n$6\r\nv0:2:0\r\n%9\r\n"),
+        "{}",
+        reply
+    );
+    assert!(
+        reply.contains("$12\r\nlast_sent_ms\r\n$2\r\n-1\r\n"),
+        "{}",
+        reply
+    );
+}
+
+#[tokio::test]
+async fn test_writes_wait_for_their_context() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let write = |cmd: &str, member: &str, context: &str| {
+        format!(
+            "*5\r\n${}\r\n{}\r\n$1\r\ns\r\n${}\r\n{}\r\n$7\r\nCONTEXT\r\n${}\r\n{}\r\n",
+            cmd.len(),
+            cmd,
+            member.len(),
+            member,
+            context.len(),
+            context
+        )
+    };
+
+    // A context from a node we haven't heard from: the write waits for it
+    let reply = command(
+        &mut client,
+        write("SADD", "x", "vv:v0:2:0:5").as_bytes(),
+        b"\r\n",
+    )
+    .await;
+    assert_eq!(reply, b"-NOTREADY vv:\r\n");
+    let reply = command(
+        &mut client,
+        write("SREM", "x", "vv:v0:2:0:5").as_bytes(),
+        b"\r\n",
+    )
+    .await;
+    assert_eq!(reply, b"-NOTREADY vv:\r\n");
+    assert_eq!(
+        command(&mut client, b"*2\r\n$5\r\nSCARD\r\n$1\r\ns\r\n", b"\r\n").await,
+        b":0\r\n"
+    );
+
+    // One we have seen is fine, and CONTEXT after no members is a member
+    let added = command(&mut client, write("SADD", "x", "vv:").as_bytes(), b"\r\n").await;
+    let added = String::from_utf8(added).unwrap();
+    let context = added.trim_end().strip_prefix("+OK ").unwrap();
+    let reply = command(&mut client, write("SREM", "x", context).as_bytes(), b"\r\n").await;
+    assert!(reply.starts_with(b"+OK vv:"));
+    command(
+        &mut client,
+        b"*4\r\n$4\r\nSADD\r\n$1\r\ns\r\n$7\r\nCONTEXT\r\n$3\r\nvv:\r\n",
+        b"\r\n",
+    )
+    .await;
+    assert_eq!(
+        command(&mut client, b"*2\r\n$5\r\nSCARD\r\n$1\r\ns\r\n", b"\r\n").await,
+        b":2\r\n"
+    );
+
+    let reply = command(
+        &mut client,
+        write("SADD", "x", "v0:2:0:5").as_bytes(),
+        b"\r\n",
+    )
+    .await;
+    assert_eq!(reply, b"-ERR invalid CONTEXT version vector\r\n");
+}
+
+#[tokio::test]
+async fn test_sintercard_counts_up_to_the_limit() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let resp = |parts: &[&str]| {
+        let mut request = format!("*{}\r\n", parts.len());
+        for part in parts {
+            request.push_str(&format!("${}\r\n{}\r\n", part.len(), part));
+        }
+        request.into_bytes()
+    };
+
+    command(&mut client, &resp(&["SADD", "a", "x", "y", "z"]), b"\r\n").await;
+    command(&mut client, &resp(&["SADD", "b", "x", "y", "w"]), b"\r\n").await;
+
+    for (request, reply) in [
+        (resp(&["SINTERCARD", "2", "a", "b"]), &b":2\r\n"[..]),
+        (
+            resp(&["SINTERCARD", "2", "a", "b", "LIMIT", "1"]),
+            b":1\r\n",
+        ),
+        (
+            resp(&["SINTERCARD", "2", "a", "b", "LIMIT", "0"]),
+            b":2\r\n",
+        ),
+        (
+            resp(&["SINTERCARD", "1", "a", "limit", "5", "vv:"]),
+            b":3\r\n",
+        ),
+        (resp(&["SINTERCARD", "2", "a", "missing"]), b":0\r\n"),
+        (
+            resp(&["SINTERCARD", "0", "a"]),
+            b"-ERR numkeys should be greater than 0\r\n",
+        ),
+        (
+            resp(&["SINTERCARD", "3", "a", "b"]),
+            b"-ERR Number of keys can't be greater than number of args\r\n",
+        ),
+        (
+            resp(&["SINTERCARD", "2", "a", "b", "LIMIT", "-1"]),
+            b"-ERR LIMIT can't be negative\r\n",
+        ),
+        (
+            resp(&["SINTERCARD", "1", "a", "b"]),
+            b"-ERR syntax error\r\n",
+        ),
+    ] {
+        assert_eq!(command(&mut client, &request, b"\r\n").await, reply);
+    }
+}
+
+#[tokio::test]
+async fn test_smembers_match_filters_members() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+
+    command(
+        &mut client,
+        b"*5\r\n$4\r\nSADD\r\n$1\r\ns\r\n$5\r\napple\r\n$7\r\napricot\r\n$6\r\nbanana\r\n",
+        b"\r\n",
+    )
+    .await;
+    assert_eq!(
+        command(
+            &mut client,
+            b"*4\r\n$8\r\nSMEMBERS\r\n$1\r\ns\r\n$5\r\nmatch\r\n$3\r\nap*\r\n",
+            b"apricot\r\n",
+        )
+        .await,
+        b"*2\r\n$5\r\napple\r\n$7\r\napricot\r\n"
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*5\r\n$8\r\nSMEMBERS\r\n$1\r\ns\r\n$5\r\nMATCH\r\n$5\r\n[^a]*\r\n$3\r\nvv:\r\n",
+            b"banana\r\n",
+        )
+        .await,
+        b"*1\r\n$6\r\nbanana\r\n"
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*3\r\n$8\r\nSMEMBERS\r\n$1\r\ns\r\n$2\r\nap\r\n",
+            b"\r\n",
+        )
+        .await,
+        b"-ERR syntax error\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_srandmember_counts() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let srandmember = |key: &str, count: &str| {
+        format!(
+            "*3\r\n$11\r\nSRANDMEMBER\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+            key.len(),
+            key,
+            count.len(),
+            count
+        )
+        .into_bytes()
+    };
+    // The array's length, and its members, which are one letter each ("$1\r\nx\r\n")
+    let members = |reply: Vec<u8>| -> (Vec<u8>, Vec<u8>) {
+        let header = reply.iter().position(|&b| b == b'\n').unwrap() + 1;
+        let members = reply[header..].chunks(7).map(|m| m[4]).collect();
+        (reply[..header].to_vec(), members)
+    };
+
+    command(
+        &mut client,
+        b"*5\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+        b"\r\n",
+    )
+    .await;
+
+    // Asking for more than the set has: each member once for a positive count, exactly
+    // that many for a negative one
+    let reply = command(&mut client, &srandmember("s", "5"), b"\r\n").await;
+    let (len, mut picked) = members(reply);
+    picked.sort();
+    assert_eq!(
+        (len.as_slice(), picked.as_slice()),
+        (&b"*3\r\n"[..], &b"abc"[..])
+    );
+
+    let reply = command(&mut client, &srandmember("s", "-7"), b"\r\n").await;
+    let (len, picked) = members(reply);
+    assert_eq!(len, b"*7\r\n");
+    assert_eq!(picked.len(), 7);
+    assert!(picked.iter().all(|m| b"abc".contains(m)));
+
+    let reply = command(&mut client, &srandmember("missing", "-3"), b"\r\n").await;
+    assert_eq!(reply, b"*0\r\n");
+    assert_eq!(
+        command(
+            &mut client,
+            b"*2\r\n$11\r\nSRANDMEMBER\r\n$7\r\nmissing\r\n",
+            b"\r\n"
+        )
+        .await,
+        b"$-1\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_sdots_and_sactors_list_dots_and_actors() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+
+    command(
+        &mut client,
+        b"*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\nx\r\n",
+        b"\r\n",
+    )
+    .await;
+    assert_eq!(
+        command(
+            &mut client,
+            b"*3\r\n$5\r\nSDOTS\r\n$1\r\ns\r\n$1\r\nx\r\n",
+            b"\r\n"
+        )
+        .await,
+        b"*1\r\n$8\r\nv0:1:0:1\r\n"
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*3\r\n$5\r\nSDOTS\r\n$1\r\ns\r\n$1\r\ny\r\n",
+            b"\r\n"
+        )
+        .await,
+        b"*0\r\n"
+    );
+    assert_eq!(
+        command(&mut client, b"*2\r\n$7\r\nSACTORS\r\n$1\r\ns\r\n", b"\r\n").await,
+        b"*1\r\n$6\r\nv0:1:0\r\n"
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*3\r\n$7\r\nSACTORS\r\n$1\r\ns\r\n$11\r\nvv:v0:1:0:1\r\n",
+            b"\r\n"
+        )
+        .await,
+        b"*1\r\n$6\r\nv0:1:0\r\n"
+    );
+    assert_eq!(
+        command(&mut client, b"*2\r\n$7\r\nSACTORS\r\n$1\r\nt\r\n", b"\r\n").await,
+        b"*0\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_subscribe_sets_and_lists_the_replicated_sets() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+
+    let list = b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nLIST\r\n";
+    assert_eq!(command(&mut client, list, b"\r\n").await, b"*0\r\n");
+    assert_eq!(
+        command(
+            &mut client,
+            b"*4\r\n$9\r\nSUBSCRIBE\r\n$3\r\nSET\r\n$6\r\nuser:*\r\n$6\r\nteam:?\r\n",
+            b"\r\n"
+        )
+        .await,
+        b"+OK\r\n"
+    );
+    assert_eq!(
+        command(&mut client, list, b"team:?\r\n").await,
+        b"*2\r\n$6\r\nuser:*\r\n$6\r\nteam:?\r\n"
+    );
+    assert_eq!(
+        command(
+            &mut client,
+            b"*2\r\n$9\r\nSUBSCRIBE\r\n$3\r\nSET\r\n",
+            b"\r\n"
+        )
+        .await,
+        b"-ERR wrong number of arguments for 'subscribe|set' command\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_checkpoint_truncates_the_wal() {
+    let temp = TempDir::new().unwrap();
+    let addr = free_addr();
+    let api = ApiServer::new(start_wrapper(&temp).await, addr.clone());
+    tokio::spawn(async move {
+        let _ = api.run().await;
+    });
+    wait_for_listener(&addr).await;
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+
+    let sadd = b"*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n";
+    assert!(
+        command(&mut client, sadd, b"\r\n")
+            .await
+            .starts_with(b"+OK")
+    );
+
+    let checkpoint = b"*1\r\n$10\r\nCHECKPOINT\r\n";
+    let reply = command(&mut client, checkpoint, b":1\r\n").await;
+    let reply = String::from_utf8(reply).unwrap();
+    let frames: u64 = reply
+        .strip_prefix("*2\r\n:")
+        .and_then(|rest| rest.strip_suffix("\r\n:1\r\n"))
+        .unwrap_or_else(|| panic!("unexpected reply {:?}", reply))
+        .parse()
+        .unwrap();
+    assert!(frames > 0);
+    let wal = temp.path().join("node.db-wal");
+    assert_eq!(std::fs::metadata(wal).unwrap().len(), 0);
+
+    assert_eq!(
+        command(&mut client, checkpoint, b":1\r\n").await,
+        b"*2\r\n:0\r\n:1\r\n"
+    );
+}
@@ -1,5 +1,8 @@
 use bigsets::config::StorageConfig;
-use bigsets::{ActorId, Operation, PendingBuffer, Server, SqliteStorage};
+use bigsets::{
+    ActorId, MemoryStorage, Operation, PendingBuffer, Server, ShardedStorage, SqliteStorage,
+    Storage,
+};
 use bytes::Bytes;
 use proptest::string::bytes_regex;
 use proptest::test_runner::Config;
@@ -105,6 +108,62 @@ prop_state_machine! {
     );
 }
 
+/// The same checks against the in-memory backend
+type MemoryCluster = Cluster<BigsetNode<MemoryStorage>>;
+
+prop_state_machine! {
+    #![proptest_config(Config {
+        verbose: 1,
+        .. Config::default()
+    })]
+
+    #[test]
+    fn bigsets_stateful_memory(
+        sequential
+        10..100
+        =>
+        MemoryCluster
+    );
+}
+
+/// The same checks against SQLite sharded over several files
+type ShardedCluster = Cluster<BigsetNode<ShardedStorage>>;
+
+prop_state_machine! {
+    #![proptest_config(Config {
+        verbose: 1,
+        .. Config::default()
+    })]
+
+    #[test]
+    fn bigsets_stateful_sharded(
+        sequential
+        10..100
+        =>
+        ShardedCluster
+    );
+}
+
+/// And against RocksDB, when built with it
+#[cfg(feature = "rocksdb")]
+type RocksdbCluster = Cluster<BigsetNode<bigsets::RocksdbStorage>>;
+
+#[cfg(feature = "rocksdb")]
+prop_state_machine! {
+    #![proptest_config(Config {
+        verbose: 1,
+        .. Config::default()
+    })]
+
+    #[test]
+    fn bigsets_stateful_rocksdb(
+        sequential
+        10..100
+        =>
+        RocksdbCluster
+    );
+}
+
 trait Node {
     type State;
 
@@ -159,32 +218,83 @@ impl Node for ModelNode {
 }
 const SET_NAME: &'static str = "testset";
 
+/// A storage backend the node under test can be built on
+trait Backend: Storage + Sized {
+    /// Fresh, empty storage, and the temp dir it lives in (if any)
+    fn create() -> (Arc<Self>, Option<TempDir>);
+}
+
+/// The storage config the SQLite backed stores are tested with
+fn sqlite_config() -> StorageConfig {
+    StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        // Every set gets a Bloom filter, so the model checks them too
+        bloom_min_elements: 1,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    }
+}
+
+impl Backend for SqliteStorage {
+    fn create() -> (Arc<Self>, Option<TempDir>) {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = Arc::new(SqliteStorage::open(&db_path, &sqlite_config()).unwrap());
+        (storage, Some(temp_dir))
+    }
+}
+
+impl Backend for ShardedStorage {
+    fn create() -> (Arc<Self>, Option<TempDir>) {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = ShardedStorage::open(temp_dir.path().join("shards"), 4, &sqlite_config());
+        (Arc::new(storage.unwrap()), Some(temp_dir))
+    }
+}
+
+impl Backend for MemoryStorage {
+    fn create() -> (Arc<Self>, Option<TempDir>) {
+        (Arc::new(MemoryStorage::new()), None)
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl Backend for bigsets::RocksdbStorage {
+    fn create() -> (Arc<Self>, Option<TempDir>) {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = bigsets::RocksdbStorage::open(temp_dir.path().join("rocksdb")).unwrap();
+        (Arc::new(storage), Some(temp_dir))
+    }
+}
+
 #[derive(Debug)]
-struct BigsetNode {
-    _temp_dir: TempDir,
+struct BigsetNode<S: Backend = SqliteStorage> {
+    _temp_dir: Option<TempDir>,
     actor_id: ActorId,
     out_buffer: Vec<Operation>,
     pending_buffer: PendingBuffer,
-    server: Arc<Server>,
+    server: Arc<Server<S>>,
     rt: tokio::runtime::Runtime,
 }
 
-impl Clone for BigsetNode {
+impl<S: Backend> Clone for BigsetNode<S> {
     fn clone(&self) -> Self {
         // Create a new runtime for the cloned instance
         let rt = tokio::runtime::Runtime::new().unwrap();
 
-        // For TempDir, we need to create a new one since it's not Clone
-        let temp_dir = TempDir::new().unwrap();
-
-        // Need to recreate the storage with the new temp directory
-        let db_path = temp_dir.path().join("test.db");
-        let config = StorageConfig {
-            sqlite_cache_size: 1000,
-            sqlite_busy_timeout: 5000,
-        };
-
-        let storage = Arc::new(SqliteStorage::open(&db_path, &config).unwrap());
+        // Storage (and its TempDir) is not Clone, so start again from empty storage
+        let (storage, temp_dir) = S::create();
 
         // Create a new server with the same actor_id but new storage
         let server =
@@ -201,7 +311,7 @@ impl Clone for BigsetNode {
     }
 }
 
-impl Node for BigsetNode {
+impl<S: Backend> Node for BigsetNode<S> {
     type State = Vec<Operation>; // Operations are the replication unit
 
     fn apply_op(&mut self, op: &SetOp, _time: u64) {
@@ -236,6 +346,14 @@ impl Node for BigsetNode {
             while progress.take().unwrap_or(false) {
                 for op in self.pending_buffer.drain() {
                     trace!("applying {:?} to {:?}", op, self.actor_id);
+                    // Only forward ops that are new to this node, otherwise every replicate
+                    // re-sends duplicates and the out buffer (and the peer's pending buffer) explodes
+                    let seen = self
+                        .server
+                        .version_vector()
+                        .read()
+                        .await
+                        .contains_dot(op.dot());
                     if self
                         .server
                         .apply_remote_operation(op.clone())
@@ -244,7 +362,9 @@ impl Node for BigsetNode {
                     {
                         trace!("applied {:?} to {:?}", op, self.actor_id);
                         // If we want to get closer to the model, we can send these ops on by adding to our out buffer
-                        self.out_buffer.push(op);
+                        if !seen {
+                            self.out_buffer.push(op);
+                        }
                         progress = Some(true);
                     } else {
                         self.pending_buffer.add(op);
@@ -264,24 +384,26 @@ impl Node for BigsetNode {
     }
 
     fn cardinality(&self) -> usize {
-        // tired and lazy, use members for now
-        self.members().len()
+        self.rt.block_on(async {
+            match self.server.scard(SET_NAME, None).await.unwrap() {
+                bigsets::CommandResult::Integer(count) => count as usize,
+                _ => panic!("Unexpected command result"),
+            }
+        })
     }
 
     fn is_member(&self, elem: &Bytes) -> bool {
-        self.members().contains(elem)
+        self.rt.block_on(async {
+            match self.server.sismember(SET_NAME, elem, None).await.unwrap() {
+                bigsets::CommandResult::Integer(found) => found == 1,
+                _ => panic!("Unexpected command result"),
+            }
+        })
     }
 
     fn new(id: u16) -> Self {
         let actor_id = ActorId::from_node_id(id);
-        let temp_dir = TempDir::new().unwrap();
-        let db_path = temp_dir.path().join("test.db");
-        let config = StorageConfig {
-            sqlite_cache_size: 1000,
-            sqlite_busy_timeout: 5000,
-        };
-
-        let storage = Arc::new(SqliteStorage::open(&db_path, &config).unwrap());
+        let (storage, temp_dir) = S::create();
 
         let rt = tokio::runtime::Runtime::new().unwrap();
         let server = rt.block_on(async {
@@ -342,6 +464,10 @@ impl<N: Node> Cluster<N> {
             .get(&id)
             .map_or(BTreeSet::new(), |node| node.members())
     }
+
+    fn node_cardinality(&self, id: NodeId) -> usize {
+        self.nodes.get(&id).map_or(0, |node| node.cardinality())
+    }
 }
 
 impl ReferenceStateMachine for Cluster<ModelNode> {
@@ -426,7 +552,7 @@ impl ReferenceStateMachine for Cluster<ModelNode> {
     }
 }
 
-impl StateMachineTest for Cluster<BigsetNode> {
+impl<S: Backend> StateMachineTest for Cluster<BigsetNode<S>> {
     type SystemUnderTest = Self;
     type Reference = Cluster<ModelNode>;
 
@@ -457,18 +583,48 @@ impl StateMachineTest for Cluster<BigsetNode> {
         ref_state: &<Self::Reference as ReferenceStateMachine>::State,
     ) {
         for id in state.nodes.keys() {
-            let node_members = state.node_members(*id);
-            let ref_node_members = ref_state.node_members(*id);
-
-            assert_eq!(
-                node_members, ref_node_members,
-                "{:?}: {:?}",
-                id, ref_state.nodes[id]
-            );
+            check_node(state, ref_state, *id);
         }
     }
 }
 
+/// Members, SCARD and SISMEMBER of the node under test must all agree with the model
+fn check_node<S: Backend>(
+    sut_state: &Cluster<BigsetNode<S>>,
+    ref_state: &Cluster<ModelNode>,
+    id: NodeId,
+) {
+    let node_members = sut_state.node_members(id);
+    let ref_node_members = ref_state.node_members(id);
+
+    assert_eq!(
+        node_members, ref_node_members,
+        "{:?}: {:?}",
+        id, ref_state.nodes[&id]
+    );
+
+    assert_eq!(
+        sut_state.node_cardinality(id),
+        node_members.len(),
+        "{:?}: SCARD disagrees with SMEMBERS",
+        id
+    );
+    assert_eq!(
+        sut_state.node_cardinality(id),
+        ref_state.node_cardinality(id),
+        "{:?}: SCARD disagrees with the model",
+        id
+    );
+    for elem in &ref_node_members {
+        assert!(
+            sut_state.nodes[&id].is_member(elem),
+            "{:?}: SISMEMBER misses {:?}",
+            id,
+            elem
+        );
+    }
+}
+
 macro_rules! b {
     ($literal:literal) => {
         Bytes::from(&$literal[..])
@@ -523,10 +679,53 @@ fn regression() {
     run_ce(3, ops);
 }
 
+/// Concurrent adds and removes of the same elements, replicated in different orders,
+/// leave elements whose support is down to the dots of a concurrent add.
+/// SCARD must count exactly the surviving members on every node.
+#[test]
+fn scard_tracks_add_wins_membership() {
+    use Op::*;
+    use SetOp::*;
+    let ops = vec![
+        Update(1, Add(b!(b"a"))),
+        Update(1, Add(b!(b"b"))),
+        Update(1, Add(b!(b"c"))),
+        Replicate(1, 2),
+        Replicate(1, 3),
+        // concurrent: 1 removes a, 2 re-adds a, 3 removes b
+        Update(1, Remove(b!(b"a"))),
+        Update(2, Add(b!(b"a"))),
+        Update(3, Remove(b!(b"b"))),
+        Update(2, Remove(b!(b"c"))),
+        Replicate(1, 3),
+        Replicate(3, 2),
+        Replicate(2, 1),
+        Update(3, Add(b!(b"b"))),
+        Update(1, Remove(b!(b"a"))),
+        Replicate(2, 3),
+        Replicate(1, 2),
+        Replicate(3, 1),
+        Replicate(1, 3),
+        Replicate(2, 1),
+        Replicate(3, 2),
+    ];
+
+    run_ce(3, ops);
+}
+
+/// Runs the counterexample against every backend
 fn run_ce(node_cnt: usize, ops: Vec<Op>) {
-    tracing_subscriber::fmt::init();
+    let _ = tracing_subscriber::fmt().try_init();
+    run_ce_on::<SqliteStorage>(node_cnt, ops.clone());
+    run_ce_on::<ShardedStorage>(node_cnt, ops.clone());
+    #[cfg(feature = "rocksdb")]
+    run_ce_on::<bigsets::RocksdbStorage>(node_cnt, ops.clone());
+    run_ce_on::<MemoryStorage>(node_cnt, ops);
+}
+
+fn run_ce_on<S: Backend>(node_cnt: usize, ops: Vec<Op>) {
     let mut ref_state = Cluster::<ModelNode>::new(node_cnt as u16);
-    let mut sut_state = Cluster::<BigsetNode>::new(node_cnt as u16);
+    let mut sut_state = Cluster::<BigsetNode<S>>::new(node_cnt as u16);
     for op in ops {
         debug!("op: {:?}", op);
         match op {
@@ -541,14 +740,7 @@ fn run_ce(node_cnt: usize, ops: Vec<Op>) {
         }
 
         for id in sut_state.nodes.keys() {
-            let node_members = sut_state.node_members(*id);
-            let ref_node_members = ref_state.node_members(*id);
-
-            assert_eq!(
-                node_members, ref_node_members,
-                "{:?}: {:?}",
-                id, ref_state.nodes[id]
-            );
+            check_node(&sut_state, &ref_state, *id);
         }
     }
 }
@@ -0,0 +1,2087 @@
+use bigsets::config::{
+    ClusterConfig, Compression, Config, ReplicaInfo, ReplicationConfig, ReplicationMode,
+    ReplicationTlsConfig, ServerConfig, StorageConfig,
+};
+use bigsets::network::{InMemoryTransport, NetworkTransport, TlsTransport};
+use bigsets::server::CommandResult;
+use bigsets::types::{ActorId, Dot, OpType, Operation, VersionVector};
+use bigsets::{
+    Node, NodeBuilder, ReplicationListener, ReplicationManager, Server, ServerWrapper,
+    SqliteStorage,
+};
+use bytes::Bytes;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+/// Reserve an ephemeral port on localhost for a replication listener
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+async fn start_server(temp: &TempDir, node_id: u16) -> Arc<Server> {
+    start_server_with(temp, node_id, false).await
+}
+
+/// A node's server, keeping an operation log if `op_log`
+async fn start_server_with(temp: &TempDir, node_id: u16, op_log: bool) -> Arc<Server> {
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let db_path = temp.path().join(format!("node{}.db", node_id));
+    let storage = Arc::new(SqliteStorage::open(&db_path, &config).unwrap());
+    Arc::new(
+        Server::new(ActorId::new(node_id, 0), storage)
+            .await
+            .unwrap(),
+    )
+}
+
+fn replication_config() -> ReplicationConfig {
+    ReplicationConfig {
+        max_retries: 5,
+        retry_backoff_ms: 10,
+        buffer_size: 100,
+        ack_timeout_ms: 50,
+        // Tests that exercise startup learning turn it on explicitly
+        rbilt_startup_delay_ms: 60_000,
+        max_batch: 1,
+        flush_interval_ms: 10,
+        digest_buckets: 16,
+        heartbeat_interval_ms: 1000,
+        heartbeat_miss_limit: 3,
+        sent_log_size: 1000,
+        gc_interval_ms: 60_000,
+        snapshot_lag_threshold: 10_000,
+        mode: ReplicationMode::Op,
+        delta_interval_ms: 100,
+        sync: false,
+        write_quorum: 2,
+        fanout: 0,
+        sync_timeout_ms: 1000,
+        subscriptions: Vec::new(),
+        compression: Compression::None,
+        packed_vv: false,
+        tls: None,
+    }
+}
+
+async fn wait_for_listener(addr: &str) {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("listener on {} never came up", addr);
+}
+
+/// A CA to sign node certificates
+fn tls_ca() -> rcgen::CertifiedIssuer<'static, rcgen::KeyPair> {
+    let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    rcgen::CertifiedIssuer::self_signed(params, rcgen::KeyPair::generate().unwrap()).unwrap()
+}
+
+/// TLS config for a node trusting `ca`, with a certificate for 127.0.0.1 signed by `signer`
+fn tls_config(
+    dir: &Path,
+    ca: &rcgen::CertifiedIssuer<'static, rcgen::KeyPair>,
+    signer: &rcgen::CertifiedIssuer<'static, rcgen::KeyPair>,
+) -> ReplicationTlsConfig {
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = rcgen::CertificateParams::new(vec!["127.0.0.1".to_string()])
+        .unwrap()
+        .signed_by(&key, signer)
+        .unwrap();
+    let config = ReplicationTlsConfig {
+        ca_cert_path: dir.join("ca.pem"),
+        cert_path: dir.join("node.pem"),
+        key_path: dir.join("node.key"),
+        require_client_cert: true,
+    };
+    std::fs::write(&config.ca_cert_path, ca.pem()).unwrap();
+    std::fs::write(&config.cert_path, cert.pem()).unwrap();
+    std::fs::write(&config.key_path, key.serialize_pem()).unwrap();
+    config
+}
+
+async fn members(server: &Server, set_name: &str) -> BTreeSet<Bytes> {
+    match server.smembers(set_name, None).await.unwrap() {
+        CommandResult::BytesArray(members) => members.into_iter().collect(),
+        other => panic!("Expected BytesArray, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_unreachable_peer_catches_up_on_reconnect() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        replication_config(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::new(),
+        replication_config(),
+    ));
+
+    // Node 2 is down: the write is kept for retry
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    let unacked = replication1.unacked_buffer();
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 1);
+
+    // Node 2 comes up
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    // The next write carries the missed one along with it, and both are acked
+    let (_, op) = server1.sadd("myset", &[Bytes::from("bar")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 0);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("foo"), Bytes::from("bar")])
+    );
+}
+
+#[tokio::test]
+async fn test_retransmit_delivers_without_further_writes() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        replication_config(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::new(),
+        replication_config(),
+    ));
+    tokio::spawn(Arc::clone(&replication1).run_retransmit());
+
+    // Node 2 is down when the write happens
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    // The retransmit timer delivers it on its own
+    let unacked = replication1.unacked_buffer();
+    for _ in 0..100 {
+        if unacked.read().await.peer_count(&peer2.actor_id()) == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 0);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("foo")])
+    );
+}
+
+#[tokio::test]
+async fn test_retransmit_gives_up_after_max_retries() {
+    let temp1 = TempDir::new().unwrap();
+    let server1 = start_server(&temp1, 1).await;
+
+    // Nothing ever listens on node 2's address
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: free_addr(),
+    };
+    let config = ReplicationConfig {
+        max_retries: 2,
+        ack_timeout_ms: 0,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        config,
+    ));
+
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    let unacked = replication1.unacked_buffer();
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 1);
+
+    // Two retries, then one more pass drops it: the backoff is 0ms, 10ms, 30ms
+    for _ in 0..2 {
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        replication1.retransmit_overdue().await;
+        assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 1);
+    }
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    replication1.retransmit_overdue().await;
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 0);
+}
+
+#[tokio::test]
+async fn test_retransmit_to_a_stalled_peer_holds_up_no_other() {
+    let temp1 = TempDir::new().unwrap();
+    let temp3 = TempDir::new().unwrap();
+    let addr3 = free_addr();
+    let server1 = start_server(&temp1, 1).await;
+    let server3 = start_server(&temp3, 3).await;
+
+    // Node 2 accepts connections and never answers
+    let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: stalled.local_addr().unwrap().to_string(),
+    };
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = stalled.accept().await {
+            held.push(socket);
+        }
+    });
+    let peer3 = ReplicaInfo {
+        node_id: 3,
+        epoch: 0,
+        addr: addr3.clone(),
+    };
+    let config = ReplicationConfig {
+        ack_timeout_ms: 200,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone(), peer3.clone()]),
+        config.clone(),
+    ));
+
+    // Node 3 is down for the write, node 2 takes it and never acks
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+    let unacked = replication1.unacked_buffer();
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 1);
+    assert_eq!(unacked.read().await.peer_count(&peer3.actor_id()), 1);
+
+    let replication3 = Arc::new(ReplicationManager::new(BTreeSet::new(), config));
+    let listener = ReplicationListener::new(Arc::clone(&server3), replication3, addr3.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr3).await;
+
+    // Once overdue, node 3 is retransmitted to while the resend to node 2 waits, and the
+    // round ends when node 2's send times out rather than waiting on it for good
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let round = tokio::time::timeout(Duration::from_secs(2), replication1.retransmit_overdue());
+    assert!(round.await.is_ok());
+    assert_eq!(unacked.read().await.peer_count(&peer3.actor_id()), 0);
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 1);
+    assert_eq!(
+        members(&server3, "myset").await,
+        BTreeSet::from([Bytes::from("foo")])
+    );
+}
+
+#[tokio::test]
+async fn test_acks_read_before_a_peer_stalls_still_count() {
+    use prost::Message;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let temp1 = TempDir::new().unwrap();
+    let server1 = start_server(&temp1, 1).await;
+    let (_, first) = server1.sadd("s", &[Bytes::from("a")]).await.unwrap();
+    let (_, second) = server1.sadd("s", &[Bytes::from("b")]).await.unwrap();
+    let (first, second) = (first.unwrap(), second.unwrap());
+
+    // Node 2 reads the send through, acks the first operation, then never closes
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: listener.local_addr().unwrap().to_string(),
+    };
+    let ack = bigsets::proto::ack_to_proto(&first).encode_to_vec();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut sink = Vec::new();
+            let _ = socket.read_to_end(&mut sink).await;
+            socket.write_u32(ack.len() as u32 + 1).await.unwrap();
+            socket.write_u8(0).await.unwrap();
+            socket.write_all(&ack).await.unwrap();
+            held.push(socket);
+        }
+    });
+
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        ReplicationConfig {
+            max_batch: 2,
+            ..replication_config()
+        },
+    ));
+    drop(replication1.enqueue(first).await);
+    let sent = tokio::time::timeout(Duration::from_secs(2), replication1.send(second));
+    assert!(sent.await.is_ok());
+
+    // The send timed out waiting for the second ack, but the first is kept
+    let unacked = replication1.unacked_buffer();
+    let left = unacked.read().await;
+    let left = left.get_peer_ops(&peer2.actor_id()).unwrap();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].0.dot().counter, 2);
+    assert_eq!(replication1.metrics().snapshot().acks_received, 1);
+}
+
+#[tokio::test]
+async fn test_one_flush_sends_a_bounded_number_of_operations() {
+    use tokio::io::AsyncReadExt;
+
+    let temp1 = TempDir::new().unwrap();
+    let server1 = start_server(&temp1, 1).await;
+
+    // Node 2 reads each send through and closes without acking
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: listener.local_addr().unwrap().to_string(),
+    };
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut sink = Vec::new();
+            let _ = socket.read_to_end(&mut sink).await;
+        }
+    });
+
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        replication_config(),
+    ));
+    // A long backlog for node 2, as if it had been down a while
+    let (_, op) = server1.sadd("s", &[Bytes::from("a")]).await.unwrap();
+    let op = op.unwrap();
+    {
+        let unacked = replication1.unacked_buffer();
+        let mut unacked = unacked.write().await;
+        for _ in 0..5000 {
+            unacked.add(peer2.actor_id(), op.clone());
+        }
+    }
+
+    replication1.send(op).await.unwrap();
+    assert_eq!(replication1.metrics().snapshot().ops_sent, 4096);
+    assert_eq!(
+        replication1
+            .unacked_buffer()
+            .read()
+            .await
+            .peer_count(&peer2.actor_id()),
+        5001
+    );
+}
+
+#[tokio::test]
+async fn test_retransmits_share_the_peers_one_connection_at_a_time() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+
+    let temp1 = TempDir::new().unwrap();
+    let server1 = start_server(&temp1, 1).await;
+
+    // Node 2 reads each send through, takes a while over it and never acks, keeping
+    // count of the most connections it has had open at once
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: listener.local_addr().unwrap().to_string(),
+    };
+    let (open, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    tokio::spawn({
+        let (open, most) = (Arc::clone(&open), Arc::clone(&most));
+        async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let (open, most) = (Arc::clone(&open), Arc::clone(&most));
+                tokio::spawn(async move {
+                    most.fetch_max(open.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    let mut sink = Vec::new();
+                    let _ = socket.read_to_end(&mut sink).await;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        }
+    });
+
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        ReplicationConfig {
+            ack_timeout_ms: 100,
+            ..replication_config()
+        },
+    ));
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    // The first write is overdue as the second is queued
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let (_, op) = server1.sadd("myset", &[Bytes::from("bar")]).await.unwrap();
+    let sent = replication1.enqueue(op.unwrap()).await;
+    replication1.retransmit_overdue().await;
+    for sent in sent {
+        let _ = sent.await;
+    }
+    assert_eq!(most.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_each_manager_encodes_with_its_own_packed_vv() {
+    use prost::Message;
+    use tokio::io::AsyncReadExt;
+
+    let temp1 = TempDir::new().unwrap();
+    let server1 = start_server(&temp1, 1).await;
+
+    // Node 2 hands over the first frame it receives
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: listener.local_addr().unwrap().to_string(),
+    };
+    let received = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let len = socket.read_u32().await.unwrap() as usize;
+        let mut frame = vec![0; len];
+        socket.read_exact(&mut frame).await.unwrap();
+        frame
+    });
+
+    let packed = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2]),
+        ReplicationConfig {
+            packed_vv: true,
+            ..replication_config()
+        },
+    ));
+    // A manager built later, unpacked, in the same process
+    let _unpacked = ReplicationManager::new(BTreeSet::new(), replication_config());
+
+    server1.sadd("s", &[Bytes::from("a")]).await.unwrap();
+    let (_, op) = server1.sadd("s", &[Bytes::from("b")]).await.unwrap();
+    drop(packed.enqueue(op.unwrap()).await);
+
+    let frame = received.await.unwrap();
+    assert_eq!(frame[0], 0, "uncompressed");
+    let message = bigsets::proto::replication::ReplicationMessage::decode(&frame[1..]).unwrap();
+    let Some(bigsets::proto::replication::replication_message::Payload::Operation(op)) =
+        message.payload
+    else {
+        panic!("expected a lone operation, got {:?}", message.payload);
+    };
+    let context = op.context.unwrap();
+    assert!(context.entries.is_empty());
+    assert!(!context.packed.is_empty());
+}
+
+#[tokio::test]
+async fn test_batched_operations_wait_for_a_full_batch() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let config = ReplicationConfig {
+        max_batch: 3,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        config.clone(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(BTreeSet::new(), config));
+
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    let unacked = replication1.unacked_buffer();
+    for elem in ["a", "b"] {
+        let (_, op) = server1.sadd("myset", &[Bytes::from(elem)]).await.unwrap();
+        replication1.send(op.unwrap()).await.unwrap();
+    }
+
+    // Nothing sent until the batch fills
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 2);
+    assert!(members(&server2, "myset").await.is_empty());
+
+    let (_, op) = server1.sadd("myset", &[Bytes::from("c")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 0);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("a"), Bytes::from("b"), Bytes::from("c")])
+    );
+
+    // A partial batch goes out when the flush interval fires
+    let (_, op) = server1.sadd("myset", &[Bytes::from("d")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 1);
+
+    replication1.flush_queued().await;
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 0);
+    assert!(members(&server2, "myset").await.contains(&Bytes::from("d")));
+}
+
+#[tokio::test]
+async fn test_delta_mode_sends_changed_sets_once_causally_ready() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let temp3 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+    let server3 = start_server(&temp3, 3).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let config = ReplicationConfig {
+        mode: ReplicationMode::Delta,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        config.clone(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(BTreeSet::new(), config));
+
+    // Nothing is sent per operation, and a delta to a node that is down is simply lost
+    for op in [
+        server1
+            .sadd("myset", &[Bytes::from("foo"), Bytes::from("bar")])
+            .await,
+        server1.srem("myset", &[Bytes::from("foo")]).await,
+    ] {
+        let (_, op) = op.unwrap();
+        replication1.send(op.unwrap()).await.unwrap();
+    }
+    assert_eq!(replication1.send_deltas(&server1).await, 0);
+
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    assert_eq!(replication1.send_deltas(&server1).await, 1);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("bar")])
+    );
+    let actor1 = ActorId::new(1, 0);
+    assert_eq!(server2.version_vector().read().await.get(actor1), 2);
+    // Everything was acked, so there is nothing more to send
+    assert_eq!(replication1.send_deltas(&server1).await, 0);
+
+    // Node 1 removes a member node 3 added, before node 2 has node 3's add
+    let (_, op3) = server3.sadd("myset", &[Bytes::from("baz")]).await.unwrap();
+    let op3 = op3.unwrap();
+    assert!(server1.apply_remote_operation(op3.clone()).await.unwrap());
+    let (_, op) = server1.srem("myset", &[Bytes::from("baz")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    // Applying the remove now would let the add back in when it arrives
+    assert_eq!(replication1.send_deltas(&server1).await, 0);
+    assert_eq!(server2.version_vector().read().await.get(actor1), 2);
+
+    assert!(server2.apply_remote_operation(op3).await.unwrap());
+    assert!(
+        members(&server2, "myset")
+            .await
+            .contains(&Bytes::from("baz"))
+    );
+    assert_eq!(replication1.send_deltas(&server1).await, 1);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("bar")])
+    );
+    assert_eq!(server2.version_vector().read().await.get(actor1), 3);
+}
+
+#[tokio::test]
+async fn test_shutdown_makes_a_final_send_of_unacked_operations() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    // Never listening, so its operations can't be delivered
+    let peer3 = ReplicaInfo {
+        node_id: 3,
+        epoch: 0,
+        addr: free_addr(),
+    };
+    let config = ReplicationConfig {
+        max_batch: 3,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone(), peer3.clone()]),
+        config.clone(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(BTreeSet::new(), config));
+
+    let shutdown = CancellationToken::new();
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone())
+        .with_shutdown(shutdown.clone());
+    let listening = tokio::spawn(async move { listener.run().await.is_ok() });
+    wait_for_listener(&addr2).await;
+
+    // Queued, not yet a full batch, and maybe not yet buffered by the senders
+    for elem in ["a", "b"] {
+        let (_, op) = server1.sadd("myset", &[Bytes::from(elem)]).await.unwrap();
+        replication1.enqueue(op.unwrap()).await;
+    }
+
+    assert_eq!(replication1.shutdown().await, 2);
+    let unacked = replication1.unacked_buffer();
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 0);
+    assert_eq!(unacked.read().await.peer_count(&peer3.actor_id()), 2);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("a"), Bytes::from("b")])
+    );
+
+    shutdown.cancel();
+    assert!(listening.await.unwrap());
+    assert!(TcpStream::connect(&addr2).await.is_err());
+}
+
+#[tokio::test]
+async fn test_anti_entropy_fills_in_missing_elements() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr1 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer1 = ReplicaInfo {
+        node_id: 1,
+        epoch: 0,
+        addr: addr1.clone(),
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::new(),
+        replication_config(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer1]),
+        replication_config(),
+    ));
+
+    let listener = ReplicationListener::new(Arc::clone(&server1), replication1, addr1.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+
+    // Node 2 never receives these operations
+    let (_, op_foo) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    let (_, op_bar) = server1.sadd("myset", &[Bytes::from("bar")]).await.unwrap();
+    server1.sadd("other", &[Bytes::from("baz")]).await.unwrap();
+
+    let vv_before = server2.version_vector().read().await.clone();
+    assert_eq!(replication2.sync_set(&server2, "myset").await, 2);
+
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("foo"), Bytes::from("bar")])
+    );
+    // Only the requested set is synchronised, and the VV waits for the ops
+    assert!(members(&server2, "other").await.is_empty());
+    assert_eq!(*server2.version_vector().read().await, vv_before);
+
+    // Nothing more to learn
+    assert_eq!(replication2.sync_set(&server2, "myset").await, 0);
+
+    // When the operations do arrive they apply cleanly over the synced state
+    for op in [op_foo, op_bar] {
+        assert!(server2.apply_remote_operation(op.unwrap()).await.unwrap());
+    }
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("foo"), Bytes::from("bar")])
+    );
+}
+
+#[tokio::test]
+async fn test_down_peer_catches_up_when_heard_from() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr1 = free_addr();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer1 = ReplicaInfo {
+        node_id: 1,
+        epoch: 0,
+        addr: addr1.clone(),
+    };
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let config = ReplicationConfig {
+        heartbeat_interval_ms: 20,
+        heartbeat_miss_limit: 3,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        config.clone(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(BTreeSet::from([peer1]), config));
+
+    let listener1 = ReplicationListener::new(
+        Arc::clone(&server1),
+        Arc::clone(&replication1),
+        addr1.clone(),
+    );
+    tokio::spawn(async move { listener1.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+
+    // Node 2 never heartbeats, so it is marked down
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let status = replication1.peer_status().await;
+    assert_eq!(status.len(), 1);
+    assert!(!status[0].up);
+    assert_eq!(status[0].last_seen, None);
+    assert_eq!(status[0].last_sent, None);
+    assert_eq!(status[0].vv, None);
+
+    // Writes for a down peer are only buffered
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+    assert_eq!(replication1.peer_status().await[0].unacked, 1);
+
+    // Node 2 comes up and starts heartbeating: it is marked up and caught up
+    let listener2 = ReplicationListener::new(
+        Arc::clone(&server2),
+        Arc::clone(&replication2),
+        addr2.clone(),
+    );
+    tokio::spawn(async move { listener2.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+    tokio::spawn(Arc::clone(&replication2).run_heartbeat(Arc::clone(&server2)));
+
+    for _ in 0..100 {
+        if replication1.peer_status().await[0].unacked == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let status = replication1.peer_status().await;
+    assert!(status[0].up);
+    assert!(status[0].last_seen.is_some());
+    assert!(status[0].last_sent.is_some());
+    assert!(status[0].vv.is_some());
+    assert_eq!(status[0].unacked, 0);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("foo")])
+    );
+}
+
+#[tokio::test]
+async fn test_pending_overflow_requests_missing_ops() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr1 = free_addr();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer1 = ReplicaInfo {
+        node_id: 1,
+        epoch: 0,
+        addr: addr1.clone(),
+    };
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        replication_config(),
+    ));
+    // Node 2 can only hold one out-of-order operation
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer1]),
+        ReplicationConfig {
+            buffer_size: 1,
+            ..replication_config()
+        },
+    ));
+
+    // The first two writes are lost on the way to node 2
+    for elem in ["a", "b"] {
+        let (_, op) = server1.sadd("myset", &[Bytes::from(elem)]).await.unwrap();
+        replication1.send(op.unwrap()).await.unwrap();
+    }
+    replication1.unacked_buffer().write().await.clear_all();
+
+    let listener1 = ReplicationListener::new(
+        Arc::clone(&server1),
+        Arc::clone(&replication1),
+        addr1.clone(),
+    );
+    tokio::spawn(async move { listener1.run().await.unwrap() });
+    let listener2 = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener2.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+    wait_for_listener(&addr2).await;
+
+    // The next write is buffered, the one after overflows the buffer
+    for elem in ["c", "d"] {
+        let (_, op) = server1.sadd("myset", &[Bytes::from(elem)]).await.unwrap();
+        replication1.send(op.unwrap()).await.unwrap();
+    }
+
+    // Node 2 asks node 1 for what it is missing and catches up
+    let expected = BTreeSet::from(["a", "b", "c", "d"].map(Bytes::from));
+    for _ in 0..100 {
+        if members(&server2, "myset").await == expected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(members(&server2, "myset").await, expected);
+    assert_eq!(
+        *server2.version_vector().read().await,
+        *server1.version_vector().read().await
+    );
+}
+
+#[tokio::test]
+async fn test_op_log_serves_missing_ops_after_a_restart() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr1 = free_addr();
+
+    let server1 = start_server_with(&temp1, 1, true).await;
+    let server2 = start_server(&temp2, 2).await;
+    let (_, op) = server1.sadd("myset", &[Bytes::from("a")]).await.unwrap();
+    server2.apply_remote_operation(op.unwrap()).await.unwrap();
+    let (_, op) = server1.sadd("myset", &[Bytes::from("b")]).await.unwrap();
+    server1.apply_remote_operation(op.unwrap()).await.unwrap();
+    let (_, op) = server2.sadd("myset", &[Bytes::from("c")]).await.unwrap();
+    server1.apply_remote_operation(op.unwrap()).await.unwrap();
+    drop(server1);
+
+    // Node 1 restarts with nothing in its sent log, but the operation log has it all
+    let server1 = start_server_with(&temp1, 1, true).await;
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::new(),
+        replication_config(),
+    ));
+    let listener = ReplicationListener::new(Arc::clone(&server1), replication1, addr1.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+
+    let peer1 = ReplicaInfo {
+        node_id: 1,
+        epoch: 0,
+        addr: addr1,
+    };
+    let replication2 =
+        ReplicationManager::new(BTreeSet::from([peer1.clone()]), replication_config());
+    let vv = server2.version_vector().read().await.clone();
+    let (ops, complete) = replication2
+        .request_missing_ops(peer1.actor_id(), &vv)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(complete);
+    assert_eq!(ops.len(), 1);
+    assert!(
+        server2
+            .apply_remote_operation(ops[0].clone())
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("a"), Bytes::from("b"), Bytes::from("c")])
+    );
+}
+
+#[tokio::test]
+async fn test_rbilt_startup_learns_missed_operations() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr1 = free_addr();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer1 = ReplicaInfo {
+        node_id: 1,
+        epoch: 0,
+        addr: addr1.clone(),
+    };
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2]),
+        replication_config(),
+    ));
+    // Learned in chunks of at most two operations
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer1]),
+        ReplicationConfig {
+            buffer_size: 2,
+            rbilt_startup_delay_ms: 0,
+            ..replication_config()
+        },
+    ));
+
+    // Node 2 saw the first write, then was down for the rest, and node 1 has given up resending them
+    for elem in ["a", "b", "c", "d"] {
+        let (_, op) = server1.sadd("myset", &[Bytes::from(elem)]).await.unwrap();
+        let op = op.unwrap();
+        if elem == "a" {
+            server2.apply_remote_operation(op.clone()).await.unwrap();
+        }
+        replication1.send(op).await.unwrap();
+    }
+    replication1.unacked_buffer().write().await.clear_all();
+
+    let listener1 = ReplicationListener::new(
+        Arc::clone(&server1),
+        Arc::clone(&replication1),
+        addr1.clone(),
+    );
+    tokio::spawn(async move { listener1.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+
+    // Node 2 restarts, refusing reads until it has learned what it missed
+    server2.set_ready(false);
+    assert!(matches!(
+        server2.smembers("myset", None).await.unwrap(),
+        CommandResult::Error(_)
+    ));
+    let listener2 = ReplicationListener::new(Arc::clone(&server2), replication2, addr2);
+    tokio::spawn(async move { listener2.run().await.unwrap() });
+
+    for _ in 0..100 {
+        if server2.is_ready() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(server2.is_ready());
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from(["a", "b", "c", "d"].map(Bytes::from))
+    );
+    assert_eq!(
+        *server2.version_vector().read().await,
+        *server1.version_vector().read().await
+    );
+}
+
+#[tokio::test]
+async fn test_in_memory_transport_replicates_without_sockets() {
+    let network = InMemoryTransport::new();
+    let temps: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let peers: Vec<ReplicaInfo> = (1..=3)
+        .map(|node_id| ReplicaInfo {
+            node_id,
+            epoch: 0,
+            addr: format!("node-{}", node_id),
+        })
+        .collect();
+
+    let mut servers = Vec::new();
+    let mut managers = Vec::new();
+    for (i, temp) in temps.iter().enumerate() {
+        servers.push(start_server(temp, i as u16 + 1).await);
+        let others = peers.iter().filter(|p| p.node_id != i as u16 + 1).cloned();
+        managers.push(Arc::new(ReplicationManager::with_transport(
+            others.collect(),
+            replication_config(),
+            Arc::new(network.clone()),
+        )));
+    }
+
+    // Nodes 1 and 2 are up, node 3 is down
+    for i in 0..2 {
+        let listener = ReplicationListener::new(
+            Arc::clone(&servers[i]),
+            Arc::clone(&managers[i]),
+            peers[i].addr.clone(),
+        );
+        tokio::spawn(async move { listener.run().await.unwrap() });
+    }
+    for peer in &peers[..2] {
+        while network.connect(&peer.addr).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    let (_, op) = servers[0]
+        .sadd("myset", &[Bytes::from("foo")])
+        .await
+        .unwrap();
+    managers[0].send(op.unwrap()).await.unwrap();
+
+    assert_eq!(
+        members(&servers[1], "myset").await,
+        BTreeSet::from([Bytes::from("foo")])
+    );
+    let unacked = managers[0].unacked_buffer();
+    assert_eq!(unacked.read().await.peer_count(&peers[1].actor_id()), 0);
+    assert_eq!(unacked.read().await.peer_count(&peers[2].actor_id()), 1);
+
+    // Node 3 comes up and catches up with the next write
+    let listener = ReplicationListener::new(
+        Arc::clone(&servers[2]),
+        Arc::clone(&managers[2]),
+        peers[2].addr.clone(),
+    );
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    while network.connect(&peers[2].addr).await.is_err() {
+        tokio::task::yield_now().await;
+    }
+
+    let (_, op) = servers[0]
+        .sadd("myset", &[Bytes::from("bar")])
+        .await
+        .unwrap();
+    managers[0].send(op.unwrap()).await.unwrap();
+
+    assert_eq!(unacked.read().await.peer_count(&peers[2].actor_id()), 0);
+    for server in &servers {
+        assert_eq!(
+            members(server, "myset").await,
+            BTreeSet::from([Bytes::from("foo"), Bytes::from("bar")])
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_heartbeats_establish_stable_vv() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr1 = free_addr();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer1 = ReplicaInfo {
+        node_id: 1,
+        epoch: 0,
+        addr: addr1.clone(),
+    };
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let config = ReplicationConfig {
+        heartbeat_interval_ms: 20,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2]),
+        config.clone(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(BTreeSet::from([peer1]), config));
+
+    let listener1 = ReplicationListener::new(
+        Arc::clone(&server1),
+        Arc::clone(&replication1),
+        addr1.clone(),
+    );
+    tokio::spawn(async move { listener1.run().await.unwrap() });
+    let listener2 = ReplicationListener::new(
+        Arc::clone(&server2),
+        Arc::clone(&replication2),
+        addr2.clone(),
+    );
+    tokio::spawn(async move { listener2.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+    wait_for_listener(&addr2).await;
+
+    // Nothing is stable before node 2 has reported what it has seen
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+    let vv1 = server1.version_vector().read().await.clone();
+    assert!(replication1.stable_vv(&vv1).await.counters.is_empty());
+
+    tokio::spawn(Arc::clone(&replication2).run_heartbeat(Arc::clone(&server2)));
+
+    for _ in 0..100 {
+        if replication1.stable_vv(&vv1).await == vv1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(replication1.stable_vv(&vv1).await, vv1);
+
+    // The one write was sent, acked and applied, and node 2 has reported it
+    let sent = replication1.metrics().snapshot();
+    assert_eq!((sent.ops_sent, sent.acks_received), (1, 1));
+    let received = replication2.metrics().snapshot();
+    assert_eq!((received.ops_applied, received.ops_buffered), (1, 0));
+    assert_eq!(
+        replication1.peer_lag(ActorId::new(2, 0), &vv1).await,
+        Some(0)
+    );
+    assert_eq!(replication2.peer_lag(ActorId::new(1, 0), &vv1).await, None);
+}
+
+#[tokio::test]
+async fn test_compressed_frames_replicate() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let config = ReplicationConfig {
+        compression: Compression::Zstd { level: 3 },
+        packed_vv: false,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2.clone()]),
+        config.clone(),
+    ));
+    let replication2 = Arc::new(ReplicationManager::new(BTreeSet::new(), config));
+
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    // Large enough to be compressed, and acked over the same connection
+    let elements: Vec<Bytes> = (0..1000)
+        .map(|i| Bytes::from(format!("member:{}", i)))
+        .collect();
+    let (_, op) = server1.sadd("myset", &elements).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+
+    let unacked = replication1.unacked_buffer();
+    assert_eq!(unacked.read().await.peer_count(&peer2.actor_id()), 0);
+    assert_eq!(
+        members(&server2, "myset").await,
+        elements.into_iter().collect()
+    );
+}
+
+#[tokio::test]
+async fn test_new_node_takes_a_state_transfer() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr1 = free_addr();
+    let addr2 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer1 = ReplicaInfo {
+        node_id: 1,
+        epoch: 0,
+        addr: addr1.clone(),
+    };
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+
+    // Node 1 has history from before node 2 joined, in several snapshot chunks
+    let big: Vec<Bytes> = (0..2500).map(|i| Bytes::from(format!("m{}", i))).collect();
+    server1.sadd("big", &big).await.unwrap();
+    server1.sadd("small", &[Bytes::from("x")]).await.unwrap();
+    server1.srem("small", &[Bytes::from("x")]).await.unwrap();
+    server1.sadd("small", &[Bytes::from("y")]).await.unwrap();
+
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer2]),
+        replication_config(),
+    ));
+    let listener1 = ReplicationListener::new(
+        Arc::clone(&server1),
+        Arc::clone(&replication1),
+        addr1.clone(),
+    );
+    tokio::spawn(async move { listener1.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+
+    // Node 2 joins with an empty database
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([peer1]),
+        ReplicationConfig {
+            rbilt_startup_delay_ms: 0,
+            ..replication_config()
+        },
+    ));
+    server2.set_ready(false);
+    let listener2 = ReplicationListener::new(Arc::clone(&server2), replication2, addr2);
+    tokio::spawn(async move { listener2.run().await.unwrap() });
+
+    for _ in 0..200 {
+        if server2.is_ready() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(server2.is_ready());
+    assert_eq!(
+        members(&server2, "big").await,
+        big.into_iter().collect::<BTreeSet<_>>()
+    );
+    assert_eq!(
+        members(&server2, "small").await,
+        BTreeSet::from([Bytes::from("y")])
+    );
+    assert_eq!(
+        *server2.version_vector().read().await,
+        *server1.version_vector().read().await
+    );
+
+    // And it is a full replica from then on
+    let (_, op) = server2.sadd("small", &[Bytes::from("z")]).await.unwrap();
+    assert!(server1.apply_remote_operation(op.unwrap()).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_cluster_meet_and_forget_change_peers_at_runtime() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+    let addr3 = free_addr();
+
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    // Node 1 starts alone, and has history before it meets anyone
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::new(),
+        replication_config(),
+    ));
+    let wrapper1 = ServerWrapper::new(Arc::clone(&server1), Arc::clone(&replication1));
+    server1.sadd("myset", &[Bytes::from("old")]).await.unwrap();
+
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::new(),
+        replication_config(),
+    ));
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    // Meeting node 2 with a transfer pushes it the history
+    assert!(matches!(
+        wrapper1.cluster_meet(&addr2, 2, true).await.unwrap(),
+        CommandResult::Ok { vv: None }
+    ));
+    for _ in 0..100 {
+        if !members(&server2, "myset").await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("old")])
+    );
+
+    // Node 3 is met but never comes up, so its operations wait for acks
+    wrapper1.cluster_meet(&addr3, 3, false).await.unwrap();
+    wrapper1
+        .sadd("myset", &[Bytes::from("new")], None)
+        .await
+        .unwrap();
+    let peer3 = ActorId::new(3, 0);
+    let unacked = replication1.unacked_buffer();
+    for _ in 0..100 {
+        if members(&server2, "myset").await.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(unacked.read().await.peer_count(&peer3), 1);
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("old"), Bytes::from("new")])
+    );
+    assert!(
+        wrapper1
+            .info(Some("replication"))
+            .await
+            .contains("peer1:node_id=3")
+    );
+
+    // Forgetting node 3 drops what was waiting for it
+    assert!(matches!(
+        wrapper1.cluster_forget(3).await.unwrap(),
+        CommandResult::Ok { vv: None }
+    ));
+    assert_eq!(unacked.read().await.peer_count(&peer3), 0);
+    assert_eq!(replication1.peers().await.len(), 1);
+    assert!(
+        !wrapper1
+            .info(Some("replication"))
+            .await
+            .contains("node_id=3")
+    );
+    assert!(matches!(
+        wrapper1.cluster_forget(3).await.unwrap(),
+        CommandResult::Error(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_synchronous_writes_wait_for_peer_acks() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let config = ReplicationConfig {
+        sync: true,
+        sync_timeout_ms: 200,
+        ..replication_config()
+    };
+    let replication1 = Arc::new(ReplicationManager::new(BTreeSet::from([peer2]), config));
+    let wrapper1 = ServerWrapper::new(Arc::clone(&server1), replication1);
+
+    // Nobody is listening yet: committed here, but not acked in time
+    match wrapper1
+        .sadd("myset", &[Bytes::from("a")], None)
+        .await
+        .unwrap()
+    {
+        CommandResult::Degraded {
+            vv: Some(_),
+            acked: 0,
+            needed: 1,
+        } => {}
+        other => panic!("Expected Degraded, got {:?}", other),
+    }
+    assert_eq!(
+        members(&server1, "myset").await,
+        BTreeSet::from([Bytes::from("a")])
+    );
+
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::new(),
+        replication_config(),
+    ));
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    // Once the write replies OK the peer has it, the earlier one included
+    assert!(matches!(
+        wrapper1
+            .srem("myset", &[Bytes::from("a")], None)
+            .await
+            .unwrap(),
+        CommandResult::Ok { vv: Some(_) }
+    ));
+    assert!(matches!(
+        wrapper1
+            .sadd("myset", &[Bytes::from("b")], None)
+            .await
+            .unwrap(),
+        CommandResult::Ok { vv: Some(_) }
+    ));
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("b")])
+    );
+}
+
+#[tokio::test]
+async fn test_causal_read_that_is_not_ready_repairs_the_reader() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+
+    // Node 2 writes while node 1 is unreachable, so node 1 never gets the operation
+    let replication2 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([ReplicaInfo {
+            node_id: 1,
+            epoch: 0,
+            addr: free_addr(),
+        }]),
+        replication_config(),
+    ));
+    let wrapper2 = ServerWrapper::new(Arc::clone(&server2), Arc::clone(&replication2));
+    wrapper2
+        .sadd("myset", &[Bytes::from("x")], None)
+        .await
+        .unwrap();
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    let replication1 = Arc::new(ReplicationManager::new(
+        BTreeSet::from([ReplicaInfo {
+            node_id: 2,
+            epoch: 0,
+            addr: addr2,
+        }]),
+        replication_config(),
+    ));
+    let wrapper1 = ServerWrapper::new(Arc::clone(&server1), Arc::clone(&replication1));
+
+    // A client that read from node 2 reads from node 1
+    let client_vv = server2.version_vector().read().await.clone();
+    assert!(matches!(
+        wrapper1.smembers("myset", Some(&client_vv)).await.unwrap(),
+        CommandResult::NotReady(_)
+    ));
+    assert_eq!(replication1.metrics().snapshot().read_repairs, 1);
+
+    let mut result = None;
+    for _ in 0..100 {
+        match wrapper1.smembers("myset", Some(&client_vv)).await.unwrap() {
+            CommandResult::BytesArray(members) => {
+                result = Some(members);
+                break;
+            }
+            _ => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+    assert_eq!(result, Some(vec![Bytes::from("x")]));
+}
+
+#[tokio::test]
+async fn test_fanout_pushes_to_some_peers_and_the_rest_catch_up() {
+    let temps = [
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    ];
+    let addrs = [free_addr(), free_addr(), free_addr()];
+    let config = ReplicationConfig {
+        sync: true,
+        write_quorum: 2,
+        fanout: 1,
+        heartbeat_interval_ms: 50,
+        ..replication_config()
+    };
+    let peers = |node_id: u16| -> BTreeSet<ReplicaInfo> {
+        (1..=3)
+            .filter(|&n| n != node_id)
+            .map(|n| ReplicaInfo {
+                node_id: n,
+                epoch: 0,
+                addr: addrs[n as usize - 1].clone(),
+            })
+            .collect()
+    };
+
+    let mut servers = Vec::new();
+    let mut managers = Vec::new();
+    for node_id in 1..=3u16 {
+        let server = start_server(&temps[node_id as usize - 1], node_id).await;
+        let replication = Arc::new(ReplicationManager::new(peers(node_id), config.clone()));
+        let addr = addrs[node_id as usize - 1].clone();
+        let listener =
+            ReplicationListener::new(Arc::clone(&server), Arc::clone(&replication), addr.clone());
+        tokio::spawn(async move { listener.run().await.unwrap() });
+        wait_for_listener(&addr).await;
+        servers.push(server);
+        managers.push(replication);
+    }
+    // Node 1 serves node 3's catch-up from what it sent, so it shares the writer's manager
+    let replication1 = Arc::clone(&managers[0]);
+    let wrapper1 = ServerWrapper::new(Arc::clone(&servers[0]), Arc::clone(&replication1));
+
+    // Pushed to node 2 only, which is all the quorum needs
+    assert!(matches!(
+        wrapper1
+            .sadd("myset", &[Bytes::from("a")], None)
+            .await
+            .unwrap(),
+        CommandResult::Ok { vv: Some(_) }
+    ));
+    assert_eq!(
+        members(&servers[1], "myset").await,
+        BTreeSet::from([Bytes::from("a")])
+    );
+    assert!(members(&servers[2], "myset").await.is_empty());
+
+    // Node 1's heartbeat shows node 3 what it is missing, and node 3 pulls it
+    tokio::spawn(Arc::clone(&replication1).run_heartbeat(Arc::clone(&servers[0])));
+    for _ in 0..100 {
+        if !members(&servers[2], "myset").await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        members(&servers[2], "myset").await,
+        BTreeSet::from([Bytes::from("a")])
+    );
+}
+
+#[tokio::test]
+async fn test_peers_are_only_sent_the_sets_they_subscribe_to() {
+    let (temp1, temp2) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let (addr1, addr2) = (free_addr(), free_addr());
+    let peer = |node_id: u16, addr: &str| {
+        BTreeSet::from([ReplicaInfo {
+            node_id,
+            epoch: 0,
+            addr: addr.to_string(),
+        }])
+    };
+
+    let server1 = start_server(&temp1, 1).await;
+    let replication1 = Arc::new(ReplicationManager::new(
+        peer(2, &addr2),
+        replication_config(),
+    ));
+    let listener1 = ReplicationListener::new(
+        Arc::clone(&server1),
+        Arc::clone(&replication1),
+        addr1.clone(),
+    );
+    tokio::spawn(async move { listener1.run().await.unwrap() });
+    wait_for_listener(&addr1).await;
+
+    let server2 = start_server(&temp2, 2).await;
+    let replication2 = Arc::new(ReplicationManager::new(
+        peer(1, &addr1),
+        ReplicationConfig {
+            subscriptions: vec!["user:*".to_string()],
+            heartbeat_interval_ms: 50,
+            ..replication_config()
+        },
+    ));
+    let listener2 = ReplicationListener::new(
+        Arc::clone(&server2),
+        Arc::clone(&replication2),
+        addr2.clone(),
+    );
+    tokio::spawn(async move { listener2.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    // Node 1 learns what node 2 replicates from its heartbeats
+    tokio::spawn(Arc::clone(&replication2).run_heartbeat(Arc::clone(&server2)));
+    for _ in 0..100 {
+        if replication1.peer_status().await[0].vv.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let wrapper1 = ServerWrapper::new(Arc::clone(&server1), Arc::clone(&replication1));
+    for (set_name, member) in [("user:1", "a"), ("order:1", "b"), ("user:2", "c")] {
+        wrapper1
+            .sadd(set_name, &[Bytes::from(member)], None)
+            .await
+            .unwrap();
+    }
+
+    let vv1 = server1.version_vector().read().await.clone();
+    for _ in 0..100 {
+        if *server2.version_vector().read().await == vv1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // The order was elided, so node 2's version vector still moved past it
+    assert_eq!(*server2.version_vector().read().await, vv1);
+    assert_eq!(
+        members(&server2, "user:1").await,
+        BTreeSet::from([Bytes::from("a")])
+    );
+    assert!(members(&server2, "order:1").await.is_empty());
+    assert_eq!(
+        members(&server2, "user:2").await,
+        BTreeSet::from([Bytes::from("c")])
+    );
+}
+
+// Several workers, so writes that were dispatched independently could overtake each other
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_operations_leave_for_a_peer_in_order() {
+    use bigsets::proto::replication::ReplicationMessage;
+    use bigsets::proto::replication::replication_message::Payload;
+    use prost::Message;
+    use tokio::io::AsyncReadExt;
+
+    let network = InMemoryTransport::new();
+    let temp1 = TempDir::new().unwrap();
+    let server1 = start_server(&temp1, 1).await;
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: "node-2".to_string(),
+    };
+    let replication1 = Arc::new(ReplicationManager::with_transport(
+        BTreeSet::from([peer2.clone()]),
+        replication_config(),
+        Arc::new(network.clone()),
+    ));
+    let wrapper1 = ServerWrapper::new(Arc::clone(&server1), Arc::clone(&replication1));
+
+    // Node 2 records the counter of every operation it is sent, and never acks
+    let mut listener = network.bind(&peer2.addr).await.unwrap();
+    let (received_tx, mut received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let received_tx = received_tx.clone();
+            tokio::spawn(async move {
+                while let Ok(len) = conn.read_u32().await {
+                    let mut frame = vec![0u8; len as usize];
+                    conn.read_exact(&mut frame).await.unwrap();
+                    assert_eq!(frame[0], 0, "raw frames only");
+                    let ops = match ReplicationMessage::decode(&frame[1..]).unwrap().payload {
+                        Some(Payload::Operation(op)) => vec![op],
+                        Some(Payload::Batch(batch)) => batch.operations,
+                        _ => vec![],
+                    };
+                    for op in &ops {
+                        let op = bigsets::proto::proto_to_operation(op).unwrap();
+                        received_tx.send(op.dot().counter).unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    // Rapid writes, none waiting for replication
+    for i in 0..50 {
+        wrapper1
+            .sadd("myset", &[Bytes::from(format!("m{}", i))], None)
+            .await
+            .unwrap();
+    }
+
+    // Unacked operations are resent with each flush, but each is first seen in order
+    let mut first_seen = Vec::new();
+    while first_seen.len() < 50 {
+        let counter = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        if !first_seen.contains(&counter) {
+            first_seen.push(counter);
+        }
+    }
+    assert_eq!(first_seen, (1..=50).collect::<Vec<u64>>());
+}
+
+#[tokio::test]
+async fn test_health_reports_readiness_and_a_full_pending_buffer() {
+    let temp = TempDir::new().unwrap();
+    let server = start_server(&temp, 1).await;
+    let mut config = replication_config();
+    config.buffer_size = 10;
+    let replication = Arc::new(ReplicationManager::new(BTreeSet::new(), config));
+    let wrapper = ServerWrapper::new(Arc::clone(&server), Arc::clone(&replication));
+
+    assert!(matches!(
+        wrapper.health().await,
+        CommandResult::Ok { vv: None }
+    ));
+
+    server.set_ready(false);
+    assert!(matches!(
+        wrapper.health().await,
+        CommandResult::Error(msg) if msg.starts_with("LOADING")
+    ));
+    server.set_ready(true);
+
+    // Operations from an actor whose earlier operations never arrived
+    let actor = ActorId::new(2, 0);
+    for counter in 2..=10 {
+        let mut context = VersionVector::new();
+        context.update(actor, counter - 1);
+        replication.pending_buffer().write().await.add(Operation {
+            set_name: "s".to_string(),
+            op_type: OpType::Add {
+                elements: vec![Bytes::from("x")],
+                dot: Dot::new(actor, counter),
+                removed_dots: vec![],
+                expires_at: None,
+            },
+            context: Arc::new(context),
+            trace_context: Default::default(),
+        });
+    }
+    assert!(matches!(
+        wrapper.health().await,
+        CommandResult::Error(msg) if msg == "DEGRADED pending buffer 9/10 full"
+    ));
+}
+
+#[tokio::test]
+async fn test_tls_replication_refuses_peers_without_a_cluster_certificate() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let temp3 = TempDir::new().unwrap();
+    let addr2 = free_addr();
+
+    let ca = tls_ca();
+    let server1 = start_server(&temp1, 1).await;
+    let server2 = start_server(&temp2, 2).await;
+    let server3 = start_server(&temp3, 3).await;
+
+    let peer2 = ReplicaInfo {
+        node_id: 2,
+        epoch: 0,
+        addr: addr2.clone(),
+    };
+    let replication1 = Arc::new(ReplicationManager::with_transport(
+        BTreeSet::from([peer2.clone()]),
+        replication_config(),
+        Arc::new(TlsTransport::new(&tls_config(temp1.path(), &ca, &ca)).unwrap()),
+    ));
+    let replication2 = Arc::new(ReplicationManager::with_transport(
+        BTreeSet::new(),
+        replication_config(),
+        Arc::new(TlsTransport::new(&tls_config(temp2.path(), &ca, &ca)).unwrap()),
+    ));
+    // Trusts the cluster's CA, but its own certificate is signed by another
+    let rogue = Arc::new(ReplicationManager::with_transport(
+        BTreeSet::from([peer2.clone()]),
+        replication_config(),
+        Arc::new(TlsTransport::new(&tls_config(temp3.path(), &ca, &tls_ca())).unwrap()),
+    ));
+
+    let listener = ReplicationListener::new(Arc::clone(&server2), replication2, addr2.clone());
+    tokio::spawn(async move { listener.run().await.unwrap() });
+    wait_for_listener(&addr2).await;
+
+    let (_, op) = server1.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+    assert_eq!(
+        replication1
+            .unacked_buffer()
+            .read()
+            .await
+            .peer_count(&peer2.actor_id()),
+        0
+    );
+
+    let (_, op) = server3
+        .sadd("myset", &[Bytes::from("forged")])
+        .await
+        .unwrap();
+    rogue.send(op.unwrap()).await.unwrap();
+    assert_eq!(
+        rogue
+            .unacked_buffer()
+            .read()
+            .await
+            .peer_count(&peer2.actor_id()),
+        1
+    );
+
+    // The refused handshake didn't stop the listener
+    let (_, op) = server1.sadd("myset", &[Bytes::from("bar")]).await.unwrap();
+    replication1.send(op.unwrap()).await.unwrap();
+    assert_eq!(
+        members(&server2, "myset").await,
+        BTreeSet::from([Bytes::from("foo"), Bytes::from("bar")])
+    );
+}
+
+/// The config of a node built with `NodeBuilder`, one of `replicas`
+fn node_config(replica: &ReplicaInfo, replicas: &[ReplicaInfo], db_path: &Path) -> Config {
+    Config {
+        server: ServerConfig {
+            node_id: replica.node_id,
+            epoch: 0,
+            api_addr: free_addr(),
+            replication_addr: replica.addr.clone(),
+            db_path: db_path.to_path_buf(),
+            slowlog_threshold_ms: 0,
+            slowlog_max_len: 128,
+            log_level: "info".to_string(),
+            tls: None,
+            http_addr: None,
+            read_buffer_size: 4096,
+            max_request_size: 64 * 1024 * 1024,
+            max_connections: 10_000,
+        },
+        cluster: ClusterConfig {
+            replicas: replicas.to_vec(),
+        },
+        replication: ReplicationConfig {
+            rbilt_startup_delay_ms: 0,
+            ..replication_config()
+        },
+        storage: StorageConfig {
+            sqlite_cache_size: 1000,
+            sqlite_busy_timeout: 5000,
+            read_pool_size: 5,
+            pool_min_idle: 1,
+            checkpoint_interval_ms: 0,
+            bloom_min_elements: 0,
+            bloom_fp_rate: 0.01,
+            max_key_length: 1024,
+            max_element_size: 65536,
+            max_members_per_command: 100_000,
+            expiry_interval_ms: 1000,
+            op_log: false,
+            mmap_size: 0,
+            page_size: 0,
+            encryption: None,
+        },
+    }
+}
+
+#[tokio::test]
+async fn test_nodes_built_from_config_replicate() {
+    let temps = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let replicas: Vec<ReplicaInfo> = (1..=2)
+        .map(|node_id| ReplicaInfo {
+            node_id,
+            epoch: 0,
+            addr: free_addr(),
+        })
+        .collect();
+
+    let mut nodes = Vec::new();
+    for (replica, temp) in replicas.iter().zip(&temps) {
+        let config = node_config(
+            replica,
+            &replicas,
+            &temp.path().join("data").join("node.db"),
+        );
+        nodes.push(Arc::new(NodeBuilder::new(config).build().await.unwrap()));
+    }
+    let running: Vec<_> = nodes
+        .iter()
+        .map(|node| {
+            let node = Arc::clone(node);
+            tokio::spawn(async move { node.run().await.is_ok() })
+        })
+        .collect();
+    for replica in &replicas {
+        wait_for_listener(&replica.addr).await;
+    }
+
+    nodes[0]
+        .wrapper()
+        .sadd("myset", &[Bytes::from("a")], None)
+        .await
+        .unwrap();
+    let server2 = nodes[1].server();
+    for _ in 0..100 {
+        if server2.is_ready() && !members(server2, "myset").await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        members(server2, "myset").await,
+        BTreeSet::from([Bytes::from("a")])
+    );
+
+    for node in &nodes {
+        node.shutdown_token().cancel();
+    }
+    for run in running {
+        assert!(run.await.unwrap(), "run should return Ok on shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_node_that_lost_its_data_restarts_at_a_new_epoch() {
+    let temps = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let replicas: Vec<ReplicaInfo> = (1..=2)
+        .map(|node_id| ReplicaInfo {
+            node_id,
+            epoch: 0,
+            addr: free_addr(),
+        })
+        .collect();
+    let config = |index: usize, db_path: &Path| {
+        let mut config = node_config(&replicas[index], &replicas, db_path);
+        config.replication.heartbeat_interval_ms = 50;
+        config
+    };
+    let start = |node: Arc<Node>| tokio::spawn(async move { node.run().await.is_ok() });
+
+    let node2 = Arc::new(
+        NodeBuilder::new(config(1, &temps[1].path().join("node.db")))
+            .build()
+            .await
+            .unwrap(),
+    );
+    let running2 = start(Arc::clone(&node2));
+    wait_for_listener(&replicas[1].addr).await;
+
+    let lost = TempDir::new().unwrap();
+    let node1 = Arc::new(
+        NodeBuilder::new(config(0, &lost.path().join("node.db")))
+            .build()
+            .await
+            .unwrap(),
+    );
+    assert_eq!(node1.server().actor_id(), ActorId::new(1, 0));
+    let running1 = start(Arc::clone(&node1));
+    wait_for_listener(&replicas[0].addr).await;
+    node1
+        .wrapper()
+        .sadd("myset", &[Bytes::from("a")], None)
+        .await
+        .unwrap();
+    for _ in 0..100 {
+        if !members(node2.server(), "myset").await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    node1.shutdown_token().cancel();
+    assert!(running1.await.unwrap());
+    drop(node1);
+    drop(lost);
+
+    // Node 2 has seen v0:1:0 write, which the new database knows nothing of
+    let db_path = temps[0].path().join("node.db");
+    let node1 = Arc::new(NodeBuilder::new(config(0, &db_path)).build().await.unwrap());
+    assert_eq!(node1.server().actor_id(), ActorId::new(1, 1));
+    let running1 = start(Arc::clone(&node1));
+    wait_for_listener(&replicas[0].addr).await;
+    for _ in 0..100 {
+        let peers = node2.replication().peers().await;
+        if peers.iter().all(|p| p.epoch == 1) && node1.server().is_ready() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    node1
+        .wrapper()
+        .sadd("myset", &[Bytes::from("b")], None)
+        .await
+        .unwrap();
+    for _ in 0..100 {
+        if members(node2.server(), "myset").await.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        members(node2.server(), "myset").await,
+        BTreeSet::from([Bytes::from("a"), Bytes::from("b")])
+    );
+    assert!(
+        node2
+            .replication()
+            .peers()
+            .await
+            .iter()
+            .all(|p| p.epoch == 1)
+    );
+    let vv = node2.server().version_vector().read().await.clone();
+    assert_eq!(vv.get(ActorId::new(1, 0)), 1);
+    assert_eq!(vv.get(ActorId::new(1, 1)), 1);
+
+    // A restart that keeps the database keeps the epoch
+    node1.shutdown_token().cancel();
+    assert!(running1.await.unwrap());
+    drop(node1);
+    let node1 = NodeBuilder::new(config(0, &db_path)).build().await.unwrap();
+    assert_eq!(node1.server().actor_id(), ActorId::new(1, 1));
+
+    node2.shutdown_token().cancel();
+    assert!(running2.await.unwrap());
+}
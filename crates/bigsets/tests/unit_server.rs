@@ -1,8 +1,9 @@
 use bigsets::config::StorageConfig;
-use bigsets::types::ActorId;
-use bigsets::{Server, SqliteStorage};
+use bigsets::types::{ActorId, Dot, OpType, Operation, VersionVector};
+use bigsets::{BigsetError, CommandResult, Limits, Server, SqliteStorage, Storage};
 use bytes::Bytes;
 use std::sync::Arc;
+use std::time::Duration;
 use tempfile::TempDir;
 
 #[tokio::test]
@@ -13,6 +14,19 @@ async fn test_server_sadd_returns_operation() {
     let config = StorageConfig {
         sqlite_cache_size: 1000,
         sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
     };
 
     let storage = Arc::new(SqliteStorage::open(&db_path, &config).unwrap());
@@ -63,10 +77,23 @@ async fn test_server_apply_remote_operation() {
     let config = StorageConfig {
         sqlite_cache_size: 1000,
         sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
     };
 
-    let storage1 = Arc::new(SqliteStorage::open(&temp1.path().join("node1.db"), &config).unwrap());
-    let storage2 = Arc::new(SqliteStorage::open(&temp2.path().join("node2.db"), &config).unwrap());
+    let storage1 = Arc::new(SqliteStorage::open(temp1.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp2.path().join("node2.db"), &config).unwrap());
 
     let server1 = Server::new(ActorId::new(1, 0), storage1).await.unwrap();
     let server2 = Server::new(ActorId::new(2, 0), storage2).await.unwrap();
@@ -101,3 +128,1148 @@ async fn test_server_apply_remote_operation() {
         _ => panic!("Expected BytesArray result"),
     }
 }
+
+#[tokio::test]
+async fn test_server_sync_concurrent_add_wins() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+
+    let storage1 = Arc::new(SqliteStorage::open(temp1.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp2.path().join("node2.db"), &config).unwrap());
+
+    let server1 = Server::new(ActorId::new(1, 0), storage1).await.unwrap();
+    let server2 = Server::new(ActorId::new(2, 0), storage2).await.unwrap();
+
+    let x = vec![Bytes::from("x")];
+
+    // Both servers see x added by server 1
+    let (_, add1) = server1.sadd("myset", &x).await.unwrap();
+    assert!(matches!(
+        server2.apply_remote_operation(add1.unwrap()).await,
+        Ok(true)
+    ));
+
+    // Server 1 removes x while server 2 concurrently re-adds it
+    let (_, rem1) = server1.srem("myset", &x).await.unwrap();
+    let (_, add2) = server2.sadd("myset", &x).await.unwrap();
+
+    // Anti-entropy in both directions: server 1 picks up the concurrent add
+    let vv1 = server1.version_vector().read().await.clone();
+    let (vv2, delta) = server2.elements_since("myset", &vv1, &[], 1).await.unwrap();
+    assert_eq!(delta.len(), 1);
+    assert_eq!(server1.merge_sync("myset", &vv2, &delta).await.unwrap(), 1);
+
+    let vv2 = server2.version_vector().read().await.clone();
+    let (vv1_after, delta) = server1.elements_since("myset", &vv2, &[], 1).await.unwrap();
+    assert!(delta.is_empty());
+    assert_eq!(
+        server2
+            .merge_sync("myset", &vv1_after, &delta)
+            .await
+            .unwrap(),
+        0
+    );
+
+    // The version vectors are untouched by the merge
+    assert_eq!(*server1.version_vector().read().await, vv1);
+
+    // The ops still arrive later, and replaying them is harmless
+    assert!(matches!(
+        server2.apply_remote_operation(rem1.unwrap()).await,
+        Ok(true)
+    ));
+    assert!(matches!(
+        server1.apply_remote_operation(add2.unwrap()).await,
+        Ok(true)
+    ));
+
+    for server in [&server1, &server2] {
+        assert_eq!(
+            server.smembers("myset", None).await.unwrap(),
+            bigsets::server::CommandResult::BytesArray(x.clone())
+        );
+        assert_eq!(
+            server.scard("myset", None).await.unwrap(),
+            bigsets::server::CommandResult::Integer(1)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_digests_localise_divergence() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+
+    let storage1 = Arc::new(SqliteStorage::open(temp1.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp2.path().join("node2.db"), &config).unwrap());
+
+    let server1 = Server::new(ActorId::new(1, 0), Arc::clone(&storage1))
+        .await
+        .unwrap();
+    let server2 = Server::new(ActorId::new(2, 0), Arc::clone(&storage2))
+        .await
+        .unwrap();
+
+    // Same state reached in a different order on each replica
+    let members: Vec<Bytes> = (0..20).map(|i| Bytes::from(format!("m{}", i))).collect();
+    let (_, op) = server1.sadd("myset", &members).await.unwrap();
+    server2.apply_remote_operation(op.unwrap()).await.unwrap();
+
+    assert_eq!(
+        storage1.set_digest("myset").unwrap(),
+        storage2.set_digest("myset").unwrap()
+    );
+    assert_eq!(
+        server1.bucket_digests("myset", 8).await.unwrap(),
+        server2.bucket_digests("myset", 8).await.unwrap()
+    );
+
+    // One new element only changes its own bucket
+    let extra = Bytes::from("extra");
+    server1
+        .sadd("myset", std::slice::from_ref(&extra))
+        .await
+        .unwrap();
+
+    assert_ne!(
+        storage1.set_digest("myset").unwrap(),
+        storage2.set_digest("myset").unwrap()
+    );
+    let digests1 = server1.bucket_digests("myset", 8).await.unwrap();
+    let digests2 = server2.bucket_digests("myset", 8).await.unwrap();
+    let differing: Vec<u32> = (0..8)
+        .filter(|&b| digests1[b as usize] != digests2[b as usize])
+        .collect();
+    assert_eq!(differing, vec![bigsets::storage::digest_bucket(&extra, 8)]);
+
+    // And the element-level sync for that bucket carries just the new element
+    let vv2 = server2.version_vector().read().await.clone();
+    let (_, delta) = server1
+        .elements_since("myset", &vv2, &differing, 8)
+        .await
+        .unwrap();
+    assert_eq!(delta.len(), 1);
+    assert_eq!(delta[0].0, extra);
+}
+
+#[tokio::test]
+async fn test_stable_dots_are_garbage_collected() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+
+    let storage1 = Arc::new(SqliteStorage::open(temp1.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp2.path().join("node2.db"), &config).unwrap());
+
+    let server1 = Server::new(ActorId::new(1, 0), storage1).await.unwrap();
+    let server2 = Server::new(ActorId::new(2, 0), storage2).await.unwrap();
+
+    // Concurrent adds of x leave it supported by two dots on both replicas
+    let x = vec![Bytes::from("x")];
+    let (_, add1) = server1.sadd("myset", &x).await.unwrap();
+    let (_, add2) = server2.sadd("myset", &x).await.unwrap();
+    server2.apply_remote_operation(add1.unwrap()).await.unwrap();
+    server1.apply_remote_operation(add2.unwrap()).await.unwrap();
+
+    let dot_count = |server: &Server| {
+        let server = server.clone();
+        async move {
+            let (_, elements) = server
+                .elements_since("myset", &bigsets::VersionVector::new(), &[], 1)
+                .await
+                .unwrap();
+            elements.iter().map(|(_, dots)| dots.len()).sum::<usize>()
+        }
+    };
+    assert_eq!(dot_count(&server1).await, 2);
+
+    // Nothing is collected until the dots are known to be stable
+    assert_eq!(server1.compact_dots(None).await.unwrap(), 0);
+
+    let vv = server1.version_vector().read().await.clone();
+    assert_eq!(vv, *server2.version_vector().read().await);
+    for server in [&server1, &server2] {
+        server.set_stable_vv(vv.clone()).await;
+        assert_eq!(server.compact_dots(None).await.unwrap(), 1);
+        assert_eq!(dot_count(server).await, 1);
+    }
+
+    // A remove of x carries no removed dots, the receiver finds them through the context
+    let (_, rem) = server1.srem("myset", &x).await.unwrap();
+    let rem = rem.unwrap();
+    match &rem.op_type {
+        bigsets::types::OpType::Remove { removed_dots, .. } => assert!(removed_dots.is_empty()),
+        _ => panic!("Expected Remove operation"),
+    }
+    server2.apply_remote_operation(rem).await.unwrap();
+
+    for server in [&server1, &server2] {
+        assert_eq!(
+            server.smembers("myset", None).await.unwrap(),
+            bigsets::server::CommandResult::BytesArray(vec![])
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_duplicate_operation_is_not_reapplied() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+
+    let storage1 = Arc::new(SqliteStorage::open(temp1.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp2.path().join("node2.db"), &config).unwrap());
+
+    let server1 = Server::new(ActorId::new(1, 0), storage1).await.unwrap();
+    let server2 = Server::new(ActorId::new(2, 0), storage2).await.unwrap();
+
+    let (_, add) = server1
+        .sadd("myset", &[Bytes::from("x"), Bytes::from("y")])
+        .await
+        .unwrap();
+    let add = add.unwrap();
+    assert!(server2.apply_remote_operation(add.clone()).await.unwrap());
+
+    // Node 2 removes x after seeing the add, so re-applying the add would resurrect it
+    server2.srem("myset", &[Bytes::from("x")]).await.unwrap();
+    let before = server2.snapshot().await.unwrap();
+
+    // The same add delivered again is acknowledged, and storage is unchanged
+    assert!(server2.apply_remote_operation(add).await.unwrap());
+    assert_eq!(server2.snapshot().await.unwrap(), before);
+    assert_eq!(
+        server2.smembers("myset", None).await.unwrap(),
+        bigsets::server::CommandResult::BytesArray(vec![Bytes::from("y")])
+    );
+}
+
+#[tokio::test]
+async fn test_multi_set_add_replicates_as_one_operation() {
+    let temp1 = TempDir::new().unwrap();
+    let temp2 = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+
+    let storage1 = Arc::new(SqliteStorage::open(temp1.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp2.path().join("node2.db"), &config).unwrap());
+
+    let server1 = Server::new(ActorId::new(1, 0), storage1).await.unwrap();
+    let server2 = Server::new(ActorId::new(2, 0), storage2).await.unwrap();
+
+    let (_, add) = server1.sadd("a", &[Bytes::from("x")]).await.unwrap();
+    assert!(server2.apply_remote_operation(add.unwrap()).await.unwrap());
+
+    let (x, y) = ([Bytes::from("x")], [Bytes::from("y")]);
+    let (_, multi) = server2
+        .sadd_multi(&[("a", &x), ("b", &y), ("c", &[])])
+        .await
+        .unwrap();
+    let multi = multi.unwrap();
+    assert_eq!(multi.set_names(), vec!["a", "b"]);
+    match &multi.op_type {
+        bigsets::OpType::AddMulti { sets, .. } => {
+            assert_eq!(
+                sets[0].removed_dots.len(),
+                1,
+                "x's add on node 1 is replaced"
+            );
+            assert!(sets[1].removed_dots.is_empty());
+        }
+        other => panic!("Expected AddMulti, got {:?}", other),
+    }
+    // One dot for the whole batch
+    assert_eq!(
+        server2
+            .version_vector()
+            .read()
+            .await
+            .get(ActorId::new(2, 0)),
+        1
+    );
+
+    assert!(server1.apply_remote_operation(multi).await.unwrap());
+    assert_eq!(
+        server1.snapshot().await.unwrap(),
+        server2.snapshot().await.unwrap()
+    );
+    assert_eq!(
+        server1.smembers("b", None).await.unwrap(),
+        bigsets::server::CommandResult::BytesArray(vec![Bytes::from("y")])
+    );
+    assert_eq!(
+        server2.sadd_multi(&[("c", &[])]).await.unwrap().1,
+        None,
+        "nothing to add"
+    );
+}
+
+#[tokio::test]
+async fn test_smembers_stream_matches_smembers() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let server = Server::new(ActorId::new(1, 0), storage).await.unwrap();
+
+    let members: Vec<Bytes> = (0..2500)
+        .map(|i| Bytes::from(format!("member-{}", i)))
+        .collect();
+    server.sadd("big", &members).await.unwrap();
+
+    let mut stream = server
+        .smembers_stream("big", None, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stream.len, 2500);
+    let mut streamed = Vec::new();
+    while let Some(batch) = stream.next_batch().await {
+        let batch = batch.unwrap();
+        assert!(batch.len() <= 1000, "batch of {}", batch.len());
+        streamed.extend(batch);
+    }
+    assert_eq!(
+        bigsets::server::CommandResult::BytesArray(streamed),
+        server.smembers("big", None).await.unwrap()
+    );
+
+    let mut empty = server
+        .smembers_stream("none", None, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(empty.len, 0);
+    assert!(empty.next_batch().await.is_none());
+
+    server.set_ready(false);
+    assert!(
+        server
+            .smembers_stream("big", None, None)
+            .await
+            .unwrap()
+            .is_err()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_dropped_writes_still_finish_before_the_next() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let server = Arc::new(
+        Server::new(ActorId::new(1, 0), Arc::clone(&storage))
+            .await
+            .unwrap(),
+    );
+
+    // Writers abandoned at random points, racing writers that run to completion
+    let mut tasks = Vec::new();
+    for i in 0..50 {
+        let server = Arc::clone(&server);
+        let task = tokio::spawn(async move {
+            let members = vec![Bytes::from(format!("member-{}", i))];
+            server.sadd("s", &members).await.unwrap();
+        });
+        if i % 2 == 0 {
+            tokio::task::yield_now().await;
+            task.abort();
+        } else {
+            tasks.push(task);
+        }
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    // Every dot the server handed out reached storage before the lock was released
+    server.sadd("s", &[Bytes::from("last")]).await.unwrap();
+    let vv = server.version_vector().read().await.clone();
+    assert_eq!(storage.load_vv().unwrap(), vv);
+}
+
+#[tokio::test]
+async fn test_operations_dont_hold_on_to_the_request_buffer() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let server = Server::new(ActorId::new(1, 0), storage).await.unwrap();
+
+    // One byte members sliced out of a 64KB read buffer, as the RESP parser leaves them
+    let buffer = Bytes::from(vec![b'x'; 64 * 1024]);
+    let members = vec![buffer.slice(0..1), buffer.slice(1..2)];
+    let within = |element: &Bytes| buffer.as_ptr_range().contains(&element.as_ptr());
+
+    let (_, add) = server.sadd("s", &members).await.unwrap();
+    let (_, remove) = server.srem("s", &members[..1]).await.unwrap();
+    for op in [add.unwrap(), remove.unwrap()] {
+        let elements = match &op.op_type {
+            OpType::Add { elements, .. } | OpType::Remove { elements, .. } => elements,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(!elements.is_empty());
+        assert!(!elements.iter().any(within), "{:?}", op.op_type);
+    }
+}
+
+#[tokio::test]
+async fn test_failed_writes_spend_no_dots() {
+    let temp = TempDir::new().unwrap();
+    let db_path = temp.path().join("node1.db");
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(&db_path, &config).unwrap());
+    let actor_id = ActorId::new(1, 0);
+    let server = Server::new(actor_id, Arc::clone(&storage)).await.unwrap();
+    server.sadd("s", &[Bytes::from("a")]).await.unwrap();
+
+    // Every write of a dot fails until the trigger is dropped
+    let injector = rusqlite::Connection::open(&db_path).unwrap();
+    injector
+        .execute_batch(
+            "CREATE TRIGGER fail BEFORE INSERT ON dots BEGIN SELECT RAISE(ABORT, 'injected'); END",
+        )
+        .unwrap();
+    let before = server.version_vector().read().await.clone();
+    assert!(server.sadd("s", &[Bytes::from("b")]).await.is_err());
+    assert!(
+        server
+            .saddex("s", &[Bytes::from("c")], Duration::from_secs(60))
+            .await
+            .is_err()
+    );
+    assert_eq!(*server.version_vector().read().await, before);
+    injector.execute_batch("DROP TRIGGER fail").unwrap();
+
+    // The next write takes the dot after the last one that reached storage
+    let (_, op) = server.sadd("s", &[Bytes::from("b")]).await.unwrap();
+    let OpType::Add { dot, .. } = op.unwrap().op_type else {
+        panic!("SADD should replicate as an add");
+    };
+    assert_eq!(dot, Dot::new(actor_id, before.get(actor_id) + 1));
+    assert_eq!(
+        storage.load_vv().unwrap(),
+        *server.version_vector().read().await
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_writes_get_their_own_dots() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let server = Arc::new(
+        Server::new(ActorId::new(1, 0), Arc::clone(&storage))
+            .await
+            .unwrap(),
+    );
+
+    // Enough at once that the writer commits them in groups
+    let tasks: Vec<_> = (0..200)
+        .map(|i| {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let member = [Bytes::from(format!("member-{}", i % 100))];
+                let (_, op) = if i % 3 == 2 {
+                    server.srem("s", &member).await.unwrap()
+                } else {
+                    server.sadd("s", &member).await.unwrap()
+                };
+                op.map(|op| (op.dot(), op.context))
+            })
+        })
+        .collect();
+
+    let mut dots = Vec::new();
+    for task in tasks {
+        if let Some((dot, context)) = task.await.unwrap() {
+            // The context is everything before the operation's own dot
+            assert_eq!(context.get(dot.actor_id), dot.counter - 1);
+            dots.push(dot.counter);
+        }
+    }
+    let count = dots.len();
+    dots.sort();
+    dots.dedup();
+    assert_eq!(dots.len(), count, "every write has its own dot");
+    assert!(count >= 134, "every add replicates");
+
+    let vv = server.version_vector().read().await.clone();
+    assert_eq!(vv.get(ActorId::new(1, 0)), 200);
+    assert_eq!(storage.load_vv().unwrap(), vv);
+    assert_eq!(
+        storage.count_elements("s").unwrap(),
+        storage.get_elements("s").unwrap().len() as i64
+    );
+}
+
+#[tokio::test]
+async fn test_load_errors_say_what_went_wrong() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("test.db"), &config).unwrap());
+    let server = Server::new(ActorId::new(1, 0), storage).await.unwrap();
+    server.sadd("myset", &[Bytes::from("foo")]).await.unwrap();
+
+    // Our own backup isn't ahead of us
+    let backup = temp.path().join("backup");
+    server.save(&backup).await.unwrap();
+    assert!(matches!(
+        server.load(&backup).await,
+        Err(BigsetError::Causality(_))
+    ));
+
+    assert!(matches!(
+        server.load(&temp.path().join("missing")).await,
+        Err(BigsetError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound
+    ));
+
+    let garbage = temp.path().join("garbage");
+    std::fs::write(&garbage, b"not a backup").unwrap();
+    assert!(matches!(
+        server.load(&garbage).await,
+        Err(BigsetError::Protocol(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_writes_over_the_limits_are_refused() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 8,
+        max_element_size: 4,
+        max_members_per_command: 2,
+        expiry_interval_ms: 1000,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("test.db"), &config).unwrap());
+    let server = Server::new(ActorId::new(1, 0), Arc::clone(&storage))
+        .await
+        .unwrap()
+        .with_limits(Limits::new(&config));
+    let refused = |result: (CommandResult, Option<_>), expected: &str| {
+        assert_eq!(result.0, CommandResult::Error(expected.to_string()));
+        assert!(result.1.is_none(), "a refused write doesn't replicate");
+    };
+
+    refused(
+        server
+            .sadd("longer than 8", &[Bytes::from("a")])
+            .await
+            .unwrap(),
+        "ERR set name is longer than max_key_length (8 bytes)",
+    );
+    refused(
+        server.sadd("s", &[Bytes::from("big!!")]).await.unwrap(),
+        "ERR member is larger than max_element_size (4 bytes)",
+    );
+    let three = [Bytes::from("a"), Bytes::from("b"), Bytes::from("c")];
+    refused(
+        server.sadd("s", &three).await.unwrap(),
+        "ERR too many members, max_members_per_command is 2",
+    );
+    refused(
+        server.srem("s", &three).await.unwrap(),
+        "ERR too many members, max_members_per_command is 2",
+    );
+    refused(
+        server
+            .sadd_multi(&[("s", &three[..2]), ("t", &three[2..])])
+            .await
+            .unwrap(),
+        "ERR too many members, max_members_per_command is 2",
+    );
+    assert_eq!(storage.count_elements("s").unwrap(), 0);
+    assert_eq!(
+        server.version_vector().read().await.get(ActorId::new(1, 0)),
+        0
+    );
+
+    // Removing a member too big to have been added is harmless
+    let (result, _) = server.srem("s", &[Bytes::from("big!!")]).await.unwrap();
+    assert!(matches!(result, CommandResult::Ok { .. }));
+    let (result, _) = server.sadd("s", &three[..2]).await.unwrap();
+    assert!(matches!(result, CommandResult::Ok { .. }));
+    assert_eq!(storage.count_elements("s").unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_expired_members_are_removed_on_every_replica() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 0,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage1 = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp.path().join("node2.db"), &config).unwrap());
+    let server1 = Server::new(ActorId::new(1, 0), Arc::clone(&storage1))
+        .await
+        .unwrap();
+    let server2 = Server::new(ActorId::new(2, 0), Arc::clone(&storage2))
+        .await
+        .unwrap();
+
+    let (_, add) = server1
+        .saddex("s", &[Bytes::from("brief")], Duration::from_millis(1))
+        .await
+        .unwrap();
+    let (_, keep) = server1.sadd("s", &[Bytes::from("kept")]).await.unwrap();
+    server2.apply_remote_operation(add.unwrap()).await.unwrap();
+    server2.apply_remote_operation(keep.unwrap()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // Hidden as soon as it expires, before anything removes it
+    assert!(matches!(
+        server2.scard("s", None).await.unwrap(),
+        CommandResult::Integer(1)
+    ));
+
+    // Any replica may sweep it; whichever remove arrives second finds nothing left
+    let removes1 = server1.expire().await.unwrap();
+    let removes2 = server2.expire().await.unwrap();
+    assert_eq!((removes1.len(), removes2.len()), (1, 1));
+    assert!(server1.expire().await.unwrap().is_empty());
+    for op in removes1 {
+        server2.apply_remote_operation(op).await.unwrap();
+    }
+    for op in removes2 {
+        server1.apply_remote_operation(op).await.unwrap();
+    }
+    for storage in [&storage1, &storage2] {
+        assert_eq!(
+            storage.get_elements("s").unwrap(),
+            vec![Bytes::from("kept")]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_saddnx_only_replicates_what_it_adds() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 0,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let actor_id = ActorId::new(1, 0);
+    let server = Server::new(actor_id, storage).await.unwrap();
+    let (a, b) = (Bytes::from("a"), Bytes::from("b"));
+
+    let (result, op) = server.saddnx("s", std::slice::from_ref(&a)).await.unwrap();
+    assert!(matches!(result, CommandResult::Integer(1)));
+    assert!(op.is_some());
+
+    // Already there: no dot, no operation
+    let (result, op) = server.saddnx("s", std::slice::from_ref(&a)).await.unwrap();
+    assert!(matches!(result, CommandResult::Integer(0)));
+    assert!(op.is_none());
+    assert_eq!(server.version_vector().read().await.get(actor_id), 1);
+
+    // Only the new member, once, goes into the operation
+    let (result, op) = server
+        .saddnx("s", &[a.clone(), b.clone(), b.clone()])
+        .await
+        .unwrap();
+    assert!(matches!(result, CommandResult::Integer(1)));
+    match op.unwrap().op_type {
+        bigsets::types::OpType::Add { elements, dot, .. } => {
+            assert_eq!(elements, vec![b]);
+            assert_eq!(dot.counter, 2);
+        }
+        other => panic!("expected an Add, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_element_dots_and_actors_show_which_adds_keep_members() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 0,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage1 = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp.path().join("node2.db"), &config).unwrap());
+    let (actor1, actor2) = (ActorId::new(1, 0), ActorId::new(2, 0));
+    let server1 = Server::new(actor1, storage1).await.unwrap();
+    let server2 = Server::new(actor2, storage2).await.unwrap();
+    let member = Bytes::from("m");
+
+    // Both add it concurrently, then node 1 removes it before hearing of node 2's add
+    let (_, add1) = server1
+        .sadd("s", std::slice::from_ref(&member))
+        .await
+        .unwrap();
+    let (_, add2) = server2
+        .sadd("s", std::slice::from_ref(&member))
+        .await
+        .unwrap();
+    let (_, rem1) = server1
+        .srem("s", std::slice::from_ref(&member))
+        .await
+        .unwrap();
+    server2.apply_remote_operation(add1.unwrap()).await.unwrap();
+    assert_eq!(
+        server2.element_dots("s", &member).await.unwrap(),
+        vec![Dot::new(actor1, 1), Dot::new(actor2, 1)]
+    );
+    assert_eq!(
+        server2.set_actors("s", None).await.unwrap().unwrap(),
+        vec![actor1, actor2]
+    );
+
+    // The remove only covers node 1's dot, so node 2's keeps the member
+    server2.apply_remote_operation(rem1.unwrap()).await.unwrap();
+    server1.apply_remote_operation(add2.unwrap()).await.unwrap();
+    for server in [&server1, &server2] {
+        assert_eq!(
+            server.element_dots("s", &member).await.unwrap(),
+            vec![Dot::new(actor2, 1)]
+        );
+        assert_eq!(
+            server.set_actors("s", None).await.unwrap().unwrap(),
+            vec![actor2]
+        );
+    }
+    assert!(
+        server1
+            .element_dots("s", &Bytes::from("absent"))
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_scard_counts_members_with_a_surviving_dot() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 0,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage1 = Arc::new(SqliteStorage::open(temp.path().join("node1.db"), &config).unwrap());
+    let storage2 = Arc::new(SqliteStorage::open(temp.path().join("node2.db"), &config).unwrap());
+    let server1 = Server::new(ActorId::new(1, 0), storage1).await.unwrap();
+    let server2 = Server::new(ActorId::new(2, 0), storage2).await.unwrap();
+    let (a, b) = (Bytes::from("a"), Bytes::from("b"));
+    async fn scard(server: &Server) -> i64 {
+        match server.scard("s", None).await.unwrap() {
+            CommandResult::Integer(count) => count,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    let (_, add) = server1.sadd("s", &[a.clone(), b.clone()]).await.unwrap();
+    server2.apply_remote_operation(add.unwrap()).await.unwrap();
+
+    // Node 2 removes the `a` it saw while node 1 adds it again: the new dot survives
+    let (_, remove) = server2.srem("s", std::slice::from_ref(&a)).await.unwrap();
+    let (_, readd) = server1.sadd("s", std::slice::from_ref(&a)).await.unwrap();
+    assert_eq!(scard(&server2).await, 1);
+    server1
+        .apply_remote_operation(remove.unwrap())
+        .await
+        .unwrap();
+    server2
+        .apply_remote_operation(readd.unwrap())
+        .await
+        .unwrap();
+    assert_eq!((scard(&server1).await, scard(&server2).await), (2, 2));
+
+    // Once every dot of `b` is removed it no longer counts
+    let (_, remove) = server1.srem("s", &[b]).await.unwrap();
+    server2
+        .apply_remote_operation(remove.unwrap())
+        .await
+        .unwrap();
+    assert_eq!((scard(&server1).await, scard(&server2).await), (1, 1));
+}
+
+#[tokio::test]
+async fn test_removes_and_reads_of_an_unknown_set_dont_create_it() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 0,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("node2.db"), &config).unwrap());
+    let server = Server::new(ActorId::new(2, 0), Arc::clone(&storage))
+        .await
+        .unwrap();
+    let a = Bytes::from("a");
+
+    // Reads of a set we don't have answer as for an empty one
+    assert!(matches!(
+        server.scard("ghost", None).await.unwrap(),
+        CommandResult::Integer(0)
+    ));
+    server.smembers("ghost", None).await.unwrap();
+    server.sismember("ghost", &a, None).await.unwrap();
+    assert!(!server.set_exists("ghost").await.unwrap());
+
+    // A peer's remove of a set this node never had: its dot is seen, the set isn't made
+    let dot = Dot::new(ActorId::new(1, 0), 1);
+    let remove = Operation {
+        set_name: "ghost".to_string(),
+        op_type: OpType::Remove {
+            elements: vec![a.clone()],
+            dot,
+            removed_dots: vec![],
+        },
+        context: Arc::new(VersionVector::new()),
+        trace_context: Default::default(),
+    };
+    assert!(server.apply_remote_operation(remove).await.unwrap());
+    assert!(!server.set_exists("ghost").await.unwrap());
+    assert!(storage.load_vv().unwrap().contains_dot(dot));
+
+    // Nor is it made by anti-entropy carrying only removals
+    let mut remote_vv = VersionVector::new();
+    remote_vv.update(dot.actor_id, dot.counter);
+    assert_eq!(
+        server
+            .merge_sync("ghost", &remote_vv, &[(a.clone(), vec![])])
+            .await
+            .unwrap(),
+        0
+    );
+    assert!(!server.set_exists("ghost").await.unwrap());
+    assert!(storage.list_sets().unwrap().is_empty());
+
+    // A local remove neither, while its dot is still recorded
+    server
+        .srem("ghost", std::slice::from_ref(&a))
+        .await
+        .unwrap();
+    assert!(!server.set_exists("ghost").await.unwrap());
+    assert!(
+        storage
+            .load_vv()
+            .unwrap()
+            .contains_dot(Dot::new(ActorId::new(2, 0), 1))
+    );
+
+    server.sadd("ghost", &[a]).await.unwrap();
+    assert!(server.set_exists("ghost").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_malformed_operations_are_refused() {
+    let temp = TempDir::new().unwrap();
+    let config = StorageConfig {
+        sqlite_cache_size: 1000,
+        sqlite_busy_timeout: 5000,
+        read_pool_size: 5,
+        pool_min_idle: 1,
+        checkpoint_interval_ms: 0,
+        bloom_min_elements: 0,
+        bloom_fp_rate: 0.01,
+        max_key_length: 1024,
+        max_element_size: 65536,
+        max_members_per_command: 100_000,
+        expiry_interval_ms: 0,
+        op_log: false,
+        mmap_size: 0,
+        page_size: 0,
+        encryption: None,
+    };
+    let storage = Arc::new(SqliteStorage::open(temp.path().join("node2.db"), &config).unwrap());
+    let server = Server::new(ActorId::new(2, 0), Arc::clone(&storage))
+        .await
+        .unwrap();
+    let peer = ActorId::new(1, 0);
+    let add = |dot: Dot, removed_dots: Vec<Dot>, context: VersionVector| Operation {
+        set_name: "s".to_string(),
+        op_type: OpType::Add {
+            elements: vec![Bytes::from("a")],
+            dot,
+            removed_dots,
+            expires_at: None,
+        },
+        context: Arc::new(context),
+        trace_context: Default::default(),
+    };
+    let mut seen = VersionVector::new();
+    seen.update(peer, 3);
+
+    let malformed = [
+        // No event has counter 0
+        add(Dot::new(peer, 0), vec![], VersionVector::new()),
+        // The context is from before the dot, so can't have it
+        add(Dot::new(peer, 3), vec![], seen.clone()),
+        // Nor be more than one event behind it; applied, this would move the VV on to 5
+        add(Dot::new(peer, 5), vec![], VersionVector::new()),
+        add(Dot::new(peer, 6), vec![], seen.clone()),
+        // An operation can't replace itself, or what its actor did after it
+        add(Dot::new(peer, 4), vec![Dot::new(peer, 4)], seen.clone()),
+        add(Dot::new(peer, 4), vec![Dot::new(peer, 5)], seen.clone()),
+        // A dot of this node's it never made
+        add(
+            Dot::new(ActorId::new(2, 0), 1),
+            vec![],
+            VersionVector::new(),
+        ),
+    ];
+    for operation in malformed {
+        assert!(matches!(
+            server.apply_remote_operation(operation).await,
+            Err(BigsetError::InvalidOperation(_))
+        ));
+    }
+    assert!(!server.set_exists("s").await.unwrap());
+    assert_eq!(storage.load_vv().unwrap(), VersionVector::new());
+
+    // Still a causality miss, not an error, for a well formed one that is early
+    let early = add(Dot::new(peer, 4), vec![Dot::new(peer, 3)], seen.clone());
+    assert!(!server.apply_remote_operation(early).await.unwrap());
+    let first = add(Dot::new(peer, 1), vec![], VersionVector::new());
+    assert!(server.apply_remote_operation(first).await.unwrap());
+}